@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+
+use axum::{
+  extract::{Query, State},
+  routing::get,
+  Json, Router,
+};
+use serde_json::{json, Value};
+
+use super::{ApiError, AppState};
+use crate::db::audit::{AuditEntry, AuditQuery};
+
+/// Entries returned when `limit` is not given, and the most one request may ask for.
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 1000;
+
+pub fn router() -> Router<AppState> {
+  Router::new().route("/audit", get(list_entries))
+}
+
+/// The stored JSON is written by MySQL, so it parses; anything else is passed through as a string rather than lost.
+fn parse_json(value: &Option<String>) -> Value {
+  match value {
+    Some(text) => serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.clone())),
+    None => Value::Null,
+  }
+}
+
+fn entry_json(entry: &AuditEntry) -> Value {
+  json!({
+    "id": entry.id,
+    "actor": entry.actor,
+    "entity": entry.entity,
+    "entity_id": entry.entity_id,
+    "operation": entry.operation,
+    "before": parse_json(&entry.before),
+    "after": parse_json(&entry.after),
+    "date_added": entry.date_added,
+  })
+}
+
+/// Newest first, filtered by `entity`, `id`, `actor` (such as `user:3`) and `since` (RFC 3339).
+async fn list_entries(State(state): State<AppState>, Query(params): Query<HashMap<String, String>>) -> Result<Json<Value>, ApiError> {
+  let since = match params.get("since") {
+    Some(since) => Some(
+      chrono::DateTime::parse_from_rfc3339(since)
+        .map_err(|err| ApiError::BadRequest(format!("since: {}", err)))?
+        .to_utc(),
+    ),
+    None => None,
+  };
+  let limit = match params.get("limit") {
+    Some(limit) => limit
+      .parse::<u32>()
+      .map_err(|err| ApiError::BadRequest(format!("limit: {}", err)))?
+      .min(MAX_LIMIT),
+    None => DEFAULT_LIMIT,
+  };
+  let filter = AuditQuery {
+    entity: params.get("entity").cloned(),
+    entity_id: params.get("id").cloned(),
+    actor: params.get("actor").cloned(),
+    since,
+    limit,
+  };
+
+  let mut tx = state.db.conn.begin().await?;
+  let entries = AuditEntry::fetch(&mut tx, &filter).await?;
+  Ok(Json(json!(entries.iter().map(entry_json).collect::<Vec<Value>>())))
+}
@@ -0,0 +1,82 @@
+//! Who a request acts as. The administrator sends `Authorization: Bearer <auth.admin_token>`; readers send the
+//! `x-auth-user` / `x-auth-key` headers of their KOReader sync account, the only credentials they have.
+//!
+//! [`router`](super::router) puts everything but the health probes, metrics, OPDS, KOReader sync and GraphQL, which
+//! checks for itself, behind [`require_admin`], save that the catalog, its files and covers stay readable through
+//! [`require_admin_to_write`] so OPDS clients can follow their links. Either middleware leaves the [`Caller`] in the request's extensions.
+
+use axum::{
+  extract::{Request, State},
+  http::{header, HeaderMap, Method},
+  middleware::Next,
+  response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+
+use super::{ApiError, AppState};
+use crate::db::koreader::KoreaderUser;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Caller {
+  Admin,
+  User(u8),
+}
+
+impl Caller {
+  /// How the caller's changes are credited in the audit log.
+  pub fn actor(&self) -> String {
+    match self {
+      Caller::Admin => String::from("admin"),
+      Caller::User(user_id) => format!("user:{}", user_id),
+    }
+  }
+}
+
+/// The caller the request's credentials name, or `None` if it sent none. Credentials that do not check out are refused
+/// rather than ignored.
+pub async fn identify(state: &AppState, headers: &HeaderMap) -> Result<Option<Caller>, ApiError> {
+  if let Some(authorization) = headers.get(header::AUTHORIZATION) {
+    let token = authorization.to_str().ok().and_then(|value| value.strip_prefix("Bearer ")).map(str::trim);
+    return match token {
+      Some(token) if same_secret(token, &state.config.auth.admin_token) => Ok(Some(Caller::Admin)),
+      _ => Err(ApiError::Unauthorized),
+    };
+  }
+
+  let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok()).filter(|value| !value.is_empty());
+  match (header("x-auth-user"), header("x-auth-key")) {
+    (Some(username), Some(userkey)) => {
+      let mut tx = state.db.conn.begin().await?;
+      let user = KoreaderUser::authenticate(&mut tx, username, userkey).await?.ok_or(ApiError::Unauthorized)?;
+      Ok(Some(Caller::User(user.user_id)))
+    }
+    (None, None) => Ok(None),
+    _ => Err(ApiError::Unauthorized),
+  }
+}
+
+/// Compares digests rather than the secrets themselves, so how long it takes says nothing about how much matched.
+fn same_secret(given: &str, expected: &str) -> bool {
+  !expected.is_empty() && Sha256::digest(given.as_bytes()) == Sha256::digest(expected.as_bytes())
+}
+
+/// Middleware refusing anyone but the administrator.
+pub async fn require_admin(State(state): State<AppState>, mut request: Request, next: Next) -> Response {
+  match identify(&state, request.headers()).await {
+    Ok(Some(Caller::Admin)) => {
+      request.extensions_mut().insert(Caller::Admin);
+      next.run(request).await
+    }
+    Ok(Some(Caller::User(_))) => ApiError::Forbidden.into_response(),
+    Ok(None) => ApiError::Unauthorized.into_response(),
+    Err(err) => err.into_response(),
+  }
+}
+
+/// [`require_admin`] for everything but `GET` and `HEAD`, which anyone may send.
+pub async fn require_admin_to_write(state: State<AppState>, request: Request, next: Next) -> Response {
+  if matches!(*request.method(), Method::GET | Method::HEAD) {
+    return next.run(request).await;
+  }
+  require_admin(state, request, next).await
+}
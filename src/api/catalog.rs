@@ -0,0 +1,252 @@
+//! Reading and editing single catalog records. Responses carry the record's version as a strong `ETag`; edits sent with
+//! `If-Match` only apply if the record is still at that version, and otherwise fail with `412 Precondition Failed` so
+//! two editors cannot silently overwrite each other.
+
+use axum::{
+  extract::{Extension, Path, State},
+  http::{header, HeaderMap, StatusCode},
+  response::{IntoResponse, Response},
+  routing::get,
+  Json, Router,
+};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value};
+
+use super::{auth::Caller, etag, ApiError, AppState};
+use crate::db::{
+  audit::AuditEntry,
+  authors::{Author, PartialAuthor},
+  books::{Book, PartialBook},
+  publisher::{PartialPublisher, Publisher},
+  series::{PartialSeries, Series},
+};
+
+pub fn router() -> Router<AppState> {
+  Router::new()
+    .route("/books/{id}", get(fetch_book).patch(update_book))
+    .route("/authors/{id}", get(fetch_author).patch(update_author))
+    .route("/publishers/{id}", get(fetch_publisher).patch(update_publisher))
+    .route("/series/{id}", get(fetch_series).patch(update_series))
+}
+
+pub fn book_json(book: &Book) -> Value {
+  json!({
+    "id": book.id,
+    "isbn": book.isbn,
+    "name": book.name,
+    "description": book.description,
+    "language": book.language,
+    "nsfw": book.nsfw,
+    "num_pages": book.num_pages,
+    "image_formatted": book.image_formatted,
+    "publisher_id": book.publisher_id,
+    "series_id": book.series_id,
+    "series_index": book.series_index,
+    "date_published": book.date_published,
+    "date_added": book.date_added,
+    "date_last_updated": book.date_last_updated,
+    "deleted_at": book.deleted_at,
+    "version": book.version,
+  })
+}
+
+pub fn author_json(author: &Author) -> Value {
+  json!({
+    "id": author.id,
+    "name": author.name,
+    "description": author.description,
+    "birth": author.birth,
+    "sort_name": author.sort_name,
+    "date_added": author.date_added,
+    "date_last_updated": author.date_last_updated,
+    "deleted_at": author.deleted_at,
+    "version": author.version,
+  })
+}
+
+fn publisher_json(publisher: &Publisher) -> Value {
+  json!({
+    "id": publisher.id,
+    "name": publisher.name,
+    "description": publisher.description,
+    "city": publisher.city,
+    "date_added": publisher.date_added,
+    "date_last_updated": publisher.date_last_updated,
+    "version": publisher.version,
+  })
+}
+
+fn series_json(series: &Series) -> Value {
+  json!({
+    "id": series.id,
+    "name": series.name,
+    "description": series.description,
+    "date_added": series.date_added,
+    "date_last_updated": series.date_last_updated,
+    "version": series.version,
+  })
+}
+
+/// The version an `If-Match` header asks for. No header, or `*`, accepts whatever version the record is at.
+pub(crate) fn if_match(headers: &HeaderMap) -> Result<Option<u32>, ApiError> {
+  let Some(value) = headers.get(header::IF_MATCH) else {
+    return Ok(None);
+  };
+  let value = value
+    .to_str()
+    .map_err(|_| ApiError::BadRequest(String::from("If-Match: not a valid header value")))?
+    .trim();
+  if value == "*" {
+    return Ok(None);
+  }
+  value
+    .strip_prefix('"')
+    .and_then(|value| value.strip_suffix('"'))
+    .and_then(|version| version.parse().ok())
+    .map(Some)
+    .ok_or_else(|| ApiError::BadRequest(format!("If-Match: {} is not an entity tag from this server", value)))
+}
+
+fn tagged(version: u32, body: Value) -> Response {
+  ([(header::ETAG, etag(version))], Json(body)).into_response()
+}
+
+/// [`tagged`], or `304 Not Modified` if the client's `If-None-Match` already names this version.
+pub(crate) fn fetched(headers: &HeaderMap, version: u32, body: Value) -> Response {
+  let tag = etag(version);
+  let unchanged = headers.get(header::IF_NONE_MATCH).and_then(|value| value.to_str().ok()).is_some_and(|value| {
+    value
+      .split(',')
+      .map(|candidate| candidate.trim().trim_start_matches("W/"))
+      .any(|candidate| candidate == "*" || candidate == tag)
+  });
+  if unchanged {
+    return (StatusCode::NOT_MODIFIED, [(header::ETAG, tag)]).into_response();
+  }
+  tagged(version, body)
+}
+
+/// The body's value for `key`, or `current` when the body leaves it out. `null` clears an optional field.
+fn field<T: DeserializeOwned>(body: &Map<String, Value>, key: &str, current: T) -> Result<T, ApiError> {
+  match body.get(key) {
+    Some(value) => serde_json::from_value(value.clone()).map_err(|err| ApiError::BadRequest(format!("{}: {}", key, err))),
+    None => Ok(current),
+  }
+}
+
+async fn fetch_book(State(state): State<AppState>, Path(book_id): Path<u64>, headers: HeaderMap) -> Result<Response, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let book = Book::fetch_one(&mut tx, book_id).await?;
+  Ok(fetched(&headers, book.version, book_json(&book)))
+}
+
+/// Changes the fields present in the body. Without `If-Match` the edit is still checked against the version it was
+/// merged with, so it never undoes a change that landed in between.
+async fn update_book(
+  State(state): State<AppState>,
+  Extension(caller): Extension<Caller>,
+  Path(book_id): Path<u64>,
+  headers: HeaderMap,
+  Json(body): Json<Map<String, Value>>,
+) -> Result<Response, ApiError> {
+  let expected = if_match(&headers)?;
+  let mut tx = state.db.conn.begin().await?;
+  AuditEntry::set_actor(&mut tx, &caller.actor()).await?;
+  let current = Book::fetch_uncached(&mut tx, book_id).await?;
+  let partial = PartialBook {
+    isbn: Some(field(&body, "isbn", current.isbn)?),
+    name: Some(field(&body, "name", current.name)?),
+    description: field(&body, "description", current.description)?,
+    language: field(&body, "language", current.language)?,
+    nsfw: Some(field(&body, "nsfw", current.nsfw)?),
+    num_pages: Some(field(&body, "num_pages", current.num_pages)?),
+    image_formatted: None,
+    publisher_id: field(&body, "publisher_id", current.publisher_id)?,
+    series_id: field(&body, "series_id", current.series_id)?,
+    series_index: field(&body, "series_index", current.series_index)?,
+    date_published: field(&body, "date_published", current.date_published)?,
+  };
+  let book = Book::update_if(&mut tx, book_id, expected.unwrap_or(current.version), partial).await?;
+  tx.commit().await?;
+  Ok(tagged(book.version, book_json(&book)))
+}
+
+async fn fetch_author(State(state): State<AppState>, Path(author_id): Path<u64>, headers: HeaderMap) -> Result<Response, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let author = Author::fetch_one(&mut tx, author_id).await?;
+  Ok(fetched(&headers, author.version, author_json(&author)))
+}
+
+async fn update_author(
+  State(state): State<AppState>,
+  Extension(caller): Extension<Caller>,
+  Path(author_id): Path<u64>,
+  headers: HeaderMap,
+  Json(body): Json<Map<String, Value>>,
+) -> Result<Response, ApiError> {
+  let expected = if_match(&headers)?;
+  let mut tx = state.db.conn.begin().await?;
+  AuditEntry::set_actor(&mut tx, &caller.actor()).await?;
+  let current = Author::fetch_uncached(&mut tx, author_id).await?;
+  let partial = PartialAuthor {
+    name: Some(field(&body, "name", current.name)?),
+    description: field(&body, "description", current.description)?,
+    birth: field(&body, "birth", current.birth)?,
+  };
+  let author = Author::update_if(&mut tx, author_id, expected.unwrap_or(current.version), partial).await?;
+  tx.commit().await?;
+  Ok(tagged(author.version, author_json(&author)))
+}
+
+async fn fetch_publisher(State(state): State<AppState>, Path(publisher_id): Path<u16>, headers: HeaderMap) -> Result<Response, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let publisher = Publisher::fetch_one(&mut tx, publisher_id).await?;
+  Ok(fetched(&headers, publisher.version, publisher_json(&publisher)))
+}
+
+async fn update_publisher(
+  State(state): State<AppState>,
+  Extension(caller): Extension<Caller>,
+  Path(publisher_id): Path<u16>,
+  headers: HeaderMap,
+  Json(body): Json<Map<String, Value>>,
+) -> Result<Response, ApiError> {
+  let expected = if_match(&headers)?;
+  let mut tx = state.db.conn.begin().await?;
+  AuditEntry::set_actor(&mut tx, &caller.actor()).await?;
+  let current = Publisher::fetch_one(&mut tx, publisher_id).await?;
+  let partial = PartialPublisher {
+    name: Some(field(&body, "name", current.name)?),
+    description: Some(field(&body, "description", current.description)?),
+    city: field(&body, "city", current.city)?,
+  };
+  let publisher = Publisher::update_if(&mut tx, publisher_id, expected.unwrap_or(current.version), partial).await?;
+  tx.commit().await?;
+  Ok(tagged(publisher.version, publisher_json(&publisher)))
+}
+
+async fn fetch_series(State(state): State<AppState>, Path(series_id): Path<u64>, headers: HeaderMap) -> Result<Response, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let series = Series::fetch_one(&mut tx, series_id).await?;
+  Ok(fetched(&headers, series.version, series_json(&series)))
+}
+
+async fn update_series(
+  State(state): State<AppState>,
+  Extension(caller): Extension<Caller>,
+  Path(series_id): Path<u64>,
+  headers: HeaderMap,
+  Json(body): Json<Map<String, Value>>,
+) -> Result<Response, ApiError> {
+  let expected = if_match(&headers)?;
+  let mut tx = state.db.conn.begin().await?;
+  AuditEntry::set_actor(&mut tx, &caller.actor()).await?;
+  let current = Series::fetch_one(&mut tx, series_id).await?;
+  let partial = PartialSeries {
+    name: Some(field(&body, "name", current.name)?),
+    description: field(&body, "description", current.description)?,
+  };
+  let series = Series::update_if(&mut tx, series_id, expected.unwrap_or(current.version), partial).await?;
+  tx.commit().await?;
+  Ok(tagged(series.version, series_json(&series)))
+}
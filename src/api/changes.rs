@@ -0,0 +1,130 @@
+use std::{
+  collections::{HashMap, VecDeque},
+  convert::Infallible,
+  time::Duration,
+};
+
+use axum::{
+  extract::{Query, State},
+  http::HeaderMap,
+  response::sse::{Event, KeepAlive, Sse},
+  routing::get,
+  Json, Router,
+};
+use futures_util::{stream, Stream};
+use serde_json::{json, Value};
+
+use super::{ApiError, AppState};
+use crate::db::{changes::ChangeEvent, Db};
+
+/// Events returned when `limit` is not given, and the most one request may ask for.
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 1000;
+
+/// How often an idle stream checks for new events.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn router() -> Router<AppState> {
+  Router::new()
+    .route("/changes", get(list_changes))
+    .route("/changes/head", get(head))
+    .route("/changes/stream", get(stream_changes))
+}
+
+fn event_json(event: &ChangeEvent) -> Value {
+  json!({
+    "offset": event.seq,
+    "entity": event.entity,
+    "entity_id": event.entity_id,
+    "operation": event.operation,
+    "date_added": event.date_added,
+  })
+}
+
+/// The offset to resume after: `Last-Event-ID`, which browsers send when they reconnect a stream, wins over `after`.
+/// Neither means from the start of the feed.
+fn resume_offset(headers: &HeaderMap, params: &HashMap<String, String>) -> Result<u64, ApiError> {
+  let last_event_id = headers.get("last-event-id").and_then(|value| value.to_str().ok());
+  match last_event_id.or(params.get("after").map(String::as_str)) {
+    Some(offset) => offset.trim().parse().map_err(|err| ApiError::BadRequest(format!("after: {}", err))),
+    None => Ok(0),
+  }
+}
+
+/// Numbers the events committed since it last ran, releasing the counter straight away.
+async fn sequence(db: &Db) -> Result<(), sqlx::Error> {
+  let mut tx = db.conn.begin().await?;
+  ChangeEvent::sequence(&mut tx).await?;
+  tx.commit().await
+}
+
+async fn next_batch(db: &Db, after: u64, limit: u32) -> Result<Vec<ChangeEvent>, sqlx::Error> {
+  sequence(db).await?;
+  let mut tx = db.conn.begin().await?;
+  ChangeEvent::fetch_after(&mut tx, after, limit).await
+}
+
+/// One page of events after `after`, oldest first. `next` is the offset to ask for the following page with; it stays
+/// put while nothing new has committed.
+async fn list_changes(State(state): State<AppState>, headers: HeaderMap, Query(params): Query<HashMap<String, String>>) -> Result<Json<Value>, ApiError> {
+  let after = resume_offset(&headers, &params)?;
+  let limit = match params.get("limit") {
+    Some(limit) => limit
+      .parse::<u32>()
+      .map_err(|err| ApiError::BadRequest(format!("limit: {}", err)))?
+      .min(MAX_LIMIT),
+    None => DEFAULT_LIMIT,
+  };
+
+  let events = next_batch(&state.db, after, limit).await?;
+  let next = events.last().map_or(after, |event| event.seq);
+  Ok(Json(json!({
+    "events": events.iter().map(event_json).collect::<Vec<Value>>(),
+    "next": next,
+  })))
+}
+
+/// The newest offset, for consumers that want to start from now rather than replay the feed.
+async fn head(State(state): State<AppState>) -> Result<Json<Value>, ApiError> {
+  sequence(&state.db).await?;
+  let mut tx = state.db.conn.begin().await?;
+  let offset = ChangeEvent::fetch_head(&mut tx).await?;
+  Ok(Json(json!({ "offset": offset })))
+}
+
+/// Server-sent events, each carrying its offset as the event id so a dropped connection resumes where it stopped. The
+/// stream ends on a database error or when the server shuts down; reconnecting picks it up again.
+async fn stream_changes(
+  State(state): State<AppState>,
+  headers: HeaderMap,
+  Query(params): Query<HashMap<String, String>>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+  let after = resume_offset(&headers, &params)?;
+  let start = (state.db, state.shutdown, after, VecDeque::<ChangeEvent>::new());
+  let events = stream::unfold(start, |(db, shutdown, mut after, mut pending)| async move {
+    loop {
+      if shutdown.is_cancelled() {
+        return None;
+      }
+      if let Some(event) = pending.pop_front() {
+        after = event.seq;
+        let sse = Event::default().id(event.seq.to_string()).data(event_json(&event).to_string());
+        return Some((Ok(sse), (db, shutdown, after, pending)));
+      }
+      match next_batch(&db, after, MAX_LIMIT).await {
+        Ok(batch) if batch.is_empty() => {
+          tokio::select! {
+            _ = shutdown.cancelled() => return None,
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+          }
+        }
+        Ok(batch) => pending.extend(batch),
+        Err(err) => {
+          tracing::warn!(error = %err, "change stream stopped");
+          return None;
+        }
+      }
+    }
+  });
+  Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
@@ -0,0 +1,226 @@
+use axum::{
+  body::{Body, Bytes},
+  extract::{DefaultBodyLimit, Extension, Path, Query, State},
+  http::{header, StatusCode},
+  response::Response,
+  routing::{get, post},
+  Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::AsyncReadExt;
+
+use super::{auth::Caller, ApiError, AppState};
+use crate::{
+  covers,
+  db::{
+    audit::AuditEntry,
+    books::Book,
+    covers::{Cover, CoverThumbnail},
+    files::{BookFile, FileFormat},
+  },
+  storage::ORPHAN_GRACE,
+};
+
+/// Largest cover image accepted by an upload.
+const MAX_COVER_SIZE: usize = 32 * 1024 * 1024;
+
+pub fn router() -> Router<AppState> {
+  Router::new()
+    .route(
+      "/books/{id}/cover",
+      get(fetch_cover)
+        .put(upload_cover)
+        .delete(delete_cover)
+        .layer(DefaultBodyLimit::max(MAX_COVER_SIZE)),
+    )
+    .route("/books/{id}/cover/info", get(cover_info))
+    .route("/books/{id}/cover/extract", post(extract_cover))
+}
+
+fn cover_json(cover: &Cover, thumbnails: &[CoverThumbnail]) -> Value {
+  json!({
+    "book_id": cover.book_id,
+    "mime": cover.mime,
+    "width": cover.width,
+    "height": cover.height,
+    "dominant_colour": cover.dominant_colour,
+    "source": cover.source,
+    "thumbnails": thumbnails
+      .iter()
+      .map(|thumbnail| json!({ "size": thumbnail.size, "format": thumbnail.format, "width": thumbnail.width, "height": thumbnail.height }))
+      .collect::<Vec<Value>>(),
+  })
+}
+
+/// Decodes `bytes`, stores the original and its thumbnails, and replaces the book's cover with them, crediting `actor`.
+pub async fn save_cover(state: &AppState, book_id: u64, bytes: Vec<u8>, source: &str, actor: &str) -> Result<Cover, ApiError> {
+  let sizes = state.covers.sizes.clone();
+  let format = state.covers.format;
+  let (bytes, processed) = tokio::task::spawn_blocking(move || {
+    let processed = covers::process(&bytes, &sizes, format);
+    (bytes, processed)
+  })
+  .await
+  .map_err(|err| ApiError::Storage(std::io::Error::other(err)))?;
+  let processed = processed.map_err(|_| ApiError::UnsupportedMediaType)?;
+
+  let storage = &state.covers.storage;
+  let original = storage.store_bytes(&bytes).await?;
+  let mut thumbnails = Vec::with_capacity(processed.thumbnails.len());
+  for thumbnail in &processed.thumbnails {
+    let stored = storage.store_bytes(&thumbnail.bytes).await?;
+    thumbnails.push(CoverThumbnail {
+      book_id,
+      size: thumbnail.size,
+      format: format.as_str().to_string(),
+      sha256: stored.sha256,
+      width: thumbnail.width,
+      height: thumbnail.height,
+    });
+  }
+
+  let mut tx = state.db.conn.begin().await?;
+  AuditEntry::set_actor(&mut tx, actor).await?;
+  let previous = previous_hashes(&mut tx, book_id).await?;
+  let cover = Cover::set(
+    &mut tx,
+    book_id,
+    &original.sha256,
+    processed.mime,
+    processed.width,
+    processed.height,
+    &processed.dominant_colour,
+    source,
+    &thumbnails,
+  )
+  .await?;
+  tx.commit().await?;
+
+  remove_unreferenced(state, previous).await?;
+  Ok(cover)
+}
+
+async fn previous_hashes<'a>(tx: &mut sqlx::Transaction<'a, sqlx::MySql>, book_id: u64) -> Result<Vec<String>, sqlx::Error> {
+  let mut hashes = match Cover::fetch_one(tx, book_id).await {
+    Ok(cover) => vec![cover.sha256],
+    Err(sqlx::Error::RowNotFound) => return Ok(Vec::new()),
+    Err(err) => return Err(err),
+  };
+  hashes.extend(Cover::fetch_thumbnails(tx, book_id).await?.into_iter().map(|thumbnail| thumbnail.sha256));
+  Ok(hashes)
+}
+
+/// Drops blobs a replaced or deleted cover no longer needs. Run after commit so a failed write never loses images; blobs
+/// stored again within the grace period are left for cleanup.
+async fn remove_unreferenced(state: &AppState, hashes: Vec<String>) -> Result<(), ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  for sha256 in hashes {
+    if !Cover::is_referenced(&mut tx, &sha256).await? {
+      state.covers.storage.remove_if_older(&sha256, ORPHAN_GRACE).await?;
+    }
+  }
+  Ok(())
+}
+
+/// Tries each attached ebook in turn until one yields a cover. Returns `None` when none carry an image.
+pub async fn cover_from_files(state: &AppState, book_id: u64, actor: &str) -> Result<Option<Cover>, ApiError> {
+  let files = {
+    let mut tx = state.db.conn.begin().await?;
+    BookFile::fetch_by_book(&mut tx, book_id).await?
+  };
+
+  for file in files {
+    let Some(format) = FileFormat::from_extension(&file.format) else {
+      continue;
+    };
+    let mut bytes = Vec::new();
+    state.storage.open(&file.sha256).await?.read_to_end(&mut bytes).await?;
+    let extracted = tokio::task::spawn_blocking(move || covers::extract(&bytes, format))
+      .await
+      .map_err(|err| ApiError::Storage(std::io::Error::other(err)))?;
+    if let Ok(Some(image)) = extracted {
+      if let Ok(cover) = save_cover(state, book_id, image, "extracted", actor).await {
+        return Ok(Some(cover));
+      }
+    }
+  }
+  Ok(None)
+}
+
+async fn upload_cover(
+  State(state): State<AppState>,
+  Extension(caller): Extension<Caller>,
+  Path(book_id): Path<u64>,
+  body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+  {
+    let mut tx = state.db.conn.begin().await?;
+    Book::fetch_one(&mut tx, book_id).await?;
+  }
+
+  let cover = save_cover(&state, book_id, body.to_vec(), "upload", &caller.actor()).await?;
+  let mut tx = state.db.conn.begin().await?;
+  let thumbnails = Cover::fetch_thumbnails(&mut tx, book_id).await?;
+  Ok(Json(cover_json(&cover, &thumbnails)))
+}
+
+async fn extract_cover(State(state): State<AppState>, Extension(caller): Extension<Caller>, Path(book_id): Path<u64>) -> Result<Json<Value>, ApiError> {
+  let cover = cover_from_files(&state, book_id, &caller.actor()).await?.ok_or(ApiError::NotFound)?;
+  let mut tx = state.db.conn.begin().await?;
+  let thumbnails = Cover::fetch_thumbnails(&mut tx, book_id).await?;
+  Ok(Json(cover_json(&cover, &thumbnails)))
+}
+
+async fn cover_info(State(state): State<AppState>, Path(book_id): Path<u64>) -> Result<Json<Value>, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let cover = Cover::fetch_one(&mut tx, book_id).await?;
+  let thumbnails = Cover::fetch_thumbnails(&mut tx, book_id).await?;
+  Ok(Json(cover_json(&cover, &thumbnails)))
+}
+
+#[derive(Debug, Deserialize)]
+struct CoverParams {
+  size: Option<u32>,
+}
+
+/// Serves the smallest thumbnail at least `size` pixels on its longest edge, falling back to the original.
+async fn fetch_cover(State(state): State<AppState>, Path(book_id): Path<u64>, Query(params): Query<CoverParams>) -> Result<Response, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let cover = Cover::fetch_one(&mut tx, book_id).await?;
+  let thumbnails = Cover::fetch_thumbnails(&mut tx, book_id).await?;
+
+  let thumbnail = params.size.and_then(|size| thumbnails.iter().find(|thumbnail| thumbnail.size >= size));
+  let (sha256, mime) = match thumbnail {
+    Some(thumbnail) => (
+      thumbnail.sha256.clone(),
+      covers::ThumbnailFormat::parse(&thumbnail.format).map_or("application/octet-stream", |format| format.mime()),
+    ),
+    None => (cover.sha256.clone(), cover.mime.as_str()),
+  };
+
+  let mut bytes = Vec::new();
+  state.covers.storage.open(&sha256).await?.read_to_end(&mut bytes).await?;
+  Ok(
+    Response::builder()
+      .header(header::CONTENT_TYPE, mime)
+      .header(header::ETAG, format!("\"{}\"", sha256))
+      .header(header::CACHE_CONTROL, "public, max-age=86400")
+      .body(Body::from(bytes))
+      .expect("valid response headers"),
+  )
+}
+
+async fn delete_cover(State(state): State<AppState>, Extension(caller): Extension<Caller>, Path(book_id): Path<u64>) -> Result<StatusCode, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  AuditEntry::set_actor(&mut tx, &caller.actor()).await?;
+  let previous = previous_hashes(&mut tx, book_id).await?;
+  if previous.is_empty() {
+    return Err(ApiError::NotFound);
+  }
+  Cover::delete(&mut tx, book_id).await?;
+  tx.commit().await?;
+
+  remove_unreferenced(&state, previous).await?;
+  Ok(StatusCode::NO_CONTENT)
+}
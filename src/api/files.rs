@@ -0,0 +1,265 @@
+use std::collections::HashSet;
+
+use axum::{
+  body::Body,
+  extract::{DefaultBodyLimit, Extension, Path, Query, State},
+  http::{header, HeaderMap, HeaderValue, StatusCode},
+  response::{IntoResponse, Response},
+  routing::{get, post},
+  Json, Router,
+};
+use futures_util::Stream;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
+use tokio_util::io::ReaderStream;
+
+use super::{auth::Caller, covers::cover_from_files, ApiError, AppState};
+use crate::{
+  db::{
+    audit::AuditEntry,
+    books::Book,
+    covers::Cover,
+    files::{BookFile, FileFormat},
+    koreader::KoreaderDocument,
+  },
+  storage::ORPHAN_GRACE,
+};
+
+/// Largest ebook accepted by an upload.
+const MAX_UPLOAD_SIZE: usize = 512 * 1024 * 1024;
+
+pub fn router() -> Router<AppState> {
+  Router::new()
+    .route("/books/{id}/files", get(list_files).post(upload).layer(DefaultBodyLimit::max(MAX_UPLOAD_SIZE)))
+    .route("/books/{id}/download", get(download_book))
+    .route("/files/{id}", get(download_file).delete(delete_file))
+    .route("/files/{id}/verify", get(verify_file))
+    .route("/files/verify", post(verify_all))
+    .route("/files/cleanup", post(cleanup))
+}
+
+fn file_json(file: &BookFile) -> Value {
+  json!({
+    "id": file.id,
+    "book_id": file.book_id,
+    "sha256": file.sha256,
+    "format": file.format,
+    "mime": file.mime,
+    "size": file.size,
+    "filename": file.filename,
+    "date_added": file.date_added,
+  })
+}
+
+async fn list_files(State(state): State<AppState>, Path(book_id): Path<u64>) -> Result<Json<Value>, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  Book::fetch_one(&mut tx, book_id).await?;
+  let files = BookFile::fetch_by_book(&mut tx, book_id).await?;
+  Ok(Json(Value::Array(files.iter().map(file_json).collect())))
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadParams {
+  filename: Option<String>,
+}
+
+/// Stores an ebook and attaches it to the book, linking its KOReader hash and extracting a cover when the book has none yet.
+/// The changes are credited to `actor`.
+pub async fn attach_file<S, B, E>(state: &AppState, book_id: u64, body: S, filename: Option<String>, actor: &str) -> Result<BookFile, ApiError>
+where
+  S: Stream<Item = Result<B, E>> + Unpin,
+  B: AsRef<[u8]>,
+  E: std::error::Error + Send + Sync + 'static,
+{
+  let stored = state.storage.store(body).await?;
+  let extension = filename.as_deref().and_then(|name| name.rsplit_once('.')).map(|(_, extension)| extension);
+  let Some(format) = FileFormat::detect(&stored.head).or_else(|| extension.and_then(FileFormat::from_extension)) else {
+    // Left to cleanup while it is within the grace period, since an upload of the same bytes may be attaching it
+    let mut tx = state.db.conn.begin().await?;
+    if !BookFile::is_referenced(&mut tx, &stored.sha256).await? {
+      state.storage.remove_if_older(&stored.sha256, ORPHAN_GRACE).await?;
+    }
+    return Err(ApiError::UnsupportedMediaType);
+  };
+
+  let koreader_hash = state.storage.koreader_hash(&stored.sha256).await?;
+
+  let mut tx = state.db.conn.begin().await?;
+  AuditEntry::set_actor(&mut tx, actor).await?;
+  let file = BookFile::create(&mut tx, book_id, &stored.sha256, format, stored.size, filename).await?;
+  KoreaderDocument::link(&mut tx, &koreader_hash, book_id).await?;
+  let has_cover = Cover::fetch_one(&mut tx, book_id).await.is_ok();
+  tx.commit().await?;
+
+  // A book's first readable ebook provides its cover; the upload itself succeeded either way.
+  if !has_cover {
+    let _ = cover_from_files(state, book_id, actor).await;
+  }
+
+  Ok(file)
+}
+
+async fn upload(
+  State(state): State<AppState>,
+  Extension(caller): Extension<Caller>,
+  Path(book_id): Path<u64>,
+  Query(params): Query<UploadParams>,
+  body: Body,
+) -> Result<Response, ApiError> {
+  {
+    let mut tx = state.db.conn.begin().await?;
+    Book::fetch_one(&mut tx, book_id).await?;
+  }
+
+  let file = attach_file(&state, book_id, body.into_data_stream(), params.filename, &caller.actor()).await?;
+  Ok((StatusCode::CREATED, Json(file_json(&file))).into_response())
+}
+
+/// Serves the book's preferred file: EPUB when there is one, otherwise the first attached. Books in the trash have none.
+async fn download_book(State(state): State<AppState>, Path(book_id): Path<u64>, headers: HeaderMap) -> Result<Response, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  Book::fetch_one(&mut tx, book_id).await?;
+  let files = BookFile::fetch_by_book(&mut tx, book_id).await?;
+  let file = files
+    .iter()
+    .find(|file| file.format == FileFormat::Epub.as_str())
+    .or(files.first())
+    .ok_or(ApiError::NotFound)?;
+  stream_file(&state, file, &headers).await
+}
+
+async fn download_file(State(state): State<AppState>, Path(file_id): Path<u64>, headers: HeaderMap) -> Result<Response, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let file = BookFile::fetch_one(&mut tx, file_id).await?;
+  // A file of a book in the trash is not found, as the book is not
+  Book::fetch_one(&mut tx, file.book_id).await?;
+  stream_file(&state, &file, &headers).await
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+  Full,
+  /// Inclusive start and end offsets.
+  Partial(u64, u64),
+  Unsatisfiable,
+}
+
+/// Parses a single `Range: bytes=…` header. Anything we do not understand, including multiple ranges, falls back to the full body.
+pub fn parse_range(header: Option<&str>, size: u64) -> ByteRange {
+  let Some(spec) = header.and_then(|value| value.trim().strip_prefix("bytes=")) else {
+    return ByteRange::Full;
+  };
+  if spec.contains(',') {
+    return ByteRange::Full;
+  }
+  let Some((start, end)) = spec.trim().split_once('-') else {
+    return ByteRange::Full;
+  };
+
+  let range = match (start.parse::<u64>().ok(), end.parse::<u64>().ok()) {
+    (Some(start), Some(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+    (Some(start), None) if end.is_empty() => (start, size.saturating_sub(1)),
+    (None, Some(suffix)) if start.is_empty() && suffix > 0 => (size.saturating_sub(suffix), size.saturating_sub(1)),
+    _ => return ByteRange::Full,
+  };
+
+  if range.0 >= size {
+    ByteRange::Unsatisfiable
+  } else {
+    ByteRange::Partial(range.0, range.1)
+  }
+}
+
+async fn stream_file(state: &AppState, file: &BookFile, headers: &HeaderMap) -> Result<Response, ApiError> {
+  let mut handle = state.storage.open(&file.sha256).await?;
+  let range = parse_range(headers.get(header::RANGE).and_then(|value| value.to_str().ok()), file.size);
+
+  let filename = file
+    .filename
+    .clone()
+    .unwrap_or_else(|| format!("book-{}.{}", file.book_id, file.format))
+    .replace(['"', '\\', '\r', '\n'], "_");
+  let mut response = Response::builder()
+    .header(header::CONTENT_TYPE, &file.mime)
+    .header(header::ACCEPT_RANGES, "bytes")
+    .header(header::ETAG, format!("\"{}\"", file.sha256))
+    .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename));
+
+  let (start, end) = match range {
+    ByteRange::Full => {
+      response = response.status(StatusCode::OK);
+      (0, file.size.saturating_sub(1))
+    }
+    ByteRange::Partial(start, end) => {
+      response = response
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, file.size));
+      (start, end)
+    }
+    ByteRange::Unsatisfiable => {
+      let mut response = StatusCode::RANGE_NOT_SATISFIABLE.into_response();
+      let content_range = HeaderValue::from_str(&format!("bytes */{}", file.size)).expect("numeric header");
+      response.headers_mut().insert(header::CONTENT_RANGE, content_range);
+      return Ok(response);
+    }
+  };
+
+  let length = if file.size == 0 { 0 } else { end - start + 1 };
+  handle.seek(SeekFrom::Start(start)).await?;
+  let body = Body::from_stream(ReaderStream::new(handle.take(length)));
+
+  Ok(response.header(header::CONTENT_LENGTH, length).body(body).expect("valid response headers"))
+}
+
+async fn delete_file(State(state): State<AppState>, Extension(caller): Extension<Caller>, Path(file_id): Path<u64>) -> Result<StatusCode, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  AuditEntry::set_actor(&mut tx, &caller.actor()).await?;
+  let file = BookFile::fetch_one(&mut tx, file_id).await?;
+  BookFile::delete(&mut tx, file_id).await?;
+  let referenced = BookFile::is_referenced(&mut tx, &file.sha256).await?;
+  tx.commit().await?;
+
+  // A blob uploaded again within the grace period may be about to gain a row, so it is left for cleanup.
+  if !referenced {
+    state.storage.remove_if_older(&file.sha256, ORPHAN_GRACE).await?;
+  }
+  Ok(StatusCode::NO_CONTENT)
+}
+
+async fn verify_file(State(state): State<AppState>, Path(file_id): Path<u64>) -> Result<Json<Value>, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let file = BookFile::fetch_one(&mut tx, file_id).await?;
+  Ok(Json(
+    json!({ "id": file.id, "sha256": file.sha256, "status": verify_status(&state, &file).await? }),
+  ))
+}
+
+async fn verify_status(state: &AppState, file: &BookFile) -> Result<&'static str, ApiError> {
+  if !state.storage.exists(&file.sha256).await? {
+    return Ok("missing");
+  }
+  Ok(if state.storage.verify(&file.sha256).await? { "ok" } else { "corrupt" })
+}
+
+/// Re-hashes every attached file and reports the ones that are missing or no longer match.
+async fn verify_all(State(state): State<AppState>) -> Result<Json<Value>, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let files = BookFile::fetch_all(&mut tx).await?;
+
+  let mut failures = Vec::new();
+  for file in &files {
+    let status = verify_status(&state, file).await?;
+    if status != "ok" {
+      failures.push(json!({ "id": file.id, "book_id": file.book_id, "sha256": file.sha256, "status": status }));
+    }
+  }
+  Ok(Json(json!({ "checked": files.len(), "failures": failures })))
+}
+
+async fn cleanup(State(state): State<AppState>) -> Result<Json<Value>, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let known: HashSet<String> = BookFile::fetch_hashes(&mut tx).await?.into_iter().collect();
+  let removed = state.storage.cleanup_orphans(&known, ORPHAN_GRACE).await?;
+  Ok(Json(json!({ "removed": removed })))
+}
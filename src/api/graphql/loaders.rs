@@ -0,0 +1,180 @@
+//! Batch loaders for the GraphQL resolvers. Every relationship a query walks goes through one of these, so resolving a
+//! field on a hundred books costs one query rather than a hundred.
+
+use std::{
+  collections::{HashMap, HashSet},
+  sync::Arc,
+};
+
+use async_graphql::dataloader::{DataLoader, Loader};
+
+use crate::db::{
+  authors::{Author, Authors},
+  books::{Book, Books},
+  progress::Progress,
+  publisher::Publisher,
+  series::Series,
+  user::User,
+  Db,
+};
+
+/// Loader errors are handed to every resolver waiting on the batch, so they have to be cloneable.
+pub type LoadError = Arc<sqlx::Error>;
+
+pub struct BookLoader(pub Db);
+pub struct AuthorLoader(pub Db);
+pub struct PublisherLoader(pub Db);
+pub struct SeriesLoader(pub Db);
+pub struct UserLoader(pub Db);
+/// A book's authors, by book id.
+pub struct BookAuthorsLoader(pub Db);
+/// An author's books, by author id.
+pub struct AuthorBooksLoader(pub Db);
+/// A publisher's books, by publisher id.
+pub struct PublisherBooksLoader(pub Db);
+/// A user's progress on a book, by `(user_id, book_id)`.
+pub struct ProgressLoader(pub Db);
+/// Everything a user has started, by user id.
+pub struct UserProgressLoader(pub Db);
+
+/// Adds a fresh set of loaders to a request. They cache what they load, so they must not outlive it.
+pub fn attach(request: async_graphql::Request, db: &Db) -> async_graphql::Request {
+  request
+    .data(DataLoader::new(BookLoader(db.clone()), tokio::spawn))
+    .data(DataLoader::new(AuthorLoader(db.clone()), tokio::spawn))
+    .data(DataLoader::new(PublisherLoader(db.clone()), tokio::spawn))
+    .data(DataLoader::new(SeriesLoader(db.clone()), tokio::spawn))
+    .data(DataLoader::new(UserLoader(db.clone()), tokio::spawn))
+    .data(DataLoader::new(BookAuthorsLoader(db.clone()), tokio::spawn))
+    .data(DataLoader::new(AuthorBooksLoader(db.clone()), tokio::spawn))
+    .data(DataLoader::new(PublisherBooksLoader(db.clone()), tokio::spawn))
+    .data(DataLoader::new(ProgressLoader(db.clone()), tokio::spawn))
+    .data(DataLoader::new(UserProgressLoader(db.clone()), tokio::spawn))
+}
+
+/// Groups `rows` under `keys`, giving keys with no rows an empty list rather than leaving them out.
+fn group<K: std::hash::Hash + Eq + Copy, V>(keys: &[K], rows: impl IntoIterator<Item = (K, V)>) -> HashMap<K, Vec<V>> {
+  let mut grouped: HashMap<K, Vec<V>> = keys.iter().map(|key| (*key, Vec::new())).collect();
+  for (key, row) in rows {
+    grouped.entry(key).or_default().push(row);
+  }
+  grouped
+}
+
+impl Loader<u64> for BookLoader {
+  type Value = Book;
+  type Error = LoadError;
+
+  async fn load(&self, keys: &[u64]) -> Result<HashMap<u64, Book>, LoadError> {
+    let mut tx = self.0.conn.begin().await?;
+    let books = Book::fetch_many(&mut tx, keys).await?;
+    Ok(books.into_iter().map(|book| (book.id, book)).collect())
+  }
+}
+
+impl Loader<u64> for AuthorLoader {
+  type Value = Author;
+  type Error = LoadError;
+
+  async fn load(&self, keys: &[u64]) -> Result<HashMap<u64, Author>, LoadError> {
+    let mut tx = self.0.conn.begin().await?;
+    let authors = Author::fetch_many(&mut tx, keys).await?;
+    Ok(authors.into_iter().map(|author| (author.id, author)).collect())
+  }
+}
+
+impl Loader<u16> for PublisherLoader {
+  type Value = Publisher;
+  type Error = LoadError;
+
+  async fn load(&self, keys: &[u16]) -> Result<HashMap<u16, Publisher>, LoadError> {
+    let mut tx = self.0.conn.begin().await?;
+    let publishers = Publisher::fetch_many(&mut tx, keys).await?;
+    Ok(publishers.into_iter().map(|publisher| (publisher.id, publisher)).collect())
+  }
+}
+
+impl Loader<u64> for SeriesLoader {
+  type Value = Series;
+  type Error = LoadError;
+
+  async fn load(&self, keys: &[u64]) -> Result<HashMap<u64, Series>, LoadError> {
+    let mut tx = self.0.conn.begin().await?;
+    let series = Series::fetch_many(&mut tx, keys).await?;
+    Ok(series.into_iter().map(|series| (series.id, series)).collect())
+  }
+}
+
+impl Loader<u8> for UserLoader {
+  type Value = User;
+  type Error = LoadError;
+
+  async fn load(&self, keys: &[u8]) -> Result<HashMap<u8, User>, LoadError> {
+    let mut tx = self.0.conn.begin().await?;
+    let users = User::fetch_many(&mut tx, keys).await?;
+    Ok(users.into_iter().map(|user| (user.id, user)).collect())
+  }
+}
+
+impl Loader<u64> for BookAuthorsLoader {
+  type Value = Authors;
+  type Error = LoadError;
+
+  async fn load(&self, keys: &[u64]) -> Result<HashMap<u64, Authors>, LoadError> {
+    let mut tx = self.0.conn.begin().await?;
+    let authors = Author::fetch_by_books(&mut tx, keys).await?;
+    Ok(group(keys, authors.into_iter().map(|row| (row.book_id, row.author))))
+  }
+}
+
+impl Loader<u64> for AuthorBooksLoader {
+  type Value = Books;
+  type Error = LoadError;
+
+  async fn load(&self, keys: &[u64]) -> Result<HashMap<u64, Books>, LoadError> {
+    let mut tx = self.0.conn.begin().await?;
+    let books = Book::fetch_by_authors(&mut tx, keys).await?;
+    Ok(group(keys, books.into_iter().map(|row| (row.author_id, row.book))))
+  }
+}
+
+impl Loader<u16> for PublisherBooksLoader {
+  type Value = Books;
+  type Error = LoadError;
+
+  async fn load(&self, keys: &[u16]) -> Result<HashMap<u16, Books>, LoadError> {
+    let mut tx = self.0.conn.begin().await?;
+    let books = Book::fetch_by_publishers(&mut tx, keys).await?;
+    Ok(group(keys, books.into_iter().filter_map(|book| Some((book.publisher_id?, book)))))
+  }
+}
+
+impl Loader<(u8, u64)> for ProgressLoader {
+  type Value = Progress;
+  type Error = LoadError;
+
+  /// One query per user in the batch, which in practice is the one viewer.
+  async fn load(&self, keys: &[(u8, u64)]) -> Result<HashMap<(u8, u64), Progress>, LoadError> {
+    let mut tx = self.0.conn.begin().await?;
+    let users: HashSet<u8> = keys.iter().map(|(user_id, _)| *user_id).collect();
+    let mut loaded = HashMap::with_capacity(keys.len());
+    for user_id in users {
+      let book_ids: Vec<u64> = keys.iter().filter(|(user, _)| *user == user_id).map(|(_, book_id)| *book_id).collect();
+      for progress in Progress::fetch_many(&mut tx, user_id, &book_ids).await? {
+        loaded.insert((progress.user_id, progress.book_id), progress);
+      }
+    }
+    Ok(loaded)
+  }
+}
+
+impl Loader<u8> for UserProgressLoader {
+  type Value = Vec<Progress>;
+  type Error = LoadError;
+
+  async fn load(&self, keys: &[u8]) -> Result<HashMap<u8, Vec<Progress>>, LoadError> {
+    let mut tx = self.0.conn.begin().await?;
+    let progress = Progress::fetch_by_users(&mut tx, keys).await?;
+    Ok(group(keys, progress.into_iter().map(|progress| (progress.user_id, progress))))
+  }
+}
@@ -0,0 +1,60 @@
+//! A GraphQL view of the catalog, for clients that want a book with its authors, publisher, series and their own
+//! progress in one round trip. `POST /graphql` runs a query; `GET /graphql` serves GraphiQL to explore the schema.
+//!
+//! Anyone may query. The viewer, whose progress `Book.progress` shows and `updateProgress` moves, is the reader whose
+//! KOReader credentials the request sends (see [`auth`](super::auth)); every other mutation needs the admin token.
+
+use axum::{
+  extract::State,
+  http::HeaderMap,
+  response::{Html, IntoResponse, Response},
+  routing::get,
+  Extension, Json, Router,
+};
+
+use super::{
+  auth::{self, Caller},
+  ApiError, AppState,
+};
+
+pub mod loaders;
+pub mod mutations;
+pub mod types;
+
+pub type LibbySchema = async_graphql::Schema<types::Query, mutations::Mutation, async_graphql::EmptySubscription>;
+
+/// How deeply a query may nest relationships, so one request cannot walk the whole catalog.
+const MAX_DEPTH: usize = 12;
+
+/// The reader a request authenticated as, if any.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Viewer(pub Option<u8>);
+
+pub fn schema() -> LibbySchema {
+  async_graphql::Schema::build(types::Query, mutations::Mutation, async_graphql::EmptySubscription)
+    .limit_depth(MAX_DEPTH)
+    .finish()
+}
+
+pub fn router() -> Router<AppState> {
+  Router::new().route("/graphql", get(graphiql).post(execute)).layer(Extension(schema()))
+}
+
+async fn execute(
+  State(state): State<AppState>,
+  Extension(schema): Extension<LibbySchema>,
+  headers: HeaderMap,
+  Json(request): Json<async_graphql::Request>,
+) -> Result<Response, ApiError> {
+  let caller = auth::identify(&state, &headers).await?;
+  let viewer = Viewer(match caller {
+    Some(Caller::User(user_id)) => Some(user_id),
+    _ => None,
+  });
+  let request = loaders::attach(request, &state.db).data(viewer).data(caller).data(state.db.clone());
+  Ok(Json(schema.execute(request).await).into_response())
+}
+
+async fn graphiql() -> Html<String> {
+  Html(async_graphql::http::GraphiQLSource::build().endpoint("/graphql").finish())
+}
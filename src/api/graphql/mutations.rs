@@ -0,0 +1,317 @@
+//! The write side of the schema. Each mutation is one transaction over the existing `db` functions, so it records the
+//! same audit entries, change events and webhooks as the REST endpoints do.
+//!
+//! Updates follow `PATCH` semantics: fields left out keep their value and `null` clears an optional one. Passing the
+//! `version` that was read makes the update fail with a `CONFLICT` error if the record has changed since.
+
+use async_graphql::{Context, Error, ErrorExtensions, InputObject, MaybeUndefined, Object, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use sqlx::{MySql, Transaction};
+
+use super::Viewer;
+use crate::{
+  api::auth::Caller,
+  db::{
+    audit::AuditEntry,
+    authors::{Author, PartialAuthor},
+    books::{Book, PartialBook},
+    progress::Progress,
+    publisher::{PartialPublisher, Publisher},
+    series::{PartialSeries, Series},
+    user::User,
+    Db, UpdateError,
+  },
+};
+
+/// The field's new value when the input sets it, or `current` when it leaves it out.
+fn or_current<T>(value: MaybeUndefined<T>, current: Option<T>) -> Option<T> {
+  match value {
+    MaybeUndefined::Undefined => current,
+    MaybeUndefined::Null => None,
+    MaybeUndefined::Value(value) => Some(value),
+  }
+}
+
+fn update_error(err: UpdateError) -> Error {
+  match err {
+    UpdateError::Conflict(version) => Error::new("Changed since it was read; fetch it again and reapply the edit").extend_with(|_, extensions| {
+      extensions.set("code", "CONFLICT");
+      extensions.set("version", version);
+    }),
+    UpdateError::Database(err) => err.into(),
+  }
+}
+
+fn forbidden(message: &str) -> Error {
+  Error::new(message).extend_with(|_, extensions| extensions.set("code", "FORBIDDEN"))
+}
+
+/// Opens the transaction of a mutation only the administrator may make, tagged as theirs for the audit log.
+async fn begin<'a>(ctx: &Context<'_>) -> Result<Transaction<'a, MySql>> {
+  if *ctx.data_unchecked::<Option<Caller>>() != Some(Caller::Admin) {
+    return Err(forbidden("Only the administrator can make this change; send the admin token"));
+  }
+  let mut tx = ctx.data_unchecked::<Db>().conn.begin().await?;
+  AuditEntry::set_actor(&mut tx, &Caller::Admin.actor()).await?;
+  Ok(tx)
+}
+
+#[derive(InputObject)]
+pub struct NewBook {
+  isbn: String,
+  name: String,
+  num_pages: u16,
+  description: Option<String>,
+  language: Option<String>,
+  nsfw: Option<bool>,
+  publisher_id: Option<u16>,
+  series_id: Option<u64>,
+  series_index: Option<u16>,
+  date_published: Option<DateTime<Utc>>,
+}
+
+#[derive(InputObject)]
+pub struct BookChanges {
+  isbn: Option<String>,
+  name: Option<String>,
+  num_pages: Option<u16>,
+  description: MaybeUndefined<String>,
+  language: MaybeUndefined<String>,
+  nsfw: Option<bool>,
+  publisher_id: MaybeUndefined<u16>,
+  series_id: MaybeUndefined<u64>,
+  series_index: MaybeUndefined<u16>,
+  date_published: MaybeUndefined<DateTime<Utc>>,
+}
+
+#[derive(InputObject)]
+pub struct NewAuthor {
+  name: String,
+  description: Option<String>,
+  birth: Option<NaiveDate>,
+}
+
+#[derive(Debug, InputObject)]
+pub struct AuthorChanges {
+  name: Option<String>,
+  description: MaybeUndefined<String>,
+  birth: MaybeUndefined<NaiveDate>,
+}
+
+impl AuthorChanges {
+  /// The full set of fields to write over `current`.
+  pub(crate) fn apply_to(self, current: Author) -> PartialAuthor {
+    PartialAuthor {
+      name: self.name,
+      description: or_current(self.description, current.description),
+      birth: or_current(self.birth, current.birth),
+    }
+  }
+}
+
+#[derive(InputObject)]
+pub struct NewPublisher {
+  name: String,
+  description: String,
+  city: Option<String>,
+}
+
+#[derive(InputObject)]
+pub struct PublisherChanges {
+  name: Option<String>,
+  description: Option<String>,
+  city: MaybeUndefined<String>,
+}
+
+#[derive(InputObject)]
+pub struct NewSeries {
+  name: String,
+  description: Option<String>,
+}
+
+#[derive(Debug, InputObject)]
+pub struct SeriesChanges {
+  name: Option<String>,
+  description: MaybeUndefined<String>,
+}
+
+impl SeriesChanges {
+  /// The full set of fields to write over `current`.
+  pub(crate) fn apply_to(self, current: Series) -> PartialSeries {
+    PartialSeries {
+      name: self.name,
+      description: or_current(self.description, current.description),
+    }
+  }
+}
+
+pub struct Mutation;
+
+#[Object]
+impl Mutation {
+  async fn create_book(&self, ctx: &Context<'_>, input: NewBook) -> Result<Book> {
+    let mut tx = begin(ctx).await?;
+    let partial = PartialBook {
+      isbn: Some(input.isbn),
+      name: Some(input.name),
+      description: input.description,
+      language: input.language,
+      nsfw: input.nsfw,
+      num_pages: Some(input.num_pages),
+      image_formatted: None,
+      publisher_id: input.publisher_id,
+      series_id: input.series_id,
+      series_index: input.series_index,
+      date_published: input.date_published,
+    };
+    let book = Book::create_partial(&mut tx, partial).await?;
+    tx.commit().await?;
+    Ok(book)
+  }
+
+  async fn update_book(&self, ctx: &Context<'_>, id: u64, version: Option<u32>, input: BookChanges) -> Result<Book> {
+    let mut tx = begin(ctx).await?;
+    let current = Book::fetch_uncached(&mut tx, id).await?;
+    let partial = PartialBook {
+      isbn: input.isbn.or(Some(current.isbn)),
+      name: input.name.or(Some(current.name)),
+      description: or_current(input.description, current.description),
+      language: or_current(input.language, current.language),
+      nsfw: input.nsfw,
+      num_pages: input.num_pages,
+      image_formatted: None,
+      publisher_id: or_current(input.publisher_id, current.publisher_id),
+      series_id: or_current(input.series_id, current.series_id),
+      series_index: or_current(input.series_index, current.series_index),
+      date_published: or_current(input.date_published, current.date_published),
+    };
+    let book = Book::update_if(&mut tx, id, version.unwrap_or(current.version), partial)
+      .await
+      .map_err(update_error)?;
+    tx.commit().await?;
+    Ok(book)
+  }
+
+  /// Moves the book to the trash, from where it can be restored over REST until it is purged.
+  async fn delete_book(&self, ctx: &Context<'_>, id: u64) -> Result<bool> {
+    let mut tx = begin(ctx).await?;
+    let deleted = Book::delete(&mut tx, id).await?.rows_affected() > 0;
+    tx.commit().await?;
+    Ok(deleted)
+  }
+
+  async fn add_book_author(&self, ctx: &Context<'_>, book_id: u64, author_id: u64) -> Result<Book> {
+    let mut tx = begin(ctx).await?;
+    Author::fetch_one(&mut tx, author_id).await?;
+    Book::add_author(&mut tx, book_id, author_id).await?;
+    let book = Book::fetch_one(&mut tx, book_id).await?;
+    tx.commit().await?;
+    Ok(book)
+  }
+
+  async fn remove_book_author(&self, ctx: &Context<'_>, book_id: u64, author_id: u64) -> Result<Book> {
+    let mut tx = begin(ctx).await?;
+    Book::remove_author(&mut tx, book_id, author_id).await?;
+    let book = Book::fetch_one(&mut tx, book_id).await?;
+    tx.commit().await?;
+    Ok(book)
+  }
+
+  async fn create_author(&self, ctx: &Context<'_>, input: NewAuthor) -> Result<Author> {
+    let mut tx = begin(ctx).await?;
+    let partial = PartialAuthor {
+      name: Some(input.name),
+      description: input.description,
+      birth: input.birth,
+    };
+    let author = Author::create(&mut tx, partial).await?;
+    tx.commit().await?;
+    Ok(author)
+  }
+
+  async fn update_author(&self, ctx: &Context<'_>, id: u64, version: Option<u32>, input: AuthorChanges) -> Result<Author> {
+    let mut tx = begin(ctx).await?;
+    let current = Author::fetch_uncached(&mut tx, id).await?;
+    let version = version.unwrap_or(current.version);
+    let author = Author::update_if(&mut tx, id, version, input.apply_to(current)).await.map_err(update_error)?;
+    tx.commit().await?;
+    Ok(author)
+  }
+
+  async fn create_publisher(&self, ctx: &Context<'_>, input: NewPublisher) -> Result<Publisher> {
+    let mut tx = begin(ctx).await?;
+    let publisher = Publisher::create(&mut tx, input.name, input.description, input.city).await?;
+    tx.commit().await?;
+    Ok(publisher)
+  }
+
+  async fn update_publisher(&self, ctx: &Context<'_>, id: u16, version: Option<u32>, input: PublisherChanges) -> Result<Publisher> {
+    let mut tx = begin(ctx).await?;
+    let current = Publisher::fetch_one(&mut tx, id).await?;
+    let partial = PartialPublisher {
+      name: input.name,
+      description: input.description,
+      city: or_current(input.city, current.city),
+    };
+    let publisher = Publisher::update_if(&mut tx, id, version.unwrap_or(current.version), partial)
+      .await
+      .map_err(update_error)?;
+    tx.commit().await?;
+    Ok(publisher)
+  }
+
+  async fn create_series(&self, ctx: &Context<'_>, input: NewSeries) -> Result<Series> {
+    let mut tx = begin(ctx).await?;
+    let partial = PartialSeries {
+      name: Some(input.name),
+      description: input.description,
+    };
+    let series = Series::create(&mut tx, partial).await?;
+    tx.commit().await?;
+    Ok(series)
+  }
+
+  async fn update_series(&self, ctx: &Context<'_>, id: u64, version: Option<u32>, input: SeriesChanges) -> Result<Series> {
+    let mut tx = begin(ctx).await?;
+    let current = Series::fetch_one(&mut tx, id).await?;
+    let version = version.unwrap_or(current.version);
+    let series = Series::update_if(&mut tx, id, version, input.apply_to(current)).await.map_err(update_error)?;
+    tx.commit().await?;
+    Ok(series)
+  }
+
+  async fn create_user(&self, ctx: &Context<'_>, name: String) -> Result<User> {
+    let mut tx = begin(ctx).await?;
+    let user = User::create(&mut tx, None, name).await?;
+    tx.commit().await?;
+    Ok(user)
+  }
+
+  async fn update_user(&self, ctx: &Context<'_>, id: u8, version: Option<u32>, name: String) -> Result<User> {
+    let mut tx = begin(ctx).await?;
+    let version = match version {
+      Some(version) => version,
+      None => User::fetch_one(&mut tx, id).await?.version,
+    };
+    let user = User::update_if(&mut tx, id, version, name).await.map_err(update_error)?;
+    tx.commit().await?;
+    Ok(user)
+  }
+
+  /// Moves the viewer's place in a book, starting their progress on it if need be. With `version`, the progress has to
+  /// exist at that version, so two devices cannot overwrite each other's place unknowingly.
+  async fn update_progress(&self, ctx: &Context<'_>, book_id: u64, version: Option<u32>, current_page: u16) -> Result<Progress> {
+    let Some(user_id) = ctx.data_unchecked::<Viewer>().0 else {
+      return Err(forbidden("Progress belongs to a reader; send their KOReader credentials"));
+    };
+    let mut tx = ctx.data_unchecked::<Db>().conn.begin().await?;
+    AuditEntry::set_actor(&mut tx, &Caller::User(user_id).actor()).await?;
+    let book = Book::fetch_one(&mut tx, book_id).await?;
+    if let Some(version) = version {
+      Progress::expect_version(&mut tx, user_id, book_id, version).await.map_err(update_error)?;
+    }
+    let progress = Progress::read_to(&mut tx, user_id, &book, current_page).await?;
+    tx.commit().await?;
+    Ok(progress)
+  }
+}
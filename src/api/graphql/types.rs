@@ -0,0 +1,334 @@
+//! The read side of the schema: the catalog records as GraphQL objects, with their relationships resolved through the
+//! request's [loaders](super::loaders).
+
+use async_graphql::{dataloader::DataLoader, Context, Object, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+
+use super::{
+  loaders::{
+    AuthorBooksLoader, AuthorLoader, BookAuthorsLoader, BookLoader, ProgressLoader, PublisherBooksLoader, PublisherLoader, SeriesLoader, UserLoader,
+    UserProgressLoader,
+  },
+  Viewer,
+};
+use crate::{
+  api::auth::Caller,
+  db::{authors::Author, books::Book, progress::Progress, publisher::Publisher, series::Series, user::User, Db},
+};
+
+/// Rows returned by a list query when `limit` is not given, and the most one query may ask for.
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 1000;
+
+/// The `LIMIT` and `OFFSET` a list query asks the database for.
+fn page(limit: Option<u32>, offset: Option<u64>) -> (u32, u64) {
+  (limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT), offset.unwrap_or(0))
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+  async fn book(&self, ctx: &Context<'_>, id: u64) -> Result<Option<Book>> {
+    Ok(ctx.data_unchecked::<DataLoader<BookLoader>>().load_one(id).await?)
+  }
+
+  async fn books(&self, ctx: &Context<'_>, limit: Option<u32>, offset: Option<u64>) -> Result<Vec<Book>> {
+    let (limit, offset) = page(limit, offset);
+    let mut tx = ctx.data_unchecked::<Db>().conn.begin().await?;
+    Ok(Book::fetch_page(&mut tx, limit, offset).await?)
+  }
+
+  async fn author(&self, ctx: &Context<'_>, id: u64) -> Result<Option<Author>> {
+    Ok(ctx.data_unchecked::<DataLoader<AuthorLoader>>().load_one(id).await?)
+  }
+
+  async fn authors(&self, ctx: &Context<'_>, limit: Option<u32>, offset: Option<u64>) -> Result<Vec<Author>> {
+    let (limit, offset) = page(limit, offset);
+    let mut tx = ctx.data_unchecked::<Db>().conn.begin().await?;
+    Ok(Author::fetch_page(&mut tx, limit, offset).await?)
+  }
+
+  async fn publisher(&self, ctx: &Context<'_>, id: u16) -> Result<Option<Publisher>> {
+    Ok(ctx.data_unchecked::<DataLoader<PublisherLoader>>().load_one(id).await?)
+  }
+
+  async fn publishers(&self, ctx: &Context<'_>) -> Result<Vec<Publisher>> {
+    let mut tx = ctx.data_unchecked::<Db>().conn.begin().await?;
+    Ok(Publisher::fetch_all(&mut tx).await?)
+  }
+
+  async fn series(&self, ctx: &Context<'_>, id: u64) -> Result<Option<Series>> {
+    Ok(ctx.data_unchecked::<DataLoader<SeriesLoader>>().load_one(id).await?)
+  }
+
+  async fn user(&self, ctx: &Context<'_>, id: u8) -> Result<Option<User>> {
+    Ok(ctx.data_unchecked::<DataLoader<UserLoader>>().load_one(id).await?)
+  }
+
+  async fn users(&self, ctx: &Context<'_>) -> Result<Vec<User>> {
+    let mut tx = ctx.data_unchecked::<Db>().conn.begin().await?;
+    Ok(User::fetch_all(&mut tx).await?)
+  }
+
+  /// The reader the request authenticated as, if any.
+  async fn viewer(&self, ctx: &Context<'_>) -> Result<Option<User>> {
+    match ctx.data_unchecked::<Viewer>().0 {
+      Some(user_id) => Ok(ctx.data_unchecked::<DataLoader<UserLoader>>().load_one(user_id).await?),
+      None => Ok(None),
+    }
+  }
+}
+
+#[Object]
+impl Book {
+  async fn id(&self) -> u64 {
+    self.id
+  }
+
+  async fn isbn(&self) -> &str {
+    &self.isbn
+  }
+
+  async fn name(&self) -> &str {
+    &self.name
+  }
+
+  async fn description(&self) -> Option<&str> {
+    self.description.as_deref()
+  }
+
+  async fn language(&self) -> Option<&str> {
+    self.language.as_deref()
+  }
+
+  async fn nsfw(&self) -> bool {
+    self.nsfw
+  }
+
+  async fn num_pages(&self) -> u16 {
+    self.num_pages
+  }
+
+  async fn series_index(&self) -> Option<u16> {
+    self.series_index
+  }
+
+  async fn date_published(&self) -> Option<DateTime<Utc>> {
+    self.date_published
+  }
+
+  async fn date_added(&self) -> Option<DateTime<Utc>> {
+    self.date_added
+  }
+
+  async fn date_last_updated(&self) -> Option<DateTime<Utc>> {
+    self.date_last_updated
+  }
+
+  /// Pass it back to an update to have the update fail if someone else got there first.
+  async fn version(&self) -> u32 {
+    self.version
+  }
+
+  async fn authors(&self, ctx: &Context<'_>) -> Result<Vec<Author>> {
+    let authors = ctx.data_unchecked::<DataLoader<BookAuthorsLoader>>().load_one(self.id).await?;
+    Ok(authors.unwrap_or_default())
+  }
+
+  async fn publisher(&self, ctx: &Context<'_>) -> Result<Option<Publisher>> {
+    match self.publisher_id {
+      Some(publisher_id) => Ok(ctx.data_unchecked::<DataLoader<PublisherLoader>>().load_one(publisher_id).await?),
+      None => Ok(None),
+    }
+  }
+
+  async fn series(&self, ctx: &Context<'_>) -> Result<Option<Series>> {
+    match self.series_id {
+      Some(series_id) => Ok(ctx.data_unchecked::<DataLoader<SeriesLoader>>().load_one(series_id).await?),
+      None => Ok(None),
+    }
+  }
+
+  /// The viewer's progress, or null when there is no viewer or they have not started the book.
+  async fn progress(&self, ctx: &Context<'_>) -> Result<Option<Progress>> {
+    match ctx.data_unchecked::<Viewer>().0 {
+      Some(user_id) => Ok(ctx.data_unchecked::<DataLoader<ProgressLoader>>().load_one((user_id, self.id)).await?),
+      None => Ok(None),
+    }
+  }
+}
+
+#[Object]
+impl Author {
+  async fn id(&self) -> u64 {
+    self.id
+  }
+
+  async fn name(&self) -> &str {
+    &self.name
+  }
+
+  async fn description(&self) -> Option<&str> {
+    self.description.as_deref()
+  }
+
+  async fn birth(&self) -> Option<NaiveDate> {
+    self.birth
+  }
+
+  async fn sort_name(&self) -> Option<&str> {
+    self.sort_name.as_deref()
+  }
+
+  async fn date_added(&self) -> Option<DateTime<Utc>> {
+    self.date_added
+  }
+
+  async fn date_last_updated(&self) -> Option<DateTime<Utc>> {
+    self.date_last_updated
+  }
+
+  async fn version(&self) -> u32 {
+    self.version
+  }
+
+  async fn books(&self, ctx: &Context<'_>) -> Result<Vec<Book>> {
+    let books = ctx.data_unchecked::<DataLoader<AuthorBooksLoader>>().load_one(self.id).await?;
+    Ok(books.unwrap_or_default())
+  }
+}
+
+#[Object]
+impl Publisher {
+  async fn id(&self) -> u16 {
+    self.id
+  }
+
+  async fn name(&self) -> &str {
+    &self.name
+  }
+
+  async fn description(&self) -> &str {
+    &self.description
+  }
+
+  async fn city(&self) -> Option<&str> {
+    self.city.as_deref()
+  }
+
+  async fn date_added(&self) -> Option<DateTime<Utc>> {
+    self.date_added
+  }
+
+  async fn date_last_updated(&self) -> Option<DateTime<Utc>> {
+    self.date_last_updated
+  }
+
+  async fn version(&self) -> u32 {
+    self.version
+  }
+
+  async fn books(&self, ctx: &Context<'_>) -> Result<Vec<Book>> {
+    let books = ctx.data_unchecked::<DataLoader<PublisherBooksLoader>>().load_one(self.id).await?;
+    Ok(books.unwrap_or_default())
+  }
+}
+
+#[Object]
+impl Series {
+  async fn id(&self) -> u64 {
+    self.id
+  }
+
+  async fn name(&self) -> &str {
+    &self.name
+  }
+
+  async fn description(&self) -> Option<&str> {
+    self.description.as_deref()
+  }
+
+  async fn date_added(&self) -> Option<DateTime<Utc>> {
+    self.date_added
+  }
+
+  async fn date_last_updated(&self) -> Option<DateTime<Utc>> {
+    self.date_last_updated
+  }
+
+  async fn version(&self) -> u32 {
+    self.version
+  }
+}
+
+#[Object]
+impl User {
+  async fn id(&self) -> u8 {
+    self.id
+  }
+
+  async fn name(&self) -> &str {
+    &self.name
+  }
+
+  async fn date_added(&self) -> Option<DateTime<Utc>> {
+    self.date_added
+  }
+
+  async fn date_last_updated(&self) -> Option<DateTime<Utc>> {
+    self.date_last_updated
+  }
+
+  /// Pass it back to an update to have the update fail if someone else got there first.
+  async fn version(&self) -> u32 {
+    self.version
+  }
+
+  /// The user's reading, shown only to that user or the administrator. Anyone else gets an empty list.
+  async fn progress(&self, ctx: &Context<'_>) -> Result<Vec<Progress>> {
+    let own = ctx.data_unchecked::<Viewer>().0 == Some(self.id);
+    if !own && *ctx.data_unchecked::<Option<Caller>>() != Some(Caller::Admin) {
+      return Ok(Vec::new());
+    }
+    let progress = ctx.data_unchecked::<DataLoader<UserProgressLoader>>().load_one(self.id).await?;
+    Ok(progress.unwrap_or_default())
+  }
+}
+
+#[Object]
+impl Progress {
+  async fn current_page(&self) -> u16 {
+    self.current_page
+  }
+
+  async fn rating(&self) -> Option<u8> {
+    self.rating
+  }
+
+  async fn date_started(&self) -> Option<DateTime<Utc>> {
+    self.date_started
+  }
+
+  async fn date_finished(&self) -> Option<DateTime<Utc>> {
+    self.date_finished
+  }
+
+  /// Pass it back to an update to have the update fail if someone else got there first.
+  async fn version(&self) -> u32 {
+    self.version
+  }
+
+  async fn date_last_updated(&self) -> Option<DateTime<Utc>> {
+    self.date_last_updated
+  }
+
+  /// Null once the book has gone to the trash.
+  async fn book(&self, ctx: &Context<'_>) -> Result<Option<Book>> {
+    Ok(ctx.data_unchecked::<DataLoader<BookLoader>>().load_one(self.book_id).await?)
+  }
+
+  async fn user(&self, ctx: &Context<'_>) -> Result<Option<User>> {
+    Ok(ctx.data_unchecked::<DataLoader<UserLoader>>().load_one(self.user_id).await?)
+  }
+}
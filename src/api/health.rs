@@ -0,0 +1,88 @@
+//! Probes for container orchestrators. `GET /health/live` answers as long as the server is serving at all, so a failure
+//! means it should be restarted. `GET /health/ready` also checks what requests depend on, so a failure means it should
+//! be sent no traffic until it recovers:
+//!
+//! ```json
+//! {
+//!   "status": "unavailable",
+//!   "checks": {
+//!     "database": { "status": "ok", "duration_ms": 2 },
+//!     "migrations": { "status": "ok", "duration_ms": 1, "version": 12, "expected": 12 },
+//!     "files": { "status": "ok", "duration_ms": 0 },
+//!     "covers": { "status": "failed", "duration_ms": 0, "error": "Permission denied (os error 13)" }
+//!   }
+//! }
+//! ```
+
+use std::{future::Future, time::Duration};
+
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use serde_json::{json, Map, Value};
+use tokio::time::Instant;
+
+use super::AppState;
+use crate::{db::SCHEMA_VERSION, storage::Storage};
+
+/// How long one check may take before it counts as failed, so a hung database cannot hang the probe too.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn router() -> Router<AppState> {
+  Router::new().route("/health/live", get(live)).route("/health/ready", get(ready))
+}
+
+async fn live() -> Json<Value> {
+  Json(json!({ "status": "ok" }))
+}
+
+/// Runs `check` under [`CHECK_TIMEOUT`] and reports it as a JSON object, with any extra fields it returns.
+async fn run<F>(check: F) -> (bool, Value)
+where
+  F: Future<Output = Result<Map<String, Value>, String>>,
+{
+  let started = Instant::now();
+  let result = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+    Ok(result) => result,
+    Err(_) => Err(format!("timed out after {}s", CHECK_TIMEOUT.as_secs())),
+  };
+  let duration_ms = started.elapsed().as_millis() as u64;
+  match result {
+    Ok(mut fields) => {
+      fields.insert(String::from("status"), json!("ok"));
+      fields.insert(String::from("duration_ms"), json!(duration_ms));
+      (true, Value::Object(fields))
+    }
+    Err(error) => (false, json!({ "status": "failed", "duration_ms": duration_ms, "error": error })),
+  }
+}
+
+async fn writable(storage: &Storage) -> Result<Map<String, Value>, String> {
+  storage.check_writable().await.map_err(|err| err.to_string())?;
+  Ok(Map::new())
+}
+
+async fn ready(State(state): State<AppState>) -> (StatusCode, Json<Value>) {
+  let database = run(async { state.db.ping().await.map(|_| Map::new()).map_err(|err| err.to_string()) });
+  // A database migrated further than we know of is left ready, since that is what a rolling upgrade looks like.
+  let migrations = run(async {
+    let version = state.db.schema_version().await.map_err(|err| err.to_string())?;
+    if version < SCHEMA_VERSION {
+      return Err(format!("schema is at version {}, {} migrations pending", version, SCHEMA_VERSION - version));
+    }
+    let mut fields = Map::new();
+    fields.insert(String::from("version"), json!(version));
+    fields.insert(String::from("expected"), json!(SCHEMA_VERSION));
+    Ok(fields)
+  });
+  let files = run(writable(&state.storage));
+  let covers = run(writable(&state.covers.storage));
+  let checks = tokio::join!(database, migrations, files, covers);
+
+  let checks = [("database", checks.0), ("migrations", checks.1), ("files", checks.2), ("covers", checks.3)];
+  let healthy = checks.iter().all(|(_, (ok, _))| *ok);
+  let checks: Map<String, Value> = checks.into_iter().map(|(name, (_, report))| (name.to_string(), report)).collect();
+  if healthy {
+    (StatusCode::OK, Json(json!({ "status": "ok", "checks": checks })))
+  } else {
+    (StatusCode::SERVICE_UNAVAILABLE, Json(json!({ "status": "unavailable", "checks": checks })))
+  }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+use axum::{
+  extract::{Path, Query, State},
+  http::StatusCode,
+  routing::{get, post},
+  Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::{ApiError, AppState};
+use crate::{
+  db::{
+    jobs::{Job, JobQuery},
+    schedule::ScheduledTask,
+  },
+  jobs::{self, Task},
+  schedule,
+};
+
+/// Jobs listed when `limit` is not given, and the most one request may ask for.
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 1000;
+
+const STATUSES: &[&str] = &["pending", "running", "succeeded", "failed", "cancelled"];
+
+pub fn router() -> Router<AppState> {
+  Router::new()
+    .route("/jobs", get(list_jobs).post(create_job))
+    .route("/jobs/{id}", get(fetch_job).delete(cancel_job))
+    .route("/jobs/{id}/retry", post(retry_job))
+    .route("/schedule", get(list_schedule))
+}
+
+/// JSON columns are written by us, so they parse; anything else is passed through as a string rather than lost.
+fn parse_json(value: &str) -> Value {
+  serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()))
+}
+
+fn job_json(job: &Job) -> Value {
+  json!({
+    "id": job.id,
+    "kind": job.kind,
+    "payload": parse_json(&job.payload),
+    "status": job.status,
+    "attempts": job.attempts,
+    "max_attempts": job.max_attempts,
+    "result": job.result.as_deref().map(parse_json),
+    "error": job.error,
+    "run_at": job.run_at,
+    "date_added": job.date_added,
+    "date_started": job.date_started,
+    "date_finished": job.date_finished,
+  })
+}
+
+/// The task's fields with its `kind`, such as `{"kind": "thumbnails", "book_id": 4}`, plus when to run it and how often
+/// to try.
+#[derive(Deserialize)]
+struct NewJob {
+  #[serde(flatten)]
+  task: Task,
+  run_at: Option<DateTime<Utc>>,
+  max_attempts: Option<u32>,
+}
+
+/// Newest first, filtered by `status` and `kind`.
+async fn list_jobs(State(state): State<AppState>, Query(params): Query<HashMap<String, String>>) -> Result<Json<Value>, ApiError> {
+  if let Some(status) = params.get("status").filter(|status| !STATUSES.contains(&status.as_str())) {
+    return Err(ApiError::BadRequest(format!(
+      "status: unknown status {}; expected one of {}",
+      status,
+      STATUSES.join(", ")
+    )));
+  }
+  let limit = match params.get("limit") {
+    Some(limit) => limit
+      .parse::<u32>()
+      .map_err(|err| ApiError::BadRequest(format!("limit: {}", err)))?
+      .min(MAX_LIMIT),
+    None => DEFAULT_LIMIT,
+  };
+  let filter = JobQuery {
+    status: params.get("status").cloned(),
+    kind: params.get("kind").cloned(),
+    limit,
+  };
+
+  let mut tx = state.db.conn.begin().await?;
+  let jobs = Job::fetch(&mut tx, &filter).await?;
+  Ok(Json(json!(jobs.iter().map(job_json).collect::<Vec<Value>>())))
+}
+
+async fn create_job(State(state): State<AppState>, Json(body): Json<NewJob>) -> Result<(StatusCode, Json<Value>), ApiError> {
+  body.task.check().map_err(ApiError::BadRequest)?;
+  if body.max_attempts == Some(0) {
+    return Err(ApiError::BadRequest(String::from("max_attempts: must be at least 1")));
+  }
+  let max_attempts = body.max_attempts.unwrap_or(state.config.jobs.max_attempts);
+
+  let mut tx = state.db.conn.begin().await?;
+  let job = jobs::enqueue(&mut tx, &body.task, body.run_at, max_attempts).await?;
+  tx.commit().await?;
+  Ok((StatusCode::ACCEPTED, Json(job_json(&job))))
+}
+
+async fn fetch_job(State(state): State<AppState>, Path(job_id): Path<u64>) -> Result<Json<Value>, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let job = Job::fetch_one(&mut tx, job_id).await?;
+  Ok(Json(job_json(&job)))
+}
+
+/// Only a job that has not started can be cancelled.
+async fn cancel_job(State(state): State<AppState>, Path(job_id): Path<u64>) -> Result<Json<Value>, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let job = Job::fetch_one(&mut tx, job_id).await?;
+  if !Job::cancel(&mut tx, job_id).await? {
+    return Err(ApiError::BadRequest(format!(
+      "job {} is {}; only pending jobs can be cancelled",
+      job_id, job.status
+    )));
+  }
+  let job = Job::fetch_one(&mut tx, job_id).await?;
+  tx.commit().await?;
+  Ok(Json(job_json(&job)))
+}
+
+/// Requeues a failed or cancelled job with its attempts reset.
+async fn retry_job(State(state): State<AppState>, Path(job_id): Path<u64>) -> Result<Json<Value>, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let job = Job::fetch_one(&mut tx, job_id).await?;
+  if !Job::retry(&mut tx, job_id).await? {
+    return Err(ApiError::BadRequest(format!(
+      "job {} is {}; only failed or cancelled jobs can be retried",
+      job_id, job.status
+    )));
+  }
+  let job = Job::fetch_one(&mut tx, job_id).await?;
+  tx.commit().await?;
+  Ok(Json(job_json(&job)))
+}
+
+/// Each recurring task switched on, with its last run and when it is next due.
+async fn list_schedule(State(state): State<AppState>) -> Result<Json<Value>, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let runs = ScheduledTask::fetch_all(&mut tx).await?;
+
+  let mut tasks = Vec::new();
+  for (name, expression) in state.config.schedule.expressions() {
+    let last = runs.iter().find(|run| run.name == name);
+    let next = schedule::parse(expression)
+      .ok()
+      .and_then(|cron| schedule::next_due(&cron, last.map_or_else(Utc::now, |last| last.last_run_at)));
+    tasks.push(json!({
+      "name": name,
+      "cron": expression,
+      "last_run_at": last.map(|last| last.last_run_at),
+      "last_job_id": last.and_then(|last| last.last_job_id),
+      "next_run_at": next,
+    }));
+  }
+  Ok(Json(json!(tasks)))
+}
@@ -0,0 +1,199 @@
+use axum::{
+  extract::{Path, State},
+  http::{HeaderMap, StatusCode},
+  response::{IntoResponse, Response},
+  routing::{get, post, put},
+  Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{MySql, Transaction};
+
+use super::AppState;
+use crate::db::{
+  audit::AuditEntry,
+  books::Book,
+  koreader::{KoreaderDocument, KoreaderProgress, KoreaderUser},
+};
+
+/// Routes implementing KOReader's progress sync protocol, so `/kosync` can be used as a custom sync server.
+pub fn router() -> Router<AppState> {
+  Router::new()
+    .route("/users/create", post(register))
+    .route("/users/auth", get(auth))
+    .route("/syncs/progress", put(put_progress))
+    .route("/syncs/progress/{document}", get(get_progress))
+    .route("/documents/{document}", put(link_document))
+}
+
+/// Errors in the shape KOReader's sync plugin understands.
+#[derive(Debug)]
+pub enum KosyncError {
+  Unauthorized,
+  UserExists,
+  RegistrationClosed,
+  InvalidRequest,
+  DocumentMissing,
+  NotFound,
+  DocumentLinked,
+  Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for KosyncError {
+  fn from(err: sqlx::Error) -> Self {
+    KosyncError::Database(err)
+  }
+}
+
+impl IntoResponse for KosyncError {
+  fn into_response(self) -> Response {
+    let (status, code, message) = match self {
+      KosyncError::Unauthorized => (StatusCode::UNAUTHORIZED, 2001, String::from("Unauthorized")),
+      KosyncError::UserExists => (StatusCode::PAYMENT_REQUIRED, 2002, String::from("Username is already registered.")),
+      KosyncError::RegistrationClosed => (StatusCode::FORBIDDEN, 2006, String::from("User registration is disabled.")),
+      KosyncError::InvalidRequest => (StatusCode::FORBIDDEN, 2003, String::from("Invalid request")),
+      KosyncError::DocumentMissing => (StatusCode::FORBIDDEN, 2004, String::from("Field 'document' not provided.")),
+      KosyncError::NotFound => (StatusCode::NOT_FOUND, 2005, String::from("Not found")),
+      KosyncError::DocumentLinked => (StatusCode::CONFLICT, 2007, String::from("Document is already linked to another book.")),
+      KosyncError::Database(err) => (StatusCode::INTERNAL_SERVER_ERROR, 2000, err.to_string()),
+    };
+    (status, Json(json!({ "code": code, "message": message }))).into_response()
+  }
+}
+
+/// Checks the `x-auth-user` / `x-auth-key` headers KOReader sends with every authenticated request.
+async fn authorize<'a>(tx: &mut Transaction<'a, MySql>, headers: &HeaderMap) -> Result<KoreaderUser, KosyncError> {
+  let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok()).filter(|value| !value.is_empty());
+  let (Some(username), Some(userkey)) = (header("x-auth-user"), header("x-auth-key")) else {
+    return Err(KosyncError::Unauthorized);
+  };
+
+  let user = KoreaderUser::authenticate(tx, username, userkey).await?.ok_or(KosyncError::Unauthorized)?;
+  AuditEntry::set_actor(tx, &format!("user:{}", user.user_id)).await?;
+  Ok(user)
+}
+
+#[derive(Debug, Deserialize)]
+struct Registration {
+  username: Option<String>,
+  password: Option<String>,
+}
+
+async fn register(State(state): State<AppState>, Json(body): Json<Registration>) -> Result<Response, KosyncError> {
+  if !state.config.auth.kosync_registration {
+    return Err(KosyncError::RegistrationClosed);
+  }
+  let (Some(username), Some(password)) = (body.username.filter(|u| !u.is_empty()), body.password.filter(|p| !p.is_empty())) else {
+    return Err(KosyncError::InvalidRequest);
+  };
+
+  let mut tx = state.db.conn.begin().await?;
+  AuditEntry::set_actor(&mut tx, &format!("kosync:{}", username)).await?;
+  let Some(user) = KoreaderUser::create(&mut tx, username, &password).await? else {
+    return Err(KosyncError::UserExists);
+  };
+  tx.commit().await?;
+
+  Ok((StatusCode::CREATED, Json(json!({ "username": user.username }))).into_response())
+}
+
+async fn auth(State(state): State<AppState>, headers: HeaderMap) -> Result<Json<Value>, KosyncError> {
+  let mut tx = state.db.conn.begin().await?;
+  authorize(&mut tx, &headers).await?;
+  Ok(Json(json!({ "authorized": "OK" })))
+}
+
+#[derive(Debug, Deserialize)]
+struct ProgressUpdate {
+  document: Option<String>,
+  progress: Option<String>,
+  percentage: Option<f64>,
+  device: Option<String>,
+  device_id: Option<String>,
+}
+
+async fn put_progress(State(state): State<AppState>, headers: HeaderMap, Json(body): Json<ProgressUpdate>) -> Result<Json<Value>, KosyncError> {
+  let mut tx = state.db.conn.begin().await?;
+  let user = authorize(&mut tx, &headers).await?;
+
+  let Some(document) = body.document.filter(|d| !d.is_empty()) else {
+    return Err(KosyncError::DocumentMissing);
+  };
+  let (Some(progress), Some(percentage), Some(device)) = (body.progress, body.percentage, body.device) else {
+    return Err(KosyncError::InvalidRequest);
+  };
+  if document.len() > 32 {
+    return Err(KosyncError::InvalidRequest);
+  }
+
+  let synced = KoreaderProgress::sync(
+    &mut tx,
+    user.user_id,
+    &document,
+    &progress,
+    percentage,
+    &device,
+    &body.device_id.unwrap_or_default(),
+  )
+  .await?;
+  tx.commit().await?;
+
+  Ok(Json(json!({
+    "document": synced.document,
+    "timestamp": synced.date_last_updated.map(|date| date.timestamp()),
+  })))
+}
+
+async fn get_progress(State(state): State<AppState>, headers: HeaderMap, Path(document): Path<String>) -> Result<Json<Value>, KosyncError> {
+  let mut tx = state.db.conn.begin().await?;
+  let user = authorize(&mut tx, &headers).await?;
+
+  // KOReader treats an empty object as "no progress stored yet".
+  match KoreaderProgress::fetch_one(&mut tx, user.user_id, &document).await {
+    Ok(synced) => Ok(Json(json!({
+      "document": synced.document,
+      "progress": synced.progress,
+      "percentage": synced.percentage,
+      "device": synced.device,
+      "device_id": synced.device_id,
+      "timestamp": synced.date_last_updated.map(|date| date.timestamp()),
+    }))),
+    Err(sqlx::Error::RowNotFound) => Ok(Json(json!({}))),
+    Err(err) => Err(err.into()),
+  }
+}
+
+#[derive(Debug, Deserialize)]
+struct DocumentLink {
+  book_id: u64,
+}
+
+/// Not part of KOReader's protocol: maps a document hash onto a catalog book so synced progress reaches `Progress`. The
+/// mapping is shared by every reader, so one that exists is only changed by the administrator, through a file upload.
+async fn link_document(
+  State(state): State<AppState>,
+  headers: HeaderMap,
+  Path(document): Path<String>,
+  Json(body): Json<DocumentLink>,
+) -> Result<Json<Value>, KosyncError> {
+  // Credits the reader in the audit log
+  let mut tx = state.db.conn.begin().await?;
+  authorize(&mut tx, &headers).await?;
+
+  if document.is_empty() || document.len() > 32 {
+    return Err(KosyncError::InvalidRequest);
+  }
+  match Book::fetch_one(&mut tx, body.book_id).await {
+    Ok(_) => {}
+    Err(sqlx::Error::RowNotFound) => return Err(KosyncError::NotFound),
+    Err(err) => return Err(err.into()),
+  }
+
+  let mapping = KoreaderDocument::link_new(&mut tx, &document, body.book_id).await?;
+  if mapping.book_id != body.book_id {
+    return Err(KosyncError::DocumentLinked);
+  }
+  tx.commit().await?;
+
+  Ok(Json(json!({ "document": mapping.document, "book_id": mapping.book_id })))
+}
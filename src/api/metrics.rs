@@ -0,0 +1,30 @@
+use axum::{extract::State, http::header, response::IntoResponse, routing::get, Router};
+use chrono::Utc;
+
+use super::{ApiError, AppState};
+use crate::{
+  db::{books::Book, progress::Progress, user::User},
+  metrics::{render, ACTIVE_READERS, BOOKS, POOL_ACTIVE, POOL_IDLE, USERS},
+};
+
+pub fn router() -> Router<AppState> {
+  Router::new().route("/metrics", get(scrape))
+}
+
+/// The gauges are read now rather than kept up to date as things change, since a scrape every few seconds is far
+/// cheaper than counting on every write.
+async fn scrape(State(state): State<AppState>) -> Result<impl IntoResponse, ApiError> {
+  let idle = state.db.conn.num_idle() as i64;
+  POOL_IDLE.set(idle);
+  POOL_ACTIVE.set(state.db.conn.size() as i64 - idle);
+
+  let midnight = Utc::now().date_naive().and_hms_opt(0, 0, 0).unwrap().and_utc();
+  let mut tx = state.db.conn.begin().await?;
+  BOOKS.set(Book::count(&mut tx).await?);
+  USERS.set(User::count(&mut tx).await?);
+  ACTIVE_READERS.set(Progress::count_readers_since(&mut tx, midnight).await?);
+  tx.commit().await?;
+
+  let (content_type, body) = render();
+  Ok(([(header::CONTENT_TYPE, content_type)], body))
+}
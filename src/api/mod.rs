@@ -0,0 +1,173 @@
+use axum::{
+  extract::Request,
+  http::{header, StatusCode},
+  middleware::{self, Next},
+  response::{IntoResponse, Response},
+  Router,
+};
+
+use std::sync::Arc;
+
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+  config::Config,
+  covers::Covers,
+  db::{cache, Db, UpdateError},
+  logging,
+  storage::Storage,
+};
+
+pub mod audit;
+pub mod auth;
+pub mod catalog;
+pub mod changes;
+pub mod covers;
+pub mod files;
+pub mod graphql;
+pub mod health;
+pub mod jobs;
+pub mod kosync;
+pub mod metrics;
+pub mod opds;
+pub mod stats;
+pub mod transfer;
+pub mod trash;
+pub mod webhooks;
+
+#[derive(Clone)]
+pub struct AppState {
+  pub db: Db,
+  pub storage: Storage,
+  pub covers: Covers,
+  pub config: Arc<Config>,
+  /// Cancelled when the server starts shutting down, so responses that would otherwise never end, like the change
+  /// stream, can finish and let the graceful shutdown complete.
+  pub shutdown: CancellationToken,
+}
+
+/// Every endpoint, less those of features switched off in the config, each behind the [auth](auth) it needs.
+pub fn router(state: AppState) -> Router {
+  let features = &state.config.features;
+  let mut router = Router::new();
+  if features.opds {
+    router = router.nest("/opds", opds::router());
+  }
+  if features.kosync {
+    router = router.nest("/kosync", kosync::router());
+  }
+  if features.graphql {
+    router = router.merge(graphql::router());
+  }
+  if features.metrics {
+    router = router.merge(metrics::router());
+  }
+  router = router.merge(health::router());
+
+  let readable = Router::new()
+    .merge(catalog::router())
+    .merge(files::router())
+    .merge(covers::router())
+    .route_layer(middleware::from_fn_with_state(state.clone(), auth::require_admin_to_write));
+  let admin = Router::new()
+    .merge(audit::router())
+    .merge(changes::router())
+    .merge(jobs::router())
+    .merge(stats::router())
+    .merge(transfer::router())
+    .merge(trash::router())
+    .merge(webhooks::router());
+  router = router
+    .merge(readable)
+    .merge(admin.route_layer(middleware::from_fn_with_state(state.clone(), auth::require_admin)));
+  if features.metrics {
+    router = router.layer(middleware::from_fn(crate::metrics::track_requests));
+  }
+  router
+    .layer(middleware::from_fn(settle_cache))
+    .layer(middleware::from_fn(logging::trace_requests))
+    .with_state(state)
+}
+
+/// Keeps what a request writes out of the [cache] until its handler has returned, having committed or rolled back.
+async fn settle_cache(request: Request, next: Next) -> Response {
+  cache::settle_after(next.run(request)).await
+}
+
+/// The strong entity tag for a record at `version`.
+fn etag(version: u32) -> String {
+  format!("\"{}\"", version)
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+  NotFound,
+  BadRequest(String),
+  /// No credentials were sent, or they did not check out.
+  Unauthorized,
+  /// The credentials are fine but do not allow this.
+  Forbidden,
+  UnsupportedMediaType,
+  /// An edit was based on an older version of the record than the one now stored, which it holds.
+  Conflict(u32),
+  Database(sqlx::Error),
+  Storage(std::io::Error),
+}
+
+impl From<sqlx::Error> for ApiError {
+  fn from(err: sqlx::Error) -> Self {
+    match err {
+      sqlx::Error::RowNotFound => ApiError::NotFound,
+      err => ApiError::Database(err),
+    }
+  }
+}
+
+impl From<UpdateError> for ApiError {
+  fn from(err: UpdateError) -> Self {
+    match err {
+      UpdateError::Conflict(version) => ApiError::Conflict(version),
+      UpdateError::Database(err) => err.into(),
+    }
+  }
+}
+
+impl From<std::io::Error> for ApiError {
+  fn from(err: std::io::Error) -> Self {
+    match err.kind() {
+      std::io::ErrorKind::NotFound => ApiError::NotFound,
+      _ => ApiError::Storage(err),
+    }
+  }
+}
+
+impl IntoResponse for ApiError {
+  fn into_response(self) -> Response {
+    match self {
+      ApiError::NotFound => (StatusCode::NOT_FOUND, "Not Found").into_response(),
+      ApiError::BadRequest(reason) => (StatusCode::BAD_REQUEST, reason).into_response(),
+      ApiError::Unauthorized => (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, r#"Bearer realm="libby""#)],
+        "Unauthorized",
+      )
+        .into_response(),
+      ApiError::Forbidden => (StatusCode::FORBIDDEN, "Forbidden").into_response(),
+      ApiError::UnsupportedMediaType => (StatusCode::UNSUPPORTED_MEDIA_TYPE, "Unsupported file format").into_response(),
+      ApiError::Conflict(version) => (
+        StatusCode::PRECONDITION_FAILED,
+        [(header::ETAG, etag(version))],
+        "Changed since it was read; fetch it again and reapply the edit",
+      )
+        .into_response(),
+      ApiError::Database(err) => {
+        tracing::error!(error = %err, "database error");
+        (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+      }
+      ApiError::Storage(err) => {
+        tracing::error!(error = %err, "storage error");
+        (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+      }
+    }
+  }
+}
@@ -0,0 +1,302 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Map, Value};
+
+pub const ATOM_NAVIGATION: &str = "application/atom+xml;profile=opds-catalog;kind=navigation";
+pub const ATOM_ACQUISITION: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+pub const ATOM_ENTRY: &str = "application/atom+xml;type=entry;profile=opds-catalog";
+pub const OPDS_JSON: &str = "application/opds+json";
+pub const OPENSEARCH: &str = "application/opensearchdescription+xml";
+
+pub const REL_ACQUISITION: &str = "http://opds-spec.org/acquisition";
+pub const REL_IMAGE: &str = "http://opds-spec.org/image";
+pub const REL_THUMBNAIL: &str = "http://opds-spec.org/image/thumbnail";
+
+/// Percent-encodes `value` for use as one path segment, leaving only RFC 3986 unreserved characters as they are.
+pub fn path_segment(value: &str) -> String {
+  let mut encoded = String::with_capacity(value.len());
+  for byte in value.bytes() {
+    match byte {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(char::from(byte)),
+      _ => encoded.push_str(&format!("%{:02X}", byte)),
+    }
+  }
+  encoded
+}
+
+/// The two serialisations we serve: OPDS 1.2 (Atom) and OPDS 2.0 (JSON).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+  Atom,
+  Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeedKind {
+  Navigation,
+  Acquisition,
+}
+
+impl Format {
+  fn prefix(self) -> &'static str {
+    match self {
+      Format::Atom => "/opds",
+      Format::Json => "/opds/v2",
+    }
+  }
+
+  /// Resolves a catalog path such as `/authors/1` to the route serving this format.
+  pub fn href(self, path: &str) -> String {
+    format!("{}{}", self.prefix(), path)
+  }
+
+  pub fn feed_type(self, kind: FeedKind) -> &'static str {
+    match (self, kind) {
+      (Format::Atom, FeedKind::Navigation) => ATOM_NAVIGATION,
+      (Format::Atom, FeedKind::Acquisition) => ATOM_ACQUISITION,
+      (Format::Json, _) => OPDS_JSON,
+    }
+  }
+
+  pub fn content_type(self) -> &'static str {
+    match self {
+      Format::Atom => "application/atom+xml;charset=utf-8",
+      Format::Json => OPDS_JSON,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Link {
+  pub rel: String,
+  pub href: String,
+  pub kind: String,
+  pub title: Option<String>,
+  pub templated: bool,
+}
+
+impl Link {
+  pub fn new(rel: &str, href: String, kind: &str) -> Self {
+    Link {
+      rel: rel.to_string(),
+      href,
+      kind: kind.to_string(),
+      title: None,
+      templated: false,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Navigation {
+  pub id: String,
+  pub title: String,
+  pub href: String,
+  pub kind: FeedKind,
+  pub content: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Publication {
+  pub id: String,
+  pub title: String,
+  pub authors: Vec<String>,
+  pub publisher: Option<String>,
+  pub language: Option<String>,
+  pub description: Option<String>,
+  pub isbn: String,
+  pub series: Option<(String, Option<u16>)>,
+  pub issued: Option<DateTime<Utc>>,
+  pub updated: DateTime<Utc>,
+  pub links: Vec<Link>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Feed {
+  pub id: String,
+  pub title: String,
+  pub kind: FeedKind,
+  pub updated: DateTime<Utc>,
+  pub links: Vec<Link>,
+  pub navigation: Vec<Navigation>,
+  pub publications: Vec<Publication>,
+}
+
+fn timestamp(date: &DateTime<Utc>) -> String {
+  date.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+pub fn escape(text: &str) -> String {
+  let mut escaped = String::with_capacity(text.len());
+  for c in text.chars() {
+    match c {
+      '&' => escaped.push_str("&amp;"),
+      '<' => escaped.push_str("&lt;"),
+      '>' => escaped.push_str("&gt;"),
+      '"' => escaped.push_str("&quot;"),
+      '\'' => escaped.push_str("&apos;"),
+      c => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+fn atom_link(out: &mut String, link: &Link) {
+  out.push_str(&format!(
+    r#"<link rel="{}" href="{}" type="{}""#,
+    escape(&link.rel),
+    escape(&link.href),
+    escape(&link.kind)
+  ));
+  if let Some(title) = &link.title {
+    out.push_str(&format!(r#" title="{}""#, escape(title)));
+  }
+  out.push_str("/>");
+}
+
+fn json_link(link: &Link) -> Value {
+  let mut value = Map::new();
+  value.insert("rel".into(), json!(link.rel));
+  value.insert("href".into(), json!(link.href));
+  value.insert("type".into(), json!(link.kind));
+  if let Some(title) = &link.title {
+    value.insert("title".into(), json!(title));
+  }
+  if link.templated {
+    value.insert("templated".into(), json!(true));
+  }
+  Value::Object(value)
+}
+
+impl Feed {
+  pub fn to_atom(&self) -> String {
+    let mut out = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    out.push_str(r#"<feed xmlns="http://www.w3.org/2005/Atom" xmlns:dc="http://purl.org/dc/terms/" xmlns:opds="http://opds-spec.org/2010/catalog">"#);
+    out.push_str(&format!("<id>{}</id>", escape(&self.id)));
+    out.push_str(&format!("<title>{}</title>", escape(&self.title)));
+    out.push_str(&format!("<updated>{}</updated>", timestamp(&self.updated)));
+    for link in &self.links {
+      atom_link(&mut out, link);
+    }
+
+    for nav in &self.navigation {
+      out.push_str("<entry>");
+      out.push_str(&format!("<title>{}</title>", escape(&nav.title)));
+      out.push_str(&format!("<id>{}</id>", escape(&nav.id)));
+      out.push_str(&format!("<updated>{}</updated>", timestamp(&self.updated)));
+      if let Some(content) = &nav.content {
+        out.push_str(&format!(r#"<content type="text">{}</content>"#, escape(content)));
+      }
+      atom_link(&mut out, &Link::new("subsection", nav.href.clone(), Format::Atom.feed_type(nav.kind)));
+      out.push_str("</entry>");
+    }
+
+    for publication in &self.publications {
+      out.push_str("<entry>");
+      out.push_str(&format!("<title>{}</title>", escape(&publication.title)));
+      out.push_str(&format!("<id>{}</id>", escape(&publication.id)));
+      out.push_str(&format!("<updated>{}</updated>", timestamp(&publication.updated)));
+      for author in &publication.authors {
+        out.push_str(&format!("<author><name>{}</name></author>", escape(author)));
+      }
+      out.push_str(&format!("<dc:identifier>urn:isbn:{}</dc:identifier>", escape(&publication.isbn)));
+      if let Some(publisher) = &publication.publisher {
+        out.push_str(&format!("<dc:publisher>{}</dc:publisher>", escape(publisher)));
+      }
+      if let Some(language) = &publication.language {
+        out.push_str(&format!("<dc:language>{}</dc:language>", escape(language)));
+      }
+      if let Some(issued) = &publication.issued {
+        out.push_str(&format!("<dc:issued>{}</dc:issued>", issued.format("%Y-%m-%d")));
+      }
+      if let Some((series, _)) = &publication.series {
+        out.push_str(&format!(r#"<category term="{0}" label="{0}"/>"#, escape(series)));
+      }
+      if let Some(description) = &publication.description {
+        out.push_str(&format!(r#"<summary type="text">{}</summary>"#, escape(description)));
+      }
+      for link in &publication.links {
+        atom_link(&mut out, link);
+      }
+      out.push_str("</entry>");
+    }
+
+    out.push_str("</feed>");
+    out
+  }
+
+  pub fn to_json(&self) -> Value {
+    let mut feed = Map::new();
+    feed.insert(
+      "metadata".into(),
+      json!({
+        "title": self.title,
+        "modified": timestamp(&self.updated),
+        "numberOfItems": self.navigation.len() + self.publications.len(),
+      }),
+    );
+    feed.insert("links".into(), Value::Array(self.links.iter().map(json_link).collect()));
+
+    if !self.navigation.is_empty() {
+      let navigation = self
+        .navigation
+        .iter()
+        .map(|nav| {
+          json!({
+            "href": nav.href,
+            "title": nav.title,
+            "type": OPDS_JSON,
+            "rel": "subsection",
+          })
+        })
+        .collect();
+      feed.insert("navigation".into(), Value::Array(navigation));
+    }
+
+    if self.kind == FeedKind::Acquisition {
+      let publications = self.publications.iter().map(Publication::to_json).collect();
+      feed.insert("publications".into(), Value::Array(publications));
+    }
+
+    Value::Object(feed)
+  }
+}
+
+impl Publication {
+  fn to_json(&self) -> Value {
+    let mut metadata = Map::new();
+    metadata.insert("@type".into(), json!("http://schema.org/Book"));
+    metadata.insert("identifier".into(), json!(format!("urn:isbn:{}", self.isbn)));
+    metadata.insert("title".into(), json!(self.title));
+    metadata.insert("modified".into(), json!(timestamp(&self.updated)));
+    if !self.authors.is_empty() {
+      let authors: Vec<Value> = self.authors.iter().map(|name| json!({ "name": name })).collect();
+      metadata.insert("author".into(), Value::Array(authors));
+    }
+    if let Some(publisher) = &self.publisher {
+      metadata.insert("publisher".into(), json!(publisher));
+    }
+    if let Some(language) = &self.language {
+      metadata.insert("language".into(), json!(language));
+    }
+    if let Some(issued) = &self.issued {
+      metadata.insert("published".into(), json!(issued.format("%Y-%m-%d").to_string()));
+    }
+    if let Some(description) = &self.description {
+      metadata.insert("description".into(), json!(description));
+    }
+    if let Some((name, position)) = &self.series {
+      metadata.insert("belongsTo".into(), json!({ "series": [{ "name": name, "position": position }] }));
+    }
+
+    // OPDS 2.0 lists covers in their own `images` collection rather than among the links.
+    let (images, links): (Vec<&Link>, Vec<&Link>) = self.links.iter().partition(|link| link.rel == REL_IMAGE || link.rel == REL_THUMBNAIL);
+    let mut publication = json!({
+      "metadata": metadata,
+      "links": links.into_iter().map(json_link).collect::<Vec<Value>>(),
+    });
+    if !images.is_empty() {
+      publication["images"] = images.into_iter().map(json_link).collect();
+    }
+    publication
+  }
+}
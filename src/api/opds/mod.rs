@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+
+use axum::{
+  extract::{Path, Query, State},
+  http::header::CONTENT_TYPE,
+  response::{IntoResponse, Response},
+  routing::get,
+  Router,
+};
+use chrono::Utc;
+use serde::Deserialize;
+use sqlx::{MySql, Transaction};
+
+use super::{ApiError, AppState};
+use crate::{
+  covers::ThumbnailFormat,
+  db::{
+    authors::Author,
+    books::Book,
+    covers::{Cover, CoverThumbnail},
+    files::BookFile,
+    publisher::Publisher,
+    series::Series,
+  },
+};
+
+pub mod feed;
+
+use feed::{path_segment, Feed, FeedKind, Format, Link, Navigation, Publication, OPENSEARCH, REL_ACQUISITION, REL_IMAGE, REL_THUMBNAIL};
+
+/// How many books the "newest additions" feed lists.
+const NEWEST_LIMIT: u32 = 50;
+
+pub fn router() -> Router<AppState> {
+  Router::new()
+    .merge(routes(Format::Atom))
+    .route("/opensearch.xml", get(opensearch))
+    .nest("/v2", routes(Format::Json))
+}
+
+fn routes(format: Format) -> Router<AppState> {
+  Router::new()
+    .route("/", get(move || async move { render(format, root(format)) }))
+    .route(
+      "/authors",
+      get(move |State(state): State<AppState>| async move { Ok::<_, ApiError>(render(format, authors(&state, format).await?)) }),
+    )
+    .route(
+      "/authors/{id}",
+      get(move |State(state): State<AppState>, Path(id): Path<u64>| async move { Ok::<_, ApiError>(render(format, author(&state, format, id).await?)) }),
+    )
+    .route(
+      "/publishers",
+      get(move |State(state): State<AppState>| async move { Ok::<_, ApiError>(render(format, publishers(&state, format).await?)) }),
+    )
+    .route(
+      "/publishers/{id}",
+      get(move |State(state): State<AppState>, Path(id): Path<u16>| async move { Ok::<_, ApiError>(render(format, publisher(&state, format, id).await?)) }),
+    )
+    .route(
+      "/series",
+      get(move |State(state): State<AppState>| async move { Ok::<_, ApiError>(render(format, series_list(&state, format).await?)) }),
+    )
+    .route(
+      "/series/{id}",
+      get(move |State(state): State<AppState>, Path(id): Path<u64>| async move { Ok::<_, ApiError>(render(format, series(&state, format, id).await?)) }),
+    )
+    .route(
+      "/languages",
+      get(move |State(state): State<AppState>| async move { Ok::<_, ApiError>(render(format, languages(&state, format).await?)) }),
+    )
+    .route(
+      "/languages/{language}",
+      get(move |State(state): State<AppState>, Path(language): Path<String>| async move {
+        Ok::<_, ApiError>(render(format, language_books(&state, format, language).await?))
+      }),
+    )
+    .route(
+      "/new",
+      get(move |State(state): State<AppState>| async move { Ok::<_, ApiError>(render(format, newest(&state, format).await?)) }),
+    )
+    .route(
+      "/search",
+      get(move |State(state): State<AppState>, Query(params): Query<SearchParams>| async move {
+        Ok::<_, ApiError>(render(format, search(&state, format, params).await?))
+      }),
+    )
+}
+
+fn render(format: Format, feed: Feed) -> Response {
+  let body = match format {
+    Format::Atom => feed.to_atom(),
+    Format::Json => feed.to_json().to_string(),
+  };
+  ([(CONTENT_TYPE, format.content_type())], body).into_response()
+}
+
+/// An empty feed with the self/start/search links every OPDS feed carries.
+fn new_feed(format: Format, path: &str, title: &str, kind: FeedKind) -> Feed {
+  let search = match format {
+    Format::Atom => Link::new("search", "/opds/opensearch.xml".to_string(), OPENSEARCH),
+    Format::Json => Link {
+      templated: true,
+      ..Link::new("search", format.href("/search{?query}"), format.feed_type(FeedKind::Acquisition))
+    },
+  };
+
+  Feed {
+    id: format!("urn:libby:opds{}", path),
+    title: title.to_string(),
+    kind,
+    updated: Utc::now(),
+    links: vec![
+      Link::new("self", format.href(path), format.feed_type(kind)),
+      Link::new("start", format.href("/"), format.feed_type(FeedKind::Navigation)),
+      search,
+    ],
+    navigation: Vec::new(),
+    publications: Vec::new(),
+  }
+}
+
+fn navigation(format: Format, path: String, title: String, kind: FeedKind, content: Option<String>) -> Navigation {
+  Navigation {
+    id: format!("urn:libby:opds{}", path),
+    href: format.href(&path),
+    title,
+    kind,
+    content,
+  }
+}
+
+/// Everything a page of publications links to, fetched with one query per table rather than per book.
+#[derive(Default)]
+struct Related {
+  authors: HashMap<u64, Vec<String>>,
+  publishers: HashMap<u16, String>,
+  series: HashMap<u64, String>,
+  files: HashMap<u64, Vec<BookFile>>,
+  covers: HashMap<u64, Cover>,
+  thumbnails: HashMap<u64, CoverThumbnail>,
+}
+
+impl Related {
+  async fn fetch<'a>(tx: &mut Transaction<'a, MySql>, books: &[Book]) -> Result<Related, sqlx::Error> {
+    let book_ids: Vec<u64> = books.iter().map(|book| book.id).collect();
+    let publisher_ids: Vec<u16> = books.iter().filter_map(|book| book.publisher_id).collect();
+    let series_ids: Vec<u64> = books.iter().filter_map(|book| book.series_id).collect();
+    let covered: Vec<u64> = books.iter().filter(|book| book.image_formatted).map(|book| book.id).collect();
+
+    let mut related = Related::default();
+    for entry in Author::fetch_by_books(tx, &book_ids).await? {
+      related.authors.entry(entry.book_id).or_default().push(entry.author.name);
+    }
+    related.publishers = Publisher::fetch_many(tx, &publisher_ids)
+      .await?
+      .into_iter()
+      .map(|publisher| (publisher.id, publisher.name))
+      .collect();
+    related.series = Series::fetch_many(tx, &series_ids)
+      .await?
+      .into_iter()
+      .map(|series| (series.id, series.name))
+      .collect();
+    for file in BookFile::fetch_by_books(tx, &book_ids).await? {
+      related.files.entry(file.book_id).or_default().push(file);
+    }
+    related.covers = Cover::fetch_many(tx, &covered).await?.into_iter().map(|cover| (cover.book_id, cover)).collect();
+    for thumbnail in Cover::fetch_thumbnails_by_books(tx, &covered).await? {
+      related.thumbnails.entry(thumbnail.book_id).or_insert(thumbnail);
+    }
+    Ok(related)
+  }
+}
+
+/// A book flagged as having a cover whose row is gone is listed without one rather than failing the feed.
+fn publication(book: Book, related: &mut Related) -> Publication {
+  let authors = related.authors.remove(&book.id).unwrap_or_default();
+  let publisher = book.publisher_id.and_then(|publisher_id| related.publishers.get(&publisher_id).cloned());
+  let series = book
+    .series_id
+    .and_then(|series_id| related.series.get(&series_id).cloned())
+    .map(|name| (name, book.series_index));
+  let mut links: Vec<Link> = related
+    .files
+    .remove(&book.id)
+    .unwrap_or_default()
+    .into_iter()
+    .map(|file| Link::new(REL_ACQUISITION, format!("/files/{}", file.id), &file.mime))
+    .collect();
+  if let Some(cover) = related.covers.get(&book.id) {
+    links.push(Link::new(REL_IMAGE, format!("/books/{}/cover", book.id), &cover.mime));
+    if let Some(thumbnail) = related.thumbnails.get(&book.id) {
+      let mime = ThumbnailFormat::parse(&thumbnail.format).map_or(cover.mime.as_str(), |format| format.mime());
+      links.push(Link::new(REL_THUMBNAIL, format!("/books/{}/cover?size={}", book.id, thumbnail.size), mime));
+    }
+  }
+
+  Publication {
+    id: format!("urn:libby:book:{}", book.id),
+    updated: book.date_last_updated.or(book.date_added).unwrap_or_else(Utc::now),
+    links,
+    title: book.name,
+    authors,
+    publisher,
+    language: book.language,
+    description: book.description,
+    isbn: book.isbn,
+    series,
+    issued: book.date_published,
+  }
+}
+
+pub(crate) async fn acquisition_feed<'a>(tx: &mut Transaction<'a, MySql>, format: Format, path: &str, title: &str, books: Vec<Book>) -> Result<Feed, ApiError> {
+  let mut feed = new_feed(format, path, title, FeedKind::Acquisition);
+  let mut related = Related::fetch(tx, &books).await?;
+  feed.publications = books.into_iter().map(|book| publication(book, &mut related)).collect();
+  Ok(feed)
+}
+
+fn root(format: Format) -> Feed {
+  let mut feed = new_feed(format, "/", "Libby", FeedKind::Navigation);
+  feed.navigation = vec![
+    navigation(format, "/new".into(), "Newest Additions".into(), FeedKind::Acquisition, None),
+    navigation(format, "/authors".into(), "By Author".into(), FeedKind::Navigation, None),
+    navigation(format, "/publishers".into(), "By Publisher".into(), FeedKind::Navigation, None),
+    navigation(format, "/series".into(), "By Series".into(), FeedKind::Navigation, None),
+    navigation(format, "/languages".into(), "By Language".into(), FeedKind::Navigation, None),
+  ];
+  feed
+}
+
+async fn authors(state: &AppState, format: Format) -> Result<Feed, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let mut feed = new_feed(format, "/authors", "Authors", FeedKind::Navigation);
+  feed.navigation = Author::fetch_all(&mut tx)
+    .await?
+    .into_iter()
+    .map(|author| {
+      navigation(
+        format,
+        format!("/authors/{}", author.id),
+        author.name,
+        FeedKind::Acquisition,
+        author.description,
+      )
+    })
+    .collect();
+  Ok(feed)
+}
+
+async fn author(state: &AppState, format: Format, id: u64) -> Result<Feed, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let author = Author::fetch_one(&mut tx, id).await?;
+  let books = Book::fetch_books_by_author(&mut tx, id).await?;
+  acquisition_feed(&mut tx, format, &format!("/authors/{}", id), &author.name, books).await
+}
+
+async fn publishers(state: &AppState, format: Format) -> Result<Feed, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let mut feed = new_feed(format, "/publishers", "Publishers", FeedKind::Navigation);
+  feed.navigation = Publisher::fetch_all(&mut tx)
+    .await?
+    .into_iter()
+    .map(|publisher| {
+      navigation(
+        format,
+        format!("/publishers/{}", publisher.id),
+        publisher.name,
+        FeedKind::Acquisition,
+        publisher.city,
+      )
+    })
+    .collect();
+  Ok(feed)
+}
+
+async fn publisher(state: &AppState, format: Format, id: u16) -> Result<Feed, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let publisher = Publisher::fetch_one(&mut tx, id).await?;
+  let books = Book::fetch_books_by_publisher(&mut tx, id).await?;
+  acquisition_feed(&mut tx, format, &format!("/publishers/{}", id), &publisher.name, books).await
+}
+
+async fn series_list(state: &AppState, format: Format) -> Result<Feed, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let mut feed = new_feed(format, "/series", "Series", FeedKind::Navigation);
+  feed.navigation = Series::fetch_all(&mut tx)
+    .await?
+    .into_iter()
+    .map(|series| navigation(format, format!("/series/{}", series.id), series.name, FeedKind::Acquisition, series.description))
+    .collect();
+  Ok(feed)
+}
+
+async fn series(state: &AppState, format: Format, id: u64) -> Result<Feed, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let series = Series::fetch_one(&mut tx, id).await?;
+  let books = Book::fetch_books_by_series(&mut tx, id).await?;
+  acquisition_feed(&mut tx, format, &format!("/series/{}", id), &series.name, books).await
+}
+
+async fn languages(state: &AppState, format: Format) -> Result<Feed, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let mut feed = new_feed(format, "/languages", "Languages", FeedKind::Navigation);
+  feed.navigation = Book::fetch_languages(&mut tx)
+    .await?
+    .into_iter()
+    .map(|language| navigation(format, format!("/languages/{}", path_segment(&language)), language, FeedKind::Acquisition, None))
+    .collect();
+  Ok(feed)
+}
+
+async fn language_books(state: &AppState, format: Format, language: String) -> Result<Feed, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let books = Book::fetch_books_by_language(&mut tx, &language).await?;
+  acquisition_feed(&mut tx, format, &format!("/languages/{}", path_segment(&language)), &language, books).await
+}
+
+async fn newest(state: &AppState, format: Format) -> Result<Feed, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let books = Book::fetch_newest(&mut tx, NEWEST_LIMIT).await?;
+  acquisition_feed(&mut tx, format, "/new", "Newest Additions", books).await
+}
+
+/// OPDS 1.2 clients send OpenSearch's `q`, OPDS 2.0 clients fill the `{?query}` template.
+#[derive(Debug, Deserialize)]
+struct SearchParams {
+  q: Option<String>,
+  query: Option<String>,
+}
+
+async fn search(state: &AppState, format: Format, params: SearchParams) -> Result<Feed, ApiError> {
+  let terms = params.q.or(params.query).unwrap_or_default();
+  if terms.trim().is_empty() {
+    return Err(ApiError::BadRequest("Missing search terms".into()));
+  }
+
+  let mut tx = state.db.conn.begin().await?;
+  let books = Book::search(&mut tx, terms.trim()).await?;
+  acquisition_feed(&mut tx, format, "/search", &format!("Search: {}", terms.trim()), books).await
+}
+
+async fn opensearch() -> Response {
+  let body = format!(
+    r#"<?xml version="1.0" encoding="UTF-8"?><OpenSearchDescription xmlns="http://a9.com/-/spec/opensearch/1.1/"><ShortName>Libby</ShortName><Description>Search the Libby catalog</Description><InputEncoding>UTF-8</InputEncoding><OutputEncoding>UTF-8</OutputEncoding><Url type="{}" template="/opds/search?q={{searchTerms}}"/></OpenSearchDescription>"#,
+    feed::ATOM_ACQUISITION
+  );
+  ([(CONTENT_TYPE, OPENSEARCH)], body).into_response()
+}
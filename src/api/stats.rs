@@ -0,0 +1,42 @@
+use std::collections::HashMap;
+
+use axum::{
+  extract::{Query, State},
+  routing::get,
+  Json, Router,
+};
+use chrono::{Days, Utc};
+use serde_json::{json, Value};
+
+use super::{ApiError, AppState};
+use crate::db::stats::DailyStats;
+
+/// Days returned when `days` is not given, and the most one request may ask for.
+const DEFAULT_DAYS: u64 = 30;
+const MAX_DAYS: u64 = 3660;
+
+pub fn router() -> Router<AppState> {
+  Router::new().route("/stats/daily", get(daily_stats))
+}
+
+/// The daily rollups for the last `days` days, oldest first. Days the rollup did not run for are left out.
+async fn daily_stats(State(state): State<AppState>, Query(params): Query<HashMap<String, String>>) -> Result<Json<Value>, ApiError> {
+  let days = match params.get("days") {
+    Some(days) => days.parse::<u64>().map_err(|err| ApiError::BadRequest(format!("days: {}", err)))?.min(MAX_DAYS),
+    None => DEFAULT_DAYS,
+  };
+  let since = Utc::now().date_naive() - Days::new(days);
+
+  let mut tx = state.db.conn.begin().await?;
+  let stats = DailyStats::fetch_since(&mut tx, since).await?;
+  Ok(Json(json!(stats
+    .iter()
+    .map(|day| json!({
+      "date": day.date,
+      "books": day.books,
+      "users": day.users,
+      "active_readers": day.active_readers,
+      "books_finished": day.books_finished,
+    }))
+    .collect::<Vec<Value>>())))
+}
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+
+use axum::{
+  body::Bytes,
+  extract::{DefaultBodyLimit, Path, Query, State},
+  http::{header, StatusCode},
+  response::{IntoResponse, Response},
+  routing::{get, post},
+  Json, Router,
+};
+use serde_json::{json, Value};
+use tokio_util::io::ReaderStream;
+
+use super::{covers::save_cover, files::attach_file, ApiError, AppState};
+use crate::{
+  db::{audit::AuditEntry, books::Book, calibre::CalibreLink, user::User},
+  transfer::{
+    calibre,
+    citation::{self, Citation, Style},
+    csv::{self, ColumnMapping, Entity, ImportReport, RowError},
+    goodreads::{self, ReadingReport, Resolution, Source},
+    marc, onix,
+  },
+};
+
+/// Largest CSV accepted by an import.
+const MAX_IMPORT_SIZE: usize = 64 * 1024 * 1024;
+
+pub fn router() -> Router<AppState> {
+  Router::new()
+    .route("/export/{entity}", get(export))
+    .route("/import/calibre", post(import_calibre))
+    .route("/import/marc", post(import_marc).layer(DefaultBodyLimit::max(MAX_IMPORT_SIZE)))
+    .route("/export/marc", get(export_marc))
+    .route("/export/citations", get(export_citations))
+    .route("/books/{id}/citation", get(cite_book))
+    .route("/import/onix", post(import_onix).layer(DefaultBodyLimit::max(MAX_IMPORT_SIZE)))
+    .route("/import/{entity}", post(import).layer(DefaultBodyLimit::max(MAX_IMPORT_SIZE)))
+    .route(
+      "/users/{id}/import/{source}",
+      post(import_reading).layer(DefaultBodyLimit::max(MAX_IMPORT_SIZE)),
+    )
+}
+
+fn entity(value: &str) -> Result<Entity, ApiError> {
+  Entity::parse(value).ok_or_else(|| ApiError::BadRequest(format!("unknown entity '{}'", value)))
+}
+
+/// Reads `column.<field>=<header>` query parameters into a column mapping.
+fn mapping(params: &HashMap<String, String>) -> ColumnMapping {
+  ColumnMapping::new(
+    params
+      .iter()
+      .filter_map(|(key, header)| key.strip_prefix("column.").map(|field| (field.to_string(), header.clone())))
+      .collect(),
+  )
+}
+
+fn dry_run(params: &HashMap<String, String>) -> bool {
+  params.get("dry_run").is_some_and(|value| value != "false" && value != "0")
+}
+
+fn error_json(error: &RowError) -> Value {
+  json!({ "row": error.row, "column": error.column, "message": error.message })
+}
+
+fn report_json(report: &ImportReport) -> Value {
+  json!({
+    "rows": report.rows,
+    "created": report.created,
+    "updated": report.updated,
+    "unchanged": report.unchanged,
+    "applied": report.applied,
+    "errors": report.errors.iter().map(error_json).collect::<Vec<Value>>(),
+  })
+}
+
+fn reading_report_json(report: &ReadingReport) -> Value {
+  let entries: Vec<Value> = report
+    .entries
+    .iter()
+    .map(|entry| {
+      let (resolution, score, candidates) = match &entry.resolution {
+        Resolution::Isbn => ("isbn", None, None),
+        Resolution::Fuzzy { score } => ("fuzzy", Some(*score), None),
+        Resolution::Created => ("created", None, None),
+        Resolution::Ambiguous { candidates } => ("ambiguous", None, Some(candidates)),
+      };
+      json!({
+        "row": entry.row,
+        "title": entry.title,
+        "isbn": entry.isbn,
+        "book_id": entry.book_id,
+        "resolution": resolution,
+        "score": score,
+        "candidates": candidates,
+      })
+    })
+    .collect();
+  json!({
+    "applied": report.applied,
+    "entries": entries,
+    "errors": report.errors.iter().map(error_json).collect::<Vec<Value>>(),
+  })
+}
+
+async fn export(State(state): State<AppState>, Path(name): Path<String>, Query(params): Query<HashMap<String, String>>) -> Result<Response, ApiError> {
+  let entity = entity(&name)?;
+  let mut tx = state.db.conn.begin().await?;
+  let body = csv::export(&mut tx, entity, &mapping(&params)).await?;
+  Ok(
+    (
+      [
+        (header::CONTENT_TYPE, String::from("text/csv; charset=utf-8")),
+        (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.csv\"", name)),
+      ],
+      body,
+    )
+      .into_response(),
+  )
+}
+
+/// Rejected imports answer 422 with the row-level errors and write nothing. `dry_run` validates without writing.
+async fn import(
+  State(state): State<AppState>,
+  Path(name): Path<String>,
+  Query(params): Query<HashMap<String, String>>,
+  body: Bytes,
+) -> Result<(StatusCode, Json<Value>), ApiError> {
+  let entity = entity(&name)?;
+  let dry_run = dry_run(&params);
+
+  let mut tx = state.db.conn.begin().await?;
+  AuditEntry::set_actor(&mut tx, &format!("import:csv:{}", name)).await?;
+  let report = csv::import(&mut tx, entity, &mapping(&params), &body, dry_run).await?;
+  if report.applied {
+    tx.commit().await?;
+  }
+
+  let status = if report.errors.is_empty() {
+    StatusCode::OK
+  } else {
+    StatusCode::UNPROCESSABLE_ENTITY
+  };
+  Ok((status, Json(report_json(&report))))
+}
+
+/// Imports a Goodreads or StoryGraph export into a user's library and answers with the reconciliation report.
+async fn import_reading(
+  State(state): State<AppState>,
+  Path((user_id, source)): Path<(u8, String)>,
+  Query(params): Query<HashMap<String, String>>,
+  body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+  let source = Source::parse(&source).ok_or_else(|| ApiError::BadRequest(format!("unknown source '{}'", source)))?;
+  let dry_run = dry_run(&params);
+
+  let mut tx = state.db.conn.begin().await?;
+  User::fetch_one(&mut tx, user_id).await?;
+  AuditEntry::set_actor(&mut tx, &format!("user:{}", user_id)).await?;
+  let report = goodreads::import(&mut tx, user_id, source, &body, dry_run).await?;
+  if report.applied {
+    tx.commit().await?;
+  }
+  Ok(Json(reading_report_json(&report)))
+}
+
+/// Syncs the configured Calibre library. Metadata is committed first; files or covers that fail to copy are reported as warnings and retried on the next sync.
+async fn import_calibre(State(state): State<AppState>) -> Result<Json<Value>, ApiError> {
+  Ok(Json(calibre_import(&state).await?))
+}
+
+/// Syncs the Calibre library at `storage.calibre_library` into the catalog, then attaches the covers and ebooks of the
+/// books it changed. Files that cannot be attached are reported as warnings rather than failing the import. Clients
+/// never name the folder, so they cannot have the server read anywhere else.
+pub async fn calibre_import(state: &AppState) -> Result<Value, ApiError> {
+  let path = &state.config.storage.calibre_library;
+  if path.is_empty() {
+    return Err(ApiError::BadRequest(String::from("Calibre import is off; set storage.calibre_library")));
+  }
+  let root = std::path::PathBuf::from(path);
+  let library = calibre::read_library(&root)
+    .await
+    .map_err(|err| ApiError::BadRequest(format!("cannot read Calibre library at {}: {}", path, err)))?;
+
+  let mut tx = state.db.conn.begin().await?;
+  AuditEntry::set_actor(&mut tx, "import:calibre").await?;
+  let report = calibre::sync(&mut tx, &library).await?;
+  tx.commit().await?;
+
+  let mut attached = 0;
+  let mut warnings = Vec::new();
+  for pending in &report.pending {
+    let failures = warnings.len();
+    // The Calibre cover goes first, so attaching files does not extract one of its own.
+    if let Some(cover) = &pending.cover {
+      let saved = match tokio::fs::read(cover).await {
+        Ok(bytes) => save_cover(state, pending.book_id, bytes, "calibre", "import:calibre").await.map(|_| ()),
+        Err(err) => Err(err.into()),
+      };
+      if let Err(err) = saved {
+        warnings.push(format!("{}: {:?}", cover.display(), err));
+      }
+    }
+    for path in &pending.files {
+      let filename = path.file_name().map(|name| name.to_string_lossy().into_owned());
+      let attached_file = match tokio::fs::File::open(path).await {
+        Ok(file) => attach_file(state, pending.book_id, ReaderStream::new(file), filename, "import:calibre")
+          .await
+          .map(|_| ()),
+        Err(err) => Err(err.into()),
+      };
+      match attached_file {
+        Ok(()) => attached += 1,
+        Err(err) => warnings.push(format!("{}: {:?}", path.display(), err)),
+      }
+    }
+    // Forget when Calibre last changed the book, so the next sync picks it up again.
+    if warnings.len() > failures {
+      let mut tx = state.db.conn.begin().await?;
+      CalibreLink::upsert(&mut tx, &pending.uuid, pending.book_id, "").await?;
+      tx.commit().await?;
+    }
+  }
+
+  Ok(json!({
+    "books": library.books.len(),
+    "created": report.created,
+    "updated": report.updated,
+    "unchanged": report.unchanged,
+    "missing": report.missing,
+    "trashed": report.trashed,
+    "files_attached": attached,
+    "warnings": warnings,
+  }))
+}
+
+/// Accepts ISO 2709 or MARCXML, telling them apart by content. Records that cannot be imported are listed and the rest committed.
+async fn import_marc(State(state): State<AppState>, body: Bytes) -> Result<Json<Value>, ApiError> {
+  let records = marc::parse(&body).map_err(|err| ApiError::BadRequest(format!("not a MARC file: {}", err)))?;
+
+  let mut tx = state.db.conn.begin().await?;
+  AuditEntry::set_actor(&mut tx, "import:marc").await?;
+  let report = marc::import(&mut tx, &records).await?;
+  tx.commit().await?;
+
+  Ok(Json(json!({
+    "records": report.records,
+    "created": report.created,
+    "updated": report.updated,
+    "errors": report.errors.iter().map(error_json).collect::<Vec<Value>>(),
+  })))
+}
+
+/// `?style=` is one of bibtex, ris, csl-json, apa, mla or chicago; BibTeX when omitted.
+fn style(params: &HashMap<String, String>) -> Result<Style, ApiError> {
+  params
+    .get("style")
+    .map_or(Ok(Style::Bibtex), |style| style.parse().map_err(ApiError::BadRequest))
+}
+
+async fn cite_book(State(state): State<AppState>, Path(book_id): Path<u64>, Query(params): Query<HashMap<String, String>>) -> Result<Response, ApiError> {
+  let style = style(&params)?;
+  let mut tx = state.db.conn.begin().await?;
+  let book = Book::fetch_one(&mut tx, book_id).await?;
+  let citation = Citation::fetch(&mut tx, &book).await?;
+  Ok(([(header::CONTENT_TYPE, style.mime())], citation.render(style)).into_response())
+}
+
+/// The whole catalog as one bibliography, ordered as the catalog lists books.
+async fn export_citations(State(state): State<AppState>, Query(params): Query<HashMap<String, String>>) -> Result<Response, ApiError> {
+  let style = style(&params)?;
+  let mut tx = state.db.conn.begin().await?;
+  let books = Book::fetch_all(&mut tx).await?;
+  let citations = Citation::fetch_many(&mut tx, &books).await?;
+  Ok(
+    (
+      [
+        (header::CONTENT_TYPE, String::from(style.mime())),
+        (header::CONTENT_DISPOSITION, format!("attachment; filename=\"citations.{}\"", style.extension())),
+      ],
+      citation::render_all(&citations, style),
+    )
+      .into_response(),
+  )
+}
+
+/// Applies an ONIX 3.0 message. Products that cannot be applied are listed and the rest committed, along with the element
+/// paths that were present but not mapped onto the catalog.
+async fn import_onix(State(state): State<AppState>, body: String) -> Result<Json<Value>, ApiError> {
+  let products = onix::parse_products(&body).map_err(|err| ApiError::BadRequest(format!("not an ONIX message: {}", err)))?;
+
+  let mut tx = state.db.conn.begin().await?;
+  AuditEntry::set_actor(&mut tx, "import:onix").await?;
+  let report = onix::ingest(&mut tx, &products).await?;
+  tx.commit().await?;
+
+  Ok(Json(json!({
+    "products": report.products,
+    "created": report.created,
+    "updated": report.updated,
+    "deleted": report.deleted,
+    "errors": report.errors.iter().map(error_json).collect::<Vec<Value>>(),
+    "unmapped": report.unmapped,
+  })))
+}
+
+/// `?format=xml` for MARCXML, otherwise ISO 2709.
+async fn export_marc(State(state): State<AppState>, Query(params): Query<HashMap<String, String>>) -> Result<Response, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let records = marc::export(&mut tx).await?;
+  let response = match params.get("format").map(String::as_str) {
+    Some("xml") | Some("marcxml") => ([(header::CONTENT_TYPE, "application/marcxml+xml")], marc::write_marcxml(&records)).into_response(),
+    _ => ([(header::CONTENT_TYPE, "application/marc")], marc::write_iso2709(&records)).into_response(),
+  };
+  Ok(response)
+}
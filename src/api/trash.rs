@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+
+use axum::{
+  extract::{Extension, Path, Query, State},
+  routing::{get, post},
+  Json, Router,
+};
+use serde_json::{json, Value};
+
+use super::{
+  auth::Caller,
+  catalog::{author_json, book_json},
+  ApiError, AppState,
+};
+use crate::{
+  db::{audit::AuditEntry, authors::Author, books::Book},
+  trash::{self, PurgeReport},
+};
+
+pub fn router() -> Router<AppState> {
+  Router::new()
+    .route("/trash", get(list_trash))
+    .route("/trash/purge", post(purge_trash))
+    .route("/books/{id}/restore", post(restore_book))
+    .route("/authors/{id}/restore", post(restore_author))
+}
+
+fn purge_json(report: &PurgeReport) -> Value {
+  json!({
+    "books": report.books,
+    "authors": report.authors,
+    "files": report.files,
+    "covers": report.covers,
+  })
+}
+
+async fn list_trash(State(state): State<AppState>) -> Result<Json<Value>, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let books = Book::fetch_deleted(&mut tx).await?;
+  let authors = Author::fetch_deleted(&mut tx).await?;
+  Ok(Json(json!({
+    "books": books.iter().map(book_json).collect::<Vec<Value>>(),
+    "authors": authors.iter().map(author_json).collect::<Vec<Value>>(),
+  })))
+}
+
+async fn restore_book(State(state): State<AppState>, Extension(caller): Extension<Caller>, Path(book_id): Path<u64>) -> Result<Json<Value>, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  AuditEntry::set_actor(&mut tx, &caller.actor()).await?;
+  let book = Book::restore(&mut tx, book_id).await?;
+  tx.commit().await?;
+  Ok(Json(book_json(&book)))
+}
+
+async fn restore_author(State(state): State<AppState>, Extension(caller): Extension<Caller>, Path(author_id): Path<u64>) -> Result<Json<Value>, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  AuditEntry::set_actor(&mut tx, &caller.actor()).await?;
+  let author = Author::restore(&mut tx, author_id).await?;
+  tx.commit().await?;
+  Ok(Json(author_json(&author)))
+}
+
+/// Empties the trash, or with `days` only what has been in it at least that long.
+async fn purge_trash(
+  State(state): State<AppState>,
+  Extension(caller): Extension<Caller>,
+  Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, ApiError> {
+  let days = match params.get("days") {
+    Some(days) => days.parse::<u32>().map_err(|err| ApiError::BadRequest(format!("days: {}", err)))?,
+    None => 0,
+  };
+  let cutoff = chrono::Utc::now() - chrono::Duration::days(days.into());
+  let report = trash::purge_expired(&state.db, &state.storage, &state.covers.storage, cutoff, &caller.actor()).await?;
+  Ok(Json(purge_json(&report)))
+}
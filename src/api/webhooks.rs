@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+
+use axum::{
+  extract::{Path, Query, State},
+  http::StatusCode,
+  routing::{get, post},
+  Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::{ApiError, AppState};
+use crate::db::webhooks::{Webhook, WebhookDelivery, EVENTS};
+
+/// Deliveries listed when `limit` is not given, and the most one request may ask for.
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 1000;
+
+/// Shortest secret accepted, so signatures cannot be brute-forced.
+const MIN_SECRET_LENGTH: usize = 16;
+
+pub fn router() -> Router<AppState> {
+  Router::new()
+    .route("/webhooks", get(list_webhooks).post(create_webhook))
+    .route("/webhooks/{id}", get(fetch_webhook).delete(delete_webhook))
+    .route("/webhooks/{id}/enable", post(enable_webhook))
+    .route("/webhooks/{id}/disable", post(disable_webhook))
+    .route("/webhooks/{id}/deliveries", get(list_deliveries))
+}
+
+/// The secret is write-only: receivers already have it, and nobody else should.
+fn webhook_json(webhook: &Webhook) -> Value {
+  json!({
+    "id": webhook.id,
+    "url": webhook.url,
+    "events": webhook.events.split(',').filter(|event| !event.is_empty()).collect::<Vec<&str>>(),
+    "enabled": webhook.enabled,
+    "failures": webhook.failures,
+    "date_added": webhook.date_added,
+    "date_last_updated": webhook.date_last_updated,
+  })
+}
+
+fn delivery_json(delivery: &WebhookDelivery) -> Value {
+  json!({
+    "id": delivery.id,
+    "event": delivery.event,
+    "payload": serde_json::from_str::<Value>(&delivery.payload).unwrap_or_else(|_| Value::String(delivery.payload.clone())),
+    "status": delivery.status,
+    "attempts": delivery.attempts,
+    "response_status": delivery.response_status,
+    "error": delivery.error,
+    "next_attempt_at": delivery.next_attempt_at,
+    "date_added": delivery.date_added,
+    "date_delivered": delivery.date_delivered,
+  })
+}
+
+#[derive(Deserialize)]
+struct NewWebhook {
+  url: String,
+  secret: String,
+  events: Vec<String>,
+}
+
+async fn list_webhooks(State(state): State<AppState>) -> Result<Json<Value>, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let webhooks = Webhook::fetch_all(&mut tx).await?;
+  Ok(Json(json!(webhooks.iter().map(webhook_json).collect::<Vec<Value>>())))
+}
+
+async fn create_webhook(State(state): State<AppState>, Json(body): Json<NewWebhook>) -> Result<(StatusCode, Json<Value>), ApiError> {
+  let url = reqwest::Url::parse(&body.url).map_err(|err| ApiError::BadRequest(format!("url: {}", err)))?;
+  if !matches!(url.scheme(), "http" | "https") {
+    return Err(ApiError::BadRequest(String::from("url: must be http or https")));
+  }
+  if body.secret.len() < MIN_SECRET_LENGTH {
+    return Err(ApiError::BadRequest(format!("secret: must be at least {} characters", MIN_SECRET_LENGTH)));
+  }
+  if body.events.is_empty() {
+    return Err(ApiError::BadRequest(format!("events: subscribe to at least one of {}", EVENTS.join(", "))));
+  }
+  if let Some(unknown) = body.events.iter().find(|event| !EVENTS.contains(&event.as_str())) {
+    return Err(ApiError::BadRequest(format!(
+      "events: unknown event {}; expected one of {}",
+      unknown,
+      EVENTS.join(", ")
+    )));
+  }
+
+  let mut tx = state.db.conn.begin().await?;
+  let webhook = Webhook::create(&mut tx, url.as_str(), &body.secret, &body.events).await?;
+  tx.commit().await?;
+  Ok((StatusCode::CREATED, Json(webhook_json(&webhook))))
+}
+
+async fn fetch_webhook(State(state): State<AppState>, Path(webhook_id): Path<u64>) -> Result<Json<Value>, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let webhook = Webhook::fetch_one(&mut tx, webhook_id).await?;
+  Ok(Json(webhook_json(&webhook)))
+}
+
+async fn delete_webhook(State(state): State<AppState>, Path(webhook_id): Path<u64>) -> Result<StatusCode, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  if Webhook::delete(&mut tx, webhook_id).await?.rows_affected() == 0 {
+    return Err(ApiError::NotFound);
+  }
+  tx.commit().await?;
+  Ok(StatusCode::NO_CONTENT)
+}
+
+/// Also clears the failure count, so a webhook disabled for failing gets a fresh run.
+async fn enable_webhook(State(state): State<AppState>, Path(webhook_id): Path<u64>) -> Result<Json<Value>, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let webhook = Webhook::set_enabled(&mut tx, webhook_id, true).await?;
+  tx.commit().await?;
+  Ok(Json(webhook_json(&webhook)))
+}
+
+async fn disable_webhook(State(state): State<AppState>, Path(webhook_id): Path<u64>) -> Result<Json<Value>, ApiError> {
+  let mut tx = state.db.conn.begin().await?;
+  let webhook = Webhook::set_enabled(&mut tx, webhook_id, false).await?;
+  tx.commit().await?;
+  Ok(Json(webhook_json(&webhook)))
+}
+
+/// The delivery log, newest first.
+async fn list_deliveries(
+  State(state): State<AppState>,
+  Path(webhook_id): Path<u64>,
+  Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, ApiError> {
+  let limit = match params.get("limit") {
+    Some(limit) => limit
+      .parse::<u32>()
+      .map_err(|err| ApiError::BadRequest(format!("limit: {}", err)))?
+      .min(MAX_LIMIT),
+    None => DEFAULT_LIMIT,
+  };
+
+  let mut tx = state.db.conn.begin().await?;
+  Webhook::fetch_one(&mut tx, webhook_id).await?;
+  let deliveries = WebhookDelivery::fetch_by_webhook(&mut tx, webhook_id, limit).await?;
+  Ok(Json(json!(deliveries.iter().map(delivery_json).collect::<Vec<Value>>())))
+}
@@ -0,0 +1,525 @@
+//! Whole-library backups: every table plus the file and cover blobs, in one zip archive with a manifest.
+//!
+//! An archive holds `manifest.json`, one `tables/<name>.jsonl` per table (a JSON array of column values per line, in the
+//! manifest's column order), and the blobs under `files/<sha256>` and `covers/<sha256>`.
+
+use std::{
+  collections::HashSet,
+  fmt,
+  fs::File,
+  io::{self, BufRead, BufReader, Read, Seek, Write},
+  path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use sqlx::{mysql::MySqlRow, query, Acquire, Column, MySql, Row, Transaction, TypeInfo};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{
+  db::{covers::Cover, files::BookFile, Db, SCHEMA_VERSION},
+  storage::Storage,
+};
+
+/// Bumped whenever the archive layout changes in a way older restores cannot read.
+pub const FORMAT_VERSION: u16 = 1;
+
+/// Rows per multi-row `INSERT` on restore, kept well under MySQL's placeholder limit.
+const INSERT_BATCH: usize = 500;
+
+#[derive(Debug)]
+pub enum BackupError {
+  Database(sqlx::Error),
+  Io(io::Error),
+  /// The archive cannot be restored as it is, or not into this database.
+  Invalid(String),
+}
+
+impl fmt::Display for BackupError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      BackupError::Database(err) => write!(f, "database error: {}", err),
+      BackupError::Io(err) => write!(f, "i/o error: {}", err),
+      BackupError::Invalid(message) => write!(f, "{}", message),
+    }
+  }
+}
+
+impl std::error::Error for BackupError {}
+
+impl From<sqlx::Error> for BackupError {
+  fn from(err: sqlx::Error) -> Self {
+    BackupError::Database(err)
+  }
+}
+
+impl From<io::Error> for BackupError {
+  fn from(err: io::Error) -> Self {
+    BackupError::Io(err)
+  }
+}
+
+impl From<zip::result::ZipError> for BackupError {
+  fn from(err: zip::result::ZipError) -> Self {
+    match err {
+      zip::result::ZipError::Io(err) => BackupError::Io(err),
+      err => BackupError::Invalid(format!("not a backup archive: {}", err)),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TableManifest {
+  pub name: String,
+  pub columns: Vec<String>,
+  pub rows: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Manifest {
+  pub format: u16,
+  pub libby_version: String,
+  pub schema_version: u16,
+  pub created: DateTime<Utc>,
+  pub tables: Vec<TableManifest>,
+  pub files: Vec<String>,
+  pub covers: Vec<String>,
+}
+
+impl Manifest {
+  /// Checks the archive is one this build can restore: a known layout, and a schema no newer than ours.
+  pub fn validate(&self) -> Result<(), BackupError> {
+    if self.format != FORMAT_VERSION {
+      return Err(BackupError::Invalid(format!(
+        "archive format {} is not supported (expected {})",
+        self.format, FORMAT_VERSION
+      )));
+    }
+    if self.schema_version > SCHEMA_VERSION {
+      return Err(BackupError::Invalid(format!(
+        "archive schema version {} is newer than this build's {}; restore it with libby {} or later",
+        self.schema_version, SCHEMA_VERSION, self.libby_version
+      )));
+    }
+    Ok(())
+  }
+}
+
+/// A table's rows as read from the database, ready to be written out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableDump {
+  pub name: String,
+  pub columns: Vec<String>,
+  pub rows: Vec<Vec<Value>>,
+}
+
+/// Encodes a column value as JSON by its MySQL type. Blobs are stored as `{"hex": "…"}` so they cannot be mistaken for text.
+fn column_value(row: &MySqlRow, index: usize) -> Result<Value, sqlx::Error> {
+  let type_name = row.column(index).type_info().name();
+  let value = match type_name {
+    "BOOLEAN" => json!(row.try_get::<Option<bool>, _>(index)?),
+    name if name.ends_with("UNSIGNED") || name == "YEAR" => json!(row.try_get::<Option<u64>, _>(index)?),
+    "TINYINT" | "SMALLINT" | "MEDIUMINT" | "INT" | "BIGINT" => json!(row.try_get::<Option<i64>, _>(index)?),
+    "FLOAT" | "DOUBLE" => json!(row.try_get::<Option<f64>, _>(index)?),
+    "TIMESTAMP" | "DATETIME" => json!(row
+      .try_get::<Option<NaiveDateTime>, _>(index)?
+      .map(|date| date.format("%Y-%m-%d %H:%M:%S%.f").to_string())),
+    "DATE" => json!(row.try_get::<Option<NaiveDate>, _>(index)?.map(|date| date.to_string())),
+    "BLOB" | "TINYBLOB" | "MEDIUMBLOB" | "LONGBLOB" | "BINARY" | "VARBINARY" => {
+      json!(row.try_get::<Option<Vec<u8>>, _>(index)?.map(|bytes| json!({ "hex": hex::encode(bytes) })))
+    }
+    // JSON columns arrive with the binary charset, and text columns may use a collation sqlx does not check for; both
+    // are text all the same, and go back in as strings.
+    _ => json!(row.try_get_unchecked::<Option<String>, _>(index)?),
+  };
+  Ok(value)
+}
+
+/// Every base table in the current database besides `schema_version`, which the migrations recreate on restore.
+async fn table_names<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Vec<String>, sqlx::Error> {
+  sqlx::query_scalar::<MySql, String>(
+    r#"SELECT CAST(`table_name` AS CHAR) FROM `information_schema`.`tables`
+    WHERE `table_schema` = DATABASE() AND `table_type` = 'BASE TABLE' AND `table_name` != 'schema_version'
+    ORDER BY `table_name`"#,
+  )
+  .fetch_all(&mut **tx)
+  .await
+}
+
+/// Describes the first foreign key whose column holds a value missing from the table it references, if any. Loading
+/// with `FOREIGN_KEY_CHECKS = 0` leaves this to be checked by hand.
+async fn dangling_reference<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Option<String>, sqlx::Error> {
+  let keys = sqlx::query_as::<MySql, (String, String, String, String)>(
+    r#"SELECT CAST(`table_name` AS CHAR), CAST(`column_name` AS CHAR),
+      CAST(`referenced_table_name` AS CHAR), CAST(`referenced_column_name` AS CHAR)
+    FROM `information_schema`.`key_column_usage`
+    WHERE `table_schema` = DATABASE() AND `referenced_table_name` IS NOT NULL
+    ORDER BY `table_name`, `column_name`"#,
+  )
+  .fetch_all(&mut **tx)
+  .await?;
+
+  for (table, column, referenced_table, referenced_column) in keys {
+    let sql = format!(
+      "SELECT COUNT(*) FROM {child} LEFT JOIN {parent} ON {parent}.{parent_column} = {child}.{column}
+      WHERE {child}.{column} IS NOT NULL AND {parent}.{parent_column} IS NULL",
+      child = quote(&table),
+      column = quote(&column),
+      parent = quote(&referenced_table),
+      parent_column = quote(&referenced_column),
+    );
+    let missing: i64 = sqlx::query_scalar(&sql).fetch_one(&mut **tx).await?;
+    if missing > 0 {
+      return Ok(Some(format!(
+        "{} rows of {}.{} reference missing {}.{}",
+        missing, table, column, referenced_table, referenced_column
+      )));
+    }
+  }
+  Ok(None)
+}
+
+async fn column_names<'a>(tx: &mut Transaction<'a, MySql>, table: &str) -> Result<Vec<String>, sqlx::Error> {
+  sqlx::query_scalar::<MySql, String>(
+    r#"SELECT CAST(`column_name` AS CHAR) FROM `information_schema`.`columns`
+    WHERE `table_schema` = DATABASE() AND `table_name` = ?
+    ORDER BY `ordinal_position`"#,
+  )
+  .bind(table)
+  .fetch_all(&mut **tx)
+  .await
+}
+
+fn quote(identifier: &str) -> String {
+  format!("`{}`", identifier.replace('`', "``"))
+}
+
+/// Reads every table inside `tx`. InnoDB's repeatable-read snapshot keeps the tables consistent with each other.
+pub async fn dump_tables<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Vec<TableDump>, sqlx::Error> {
+  let mut tables = Vec::new();
+  for name in table_names(tx).await? {
+    let columns = column_names(tx, &name).await?;
+    let select = format!(
+      "SELECT {} FROM {}",
+      columns.iter().map(|column| quote(column)).collect::<Vec<String>>().join(", "),
+      quote(&name)
+    );
+    let mut rows = Vec::new();
+    for row in query(&select).fetch_all(&mut **tx).await? {
+      rows.push(
+        (0..columns.len())
+          .map(|index| column_value(&row, index))
+          .collect::<Result<Vec<Value>, sqlx::Error>>()?,
+      );
+    }
+    tables.push(TableDump { name, columns, rows });
+  }
+  Ok(tables)
+}
+
+fn options(size: u64) -> SimpleFileOptions {
+  SimpleFileOptions::default()
+    .compression_method(CompressionMethod::Deflated)
+    .large_file(size > u32::MAX as u64)
+}
+
+/// Writes the archive to `path` through a temporary file, so an interrupted backup never leaves a truncated archive behind.
+pub fn write_archive(path: &Path, manifest: &Manifest, tables: &[TableDump], files: &Storage, covers: &Storage) -> Result<(), BackupError> {
+  let tmp_path = path.with_extension("partial");
+  let written = (|| -> Result<(), BackupError> {
+    let mut archive = ZipWriter::new(File::create(&tmp_path)?);
+
+    archive.start_file("manifest.json", options(0))?;
+    serde_json::to_writer_pretty(&mut archive, manifest).map_err(io::Error::from)?;
+
+    for table in tables {
+      archive.start_file(format!("tables/{}.jsonl", table.name), options(0))?;
+      for row in &table.rows {
+        serde_json::to_writer(&mut archive, row).map_err(io::Error::from)?;
+        archive.write_all(b"\n")?;
+      }
+    }
+
+    for (prefix, storage, hashes) in [("files", files, &manifest.files), ("covers", covers, &manifest.covers)] {
+      for sha256 in hashes {
+        let mut blob = File::open(storage.path_for(sha256))?;
+        archive.start_file(format!("{}/{}", prefix, sha256), options(blob.metadata()?.len()))?;
+        io::copy(&mut blob, &mut archive)?;
+      }
+    }
+
+    archive.finish()?.sync_all()?;
+    Ok(())
+  })();
+
+  match written {
+    Ok(()) => Ok(std::fs::rename(&tmp_path, path)?),
+    Err(err) => {
+      let _ = std::fs::remove_file(&tmp_path);
+      Err(err)
+    }
+  }
+}
+
+pub fn read_manifest<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Result<Manifest, BackupError> {
+  let entry = archive.by_name("manifest.json")?;
+  serde_json::from_reader(entry).map_err(|err| BackupError::Invalid(format!("unreadable manifest: {}", err)))
+}
+
+/// Reads a table's rows, checking each has as many values as the manifest has columns.
+pub fn read_table<R: Read + Seek>(archive: &mut ZipArchive<R>, table: &TableManifest) -> Result<TableDump, BackupError> {
+  let entry = archive.by_name(&format!("tables/{}.jsonl", table.name))?;
+  let mut rows = Vec::with_capacity(table.rows);
+  for (number, line) in BufReader::new(entry).lines().enumerate() {
+    let line = line?;
+    if line.is_empty() {
+      continue;
+    }
+    let row: Vec<Value> = serde_json::from_str(&line).map_err(|err| BackupError::Invalid(format!("{} row {}: {}", table.name, number + 1, err)))?;
+    if row.len() != table.columns.len() {
+      return Err(BackupError::Invalid(format!(
+        "{} row {} has {} values for {} columns",
+        table.name,
+        number + 1,
+        row.len(),
+        table.columns.len()
+      )));
+    }
+    rows.push(row);
+  }
+  if rows.len() != table.rows {
+    return Err(BackupError::Invalid(format!(
+      "{} has {} rows but the manifest lists {}",
+      table.name,
+      rows.len(),
+      table.rows
+    )));
+  }
+  Ok(TableDump {
+    name: table.name.clone(),
+    columns: table.columns.clone(),
+    rows,
+  })
+}
+
+/// Copies the blobs under `prefix` into `storage`, checking each against its hash. Returns the paths it created, so a
+/// failed restore can take them back out; blobs already present are left alone.
+pub fn extract_blobs<R: Read + Seek>(archive: &mut ZipArchive<R>, prefix: &str, hashes: &[String], storage: &Storage) -> Result<Vec<PathBuf>, BackupError> {
+  let mut created = Vec::new();
+  let extracted = (|| -> Result<(), BackupError> {
+    for sha256 in hashes {
+      if sha256.len() != 64 || !sha256.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(BackupError::Invalid(format!("'{}' is not a blob hash", sha256)));
+      }
+      let path = storage.path_for(sha256);
+      if path.exists() {
+        continue;
+      }
+      std::fs::create_dir_all(path.parent().expect("blob paths are nested"))?;
+      let tmp_path = path.with_extension("restore");
+      let mut entry = archive.by_name(&format!("{}/{}", prefix, sha256))?;
+      let mut out = File::create(&tmp_path)?;
+      let mut hasher = Sha256::new();
+      let mut buf = vec![0u8; 64 * 1024];
+      loop {
+        let read = entry.read(&mut buf)?;
+        if read == 0 {
+          break;
+        }
+        hasher.update(&buf[..read]);
+        out.write_all(&buf[..read])?;
+      }
+      out.sync_all()?;
+      if hex::encode(hasher.finalize()) != *sha256 {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(BackupError::Invalid(format!("{}/{} does not match its hash", prefix, sha256)));
+      }
+      std::fs::rename(&tmp_path, &path)?;
+      created.push(path);
+    }
+    Ok(())
+  })();
+
+  match extracted {
+    Ok(()) => Ok(created),
+    Err(err) => {
+      remove_paths(&created);
+      Err(err)
+    }
+  }
+}
+
+fn remove_paths(paths: &[PathBuf]) {
+  for path in paths {
+    let _ = std::fs::remove_file(path);
+  }
+}
+
+/// Dumps the database and the blobs it references into a new archive at `path`.
+pub async fn backup(db: &Db, files: &Storage, covers: &Storage, path: &Path) -> Result<Manifest, BackupError> {
+  // The version is read in the dump's snapshot, so a migration committing meanwhile cannot mislabel the rows.
+  let mut tx = db.conn.begin().await?;
+  let schema_version = Db::schema_version_in(&mut tx).await?;
+  let tables = dump_tables(&mut tx).await?;
+  let mut file_hashes = BookFile::fetch_hashes(&mut tx).await?;
+  let mut cover_hashes = Cover::fetch_hashes(&mut tx).await?;
+  tx.commit().await?;
+  file_hashes.sort();
+  cover_hashes.sort();
+  cover_hashes.dedup();
+
+  let manifest = Manifest {
+    format: FORMAT_VERSION,
+    libby_version: env!("CARGO_PKG_VERSION").to_string(),
+    schema_version,
+    created: Utc::now(),
+    tables: tables
+      .iter()
+      .map(|table| TableManifest {
+        name: table.name.clone(),
+        columns: table.columns.clone(),
+        rows: table.rows.len(),
+      })
+      .collect(),
+    files: file_hashes,
+    covers: cover_hashes,
+  };
+
+  let (files, covers, path, written) = (files.clone(), covers.clone(), path.to_path_buf(), manifest.clone());
+  tokio::task::spawn_blocking(move || write_archive(&path, &written, &tables, &files, &covers))
+    .await
+    .map_err(io::Error::other)??;
+  Ok(manifest)
+}
+
+fn bind_value<'q>(
+  query: sqlx::query::Query<'q, MySql, sqlx::mysql::MySqlArguments>,
+  value: &Value,
+) -> sqlx::query::Query<'q, MySql, sqlx::mysql::MySqlArguments> {
+  match value {
+    Value::Null => query.bind(None::<String>),
+    Value::Bool(value) => query.bind(*value),
+    Value::Number(number) => match (number.as_u64(), number.as_i64()) {
+      (Some(value), _) => query.bind(value),
+      (None, Some(value)) => query.bind(value),
+      (None, None) => query.bind(number.as_f64()),
+    },
+    Value::String(value) => query.bind(value.clone()),
+    Value::Object(object) => query.bind(object.get("hex").and_then(Value::as_str).and_then(|hex| hex::decode(hex).ok())),
+    Value::Array(_) => query.bind(value.to_string()),
+  }
+}
+
+async fn insert_rows<'a>(tx: &mut Transaction<'a, MySql>, table: &TableDump) -> Result<(), sqlx::Error> {
+  let columns = table.columns.iter().map(|column| quote(column)).collect::<Vec<String>>().join(", ");
+  let placeholders = format!("({})", vec!["?"; table.columns.len()].join(", "));
+  for batch in table.rows.chunks(INSERT_BATCH) {
+    let statement = format!(
+      "INSERT INTO {} ({}) VALUES {}",
+      quote(&table.name),
+      columns,
+      vec![placeholders.as_str(); batch.len()].join(", ")
+    );
+    let mut insert = query(&statement);
+    for value in batch.iter().flatten() {
+      insert = bind_value(insert, value);
+    }
+    insert.execute(&mut **tx).await?;
+  }
+  Ok(())
+}
+
+/// Restores an archive into an empty database: no tables at all, or only empty ones at or below the archive's schema.
+///
+/// The schema is migrated to the archive's version, every row goes in under one transaction, and the remaining
+/// migrations then bring the data up to date. A failure while loading rolls the rows back and removes extracted blobs.
+pub async fn restore(db: &Db, files: &Storage, covers: &Storage, path: &Path) -> Result<Manifest, BackupError> {
+  let archive_path = path.to_path_buf();
+  let (manifest, tables) = tokio::task::spawn_blocking(move || -> Result<(Manifest, Vec<TableDump>), BackupError> {
+    let mut archive = ZipArchive::new(File::open(&archive_path)?)?;
+    let manifest = read_manifest(&mut archive)?;
+    manifest.validate()?;
+    let tables = manifest.tables.iter().map(|table| read_table(&mut archive, table)).collect::<Result<_, _>>()?;
+    Ok((manifest, tables))
+  })
+  .await
+  .map_err(io::Error::other)??;
+
+  let existing: i64 = sqlx::query_scalar(r#"SELECT COUNT(*) FROM `information_schema`.`tables` WHERE `table_schema` = DATABASE()"#)
+    .fetch_one(&db.conn)
+    .await?;
+  if existing > 0 {
+    let version = db.schema_version().await?;
+    if version > manifest.schema_version {
+      return Err(BackupError::Invalid(format!(
+        "database is already at schema version {}, past the archive's {}",
+        version, manifest.schema_version
+      )));
+    }
+    let mut tx = db.conn.begin().await?;
+    for name in table_names(&mut tx).await? {
+      let populated: bool = sqlx::query_scalar(&format!("SELECT EXISTS (SELECT 1 FROM {})", quote(&name)))
+        .fetch_one(&mut *tx)
+        .await?;
+      if populated {
+        return Err(BackupError::Invalid(format!("database is not empty: {} has rows", name)));
+      }
+    }
+  }
+
+  db.migrate_to(manifest.schema_version).await?;
+
+  let (archive_path, blob_manifest, files_storage, covers_storage) = (path.to_path_buf(), manifest.clone(), files.clone(), covers.clone());
+  let created = tokio::task::spawn_blocking(move || -> Result<Vec<PathBuf>, BackupError> {
+    let mut archive = ZipArchive::new(File::open(&archive_path)?)?;
+    let mut created = extract_blobs(&mut archive, "files", &blob_manifest.files, &files_storage)?;
+    match extract_blobs(&mut archive, "covers", &blob_manifest.covers, &covers_storage) {
+      Ok(more) => created.extend(more),
+      Err(err) => {
+        remove_paths(&created);
+        return Err(err);
+      }
+    }
+    Ok(created)
+  })
+  .await
+  .map_err(io::Error::other)??;
+
+  // Rows go in table by table, so foreign keys are switched off while loading and every reference is checked once
+  // everything is in, before the commit. The archive's audit log already covers the rows. Both settings belong to the
+  // session, so they are reset before the connection returns to the pool whatever happens.
+  let mut conn = db.conn.acquire().await?;
+  query("SET FOREIGN_KEY_CHECKS = 0, @libby_audit = 0").execute(&mut *conn).await?;
+  let loaded: Result<(), BackupError> = async {
+    let mut tx = conn.begin().await?;
+    let known: HashSet<String> = table_names(&mut tx).await?.into_iter().collect();
+    if let Some(missing) = tables.iter().find(|table| !known.contains(&table.name)) {
+      return Err(BackupError::Invalid(format!(
+        "table {} is not part of schema version {}",
+        missing.name, manifest.schema_version
+      )));
+    }
+    for table in &tables {
+      insert_rows(&mut tx, table).await?;
+    }
+    if let Some(dangling) = dangling_reference(&mut tx).await? {
+      return Err(BackupError::Invalid(dangling));
+    }
+    tx.commit().await?;
+    Ok(())
+  }
+  .await;
+  if query("SET FOREIGN_KEY_CHECKS = 1, @libby_audit = NULL").execute(&mut *conn).await.is_err() {
+    conn.detach();
+  }
+
+  if let Err(err) = loaded {
+    remove_paths(&created);
+    return Err(err);
+  }
+
+  db.migrate().await?;
+  Ok(manifest)
+}
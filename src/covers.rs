@@ -0,0 +1,251 @@
+use std::{
+  collections::HashMap,
+  io::{self, Cursor, Read, Seek},
+};
+
+use image::{codecs::webp::WebPEncoder, imageops::FilterType, DynamicImage, GenericImageView, ImageFormat};
+use quick_xml::{events::Event, Reader};
+use zip::ZipArchive;
+
+use crate::{db::files::FileFormat, storage::Storage};
+
+/// Encoding used for generated thumbnails. Originals are always kept as uploaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThumbnailFormat {
+  Webp,
+  Jpeg,
+}
+
+impl ThumbnailFormat {
+  pub fn as_str(self) -> &'static str {
+    match self {
+      ThumbnailFormat::Webp => "webp",
+      ThumbnailFormat::Jpeg => "jpeg",
+    }
+  }
+
+  pub fn mime(self) -> &'static str {
+    match self {
+      ThumbnailFormat::Webp => "image/webp",
+      ThumbnailFormat::Jpeg => "image/jpeg",
+    }
+  }
+
+  pub fn parse(value: &str) -> Option<ThumbnailFormat> {
+    match value.to_ascii_lowercase().as_str() {
+      "webp" => Some(ThumbnailFormat::Webp),
+      "jpeg" | "jpg" => Some(ThumbnailFormat::Jpeg),
+      _ => None,
+    }
+  }
+}
+
+/// Where covers are kept and which thumbnails are generated for them.
+#[derive(Debug, Clone)]
+pub struct Covers {
+  pub storage: Storage,
+  /// Longest-edge sizes, in pixels, generated for every cover.
+  pub sizes: Vec<u32>,
+  pub format: ThumbnailFormat,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Thumbnail {
+  pub size: u32,
+  pub width: u32,
+  pub height: u32,
+  pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessedCover {
+  pub mime: &'static str,
+  pub width: u32,
+  pub height: u32,
+  /// `#rrggbb`
+  pub dominant_colour: String,
+  pub thumbnails: Vec<Thumbnail>,
+}
+
+fn invalid(err: impl std::fmt::Display) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+/// Decodes an uploaded cover and renders a thumbnail for every configured size no larger than the original.
+pub fn process(bytes: &[u8], sizes: &[u32], format: ThumbnailFormat) -> io::Result<ProcessedCover> {
+  let source_format = image::guess_format(bytes).map_err(invalid)?;
+  let mime = match source_format {
+    ImageFormat::Jpeg => "image/jpeg",
+    ImageFormat::Png => "image/png",
+    ImageFormat::WebP => "image/webp",
+    ImageFormat::Gif => "image/gif",
+    _ => return Err(invalid("unsupported cover image format")),
+  };
+  let image = image::load_from_memory_with_format(bytes, source_format).map_err(invalid)?;
+  let (width, height) = image.dimensions();
+
+  let mut thumbnails = Vec::new();
+  for &size in sizes {
+    if size == 0 || size >= width.max(height) {
+      continue;
+    }
+    let thumbnail = image.resize(size, size, FilterType::Lanczos3);
+    let mut encoded = Cursor::new(Vec::new());
+    match format {
+      ThumbnailFormat::Webp => DynamicImage::ImageRgba8(thumbnail.to_rgba8()).write_with_encoder(WebPEncoder::new_lossless(&mut encoded)),
+      ThumbnailFormat::Jpeg => DynamicImage::ImageRgb8(thumbnail.to_rgb8()).write_to(&mut encoded, ImageFormat::Jpeg),
+    }
+    .map_err(invalid)?;
+
+    thumbnails.push(Thumbnail {
+      size,
+      width: thumbnail.width(),
+      height: thumbnail.height(),
+      bytes: encoded.into_inner(),
+    });
+  }
+
+  Ok(ProcessedCover {
+    mime,
+    width,
+    height,
+    dominant_colour: dominant_colour(&image),
+    thumbnails,
+  })
+}
+
+/// The average colour of the most common 4-bit-per-channel bucket, ignoring mostly transparent pixels.
+pub fn dominant_colour(image: &DynamicImage) -> String {
+  let sample = image.resize(64, 64, FilterType::Triangle).to_rgba8();
+  let mut buckets: HashMap<(u8, u8, u8), (u32, [u64; 3])> = HashMap::new();
+  for pixel in sample.pixels() {
+    let [r, g, b, a] = pixel.0;
+    if a < 128 {
+      continue;
+    }
+    let bucket = buckets.entry((r >> 4, g >> 4, b >> 4)).or_insert((0, [0; 3]));
+    bucket.0 += 1;
+    bucket.1[0] += u64::from(r);
+    bucket.1[1] += u64::from(g);
+    bucket.1[2] += u64::from(b);
+  }
+
+  let Some((count, sums)) = buckets.into_values().max_by_key(|(count, _)| *count) else {
+    return String::from("#000000");
+  };
+  let count = u64::from(count);
+  format!("#{:02x}{:02x}{:02x}", sums[0] / count, sums[1] / count, sums[2] / count)
+}
+
+fn is_image_name(name: &str) -> bool {
+  let lower = name.to_ascii_lowercase();
+  [".jpg", ".jpeg", ".png", ".webp", ".gif"].iter().any(|extension| lower.ends_with(extension))
+}
+
+fn read_entry<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str) -> io::Result<Vec<u8>> {
+  let mut entry = archive.by_name(name).map_err(invalid)?;
+  let mut bytes = Vec::new();
+  entry.read_to_end(&mut bytes)?;
+  Ok(bytes)
+}
+
+/// Attribute lookup on the current XML element, ignoring namespace prefixes.
+fn attribute(element: &quick_xml::events::BytesStart, key: &[u8]) -> Option<String> {
+  element
+    .attributes()
+    .flatten()
+    .find(|attr| attr.key.local_name().as_ref() == key)
+    .and_then(|attr| attr.unescape_value().ok().map(|value| value.into_owned()))
+}
+
+/// Resolves `href` relative to the directory of the OPF at `base`.
+fn resolve(base: &str, href: &str) -> String {
+  let mut parts: Vec<&str> = base.split('/').collect();
+  parts.pop();
+  for segment in href.split('/') {
+    match segment {
+      ".." => {
+        parts.pop();
+      }
+      "." | "" => {}
+      segment => parts.push(segment),
+    }
+  }
+  parts.join("/")
+}
+
+/// Finds the cover image of an EPUB through its OPF: the EPUB 3 `cover-image` item, the EPUB 2 `<meta name="cover">`, then the first image.
+fn epub_cover<R: Read + Seek>(archive: &mut ZipArchive<R>) -> io::Result<Option<Vec<u8>>> {
+  let container = String::from_utf8_lossy(&read_entry(archive, "META-INF/container.xml")?).into_owned();
+  let mut reader = Reader::from_str(&container);
+  let mut opf_path = None;
+  loop {
+    match reader.read_event().map_err(invalid)? {
+      Event::Start(element) | Event::Empty(element) if element.local_name().as_ref() == b"rootfile" => {
+        opf_path = attribute(&element, b"full-path");
+        break;
+      }
+      Event::Eof => break,
+      _ => {}
+    }
+  }
+  let Some(opf_path) = opf_path else {
+    return Ok(None);
+  };
+
+  let opf = String::from_utf8_lossy(&read_entry(archive, &opf_path)?).into_owned();
+  let mut reader = Reader::from_str(&opf);
+  let mut cover_id = None;
+  let mut cover_image = None;
+  let mut images: Vec<(String, String)> = Vec::new();
+  loop {
+    match reader.read_event().map_err(invalid)? {
+      Event::Start(element) | Event::Empty(element) => match element.local_name().as_ref() {
+        b"meta" if attribute(&element, b"name").as_deref() == Some("cover") => cover_id = attribute(&element, b"content"),
+        b"item" => {
+          let (Some(id), Some(href)) = (attribute(&element, b"id"), attribute(&element, b"href")) else {
+            continue;
+          };
+          let properties = attribute(&element, b"properties").unwrap_or_default();
+          if properties.split_whitespace().any(|property| property == "cover-image") {
+            cover_image = Some(href.clone());
+          }
+          if attribute(&element, b"media-type").is_some_and(|kind| kind.starts_with("image/")) {
+            images.push((id, href));
+          }
+        }
+        _ => {}
+      },
+      Event::Eof => break,
+      _ => {}
+    }
+  }
+
+  let href = cover_image
+    .or_else(|| cover_id.and_then(|id| images.iter().find(|(item, _)| *item == id).map(|(_, href)| href.clone())))
+    .or_else(|| images.first().map(|(_, href)| href.clone()));
+  match href {
+    Some(href) => Ok(Some(read_entry(archive, &resolve(&opf_path, &href))?)),
+    None => Ok(None),
+  }
+}
+
+/// Pulls a cover out of an attached ebook. Only the zip-based formats carry images we can read; PDF and MOBI return `None`.
+pub fn extract(bytes: &[u8], format: FileFormat) -> io::Result<Option<Vec<u8>>> {
+  match format {
+    FileFormat::Epub => {
+      let mut archive = ZipArchive::new(Cursor::new(bytes)).map_err(invalid)?;
+      epub_cover(&mut archive)
+    }
+    FileFormat::Cbz => {
+      let mut archive = ZipArchive::new(Cursor::new(bytes)).map_err(invalid)?;
+      let mut names: Vec<String> = archive.file_names().filter(|name| is_image_name(name)).map(String::from).collect();
+      names.sort();
+      match names.first() {
+        Some(name) => Ok(Some(read_entry(&mut archive, name)?)),
+        None => Ok(None),
+      }
+    }
+    FileFormat::Pdf | FileFormat::Mobi => Ok(None),
+  }
+}
@@ -0,0 +1,248 @@
+//! The audit log. Triggers on the catalog and user tables record every insert, update and delete, whichever code path
+//! made it, along with the actor the connection was tagged with through [`AuditEntry::set_actor`].
+//!
+//! Updates keep only the columns that changed, so `before` and `after` together are the diff. Inserts keep the new row
+//! and deletes the old one in full.
+
+use chrono::{DateTime, Utc};
+use sqlx::{mysql::MySqlQueryResult, query, query_as, Executor, FromRow, MySql, Transaction};
+use tracing::instrument;
+
+/// Tables whose changes are recorded. Derived data (thumbnails, KOReader hashes and sync positions) and import
+/// bookkeeping are left out.
+pub const AUDITED: &[&str] = &[
+  "author",
+  "book",
+  "book_author",
+  "book_cover",
+  "book_file",
+  "book_identifier",
+  "book_tag",
+  "koreader_user",
+  "progress",
+  "publisher",
+  "series",
+  "shelf",
+  "shelf_book",
+  "tag",
+  "user",
+];
+
+/// Columns never copied into the log.
+const REDACTED: &[(&str, &str)] = &[("koreader_user", "userkey")];
+
+/// Columns bumped on every update, which would otherwise make each diff non-empty.
+const UNDIFFED: &[&str] = &["date_last_updated", "version"];
+
+#[derive(Debug, Clone, FromRow, PartialEq, Eq)]
+pub struct AuditEntry {
+  pub id: u64,
+  /// `None` when the change was made on a connection nobody tagged, such as by a migration.
+  pub actor: Option<String>,
+  pub entity: String,
+  /// The primary key, with composite keys joined by `:` in column order.
+  pub entity_id: String,
+  /// `create`, `update` or `delete`.
+  pub operation: String,
+  pub before: Option<String>,
+  pub after: Option<String>,
+  pub date_added: Option<DateTime<Utc>>,
+}
+
+/// Filters for [`AuditEntry::fetch`]. Unset fields match everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditQuery {
+  pub entity: Option<String>,
+  pub entity_id: Option<String>,
+  pub actor: Option<String>,
+  pub since: Option<DateTime<Utc>>,
+  pub limit: u32,
+}
+
+impl AuditEntry {
+  /// Attributes every change made on this connection to `actor` until it is returned to the pool, which clears it.
+  #[instrument(level = "debug", skip_all, fields(entity = "audit_entry"))]
+  pub async fn set_actor<'a>(tx: &mut Transaction<'a, MySql>, actor: &str) -> Result<MySqlQueryResult, sqlx::Error> {
+    query(r#"SET @libby_actor = ?"#).bind(actor).execute(&mut **tx).await
+  }
+
+  /// Newest first.
+  #[instrument(level = "debug", skip_all, fields(entity = "audit_entry"))]
+  pub async fn fetch<'a>(tx: &mut Transaction<'a, MySql>, filter: &AuditQuery) -> Result<Vec<AuditEntry>, sqlx::Error> {
+    query_as::<MySql, AuditEntry>(
+      r#"SELECT `id`, `actor`, `entity`, `entity_id`, `operation`,
+        CAST(`before` AS CHAR) AS `before`, CAST(`after` AS CHAR) AS `after`, `date_added`
+      FROM `audit_log`
+      WHERE (? IS NULL OR `entity` = ?)
+        AND (? IS NULL OR `entity_id` = ?)
+        AND (? IS NULL OR `actor` = ?)
+        AND (? IS NULL OR `date_added` >= ?)
+      ORDER BY `id` DESC
+      LIMIT ?"#,
+    )
+    .bind(&filter.entity)
+    .bind(&filter.entity)
+    .bind(&filter.entity_id)
+    .bind(&filter.entity_id)
+    .bind(&filter.actor)
+    .bind(&filter.actor)
+    .bind(filter.since)
+    .bind(filter.since)
+    .bind(filter.limit)
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "audit_entry", entity_id = entity_id))]
+  pub async fn fetch_by_entity<'a>(tx: &mut Transaction<'a, MySql>, entity: &str, entity_id: &str) -> Result<Vec<AuditEntry>, sqlx::Error> {
+    AuditEntry::fetch(
+      tx,
+      &AuditQuery {
+        entity: Some(entity.to_string()),
+        entity_id: Some(entity_id.to_string()),
+        limit: u32::MAX,
+        ..Default::default()
+      },
+    )
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "audit_entry"))]
+  pub async fn fetch_by_actor<'a>(tx: &mut Transaction<'a, MySql>, actor: &str, limit: u32) -> Result<Vec<AuditEntry>, sqlx::Error> {
+    AuditEntry::fetch(
+      tx,
+      &AuditQuery {
+        actor: Some(actor.to_string()),
+        limit,
+        ..Default::default()
+      },
+    )
+    .await
+  }
+
+  /// Deletes entries recorded before `cutoff`, returning how many went.
+  #[instrument(level = "debug", skip_all, fields(entity = "audit_entry"))]
+  pub async fn prune<'a>(tx: &mut Transaction<'a, MySql>, cutoff: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+    let result = query(
+      r#"DELETE FROM `audit_log`
+      WHERE `date_added` < ?"#,
+    )
+    .bind(cutoff)
+    .execute(&mut **tx)
+    .await?;
+    Ok(result.rows_affected())
+  }
+}
+
+fn quote(identifier: &str) -> String {
+  format!("`{}`", identifier.replace('`', "``"))
+}
+
+fn literal(value: &str) -> String {
+  format!("'{}'", value.replace('\\', "\\\\").replace('\'', "''"))
+}
+
+/// `JSON_OBJECT('id', NEW.`id`, …)` over `columns`.
+fn row_json(row: &str, columns: &[String]) -> String {
+  let pairs: Vec<String> = columns.iter().map(|column| format!("{}, {}.{}", literal(column), row, quote(column))).collect();
+  format!("JSON_OBJECT({})", pairs.join(", "))
+}
+
+/// A JSON object holding `row`'s value of each column that differs between `OLD` and `NEW`. `JSON_MERGE_PRESERVE` keeps
+/// keys whose value became `NULL`, which `JSON_MERGE_PATCH` would drop.
+fn diff_json(row: &str, columns: &[String]) -> String {
+  if columns.is_empty() {
+    return String::from("JSON_OBJECT()");
+  }
+  let parts: Vec<String> = columns
+    .iter()
+    .map(|column| {
+      format!(
+        "IF(OLD.{column} <=> NEW.{column}, JSON_OBJECT(), JSON_OBJECT({}, {}.{column}))",
+        literal(column),
+        row,
+        column = quote(column)
+      )
+    })
+    .collect();
+  format!("JSON_MERGE_PRESERVE(JSON_OBJECT(), {})", parts.join(", "))
+}
+
+/// The `CREATE TRIGGER` statements auditing `table`, given its primary key and the columns to record.
+pub fn trigger_statements(table: &str, keys: &[String], columns: &[String]) -> Vec<String> {
+  let key = |row: &str| {
+    let parts: Vec<String> = keys.iter().map(|key| format!("{}.{}", row, quote(key))).collect();
+    format!("CONCAT_WS(':', {})", parts.join(", "))
+  };
+  let diffed: Vec<String> = columns.iter().filter(|column| !UNDIFFED.contains(&column.as_str())).cloned().collect();
+  let changed = if diffed.is_empty() {
+    String::from("FALSE")
+  } else {
+    diffed
+      .iter()
+      .map(|column| format!("NOT (OLD.{column} <=> NEW.{column})", column = quote(column)))
+      .collect::<Vec<String>>()
+      .join(" OR ")
+  };
+
+  // Restores turn the log off with `@libby_audit = 0`, since the archive brings its own history.
+  let trigger = |event: &str, condition: String, operation: &str, row: &str, before: String, after: String| {
+    format!(
+      "CREATE TRIGGER {name} AFTER {event} ON {table} FOR EACH ROW
+      BEGIN
+        IF COALESCE(@libby_audit, 1) AND ({condition}) THEN
+          INSERT INTO `audit_log` (`actor`, `entity`, `entity_id`, `operation`, `before`, `after`)
+          VALUES (@libby_actor, {entity}, {key}, '{operation}', {before}, {after});
+        END IF;
+      END",
+      name = quote(&format!("audit_{}_{}", table, event.to_lowercase())),
+      table = quote(table),
+      entity = literal(table),
+      key = key(row),
+    )
+  };
+
+  vec![
+    trigger("INSERT", String::from("TRUE"), "create", "NEW", String::from("NULL"), row_json("NEW", columns)),
+    trigger("UPDATE", changed, "update", "NEW", diff_json("OLD", &diffed), diff_json("NEW", &diffed)),
+    trigger("DELETE", String::from("TRUE"), "delete", "OLD", row_json("OLD", columns), String::from("NULL")),
+  ]
+}
+
+/// (Re)creates the triggers on every audited table from its current columns. Migrations that change an audited table's
+/// columns run this again afterwards.
+#[instrument(level = "debug", skip_all, fields(entity = "audit"))]
+pub async fn install_triggers<'a>(tx: &mut Transaction<'a, MySql>) -> Result<(), sqlx::Error> {
+  for table in AUDITED {
+    let columns: Vec<String> = sqlx::query_scalar::<MySql, String>(
+      r#"SELECT CAST(`column_name` AS CHAR) FROM `information_schema`.`columns`
+      WHERE `table_schema` = DATABASE() AND `table_name` = ?
+      ORDER BY `ordinal_position`"#,
+    )
+    .bind(table)
+    .fetch_all(&mut **tx)
+    .await?
+    .into_iter()
+    .filter(|column| !REDACTED.contains(&(*table, column.as_str())))
+    .collect();
+    let keys: Vec<String> = sqlx::query_scalar::<MySql, String>(
+      r#"SELECT CAST(`column_name` AS CHAR) FROM `information_schema`.`key_column_usage`
+      WHERE `table_schema` = DATABASE() AND `table_name` = ? AND `constraint_name` = 'PRIMARY'
+      ORDER BY `ordinal_position`"#,
+    )
+    .bind(table)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    // Trigger DDL is not allowed as a prepared statement, so these go over the text protocol.
+    for event in ["insert", "update", "delete"] {
+      (&mut **tx)
+        .execute(format!("DROP TRIGGER IF EXISTS {}", quote(&format!("audit_{}_{}", table, event))).as_str())
+        .await?;
+    }
+    for statement in trigger_statements(table, &keys, &columns) {
+      (&mut **tx).execute(statement.as_str()).await?;
+    }
+  }
+  Ok(())
+}
@@ -1,16 +1,33 @@
 use chrono::{DateTime, NaiveDate, Utc};
 use sqlx::{mysql::MySqlQueryResult, query, query_as, FromRow, MySql, Transaction};
+use tracing::instrument;
+
+use super::{cache, changes::ChangeEvent, placeholders, UpdateError};
 
 pub type Authors = Vec<Author>;
 
+/// An author along with the book they were looked up by, for batched lookups.
+#[derive(Debug, Clone, FromRow, PartialEq, Eq)]
+pub struct BookAuthor {
+  pub book_id: u64,
+  #[sqlx(flatten)]
+  pub author: Author,
+}
+
 #[derive(Debug, Clone, FromRow, PartialEq, Eq)]
 pub struct Author {
   pub id: u64,
   pub name: String,
   pub description: Option<String>,
   pub birth: Option<NaiveDate>,
+  /// How the name files alphabetically, such as `Pratchett, Terry`.
+  pub sort_name: Option<String>,
   pub date_added: Option<DateTime<Utc>>,
   pub date_last_updated: Option<DateTime<Utc>>,
+  /// Set while the author is in the trash. Trashed authors are left out of every fetch but [`Author::fetch_deleted`].
+  pub deleted_at: Option<DateTime<Utc>>,
+  /// Bumped by every update, and checked by [`Author::update_if`].
+  pub version: u32,
 }
 
 #[derive(Debug, Clone, FromRow, PartialEq, Eq)]
@@ -25,29 +42,107 @@ impl Author {
     if let Some(name) = partial.name {
       self.name = name;
     }
-    if let Some(description) = partial.description {
-      self.description = Some(description);
-    }
-    if let Some(birth) = partial.birth {
-      self.birth = Some(birth);
-    }
+    self.description = partial.description;
+    self.birth = partial.birth;
     self
   }
 
+  /// Served from the [cache](cache::AUTHORS) when it holds the author.
+  #[instrument(level = "debug", skip_all, fields(entity = "author", id = author_id))]
   pub async fn fetch_one<'a>(tx: &mut Transaction<'a, MySql>, author_id: u64) -> Result<Author, sqlx::Error> {
+    if let Some(author) = cache::AUTHORS.get(author_id) {
+      return Ok(author);
+    }
+    let ticket = cache::AUTHORS.read_started();
+    let author = Author::fetch_uncached(tx, author_id).await?;
+    cache::AUTHORS.insert(author_id, author.clone(), ticket);
+    Ok(author)
+  }
+
+  /// [`Author::fetch_one`] from the database, for writes returning what they changed and for the version a write checks
+  /// against, which a cached record may be behind on.
+  #[instrument(level = "debug", skip_all, fields(entity = "author", id = author_id))]
+  pub async fn fetch_uncached<'a>(tx: &mut Transaction<'a, MySql>, author_id: u64) -> Result<Author, sqlx::Error> {
     query_as::<MySql, Author>(
       r#"SELECT * FROM `author`
-      WHERE `id`= ?"#,
+      WHERE `id`= ? AND `deleted_at` IS NULL"#,
     )
     .bind(author_id)
     .fetch_one(&mut **tx)
     .await
   }
 
+  /// Up to `limit` authors after skipping `offset`, in the order they were added so pages stay put between requests.
+  #[instrument(level = "debug", skip_all, fields(entity = "author"))]
+  pub async fn fetch_page<'a>(tx: &mut Transaction<'a, MySql>, limit: u32, offset: u64) -> Result<Authors, sqlx::Error> {
+    query_as::<MySql, Author>(
+      r#"SELECT * FROM `author` WHERE `deleted_at` IS NULL
+      ORDER BY `id`
+      LIMIT ? OFFSET ?"#,
+    )
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "author"))]
   pub async fn fetch_all<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Authors, sqlx::Error> {
-    query_as::<MySql, Author>(r#"SELECT * FROM `author`"#).fetch_all(&mut **tx).await
+    query_as::<MySql, Author>(r#"SELECT * FROM `author` WHERE `deleted_at` IS NULL"#)
+      .fetch_all(&mut **tx)
+      .await
+  }
+
+  /// The authors among `author_ids` that exist, in no particular order.
+  #[instrument(level = "debug", skip_all, fields(entity = "author"))]
+  pub async fn fetch_many<'a>(tx: &mut Transaction<'a, MySql>, author_ids: &[u64]) -> Result<Authors, sqlx::Error> {
+    if author_ids.is_empty() {
+      return Ok(Vec::new());
+    }
+    let sql = format!(
+      "SELECT * FROM `author` WHERE `id` IN ({}) AND `deleted_at` IS NULL",
+      placeholders(author_ids.len())
+    );
+    let mut authors = query_as::<MySql, Author>(&sql);
+    for author_id in author_ids {
+      authors = authors.bind(author_id);
+    }
+    authors.fetch_all(&mut **tx).await
+  }
+
+  /// The authors of each of `book_ids`, by name.
+  #[instrument(level = "debug", skip_all, fields(entity = "author"))]
+  pub async fn fetch_by_books<'a>(tx: &mut Transaction<'a, MySql>, book_ids: &[u64]) -> Result<Vec<BookAuthor>, sqlx::Error> {
+    if book_ids.is_empty() {
+      return Ok(Vec::new());
+    }
+    let sql = format!(
+      "SELECT `book_author`.`book_id`, `author`.* FROM `author`
+      INNER JOIN `book_author` ON `book_author`.`author_id` = `author`.`id`
+      WHERE `book_author`.`book_id` IN ({}) AND `author`.`deleted_at` IS NULL
+      ORDER BY `author`.`name`",
+      placeholders(book_ids.len())
+    );
+    let mut authors = query_as::<MySql, BookAuthor>(&sql);
+    for book_id in book_ids {
+      authors = authors.bind(book_id);
+    }
+    authors.fetch_all(&mut **tx).await
   }
 
+  #[instrument(level = "debug", skip_all, fields(entity = "author"))]
+  pub async fn fetch_by_name<'a>(tx: &mut Transaction<'a, MySql>, name: &str) -> Result<Option<Author>, sqlx::Error> {
+    query_as::<MySql, Author>(
+      r#"SELECT * FROM `author`
+      WHERE `name`= ? AND `deleted_at` IS NULL
+      LIMIT 1"#,
+    )
+    .bind(name)
+    .fetch_optional(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "author"))]
   pub async fn fetch_last<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Author, sqlx::Error> {
     query_as::<MySql, Author>(
       r#"SELECT * FROM `author`
@@ -57,6 +152,7 @@ impl Author {
     .await
   }
 
+  #[instrument(level = "debug", skip_all, fields(entity = "author"))]
   pub async fn create<'a>(tx: &mut Transaction<'a, MySql>, partial: PartialAuthor) -> Result<Author, sqlx::Error> {
     query(
       r#"INSERT INTO `author` (`name`, `description`, `birth`)
@@ -68,35 +164,171 @@ impl Author {
     .execute(&mut **tx)
     .await?;
 
-    Author::fetch_last(tx).await
+    let author = Author::fetch_last(tx).await?;
+    ChangeEvent::record(tx, "author", author.id, "create").await?;
+    Ok(author)
   }
 
+  /// Reads the author and locks its row until the transaction ends, so a read-merge-write cannot interleave with another.
+  #[instrument(level = "debug", skip_all, fields(entity = "author", id = author_id))]
+  async fn fetch_locked<'a>(tx: &mut Transaction<'a, MySql>, author_id: u64) -> Result<Author, sqlx::Error> {
+    query_as::<MySql, Author>(
+      r#"SELECT * FROM `author`
+      WHERE `id`= ? AND `deleted_at` IS NULL
+      FOR UPDATE"#,
+    )
+    .bind(author_id)
+    .fetch_one(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "author", id = author_id))]
   pub async fn update<'a>(tx: &mut Transaction<'a, MySql>, author_id: u64, partial: PartialAuthor) -> Result<Author, sqlx::Error> {
-    let old_author = Author::fetch_one(tx, author_id).await?;
-    let updated_author = old_author.merge(partial);
+    let old_author = Author::fetch_locked(tx, author_id).await?;
+    Author::write(tx, old_author.merge(partial)).await
+  }
+
+  /// [`Author::update`] for a client editing what it read earlier: fails with [`UpdateError::Conflict`] if the author is no
+  /// longer at `version`.
+  #[instrument(level = "debug", skip_all, fields(entity = "author", id = author_id))]
+  pub async fn update_if<'a>(tx: &mut Transaction<'a, MySql>, author_id: u64, version: u32, partial: PartialAuthor) -> Result<Author, UpdateError> {
+    let old_author = Author::fetch_locked(tx, author_id).await?;
+    if old_author.version != version {
+      return Err(UpdateError::Conflict(old_author.version));
+    }
+    Ok(Author::write(tx, old_author.merge(partial)).await?)
+  }
 
+  #[instrument(level = "debug", skip_all, fields(entity = "author"))]
+  async fn write<'a>(tx: &mut Transaction<'a, MySql>, author: Author) -> Result<Author, sqlx::Error> {
     query(
       r#"UPDATE `author`
-      SET `name` = ?, `description` = ?, `birth` = ?
+      SET `name` = ?, `description` = ?, `birth` = ?, `version` = `version` + 1
+      WHERE `id` = ? AND `version` = ?"#,
+    )
+    .bind(author.name)
+    .bind(author.description)
+    .bind(author.birth)
+    .bind(author.id)
+    .bind(author.version)
+    .execute(&mut **tx)
+    .await?;
+    cache::AUTHORS.invalidate(author.id);
+    ChangeEvent::record(tx, "author", author.id, "update").await?;
+
+    Author::fetch_uncached(tx, author.id).await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "author", id = author_id))]
+  pub async fn set_sort_name<'a>(tx: &mut Transaction<'a, MySql>, author_id: u64, sort_name: Option<&str>) -> Result<MySqlQueryResult, sqlx::Error> {
+    let result = query(
+      r#"UPDATE `author`
+      SET `sort_name` = ?, `version` = `version` + 1
       WHERE `id` = ?"#,
     )
-    .bind(updated_author.name)
-    .bind(updated_author.description)
-    .bind(updated_author.birth)
+    .bind(sort_name)
     .bind(author_id)
     .execute(&mut **tx)
     .await?;
-
-    Author::fetch_one(tx, author_id).await
+    cache::AUTHORS.invalidate(author_id);
+    if result.rows_affected() > 0 {
+      ChangeEvent::record(tx, "author", author_id, "update").await?;
+    }
+    Ok(result)
   }
 
+  /// Moves the author to the trash. Their books keep the link, but stop listing them until they are restored.
+  #[instrument(level = "debug", skip_all, fields(entity = "author", id = author_id))]
   pub async fn delete<'a>(tx: &mut Transaction<'a, MySql>, author_id: u64) -> Result<MySqlQueryResult, sqlx::Error> {
+    let result = query(
+      r#"UPDATE `author`
+      SET `deleted_at` = NOW(), `version` = `version` + 1
+      WHERE `id` = ? AND `deleted_at` IS NULL"#,
+    )
+    .bind(author_id)
+    .execute(&mut **tx)
+    .await?;
+    cache::AUTHORS.invalidate(author_id);
+    if result.rows_affected() > 0 {
+      ChangeEvent::record(tx, "author", author_id, "delete").await?;
+    }
+    Ok(result)
+  }
+
+  /// The trash, most recently deleted first.
+  #[instrument(level = "debug", skip_all, fields(entity = "author"))]
+  pub async fn fetch_deleted<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Authors, sqlx::Error> {
+    query_as::<MySql, Author>(
+      r#"SELECT * FROM `author`
+      WHERE `deleted_at` IS NOT NULL
+      ORDER BY `deleted_at` DESC"#,
+    )
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  /// Takes an author back out of the trash. Fails with `RowNotFound` if they are not there.
+  #[instrument(level = "debug", skip_all, fields(entity = "author", id = author_id))]
+  pub async fn restore<'a>(tx: &mut Transaction<'a, MySql>, author_id: u64) -> Result<Author, sqlx::Error> {
+    let result = query(
+      r#"UPDATE `author`
+      SET `deleted_at` = NULL, `version` = `version` + 1
+      WHERE `id` = ? AND `deleted_at` IS NOT NULL"#,
+    )
+    .bind(author_id)
+    .execute(&mut **tx)
+    .await?;
+    if result.rows_affected() == 0 {
+      return Err(sqlx::Error::RowNotFound);
+    }
+    cache::AUTHORS.invalidate(author_id);
+    ChangeEvent::record(tx, "author", author_id, "restore").await?;
+
+    Author::fetch_uncached(tx, author_id).await
+  }
+
+  /// Trashed authors deleted before `cutoff`, due to be purged.
+  #[instrument(level = "debug", skip_all, fields(entity = "author"))]
+  pub async fn fetch_expired<'a>(tx: &mut Transaction<'a, MySql>, cutoff: DateTime<Utc>) -> Result<Vec<u64>, sqlx::Error> {
+    sqlx::query_scalar::<MySql, u64>(
+      r#"SELECT `id` FROM `author`
+      WHERE `deleted_at` < ?"#,
+    )
+    .bind(cutoff)
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  /// Permanently removes a trashed author and their book links; authors not in the trash fail with `RowNotFound`.
+  #[instrument(level = "debug", skip_all, fields(entity = "author", id = author_id))]
+  pub async fn purge<'a>(tx: &mut Transaction<'a, MySql>, author_id: u64) -> Result<MySqlQueryResult, sqlx::Error> {
+    let trashed: bool = sqlx::query_scalar(r#"SELECT EXISTS (SELECT 1 FROM `author` WHERE `id` = ? AND `deleted_at` IS NOT NULL)"#)
+      .bind(author_id)
+      .fetch_one(&mut **tx)
+      .await?;
+    if !trashed {
+      return Err(sqlx::Error::RowNotFound);
+    }
+
     query(
+      r#"DELETE FROM `book_author`
+      WHERE `author_id` = ?"#,
+    )
+    .bind(author_id)
+    .execute(&mut **tx)
+    .await?;
+
+    let result = query(
       r#"DELETE FROM `author`
       WHERE `id` = ?"#,
     )
     .bind(author_id)
     .execute(&mut **tx)
-    .await
+    .await?;
+    cache::AUTHORS.invalidate(author_id);
+    if result.rows_affected() > 0 {
+      ChangeEvent::record(tx, "author", author_id, "purge").await?;
+    }
+    Ok(result)
   }
 }
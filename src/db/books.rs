@@ -1,9 +1,36 @@
 use chrono::{DateTime, Utc};
+use serde_json::json;
 use sqlx::{mysql::MySqlQueryResult, query, query_as, FromRow, MySql, Transaction};
+use tracing::instrument;
 
-use super::{authors::Authors, publisher::Publisher};
+use super::{
+  authors::{Author, Authors},
+  cache,
+  changes::ChangeEvent,
+  placeholders,
+  publisher::Publisher,
+  webhooks::WebhookDelivery,
+  UpdateError,
+};
 
-type Books = Vec<Book>;
+pub type Books = Vec<Book>;
+
+/// Normalises an ISBN to its digits (and a trailing X). Books store their ISBN this way, which is how they are matched.
+pub fn normalize_isbn(isbn: &str) -> String {
+  isbn
+    .chars()
+    .filter(|c| c.is_ascii_digit() || *c == 'X' || *c == 'x')
+    .collect::<String>()
+    .to_uppercase()
+}
+
+/// A book along with the author it was looked up by, for batched lookups.
+#[derive(Debug, Clone, FromRow, PartialEq, Eq)]
+pub struct AuthoredBook {
+  pub author_id: u64,
+  #[sqlx(flatten)]
+  pub book: Book,
+}
 
 #[derive(Debug, Clone, FromRow, PartialEq, Eq)]
 pub struct Book {
@@ -16,9 +43,15 @@ pub struct Book {
   pub num_pages: u16,
   pub image_formatted: bool,
   pub publisher_id: Option<u16>,
+  pub series_id: Option<u64>,
+  pub series_index: Option<u16>,
   pub date_published: Option<DateTime<Utc>>,
   pub date_added: Option<DateTime<Utc>>,
   pub date_last_updated: Option<DateTime<Utc>>,
+  /// Set while the book is in the trash. Trashed books are left out of every fetch but [`Book::fetch_deleted`].
+  pub deleted_at: Option<DateTime<Utc>>,
+  /// Bumped by every update, and checked by [`Book::update_if`].
+  pub version: u32,
 }
 
 #[derive(Debug, Clone, FromRow, PartialEq, Eq)]
@@ -31,6 +64,8 @@ pub struct PartialBook {
   pub num_pages: Option<u16>,
   pub image_formatted: Option<bool>,
   pub publisher_id: Option<u16>,
+  pub series_id: Option<u64>,
+  pub series_index: Option<u16>,
   pub date_published: Option<DateTime<Utc>>,
 }
 
@@ -54,52 +89,280 @@ impl Book {
       self.image_formatted = image_formatted;
     }
     self.publisher_id = partial.publisher_id;
+    self.series_id = partial.series_id;
+    self.series_index = partial.series_index;
     self.date_published = partial.date_published;
     self
   }
 
-  pub fn fetch_authors() -> Result<Authors, sqlx::Error> {
-    todo!("Unimplemented"); // TODO:
+  #[instrument(level = "debug", skip_all, fields(entity = "book"))]
+  pub async fn fetch_authors<'a>(&self, tx: &mut Transaction<'a, MySql>) -> Result<Authors, sqlx::Error> {
+    query_as::<MySql, Author>(
+      r#"SELECT `author`.* FROM `author`
+      INNER JOIN `book_author` ON `book_author`.`author_id` = `author`.`id`
+      WHERE `book_author`.`book_id` = ? AND `author`.`deleted_at` IS NULL
+      ORDER BY `author`.`name`"#,
+    )
+    .bind(self.id)
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "book", id = book_id))]
+  pub async fn add_author<'a>(tx: &mut Transaction<'a, MySql>, book_id: u64, author_id: u64) -> Result<MySqlQueryResult, sqlx::Error> {
+    let result = query(
+      r#"INSERT IGNORE INTO `book_author` (`book_id`, `author_id`)
+      VALUES (?, ?)"#,
+    )
+    .bind(book_id)
+    .bind(author_id)
+    .execute(&mut **tx)
+    .await?;
+    if result.rows_affected() > 0 {
+      ChangeEvent::record(tx, "book", book_id, "update").await?;
+    }
+    Ok(result)
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "book", id = book_id))]
+  pub async fn remove_author<'a>(tx: &mut Transaction<'a, MySql>, book_id: u64, author_id: u64) -> Result<MySqlQueryResult, sqlx::Error> {
+    let result = query(
+      r#"DELETE FROM `book_author`
+      WHERE `book_id` = ? AND `author_id` = ?"#,
+    )
+    .bind(book_id)
+    .bind(author_id)
+    .execute(&mut **tx)
+    .await?;
+    if result.rows_affected() > 0 {
+      ChangeEvent::record(tx, "book", book_id, "update").await?;
+    }
+    Ok(result)
   }
 
+  #[instrument(level = "debug", skip_all, fields(entity = "book"))]
   pub async fn fetch_publisher<'a>(&self, tx: &mut Transaction<'a, MySql>) -> Result<Publisher, sqlx::Error> {
     query_as::<MySql, Publisher>(
-      r#"SELECT * FROM `book`
-      WHERE `publisher_id`= ?"#,
+      r#"SELECT * FROM `publisher`
+      WHERE `id`= ?"#,
     )
     .bind(self.publisher_id)
     .fetch_one(&mut **tx)
     .await
   }
 
+  #[instrument(level = "debug", skip_all, fields(entity = "book", publisher_id = publisher_id))]
   pub async fn fetch_books_by_publisher<'a>(tx: &mut Transaction<'a, MySql>, publisher_id: u16) -> Result<Books, sqlx::Error> {
     query_as::<MySql, Book>(
       r#"SELECT * FROM `book`
-      WHERE `publisher_id`= ?"#,
+      WHERE `publisher_id`= ? AND `deleted_at` IS NULL"#,
     )
     .bind(publisher_id)
     .fetch_all(&mut **tx)
     .await
   }
 
-  pub async fn fetch_books_by_author<'a>(tx: &mut Transaction<'a, MySql>, author_id: u64) -> Result<Book, sqlx::Error> {
-    todo!("Unimplemented"); // TODO:
+  #[instrument(level = "debug", skip_all, fields(entity = "book", author_id = author_id))]
+  pub async fn fetch_books_by_author<'a>(tx: &mut Transaction<'a, MySql>, author_id: u64) -> Result<Books, sqlx::Error> {
+    query_as::<MySql, Book>(
+      r#"SELECT `book`.* FROM `book`
+      INNER JOIN `book_author` ON `book_author`.`book_id` = `book`.`id`
+      WHERE `book_author`.`author_id` = ? AND `book`.`deleted_at` IS NULL"#,
+    )
+    .bind(author_id)
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "book", series_id = series_id))]
+  pub async fn fetch_books_by_series<'a>(tx: &mut Transaction<'a, MySql>, series_id: u64) -> Result<Books, sqlx::Error> {
+    query_as::<MySql, Book>(
+      r#"SELECT * FROM `book`
+      WHERE `series_id`= ? AND `deleted_at` IS NULL
+      ORDER BY `series_index`"#,
+    )
+    .bind(series_id)
+    .fetch_all(&mut **tx)
+    .await
   }
 
+  #[instrument(level = "debug", skip_all, fields(entity = "book"))]
+  pub async fn fetch_books_by_language<'a>(tx: &mut Transaction<'a, MySql>, language: &str) -> Result<Books, sqlx::Error> {
+    query_as::<MySql, Book>(
+      r#"SELECT * FROM `book`
+      WHERE `language`= ? AND `deleted_at` IS NULL"#,
+    )
+    .bind(language)
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  /// Distinct, non-empty languages across the catalog.
+  #[instrument(level = "debug", skip_all, fields(entity = "book"))]
+  pub async fn fetch_languages<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar::<MySql, String>(
+      r#"SELECT DISTINCT `language` FROM `book`
+      WHERE `language` IS NOT NULL AND `language` <> '' AND `deleted_at` IS NULL
+      ORDER BY `language`"#,
+    )
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "book"))]
+  pub async fn fetch_newest<'a>(tx: &mut Transaction<'a, MySql>, limit: u32) -> Result<Books, sqlx::Error> {
+    query_as::<MySql, Book>(
+      r#"SELECT * FROM `book`
+      WHERE `deleted_at` IS NULL
+      ORDER BY `date_added` DESC, `id` DESC
+      LIMIT ?"#,
+    )
+    .bind(limit)
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  /// Case-insensitive substring search over book names, ISBNs and author names.
+  #[instrument(level = "debug", skip_all, fields(entity = "book"))]
+  pub async fn search<'a>(tx: &mut Transaction<'a, MySql>, terms: &str) -> Result<Books, sqlx::Error> {
+    let pattern = format!("%{}%", terms.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+    query_as::<MySql, Book>(
+      r#"SELECT DISTINCT `book`.* FROM `book`
+      LEFT JOIN `book_author` ON `book_author`.`book_id` = `book`.`id`
+      LEFT JOIN `author` ON `author`.`id` = `book_author`.`author_id` AND `author`.`deleted_at` IS NULL
+      WHERE `book`.`deleted_at` IS NULL AND (`book`.`name` LIKE ? OR `book`.`isbn` LIKE ? OR `author`.`name` LIKE ?)
+      ORDER BY `book`.`name`"#,
+    )
+    .bind(&pattern)
+    .bind(&pattern)
+    .bind(&pattern)
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  /// Served from the [cache](cache::BOOKS) when it holds the book.
+  #[instrument(level = "debug", skip_all, fields(entity = "book", id = book_id))]
   pub async fn fetch_one<'a>(tx: &mut Transaction<'a, MySql>, book_id: u64) -> Result<Book, sqlx::Error> {
+    if let Some(book) = cache::BOOKS.get(book_id) {
+      return Ok(book);
+    }
+    let ticket = cache::BOOKS.read_started();
+    let book = Book::fetch_uncached(tx, book_id).await?;
+    cache::BOOKS.insert(book_id, book.clone(), ticket);
+    Ok(book)
+  }
+
+  /// [`Book::fetch_one`] from the database, for writes returning what they changed and for the version a write checks
+  /// against, which a cached record may be behind on.
+  #[instrument(level = "debug", skip_all, fields(entity = "book", id = book_id))]
+  pub async fn fetch_uncached<'a>(tx: &mut Transaction<'a, MySql>, book_id: u64) -> Result<Book, sqlx::Error> {
     query_as::<MySql, Book>(
       r#"SELECT * FROM `book`
-      WHERE `id`= ?"#,
+      WHERE `id`= ? AND `deleted_at` IS NULL"#,
     )
     .bind(book_id)
     .fetch_one(&mut **tx)
     .await
   }
 
+  /// The books among `book_ids` that exist, in no particular order.
+  #[instrument(level = "debug", skip_all, fields(entity = "book"))]
+  pub async fn fetch_many<'a>(tx: &mut Transaction<'a, MySql>, book_ids: &[u64]) -> Result<Books, sqlx::Error> {
+    if book_ids.is_empty() {
+      return Ok(Vec::new());
+    }
+    let sql = format!("SELECT * FROM `book` WHERE `id` IN ({}) AND `deleted_at` IS NULL", placeholders(book_ids.len()));
+    let mut books = query_as::<MySql, Book>(&sql);
+    for book_id in book_ids {
+      books = books.bind(book_id);
+    }
+    books.fetch_all(&mut **tx).await
+  }
+
+  /// The books of each of `author_ids`, by name.
+  #[instrument(level = "debug", skip_all, fields(entity = "book"))]
+  pub async fn fetch_by_authors<'a>(tx: &mut Transaction<'a, MySql>, author_ids: &[u64]) -> Result<Vec<AuthoredBook>, sqlx::Error> {
+    if author_ids.is_empty() {
+      return Ok(Vec::new());
+    }
+    let sql = format!(
+      "SELECT `book_author`.`author_id`, `book`.* FROM `book`
+      INNER JOIN `book_author` ON `book_author`.`book_id` = `book`.`id`
+      WHERE `book_author`.`author_id` IN ({}) AND `book`.`deleted_at` IS NULL
+      ORDER BY `book`.`name`",
+      placeholders(author_ids.len())
+    );
+    let mut books = query_as::<MySql, AuthoredBook>(&sql);
+    for author_id in author_ids {
+      books = books.bind(author_id);
+    }
+    books.fetch_all(&mut **tx).await
+  }
+
+  /// The books of each of `publisher_ids`, by name.
+  #[instrument(level = "debug", skip_all, fields(entity = "book"))]
+  pub async fn fetch_by_publishers<'a>(tx: &mut Transaction<'a, MySql>, publisher_ids: &[u16]) -> Result<Books, sqlx::Error> {
+    if publisher_ids.is_empty() {
+      return Ok(Vec::new());
+    }
+    let sql = format!(
+      "SELECT * FROM `book` WHERE `publisher_id` IN ({}) AND `deleted_at` IS NULL ORDER BY `name`",
+      placeholders(publisher_ids.len())
+    );
+    let mut books = query_as::<MySql, Book>(&sql);
+    for publisher_id in publisher_ids {
+      books = books.bind(publisher_id);
+    }
+    books.fetch_all(&mut **tx).await
+  }
+
+  /// The book whose ISBN is `isbn` however it is written. A book without one is never matched.
+  #[instrument(level = "debug", skip_all, fields(entity = "book"))]
+  pub async fn fetch_by_isbn<'a>(tx: &mut Transaction<'a, MySql>, isbn: &str) -> Result<Option<Book>, sqlx::Error> {
+    let isbn = normalize_isbn(isbn);
+    if isbn.is_empty() {
+      return Ok(None);
+    }
+    query_as::<MySql, Book>(
+      r#"SELECT * FROM `book`
+      WHERE `isbn`= ? AND `deleted_at` IS NULL
+      LIMIT 1"#,
+    )
+    .bind(isbn)
+    .fetch_optional(&mut **tx)
+    .await
+  }
+
+  /// Up to `limit` books after skipping `offset`, in the order they were added so pages stay put between requests.
+  #[instrument(level = "debug", skip_all, fields(entity = "book"))]
+  pub async fn fetch_page<'a>(tx: &mut Transaction<'a, MySql>, limit: u32, offset: u64) -> Result<Books, sqlx::Error> {
+    query_as::<MySql, Book>(
+      r#"SELECT * FROM `book` WHERE `deleted_at` IS NULL
+      ORDER BY `id`
+      LIMIT ? OFFSET ?"#,
+    )
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "book"))]
   pub async fn fetch_all<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Books, sqlx::Error> {
-    query_as::<MySql, Book>(r#"SELECT * FROM `book`"#).fetch_all(&mut **tx).await
+    query_as::<MySql, Book>(r#"SELECT * FROM `book` WHERE `deleted_at` IS NULL"#)
+      .fetch_all(&mut **tx)
+      .await
   }
 
+  /// Books in the catalog, leaving out the trash.
+  #[instrument(level = "debug", skip_all, fields(entity = "book"))]
+  pub async fn count<'a>(tx: &mut Transaction<'a, MySql>) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar(r#"SELECT COUNT(*) FROM `book` WHERE `deleted_at` IS NULL"#)
+      .fetch_one(&mut **tx)
+      .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "book"))]
   pub async fn fetch_last<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Book, sqlx::Error> {
     query_as::<MySql, Book>(
       r#"SELECT * FROM `book`
@@ -109,6 +372,8 @@ impl Book {
     .await
   }
 
+  #[allow(clippy::too_many_arguments)]
+  #[instrument(level = "debug", skip_all, fields(entity = "book", publisher_id = publisher_id))]
   pub async fn create<'a>(
     tx: &mut Transaction<'a, MySql>,
     isbn: String,
@@ -125,7 +390,7 @@ impl Book {
       r#"INSERT INTO `book` (`isbn`, `name`, `description`, `language`, `nsfw`, `num_pages`, `image_formatted`, `publisher_id`, `date_published`)
       VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
     )
-    .bind(isbn)
+    .bind(normalize_isbn(&isbn))
     .bind(name)
     .bind(description)
     .bind(language)
@@ -134,44 +399,214 @@ impl Book {
     .bind(image_formatted)
     .bind(publisher_id)
     .bind(date_published)
-    .fetch_one(&mut **tx)
+    .execute(&mut **tx)
+    .await?;
+
+    let book = Book::fetch_last(tx).await?;
+    Book::record_created(tx, &book).await?;
+    Ok(book)
+  }
+
+  /// Creates a book from a partial, for callers such as importers that may not know every field. `isbn`, `name` and `num_pages` are required.
+  #[instrument(level = "debug", skip_all, fields(entity = "book"))]
+  pub async fn create_partial<'a>(tx: &mut Transaction<'a, MySql>, partial: PartialBook) -> Result<Book, sqlx::Error> {
+    query(
+      r#"INSERT INTO `book` (`isbn`, `name`, `description`, `language`, `nsfw`, `num_pages`, `image_formatted`, `publisher_id`, `series_id`, `series_index`, `date_published`)
+      VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
+    )
+    .bind(partial.isbn.as_deref().map(normalize_isbn))
+    .bind(partial.name)
+    .bind(partial.description)
+    .bind(partial.language)
+    .bind(partial.nsfw.unwrap_or(false))
+    .bind(partial.num_pages)
+    .bind(partial.image_formatted.unwrap_or(false))
+    .bind(partial.publisher_id)
+    .bind(partial.series_id)
+    .bind(partial.series_index)
+    .bind(partial.date_published)
+    .execute(&mut **tx)
     .await?;
 
-    Book::fetch_last(tx).await
+    let book = Book::fetch_last(tx).await?;
+    Book::record_created(tx, &book).await?;
+    Ok(book)
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "book"))]
+  async fn record_created<'a>(tx: &mut Transaction<'a, MySql>, book: &Book) -> Result<(), sqlx::Error> {
+    ChangeEvent::record(tx, "book", book.id, "create").await?;
+    let payload = json!({ "book": { "id": book.id, "isbn": book.isbn, "name": book.name } });
+    WebhookDelivery::enqueue(tx, "book.added", &payload).await?;
+    Ok(())
+  }
+
+  /// Reads the book and locks its row until the transaction ends, so a read-merge-write cannot interleave with another.
+  #[instrument(level = "debug", skip_all, fields(entity = "book", id = book_id))]
+  async fn fetch_locked<'a>(tx: &mut Transaction<'a, MySql>, book_id: u64) -> Result<Book, sqlx::Error> {
+    query_as::<MySql, Book>(
+      r#"SELECT * FROM `book`
+      WHERE `id`= ? AND `deleted_at` IS NULL
+      FOR UPDATE"#,
+    )
+    .bind(book_id)
+    .fetch_one(&mut **tx)
+    .await
   }
 
+  #[instrument(level = "debug", skip_all, fields(entity = "book", id = book_id))]
   pub async fn update<'a>(tx: &mut Transaction<'a, MySql>, book_id: u64, partial: PartialBook) -> Result<Book, sqlx::Error> {
-    let old_book = Book::fetch_one(tx, book_id).await?;
-    let updated_book = old_book.merge(partial);
+    let old_book = Book::fetch_locked(tx, book_id).await?;
+    Book::write(tx, old_book.merge(partial)).await
+  }
 
+  /// [`Book::update`] for a client editing what it read earlier: fails with [`UpdateError::Conflict`] if the book is no
+  /// longer at `version`.
+  #[instrument(level = "debug", skip_all, fields(entity = "book", id = book_id))]
+  pub async fn update_if<'a>(tx: &mut Transaction<'a, MySql>, book_id: u64, version: u32, partial: PartialBook) -> Result<Book, UpdateError> {
+    let old_book = Book::fetch_locked(tx, book_id).await?;
+    if old_book.version != version {
+      return Err(UpdateError::Conflict(old_book.version));
+    }
+    Ok(Book::write(tx, old_book.merge(partial)).await?)
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "book"))]
+  async fn write<'a>(tx: &mut Transaction<'a, MySql>, book: Book) -> Result<Book, sqlx::Error> {
     query(
       r#"UPDATE `book`
-      SET `isbn` = ?, `name` = ?, `description`= ?, `language`= ?, `nsfw`= ?, `num_pages`= ?, `image_formatted`= ?, `publisher_id`= ?, `date_published`= ?
-      WHERE `id` = ?"#,
-    )
-    .bind(updated_book.isbn)
-    .bind(updated_book.name)
-    .bind(updated_book.description)
-    .bind(updated_book.language)
-    .bind(updated_book.nsfw)
-    .bind(updated_book.num_pages)
-    .bind(updated_book.image_formatted)
-    .bind(updated_book.publisher_id)
-    .bind(updated_book.date_published)
-    .bind(book_id)
+      SET `isbn` = ?, `name` = ?, `description`= ?, `language`= ?, `nsfw`= ?, `num_pages`= ?, `image_formatted`= ?, `publisher_id`= ?, `series_id`= ?, `series_index`= ?, `date_published`= ?, `version` = `version` + 1
+      WHERE `id` = ? AND `version` = ?"#,
+    )
+    .bind(normalize_isbn(&book.isbn))
+    .bind(book.name)
+    .bind(book.description)
+    .bind(book.language)
+    .bind(book.nsfw)
+    .bind(book.num_pages)
+    .bind(book.image_formatted)
+    .bind(book.publisher_id)
+    .bind(book.series_id)
+    .bind(book.series_index)
+    .bind(book.date_published)
+    .bind(book.id)
+    .bind(book.version)
     .execute(&mut **tx)
     .await?;
+    cache::BOOKS.invalidate(book.id);
+    ChangeEvent::record(tx, "book", book.id, "update").await?;
 
-    Book::fetch_one(tx, book_id).await
+    Book::fetch_uncached(tx, book.id).await
   }
 
+  /// Moves the book to the trash. Its files, cover, authors and reading progress stay attached until it is purged.
+  #[instrument(level = "debug", skip_all, fields(entity = "book", id = book_id))]
   pub async fn delete<'a>(tx: &mut Transaction<'a, MySql>, book_id: u64) -> Result<MySqlQueryResult, sqlx::Error> {
-    query(
+    let result = query(
+      r#"UPDATE `book`
+      SET `deleted_at` = NOW(), `version` = `version` + 1
+      WHERE `id`= ? AND `deleted_at` IS NULL"#,
+    )
+    .bind(book_id)
+    .execute(&mut **tx)
+    .await?;
+    cache::BOOKS.invalidate(book_id);
+    if result.rows_affected() > 0 {
+      ChangeEvent::record(tx, "book", book_id, "delete").await?;
+    }
+    Ok(result)
+  }
+
+  /// The trash, most recently deleted first.
+  #[instrument(level = "debug", skip_all, fields(entity = "book"))]
+  pub async fn fetch_deleted<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Books, sqlx::Error> {
+    query_as::<MySql, Book>(
+      r#"SELECT * FROM `book`
+      WHERE `deleted_at` IS NOT NULL
+      ORDER BY `deleted_at` DESC"#,
+    )
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  /// Whether the book is in the trash, which the lookups above treat as not there at all.
+  #[instrument(level = "debug", skip_all, fields(entity = "book", id = book_id))]
+  pub async fn is_trashed<'a>(tx: &mut Transaction<'a, MySql>, book_id: u64) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(r#"SELECT EXISTS (SELECT 1 FROM `book` WHERE `id` = ? AND `deleted_at` IS NOT NULL)"#)
+      .bind(book_id)
+      .fetch_one(&mut **tx)
+      .await
+  }
+
+  /// Takes a book back out of the trash. Fails with `RowNotFound` if it is not there.
+  #[instrument(level = "debug", skip_all, fields(entity = "book", id = book_id))]
+  pub async fn restore<'a>(tx: &mut Transaction<'a, MySql>, book_id: u64) -> Result<Book, sqlx::Error> {
+    let result = query(
+      r#"UPDATE `book`
+      SET `deleted_at` = NULL, `version` = `version` + 1
+      WHERE `id`= ? AND `deleted_at` IS NOT NULL"#,
+    )
+    .bind(book_id)
+    .execute(&mut **tx)
+    .await?;
+    if result.rows_affected() == 0 {
+      return Err(sqlx::Error::RowNotFound);
+    }
+    cache::BOOKS.invalidate(book_id);
+    ChangeEvent::record(tx, "book", book_id, "restore").await?;
+
+    Book::fetch_uncached(tx, book_id).await
+  }
+
+  /// Trashed books deleted before `cutoff`, due to be purged.
+  #[instrument(level = "debug", skip_all, fields(entity = "book"))]
+  pub async fn fetch_expired<'a>(tx: &mut Transaction<'a, MySql>, cutoff: DateTime<Utc>) -> Result<Vec<u64>, sqlx::Error> {
+    sqlx::query_scalar::<MySql, u64>(
+      r#"SELECT `id` FROM `book`
+      WHERE `deleted_at` < ?"#,
+    )
+    .bind(cutoff)
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  /// Permanently removes a trashed book and every row that depends on it; books not in the trash fail with `RowNotFound`. The blobs its files and cover used are left
+  /// in storage for the caller to remove once nothing else references them.
+  #[instrument(level = "debug", skip_all, fields(entity = "book", id = book_id))]
+  pub async fn purge<'a>(tx: &mut Transaction<'a, MySql>, book_id: u64) -> Result<MySqlQueryResult, sqlx::Error> {
+    if !Book::is_trashed(tx, book_id).await? {
+      return Err(sqlx::Error::RowNotFound);
+    }
+
+    for table in [
+      "progress",
+      "book_author",
+      "book_tag",
+      "book_identifier",
+      "shelf_book",
+      "calibre_book",
+      "koreader_document",
+      "book_file",
+      "book_cover_thumbnail",
+      "book_cover",
+    ] {
+      query(&format!("DELETE FROM `{}` WHERE `book_id` = ?", table))
+        .bind(book_id)
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    let result = query(
       r#"DELETE FROM `book`
       WHERE `id`= ?"#,
     )
     .bind(book_id)
     .execute(&mut **tx)
-    .await
+    .await?;
+    cache::BOOKS.invalidate(book_id);
+    if result.rows_affected() > 0 {
+      ChangeEvent::record(tx, "book", book_id, "purge").await?;
+    }
+    Ok(result)
   }
 }
@@ -0,0 +1,179 @@
+//! An in-process cache in front of [`Book::fetch_one`] and [`Author::fetch_one`], the lookups nearly every request makes.
+//! Records are only kept for `cache.ttl_secs`, which bounds how stale a lookup can be when the record was changed
+//! through another server.
+//!
+//! Writes on this server are kept out of the cache. A write drops its record straight away and holds it out of the
+//! cache until the work it was part of, a request or a job run in [`settle_after`], has finished and so committed or
+//! rolled back; the record is then dropped again, in case a read of the old row slipped in. Nothing read by that work
+//! after it wrote is cached, and a read that overlapped any write is not cached either. Writes made outside
+//! [`settle_after`], such as by the command line tools, are only dropped once, so the TTL bounds them too.
+//!
+//! Both caches are off until [`configure`] switches them on, so anything that never calls it reads the database.
+
+use std::{
+  collections::HashMap,
+  future::Future,
+  num::NonZeroUsize,
+  sync::{Arc, Mutex, MutexGuard, PoisonError},
+  time::{Duration, Instant},
+};
+
+use lru::LruCache;
+
+use super::{authors::Author, books::Book};
+use crate::{config::CacheConfig, metrics::CACHE_LOOKUPS};
+
+pub static BOOKS: EntityCache<Book> = EntityCache::new("book");
+pub static AUTHORS: EntityCache<Author> = EntityCache::new("author");
+
+/// Sets both caches up as `config` says, dropping whatever they held.
+pub fn configure(config: &CacheConfig) {
+  BOOKS.configure(config);
+  AUTHORS.configure(config);
+}
+
+/// A cache a write was recorded against, to be settled once the work that wrote has finished.
+trait Settle: Sync {
+  fn settle(&self, id: u64);
+}
+
+type Writes = Arc<Mutex<Vec<(&'static dyn Settle, u64)>>>;
+
+tokio::task_local! {
+  static WRITES: Writes;
+}
+
+/// Settles the writes recorded by the work it guards when dropped, whether that work finished or was abandoned.
+struct SettleOnDrop(Writes);
+
+impl Drop for SettleOnDrop {
+  fn drop(&mut self) {
+    let writes = std::mem::take(&mut *self.0.lock().unwrap_or_else(PoisonError::into_inner));
+    for (cache, id) in writes {
+      cache.settle(id);
+    }
+  }
+}
+
+/// Runs `work`, keeping every book and author it writes out of the cache until it has finished, by which time its
+/// transactions have committed or rolled back.
+pub async fn settle_after<F: Future>(work: F) -> F::Output {
+  let writes = Writes::default();
+  let _settle = SettleOnDrop(writes.clone());
+  WRITES.scope(writes, work).await
+}
+
+/// Whether the current work has written anything cached, after which what it reads may not be committed.
+fn has_written() -> bool {
+  WRITES
+    .try_with(|writes| !writes.lock().unwrap_or_else(PoisonError::into_inner).is_empty())
+    .unwrap_or(false)
+}
+
+/// Records of one entity by id, least recently used dropped first. Hits and misses are counted in [`CACHE_LOOKUPS`],
+/// labelled with `entity`.
+pub struct EntityCache<V> {
+  entity: &'static str,
+  /// `None` while the cache is off.
+  entries: Mutex<Option<Entries<V>>>,
+}
+
+struct Entries<V> {
+  records: LruCache<u64, (Instant, V)>,
+  ttl: Duration,
+  /// Records written by work that has not finished yet, with how many such writes there are.
+  pending: HashMap<u64, u32>,
+  /// Bumped by every write, so a read that overlapped one can tell.
+  generation: u64,
+}
+
+/// Handed out by [`EntityCache::read_started`] before a read, and given back to [`EntityCache::insert`] with its result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadTicket(u64);
+
+impl<V: Clone + Send> EntityCache<V> {
+  pub const fn new(entity: &'static str) -> Self {
+    EntityCache {
+      entity,
+      entries: Mutex::new(None),
+    }
+  }
+
+  pub fn configure(&self, config: &CacheConfig) {
+    *self.lock() = match NonZeroUsize::new(config.capacity as usize) {
+      Some(capacity) if config.enabled => Some(Entries {
+        records: LruCache::new(capacity),
+        ttl: Duration::from_secs(config.ttl_secs),
+        pending: HashMap::new(),
+        generation: 0,
+      }),
+      _ => None,
+    };
+  }
+
+  /// The record cached for `id`, unless it has expired. Nothing is counted while the cache is off.
+  pub fn get(&self, id: u64) -> Option<V> {
+    let mut entries = self.lock();
+    let entries = entries.as_mut()?;
+    let ttl = entries.ttl;
+    let hit = match entries.records.get(&id) {
+      Some((cached_at, record)) if cached_at.elapsed() < ttl => Some(record.clone()),
+      _ => None,
+    };
+    if hit.is_none() {
+      entries.records.pop(&id);
+    }
+    let result = if hit.is_some() { "hit" } else { "miss" };
+    CACHE_LOOKUPS.with_label_values(&[self.entity, result]).inc();
+    hit
+  }
+
+  /// Taken before reading a record to [`insert`](Self::insert).
+  pub fn read_started(&self) -> ReadTicket {
+    ReadTicket(self.lock().as_ref().map_or(0, |entries| entries.generation))
+  }
+
+  /// Caches what a read begun with `ticket` found, unless a write may have made it stale or not yet committed.
+  pub fn insert(&self, id: u64, record: V, ticket: ReadTicket) {
+    if has_written() {
+      return;
+    }
+    if let Some(entries) = self.lock().as_mut() {
+      if entries.generation == ticket.0 && !entries.pending.contains_key(&id) {
+        entries.records.put(id, (Instant::now(), record));
+      }
+    }
+  }
+
+  /// Drops `id` because it is being written, and keeps it out until the work writing it has finished.
+  pub fn invalidate(&'static self, id: u64) {
+    let recorded = WRITES.try_with(|writes| writes.lock().unwrap_or_else(PoisonError::into_inner).push((self, id)));
+    if let Some(entries) = self.lock().as_mut() {
+      entries.records.pop(&id);
+      entries.generation += 1;
+      if recorded.is_ok() {
+        *entries.pending.entry(id).or_default() += 1;
+      }
+    }
+  }
+
+  /// The entries only ever hold whole records, so they are still sound after a panic elsewhere.
+  fn lock(&self) -> MutexGuard<'_, Option<Entries<V>>> {
+    self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+}
+
+impl<V: Clone + Send> Settle for EntityCache<V> {
+  fn settle(&self, id: u64) {
+    if let Some(entries) = self.lock().as_mut() {
+      entries.records.pop(&id);
+      entries.generation += 1;
+      if let Some(writes) = entries.pending.get_mut(&id) {
+        *writes -= 1;
+        if *writes == 0 {
+          entries.pending.remove(&id);
+        }
+      }
+    }
+  }
+}
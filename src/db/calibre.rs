@@ -0,0 +1,44 @@
+use chrono::{DateTime, Utc};
+use sqlx::{mysql::MySqlQueryResult, query, query_as, FromRow, MySql, Transaction};
+use tracing::instrument;
+
+/// Ties a Calibre book, by its UUID, to the book it was imported as. `last_modified` is Calibre's own timestamp, kept verbatim.
+#[derive(Debug, Clone, FromRow, PartialEq, Eq)]
+pub struct CalibreLink {
+  pub uuid: String,
+  pub book_id: u64,
+  pub last_modified: String,
+  pub date_synced: Option<DateTime<Utc>>,
+}
+
+impl CalibreLink {
+  #[instrument(level = "debug", skip_all, fields(entity = "calibre_link"))]
+  pub async fn fetch_all<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Vec<CalibreLink>, sqlx::Error> {
+    query_as::<MySql, CalibreLink>(r#"SELECT * FROM `calibre_book`"#).fetch_all(&mut **tx).await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "calibre_link", book_id = book_id))]
+  pub async fn upsert<'a>(tx: &mut Transaction<'a, MySql>, uuid: &str, book_id: u64, last_modified: &str) -> Result<MySqlQueryResult, sqlx::Error> {
+    query(
+      r#"INSERT INTO `calibre_book` (`uuid`, `book_id`, `last_modified`)
+      VALUES (?, ?, ?)
+      ON DUPLICATE KEY UPDATE `book_id` = VALUES(`book_id`), `last_modified` = VALUES(`last_modified`)"#,
+    )
+    .bind(uuid)
+    .bind(book_id)
+    .bind(last_modified)
+    .execute(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "calibre_link"))]
+  pub async fn delete<'a>(tx: &mut Transaction<'a, MySql>, uuid: &str) -> Result<MySqlQueryResult, sqlx::Error> {
+    query(
+      r#"DELETE FROM `calibre_book`
+      WHERE `uuid` = ?"#,
+    )
+    .bind(uuid)
+    .execute(&mut **tx)
+    .await
+  }
+}
@@ -0,0 +1,120 @@
+//! The change feed: a transactional outbox of mutations. Every create, update and delete of a book, author, publisher,
+//! series, tag, file, shelf, reading progress or user records an event in the same transaction, so an event exists
+//! exactly when its change committed.
+//!
+//! The feed's offsets are sequence numbers given to events only once they have committed, by [`ChangeEvent::sequence`],
+//! which numbers every committed event still without one under the lock of a single counter row. Offsets therefore grow
+//! in the order events became visible, however long the transaction that wrote them ran, and a reader that has seen
+//! offset 7 will never later find a 6.
+
+use chrono::{DateTime, Utc};
+use sqlx::{mysql::MySqlQueryResult, query, query_as, FromRow, MySql, Transaction};
+use tracing::instrument;
+
+/// Events numbered per [`ChangeEvent::sequence`] call, so a backlog is worked through in bounded steps.
+const SEQUENCE_BATCH: u32 = 1000;
+
+#[derive(Debug, Clone, FromRow, PartialEq, Eq)]
+pub struct ChangeEvent {
+  pub id: u64,
+  /// The event's offset in the feed.
+  pub seq: u64,
+  /// `book`, `author`, `publisher`, `series`, `tag`, `book_file`, `shelf`, `progress` or `user`, or `book_tag` and
+  /// `book_identifier` when a book's tags or identifiers were replaced, with the book as `entity_id`.
+  pub entity: String,
+  pub entity_id: u64,
+  /// `create`, `update`, `delete` (into the trash, for books and authors), `restore` or `purge`.
+  pub operation: String,
+  pub date_added: Option<DateTime<Utc>>,
+}
+
+impl ChangeEvent {
+  #[instrument(level = "debug", skip_all, fields(entity = "change_event", entity_id = entity_id))]
+  pub async fn record<'a>(tx: &mut Transaction<'a, MySql>, entity: &str, entity_id: u64, operation: &str) -> Result<MySqlQueryResult, sqlx::Error> {
+    query(
+      r#"INSERT INTO `change_event` (`entity`, `entity_id`, `operation`)
+      VALUES (?, ?, ?)"#,
+    )
+    .bind(entity)
+    .bind(entity_id)
+    .bind(operation)
+    .execute(&mut **tx)
+    .await
+  }
+
+  /// Gives the committed events that have none yet their offsets, oldest first, returning how many it numbered. The
+  /// caller commits `tx` straight after, since the counter row stays locked until then. Events of transactions still
+  /// open are skipped, and numbered by a later call once they commit.
+  #[instrument(level = "debug", skip_all, fields(entity = "change_event"))]
+  pub async fn sequence<'a>(tx: &mut Transaction<'a, MySql>) -> Result<u64, sqlx::Error> {
+    query(
+      r#"INSERT INTO `change_sequence` (`id`, `value`) VALUES (1, 0)
+      ON DUPLICATE KEY UPDATE `value` = `value`"#,
+    )
+    .execute(&mut **tx)
+    .await?;
+    let last: u64 = sqlx::query_scalar(r#"SELECT `value` FROM `change_sequence` WHERE `id` = 1 FOR UPDATE"#)
+      .fetch_one(&mut **tx)
+      .await?;
+
+    let ids: Vec<u64> = sqlx::query_scalar(
+      r#"SELECT `id` FROM `change_event`
+      WHERE `seq` IS NULL
+      ORDER BY `id`
+      LIMIT ?
+      FOR UPDATE SKIP LOCKED"#,
+    )
+    .bind(SEQUENCE_BATCH)
+    .fetch_all(&mut **tx)
+    .await?;
+    for (seq, id) in (last + 1..).zip(&ids) {
+      query(r#"UPDATE `change_event` SET `seq` = ? WHERE `id` = ?"#)
+        .bind(seq)
+        .bind(id)
+        .execute(&mut **tx)
+        .await?;
+    }
+    let numbered = ids.len() as u64;
+    query(r#"UPDATE `change_sequence` SET `value` = ? WHERE `id` = 1"#)
+      .bind(last + numbered)
+      .execute(&mut **tx)
+      .await?;
+    Ok(numbered)
+  }
+
+  /// Up to `limit` events after offset `after`, oldest first. Only events [`ChangeEvent::sequence`] has numbered are
+  /// returned, so the last one is a safe offset to resume from.
+  #[instrument(level = "debug", skip_all, fields(entity = "change_event"))]
+  pub async fn fetch_after<'a>(tx: &mut Transaction<'a, MySql>, after: u64, limit: u32) -> Result<Vec<ChangeEvent>, sqlx::Error> {
+    query_as::<MySql, ChangeEvent>(
+      r#"SELECT * FROM `change_event`
+      WHERE `seq` > ?
+      ORDER BY `seq`
+      LIMIT ?"#,
+    )
+    .bind(after)
+    .bind(limit)
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  /// The newest offset, for consumers that only want what happens from now on.
+  #[instrument(level = "debug", skip_all, fields(entity = "change_event"))]
+  pub async fn fetch_head<'a>(tx: &mut Transaction<'a, MySql>) -> Result<u64, sqlx::Error> {
+    let head: Option<u64> = sqlx::query_scalar(r#"SELECT MAX(`seq`) FROM `change_event`"#).fetch_one(&mut **tx).await?;
+    Ok(head.unwrap_or(0))
+  }
+
+  /// Deletes events recorded before `cutoff`, returning how many went. Consumers further behind than that have to resync.
+  #[instrument(level = "debug", skip_all, fields(entity = "change_event"))]
+  pub async fn prune<'a>(tx: &mut Transaction<'a, MySql>, cutoff: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+    let result = query(
+      r#"DELETE FROM `change_event`
+      WHERE `date_added` < ?"#,
+    )
+    .bind(cutoff)
+    .execute(&mut **tx)
+    .await?;
+    Ok(result.rows_affected())
+  }
+}
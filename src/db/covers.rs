@@ -0,0 +1,195 @@
+use chrono::{DateTime, Utc};
+use sqlx::{mysql::MySqlQueryResult, query, query_as, FromRow, MySql, Transaction};
+use tracing::instrument;
+
+use super::{cache, changes::ChangeEvent, placeholders};
+
+/// The original cover image of a book. Its bytes, and those of its thumbnails, live in the cover `Storage`.
+#[derive(Debug, Clone, FromRow, PartialEq, Eq)]
+pub struct Cover {
+  pub book_id: u64,
+  pub sha256: String,
+  pub mime: String,
+  pub width: u32,
+  pub height: u32,
+  pub dominant_colour: String,
+  pub source: String,
+  pub date_added: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, FromRow, PartialEq, Eq)]
+pub struct CoverThumbnail {
+  pub book_id: u64,
+  pub size: u32,
+  pub format: String,
+  pub sha256: String,
+  pub width: u32,
+  pub height: u32,
+}
+
+impl Cover {
+  #[instrument(level = "debug", skip_all, fields(entity = "cover", book_id = book_id))]
+  pub async fn fetch_one<'a>(tx: &mut Transaction<'a, MySql>, book_id: u64) -> Result<Cover, sqlx::Error> {
+    query_as::<MySql, Cover>(
+      r#"SELECT * FROM `book_cover`
+      WHERE `book_id`= ?"#,
+    )
+    .bind(book_id)
+    .fetch_one(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "cover", book_id = book_id))]
+  pub async fn fetch_thumbnails<'a>(tx: &mut Transaction<'a, MySql>, book_id: u64) -> Result<Vec<CoverThumbnail>, sqlx::Error> {
+    query_as::<MySql, CoverThumbnail>(
+      r#"SELECT * FROM `book_cover_thumbnail`
+      WHERE `book_id`= ?
+      ORDER BY `size`"#,
+    )
+    .bind(book_id)
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  /// The covers among `book_ids` that exist, in no particular order.
+  #[instrument(level = "debug", skip_all, fields(entity = "cover"))]
+  pub async fn fetch_many<'a>(tx: &mut Transaction<'a, MySql>, book_ids: &[u64]) -> Result<Vec<Cover>, sqlx::Error> {
+    if book_ids.is_empty() {
+      return Ok(Vec::new());
+    }
+    let sql = format!("SELECT * FROM `book_cover` WHERE `book_id` IN ({})", placeholders(book_ids.len()));
+    let mut covers = query_as::<MySql, Cover>(&sql);
+    for book_id in book_ids {
+      covers = covers.bind(book_id);
+    }
+    covers.fetch_all(&mut **tx).await
+  }
+
+  /// The thumbnails of each of `book_ids`, smallest first.
+  #[instrument(level = "debug", skip_all, fields(entity = "cover"))]
+  pub async fn fetch_thumbnails_by_books<'a>(tx: &mut Transaction<'a, MySql>, book_ids: &[u64]) -> Result<Vec<CoverThumbnail>, sqlx::Error> {
+    if book_ids.is_empty() {
+      return Ok(Vec::new());
+    }
+    let sql = format!(
+      "SELECT * FROM `book_cover_thumbnail` WHERE `book_id` IN ({}) ORDER BY `size`",
+      placeholders(book_ids.len())
+    );
+    let mut thumbnails = query_as::<MySql, CoverThumbnail>(&sql);
+    for book_id in book_ids {
+      thumbnails = thumbnails.bind(book_id);
+    }
+    thumbnails.fetch_all(&mut **tx).await
+  }
+
+  /// Every blob hash referenced by an original or a thumbnail.
+  #[instrument(level = "debug", skip_all, fields(entity = "cover"))]
+  pub async fn fetch_hashes<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar::<MySql, String>(
+      r#"SELECT `sha256` FROM `book_cover`
+      UNION SELECT `sha256` FROM `book_cover_thumbnail`"#,
+    )
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "cover"))]
+  pub async fn is_referenced<'a>(tx: &mut Transaction<'a, MySql>, sha256: &str) -> Result<bool, sqlx::Error> {
+    let count: i64 = sqlx::query_scalar(
+      r#"SELECT (SELECT COUNT(*) FROM `book_cover` WHERE `sha256` = ?)
+        + (SELECT COUNT(*) FROM `book_cover_thumbnail` WHERE `sha256` = ?)"#,
+    )
+    .bind(sha256)
+    .bind(sha256)
+    .fetch_one(&mut **tx)
+    .await?;
+    Ok(count > 0)
+  }
+
+  /// Replaces the book's cover and thumbnails, and flags the book as having an image.
+  #[allow(clippy::too_many_arguments)]
+  #[instrument(level = "debug", skip_all, fields(entity = "cover", book_id = book_id))]
+  pub async fn set<'a>(
+    tx: &mut Transaction<'a, MySql>,
+    book_id: u64,
+    sha256: &str,
+    mime: &str,
+    width: u32,
+    height: u32,
+    dominant_colour: &str,
+    source: &str,
+    thumbnails: &[CoverThumbnail],
+  ) -> Result<Cover, sqlx::Error> {
+    Cover::clear(tx, book_id).await?;
+
+    query(
+      r#"INSERT INTO `book_cover` (`book_id`, `sha256`, `mime`, `width`, `height`, `dominant_colour`, `source`)
+      VALUES (?, ?, ?, ?, ?, ?, ?)"#,
+    )
+    .bind(book_id)
+    .bind(sha256)
+    .bind(mime)
+    .bind(width)
+    .bind(height)
+    .bind(dominant_colour)
+    .bind(source)
+    .execute(&mut **tx)
+    .await?;
+
+    for thumbnail in thumbnails {
+      query(
+        r#"INSERT INTO `book_cover_thumbnail` (`book_id`, `size`, `format`, `sha256`, `width`, `height`)
+        VALUES (?, ?, ?, ?, ?, ?)"#,
+      )
+      .bind(book_id)
+      .bind(thumbnail.size)
+      .bind(&thumbnail.format)
+      .bind(&thumbnail.sha256)
+      .bind(thumbnail.width)
+      .bind(thumbnail.height)
+      .execute(&mut **tx)
+      .await?;
+    }
+
+    query(r#"UPDATE `book` SET `image_formatted` = TRUE, `version` = `version` + 1 WHERE `id` = ?"#)
+      .bind(book_id)
+      .execute(&mut **tx)
+      .await?;
+    cache::BOOKS.invalidate(book_id);
+    ChangeEvent::record(tx, "book", book_id, "update").await?;
+
+    Cover::fetch_one(tx, book_id).await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "cover", book_id = book_id))]
+  pub async fn delete<'a>(tx: &mut Transaction<'a, MySql>, book_id: u64) -> Result<MySqlQueryResult, sqlx::Error> {
+    let result = Cover::clear(tx, book_id).await?;
+    ChangeEvent::record(tx, "book", book_id, "update").await?;
+    Ok(result)
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "cover", book_id = book_id))]
+  async fn clear<'a>(tx: &mut Transaction<'a, MySql>, book_id: u64) -> Result<MySqlQueryResult, sqlx::Error> {
+    query(
+      r#"DELETE FROM `book_cover_thumbnail`
+      WHERE `book_id` = ?"#,
+    )
+    .bind(book_id)
+    .execute(&mut **tx)
+    .await?;
+
+    query(r#"UPDATE `book` SET `image_formatted` = FALSE, `version` = `version` + 1 WHERE `id` = ?"#)
+      .bind(book_id)
+      .execute(&mut **tx)
+      .await?;
+    cache::BOOKS.invalidate(book_id);
+
+    query(
+      r#"DELETE FROM `book_cover`
+      WHERE `book_id` = ?"#,
+    )
+    .bind(book_id)
+    .execute(&mut **tx)
+    .await
+  }
+}
@@ -0,0 +1,198 @@
+use chrono::{DateTime, Utc};
+use sqlx::{mysql::MySqlQueryResult, query, query_as, FromRow, MySql, Transaction};
+use tracing::instrument;
+
+use super::{changes::ChangeEvent, placeholders};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+  Epub,
+  Pdf,
+  Cbz,
+  Mobi,
+}
+
+impl FileFormat {
+  pub fn as_str(self) -> &'static str {
+    match self {
+      FileFormat::Epub => "epub",
+      FileFormat::Pdf => "pdf",
+      FileFormat::Cbz => "cbz",
+      FileFormat::Mobi => "mobi",
+    }
+  }
+
+  pub fn mime(self) -> &'static str {
+    match self {
+      FileFormat::Epub => "application/epub+zip",
+      FileFormat::Pdf => "application/pdf",
+      FileFormat::Cbz => "application/vnd.comicbook+zip",
+      FileFormat::Mobi => "application/x-mobipocket-ebook",
+    }
+  }
+
+  pub fn from_extension(extension: &str) -> Option<FileFormat> {
+    match extension.to_ascii_lowercase().as_str() {
+      "epub" => Some(FileFormat::Epub),
+      "pdf" => Some(FileFormat::Pdf),
+      "cbz" => Some(FileFormat::Cbz),
+      "mobi" | "azw" | "azw3" => Some(FileFormat::Mobi),
+      _ => None,
+    }
+  }
+
+  /// Identifies a format from the file's leading bytes, which is trusted over any client-supplied name or type.
+  pub fn detect(head: &[u8]) -> Option<FileFormat> {
+    if head.starts_with(b"%PDF-") {
+      Some(FileFormat::Pdf)
+    } else if head.len() >= 68 && &head[60..68] == b"BOOKMOBI" {
+      Some(FileFormat::Mobi)
+    } else if head.starts_with(b"PK\x03\x04") {
+      // EPUBs must store an uncompressed `mimetype` entry first, so its contents sit right after the 30 byte local header.
+      let epub = head.len() >= 58 && &head[30..38] == b"mimetype" && &head[38..58] == b"application/epub+zip";
+      Some(if epub { FileFormat::Epub } else { FileFormat::Cbz })
+    } else {
+      None
+    }
+  }
+}
+
+/// An ebook file attached to a `Book`. The bytes live in `Storage`, addressed by `sha256`.
+#[derive(Debug, Clone, FromRow, PartialEq, Eq)]
+pub struct BookFile {
+  pub id: u64,
+  pub book_id: u64,
+  pub sha256: String,
+  pub format: String,
+  pub mime: String,
+  pub size: u64,
+  pub filename: Option<String>,
+  pub date_added: Option<DateTime<Utc>>,
+}
+
+impl BookFile {
+  #[instrument(level = "debug", skip_all, fields(entity = "book_file", id = file_id))]
+  pub async fn fetch_one<'a>(tx: &mut Transaction<'a, MySql>, file_id: u64) -> Result<BookFile, sqlx::Error> {
+    query_as::<MySql, BookFile>(
+      r#"SELECT * FROM `book_file`
+      WHERE `id`= ?"#,
+    )
+    .bind(file_id)
+    .fetch_one(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "book_file", book_id = book_id))]
+  pub async fn fetch_by_book<'a>(tx: &mut Transaction<'a, MySql>, book_id: u64) -> Result<Vec<BookFile>, sqlx::Error> {
+    query_as::<MySql, BookFile>(
+      r#"SELECT * FROM `book_file`
+      WHERE `book_id`= ?
+      ORDER BY `id`"#,
+    )
+    .bind(book_id)
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  /// The files of each of `book_ids`, oldest first.
+  #[instrument(level = "debug", skip_all, fields(entity = "book_file"))]
+  pub async fn fetch_by_books<'a>(tx: &mut Transaction<'a, MySql>, book_ids: &[u64]) -> Result<Vec<BookFile>, sqlx::Error> {
+    if book_ids.is_empty() {
+      return Ok(Vec::new());
+    }
+    let sql = format!("SELECT * FROM `book_file` WHERE `book_id` IN ({}) ORDER BY `id`", placeholders(book_ids.len()));
+    let mut files = query_as::<MySql, BookFile>(&sql);
+    for book_id in book_ids {
+      files = files.bind(book_id);
+    }
+    files.fetch_all(&mut **tx).await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "book_file"))]
+  pub async fn fetch_all<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Vec<BookFile>, sqlx::Error> {
+    query_as::<MySql, BookFile>(r#"SELECT * FROM `book_file`"#).fetch_all(&mut **tx).await
+  }
+
+  /// Every blob hash still referenced by a file row.
+  #[instrument(level = "debug", skip_all, fields(entity = "book_file"))]
+  pub async fn fetch_hashes<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar::<MySql, String>(r#"SELECT DISTINCT `sha256` FROM `book_file`"#)
+      .fetch_all(&mut **tx)
+      .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "book_file"))]
+  pub async fn fetch_last<'a>(tx: &mut Transaction<'a, MySql>) -> Result<BookFile, sqlx::Error> {
+    query_as::<MySql, BookFile>(
+      r#"SELECT * FROM `book_file`
+      WHERE `id` = LAST_INSERT_ID();"#,
+    )
+    .fetch_one(&mut **tx)
+    .await
+  }
+
+  /// Attaches a stored blob to a book. Attaching the same content twice returns the existing row.
+  #[instrument(level = "debug", skip_all, fields(entity = "book_file", book_id = book_id))]
+  pub async fn create<'a>(
+    tx: &mut Transaction<'a, MySql>,
+    book_id: u64,
+    sha256: &str,
+    format: FileFormat,
+    size: u64,
+    filename: Option<String>,
+  ) -> Result<BookFile, sqlx::Error> {
+    let existing = query_as::<MySql, BookFile>(
+      r#"SELECT * FROM `book_file`
+      WHERE `book_id` = ? AND `sha256` = ?"#,
+    )
+    .bind(book_id)
+    .bind(sha256)
+    .fetch_optional(&mut **tx)
+    .await?;
+    if let Some(existing) = existing {
+      return Ok(existing);
+    }
+
+    query(
+      r#"INSERT INTO `book_file` (`book_id`, `sha256`, `format`, `mime`, `size`, `filename`)
+      VALUES (?, ?, ?, ?, ?, ?)"#,
+    )
+    .bind(book_id)
+    .bind(sha256)
+    .bind(format.as_str())
+    .bind(format.mime())
+    .bind(size)
+    .bind(filename)
+    .execute(&mut **tx)
+    .await?;
+
+    let file = BookFile::fetch_last(tx).await?;
+    ChangeEvent::record(tx, "book_file", file.id, "create").await?;
+    Ok(file)
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "book_file", id = file_id))]
+  pub async fn delete<'a>(tx: &mut Transaction<'a, MySql>, file_id: u64) -> Result<MySqlQueryResult, sqlx::Error> {
+    let result = query(
+      r#"DELETE FROM `book_file`
+      WHERE `id` = ?"#,
+    )
+    .bind(file_id)
+    .execute(&mut **tx)
+    .await?;
+    if result.rows_affected() > 0 {
+      ChangeEvent::record(tx, "book_file", file_id, "delete").await?;
+    }
+    Ok(result)
+  }
+
+  /// Whether any file row still points at the blob, so it is safe to remove from storage when not.
+  #[instrument(level = "debug", skip_all, fields(entity = "book_file"))]
+  pub async fn is_referenced<'a>(tx: &mut Transaction<'a, MySql>, sha256: &str) -> Result<bool, sqlx::Error> {
+    let count: i64 = sqlx::query_scalar(r#"SELECT COUNT(*) FROM `book_file` WHERE `sha256` = ?"#)
+      .bind(sha256)
+      .fetch_one(&mut **tx)
+      .await?;
+    Ok(count > 0)
+  }
+}
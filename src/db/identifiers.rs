@@ -0,0 +1,54 @@
+use sqlx::{query, query_as, FromRow, MySql, Transaction};
+use tracing::instrument;
+
+use super::changes::ChangeEvent;
+
+/// An external identifier for a book besides its ISBN, such as `goodreads` or `amazon`.
+#[derive(Debug, Clone, FromRow, PartialEq, Eq)]
+pub struct Identifier {
+  pub book_id: u64,
+  pub scheme: String,
+  pub value: String,
+}
+
+impl Identifier {
+  #[instrument(level = "debug", skip_all, fields(entity = "identifier", book_id = book_id))]
+  pub async fn fetch_by_book<'a>(tx: &mut Transaction<'a, MySql>, book_id: u64) -> Result<Vec<Identifier>, sqlx::Error> {
+    query_as::<MySql, Identifier>(
+      r#"SELECT * FROM `book_identifier`
+      WHERE `book_id` = ?
+      ORDER BY `scheme`"#,
+    )
+    .bind(book_id)
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  /// Replaces the book's identifiers with `identifiers`, given as scheme and value pairs.
+  #[instrument(level = "debug", skip_all, fields(entity = "identifier", book_id = book_id))]
+  pub async fn set_for_book<'a>(tx: &mut Transaction<'a, MySql>, book_id: u64, identifiers: &[(String, String)]) -> Result<Vec<Identifier>, sqlx::Error> {
+    query(
+      r#"DELETE FROM `book_identifier`
+      WHERE `book_id` = ?"#,
+    )
+    .bind(book_id)
+    .execute(&mut **tx)
+    .await?;
+
+    for (scheme, value) in identifiers {
+      query(
+        r#"INSERT INTO `book_identifier` (`book_id`, `scheme`, `value`)
+        VALUES (?, ?, ?)
+        ON DUPLICATE KEY UPDATE `value` = VALUES(`value`)"#,
+      )
+      .bind(book_id)
+      .bind(scheme)
+      .bind(value)
+      .execute(&mut **tx)
+      .await?;
+    }
+    ChangeEvent::record(tx, "book_identifier", book_id, "update").await?;
+
+    Identifier::fetch_by_book(tx, book_id).await
+  }
+}
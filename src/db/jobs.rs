@@ -0,0 +1,223 @@
+use chrono::{DateTime, Utc};
+use sqlx::{query, query_as, FromRow, MySql, Transaction};
+use tracing::instrument;
+
+#[derive(Debug, Clone, FromRow, PartialEq, Eq)]
+pub struct Job {
+  pub id: u64,
+  /// The payload's `kind`, kept in its own column to filter on.
+  pub kind: String,
+  /// The task's JSON, as written by [`Job::enqueue`].
+  pub payload: String,
+  /// `pending`, `running`, `succeeded`, `failed` once every attempt is spent, or `cancelled`.
+  pub status: String,
+  /// Runs started, counted as each is claimed so a run that never reports back still uses one up.
+  pub attempts: u32,
+  pub max_attempts: u32,
+  /// What the last successful run reported, as JSON.
+  pub result: Option<String>,
+  pub error: Option<String>,
+  /// When the job may next be started. While it runs, when its lease runs out.
+  pub run_at: Option<DateTime<Utc>>,
+  pub date_added: Option<DateTime<Utc>>,
+  pub date_started: Option<DateTime<Utc>>,
+  pub date_finished: Option<DateTime<Utc>>,
+  /// The token of the run holding the job while it is running. Only that run may record the outcome.
+  pub claim: Option<String>,
+}
+
+/// Filters for [`Job::fetch`]. Unset fields match everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JobQuery {
+  pub status: Option<String>,
+  pub kind: Option<String>,
+  pub limit: u32,
+}
+
+impl Job {
+  #[instrument(level = "debug", skip_all, fields(entity = "job", id = job_id))]
+  pub async fn fetch_one<'a>(tx: &mut Transaction<'a, MySql>, job_id: u64) -> Result<Job, sqlx::Error> {
+    query_as::<MySql, Job>(
+      r#"SELECT * FROM `job`
+      WHERE `id`= ?"#,
+    )
+    .bind(job_id)
+    .fetch_one(&mut **tx)
+    .await
+  }
+
+  /// Newest first.
+  #[instrument(level = "debug", skip_all, fields(entity = "job"))]
+  pub async fn fetch<'a>(tx: &mut Transaction<'a, MySql>, filter: &JobQuery) -> Result<Vec<Job>, sqlx::Error> {
+    query_as::<MySql, Job>(
+      r#"SELECT * FROM `job`
+      WHERE (? IS NULL OR `status` = ?)
+        AND (? IS NULL OR `kind` = ?)
+      ORDER BY `id` DESC
+      LIMIT ?"#,
+    )
+    .bind(&filter.status)
+    .bind(&filter.status)
+    .bind(&filter.kind)
+    .bind(&filter.kind)
+    .bind(filter.limit)
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "job"))]
+  pub async fn fetch_last<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Job, sqlx::Error> {
+    query_as::<MySql, Job>(
+      r#"SELECT * FROM `job`
+      WHERE `id` = LAST_INSERT_ID();"#,
+    )
+    .fetch_one(&mut **tx)
+    .await
+  }
+
+  /// Queues a job to start at `run_at`, or as soon as a worker is free. Runs in the caller's transaction, so a job queued
+  /// alongside a change only exists if the change commits.
+  #[instrument(level = "debug", skip_all, fields(entity = "job", kind = kind))]
+  pub async fn enqueue<'a>(
+    tx: &mut Transaction<'a, MySql>,
+    kind: &str,
+    payload: &serde_json::Value,
+    run_at: Option<DateTime<Utc>>,
+    max_attempts: u32,
+  ) -> Result<Job, sqlx::Error> {
+    query(
+      r#"INSERT INTO `job` (`kind`, `payload`, `max_attempts`, `run_at`)
+      VALUES (?, ?, ?, COALESCE(?, NOW()))"#,
+    )
+    .bind(kind)
+    .bind(payload.to_string())
+    .bind(max_attempts)
+    .bind(run_at)
+    .execute(&mut **tx)
+    .await?;
+
+    Job::fetch_last(tx).await
+  }
+
+  /// Claims up to `limit` jobs that are due, oldest first, marking them running until `lease` under a fresh claim token
+  /// and counting the attempt. A job whose worker died is due again once its lease runs out, unless that was its last
+  /// attempt, in which case it fails for good here.
+  #[instrument(level = "debug", skip_all, fields(entity = "job"))]
+  pub async fn claim_due<'a>(tx: &mut Transaction<'a, MySql>, limit: u32, lease: DateTime<Utc>) -> Result<Vec<Job>, sqlx::Error> {
+    let due = query_as::<MySql, Job>(
+      r#"SELECT * FROM `job`
+      WHERE `status` IN ('pending', 'running') AND `run_at` <= NOW()
+      ORDER BY `run_at`, `id`
+      LIMIT ?
+      FOR UPDATE SKIP LOCKED"#,
+    )
+    .bind(limit)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    let mut claimed = Vec::with_capacity(due.len());
+    for mut job in due {
+      if job.attempts >= job.max_attempts {
+        query(
+          r#"UPDATE `job`
+          SET `status` = 'failed', `error` = 'lease expired on the last attempt', `run_at` = NULL, `claim` = NULL, `date_finished` = NOW()
+          WHERE `id` = ?"#,
+        )
+        .bind(job.id)
+        .execute(&mut **tx)
+        .await?;
+        continue;
+      }
+
+      let claim = uuid::Uuid::new_v4().simple().to_string();
+      query(
+        r#"UPDATE `job`
+        SET `status` = 'running', `attempts` = `attempts` + 1, `run_at` = ?, `claim` = ?, `date_started` = NOW()
+        WHERE `id` = ?"#,
+      )
+      .bind(lease)
+      .bind(&claim)
+      .bind(job.id)
+      .execute(&mut **tx)
+      .await?;
+      job.status = String::from("running");
+      job.attempts += 1;
+      job.run_at = Some(lease);
+      job.claim = Some(claim);
+      claimed.push(job);
+    }
+    Ok(claimed)
+  }
+
+  /// Records the run under `claim` as a success. Returns whether that run still held the job; if its lease ran out and
+  /// another run took over, nothing is recorded.
+  #[instrument(level = "debug", skip_all, fields(entity = "job", id = job_id))]
+  pub async fn mark_succeeded<'a>(tx: &mut Transaction<'a, MySql>, job_id: u64, claim: &str, result: &serde_json::Value) -> Result<bool, sqlx::Error> {
+    let marked = query(
+      r#"UPDATE `job`
+      SET `status` = 'succeeded', `result` = ?, `error` = NULL, `run_at` = NULL, `claim` = NULL, `date_finished` = NOW()
+      WHERE `id` = ? AND `claim` = ?"#,
+    )
+    .bind(result.to_string())
+    .bind(job_id)
+    .bind(claim)
+    .execute(&mut **tx)
+    .await?;
+    Ok(marked.rows_affected() > 0)
+  }
+
+  /// Records the run under `claim` as failed. With a `retry_at` the job runs again then; without one it has failed for
+  /// good. Returns whether that run still held the job, as [`Job::mark_succeeded`] does.
+  #[instrument(level = "debug", skip_all, fields(entity = "job", id = job_id))]
+  pub async fn mark_failed<'a>(
+    tx: &mut Transaction<'a, MySql>,
+    job_id: u64,
+    claim: &str,
+    error: &str,
+    retry_at: Option<DateTime<Utc>>,
+  ) -> Result<bool, sqlx::Error> {
+    let marked = query(
+      r#"UPDATE `job`
+      SET `status` = IF(? IS NULL, 'failed', 'pending'), `error` = ?, `run_at` = ?, `claim` = NULL,
+        `date_finished` = IF(? IS NULL, NOW(), NULL)
+      WHERE `id` = ? AND `claim` = ?"#,
+    )
+    .bind(retry_at)
+    .bind(error)
+    .bind(retry_at)
+    .bind(retry_at)
+    .bind(job_id)
+    .bind(claim)
+    .execute(&mut **tx)
+    .await?;
+    Ok(marked.rows_affected() > 0)
+  }
+
+  /// Cancels a job that has not started. Returns whether it was still pending.
+  #[instrument(level = "debug", skip_all, fields(entity = "job", id = job_id))]
+  pub async fn cancel<'a>(tx: &mut Transaction<'a, MySql>, job_id: u64) -> Result<bool, sqlx::Error> {
+    let cancelled = query(
+      r#"UPDATE `job`
+      SET `status` = 'cancelled', `run_at` = NULL, `date_finished` = NOW()
+      WHERE `id` = ? AND `status` = 'pending'"#,
+    )
+    .bind(job_id)
+    .execute(&mut **tx)
+    .await?;
+    Ok(cancelled.rows_affected() > 0)
+  }
+
+  /// Puts a failed or cancelled job back in the queue with a fresh set of attempts. Returns whether it was either.
+  #[instrument(level = "debug", skip_all, fields(entity = "job", id = job_id))]
+  pub async fn retry<'a>(tx: &mut Transaction<'a, MySql>, job_id: u64) -> Result<bool, sqlx::Error> {
+    let retried = query(
+      r#"UPDATE `job`
+      SET `status` = 'pending', `attempts` = 0, `error` = NULL, `run_at` = NOW(), `claim` = NULL, `date_finished` = NULL
+      WHERE `id` = ? AND `status` IN ('failed', 'cancelled')"#,
+    )
+    .bind(job_id)
+    .execute(&mut **tx)
+    .await?;
+    Ok(retried.rows_affected() > 0)
+  }
+}
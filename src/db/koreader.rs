@@ -0,0 +1,229 @@
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::{mysql::MySqlQueryResult, query, query_as, FromRow, MySql, Transaction};
+use tracing::instrument;
+
+use super::{books::Book, progress::Progress, user::User};
+
+/// Credentials for KOReader's sync plugin, which authenticates with a username and the MD5 of the password (the "userkey").
+#[derive(Debug, Clone, FromRow, PartialEq, Eq)]
+pub struct KoreaderUser {
+  pub user_id: u8,
+  pub username: String,
+  pub userkey: String,
+  pub date_added: Option<DateTime<Utc>>,
+}
+
+/// Links a KOReader document hash to the catalog book it identifies.
+#[derive(Debug, Clone, FromRow, PartialEq, Eq)]
+pub struct KoreaderDocument {
+  pub document: String,
+  pub book_id: u64,
+  pub date_added: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, FromRow, PartialEq)]
+pub struct KoreaderProgress {
+  pub user_id: u8,
+  pub document: String,
+  pub progress: String,
+  pub percentage: f64,
+  pub device: String,
+  pub device_id: String,
+  pub date_last_updated: Option<DateTime<Utc>>,
+}
+
+/// The userkey is already an MD5 of the password, so it is hashed again before storage rather than kept as-is.
+fn hash_key(userkey: &str) -> String {
+  hex::encode(Sha256::digest(userkey.as_bytes()))
+}
+
+impl KoreaderUser {
+  #[instrument(level = "debug", skip_all, fields(entity = "koreader_user"))]
+  pub async fn fetch_by_username<'a>(tx: &mut Transaction<'a, MySql>, username: &str) -> Result<KoreaderUser, sqlx::Error> {
+    query_as::<MySql, KoreaderUser>(
+      r#"SELECT * FROM `koreader_user`
+      WHERE `username`= ?"#,
+    )
+    .bind(username)
+    .fetch_one(&mut **tx)
+    .await
+  }
+
+  /// Registers a KOReader account along with a new `User` of the same name. Returns `None` when an account or a user
+  /// already has the name, rather than handing someone else's reading history to whoever registers it.
+  #[instrument(level = "debug", skip_all, fields(entity = "koreader_user"))]
+  pub async fn create<'a>(tx: &mut Transaction<'a, MySql>, username: String, userkey: &str) -> Result<Option<KoreaderUser>, sqlx::Error> {
+    for taken in [
+      KoreaderUser::fetch_by_username(tx, &username).await.map(|_| ()),
+      User::fetch_by_name(tx, &username).await.map(|_| ()),
+    ] {
+      match taken {
+        Ok(()) => return Ok(None),
+        Err(sqlx::Error::RowNotFound) => {}
+        Err(err) => return Err(err),
+      }
+    }
+    let user = User::create(tx, None, username.clone()).await?;
+
+    query(
+      r#"INSERT INTO `koreader_user` (`user_id`, `username`, `userkey`)
+      VALUES (?, ?, ?)"#,
+    )
+    .bind(user.id)
+    .bind(&username)
+    .bind(hash_key(userkey))
+    .execute(&mut **tx)
+    .await?;
+
+    KoreaderUser::fetch_by_username(tx, &username).await.map(Some)
+  }
+
+  /// Returns the account only when the userkey matches.
+  #[instrument(level = "debug", skip_all, fields(entity = "koreader_user"))]
+  pub async fn authenticate<'a>(tx: &mut Transaction<'a, MySql>, username: &str, userkey: &str) -> Result<Option<KoreaderUser>, sqlx::Error> {
+    match KoreaderUser::fetch_by_username(tx, username).await {
+      Ok(user) if user.userkey == hash_key(userkey) => Ok(Some(user)),
+      Ok(_) | Err(sqlx::Error::RowNotFound) => Ok(None),
+      Err(err) => Err(err),
+    }
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "koreader_user", id = user_id))]
+  pub async fn delete<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8) -> Result<MySqlQueryResult, sqlx::Error> {
+    query(
+      r#"DELETE FROM `koreader_user`
+      WHERE `user_id` = ?"#,
+    )
+    .bind(user_id)
+    .execute(&mut **tx)
+    .await
+  }
+}
+
+impl KoreaderDocument {
+  #[instrument(level = "debug", skip_all, fields(entity = "koreader_document"))]
+  pub async fn fetch_one<'a>(tx: &mut Transaction<'a, MySql>, document: &str) -> Result<KoreaderDocument, sqlx::Error> {
+    query_as::<MySql, KoreaderDocument>(
+      r#"SELECT * FROM `koreader_document`
+      WHERE `document`= ?"#,
+    )
+    .bind(document)
+    .fetch_one(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "koreader_document", book_id = book_id))]
+  pub async fn fetch_by_book<'a>(tx: &mut Transaction<'a, MySql>, book_id: u64) -> Result<Vec<KoreaderDocument>, sqlx::Error> {
+    query_as::<MySql, KoreaderDocument>(
+      r#"SELECT * FROM `koreader_document`
+      WHERE `book_id`= ?"#,
+    )
+    .bind(book_id)
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  /// Points a document hash at a book, replacing any previous mapping.
+  #[instrument(level = "debug", skip_all, fields(entity = "koreader_document", book_id = book_id))]
+  pub async fn link<'a>(tx: &mut Transaction<'a, MySql>, document: &str, book_id: u64) -> Result<KoreaderDocument, sqlx::Error> {
+    query(
+      r#"INSERT INTO `koreader_document` (`document`, `book_id`)
+      VALUES (?, ?)
+      ON DUPLICATE KEY UPDATE `book_id` = VALUES(`book_id`)"#,
+    )
+    .bind(document)
+    .bind(book_id)
+    .execute(&mut **tx)
+    .await?;
+
+    KoreaderDocument::fetch_one(tx, document).await
+  }
+
+  /// Points a document hash at a book unless it already points somewhere, returning the mapping as it now stands.
+  #[instrument(level = "debug", skip_all, fields(entity = "koreader_document", book_id = book_id))]
+  pub async fn link_new<'a>(tx: &mut Transaction<'a, MySql>, document: &str, book_id: u64) -> Result<KoreaderDocument, sqlx::Error> {
+    query(
+      r#"INSERT IGNORE INTO `koreader_document` (`document`, `book_id`)
+      VALUES (?, ?)"#,
+    )
+    .bind(document)
+    .bind(book_id)
+    .execute(&mut **tx)
+    .await?;
+
+    KoreaderDocument::fetch_one(tx, document).await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "koreader_document"))]
+  pub async fn delete<'a>(tx: &mut Transaction<'a, MySql>, document: &str) -> Result<MySqlQueryResult, sqlx::Error> {
+    query(
+      r#"DELETE FROM `koreader_document`
+      WHERE `document` = ?"#,
+    )
+    .bind(document)
+    .execute(&mut **tx)
+    .await
+  }
+}
+
+impl KoreaderProgress {
+  #[instrument(level = "debug", skip_all, fields(entity = "koreader_progress", user_id = user_id))]
+  pub async fn fetch_one<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8, document: &str) -> Result<KoreaderProgress, sqlx::Error> {
+    query_as::<MySql, KoreaderProgress>(
+      r#"SELECT * FROM `koreader_progress`
+      WHERE `user_id`= ? AND `document` = ?"#,
+    )
+    .bind(user_id)
+    .bind(document)
+    .fetch_one(&mut **tx)
+    .await
+  }
+
+  /// Stores the reader's position and, when the document is mapped to a book, mirrors it onto that book's `Progress`.
+  #[instrument(level = "debug", skip_all, fields(entity = "koreader_progress", user_id = user_id))]
+  pub async fn sync<'a>(
+    tx: &mut Transaction<'a, MySql>,
+    user_id: u8,
+    document: &str,
+    progress: &str,
+    percentage: f64,
+    device: &str,
+    device_id: &str,
+  ) -> Result<KoreaderProgress, sqlx::Error> {
+    query(
+      r#"INSERT INTO `koreader_progress` (`user_id`, `document`, `progress`, `percentage`, `device`, `device_id`)
+      VALUES (?, ?, ?, ?, ?, ?)
+      ON DUPLICATE KEY UPDATE `progress` = VALUES(`progress`), `percentage` = VALUES(`percentage`),
+        `device` = VALUES(`device`), `device_id` = VALUES(`device_id`), `date_last_updated` = NOW()"#,
+    )
+    .bind(user_id)
+    .bind(document)
+    .bind(progress)
+    .bind(percentage)
+    .bind(device)
+    .bind(device_id)
+    .execute(&mut **tx)
+    .await?;
+
+    // A document of a book in the trash only keeps KOReader's record, as if it were not mapped.
+    let book = match KoreaderDocument::fetch_one(tx, document).await {
+      Ok(mapping) => Book::fetch_one(tx, mapping.book_id).await,
+      Err(err) => Err(err),
+    };
+    match book {
+      Ok(book) => {
+        Progress::read_to(tx, user_id, &book, page_for(percentage, book.num_pages)).await?;
+      }
+      Err(sqlx::Error::RowNotFound) => {}
+      Err(err) => return Err(err),
+    }
+
+    KoreaderProgress::fetch_one(tx, user_id, document).await
+  }
+}
+
+/// Converts KOReader's 0..1 percentage into a page of the catalog edition.
+pub fn page_for(percentage: f64, num_pages: u16) -> u16 {
+  (percentage.clamp(0.0, 1.0) * f64::from(num_pages)).round() as u16
+}
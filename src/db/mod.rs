@@ -1,37 +1,235 @@
-use sqlx::{mysql::MySqlPoolOptions, query, MySqlPool};
+use std::{fmt, time::Duration};
 
+use sqlx::{
+  mysql::{MySqlConnectOptions, MySqlPoolOptions},
+  query, ConnectOptions, Executor, MySql, MySqlPool, Transaction,
+};
+use tracing::instrument;
+
+use crate::config::DatabaseConfig;
+
+pub mod audit;
 pub mod authors;
 pub mod books;
+pub mod cache;
+pub mod calibre;
+pub mod changes;
+pub mod covers;
+pub mod files;
+pub mod identifiers;
+pub mod jobs;
+pub mod koreader;
 pub mod progress;
 pub mod publisher;
+pub mod schedule;
+pub mod series;
+pub mod shelves;
+pub mod stats;
+pub mod tags;
 pub mod user;
+pub mod webhooks;
+
+/// The schema version produced by running every migration in [`Db::migrate`].
+pub const SCHEMA_VERSION: u16 = 18;
+
+/// Pool settings shared by every constructor. Session variables a request set, such as the audit actor, are cleared
+/// before the connection is handed to anyone else.
+fn pool_options(max: u32) -> MySqlPoolOptions {
+  MySqlPoolOptions::new().max_connections(max).after_release(|conn, _| {
+    Box::pin(async move {
+      conn.execute("SET @libby_actor = NULL, @libby_audit = NULL").await?;
+      Ok(true)
+    })
+  })
+}
+
+/// Every statement is logged at debug level with its duration and row counts, inside the span of the `db` function that
+/// ran it; those slower than the configured threshold are logged as warnings too.
+fn connect_options(config: &DatabaseConfig) -> Result<MySqlConnectOptions, sqlx::Error> {
+  let options: MySqlConnectOptions = config.url.parse()?;
+  Ok(options.log_slow_statements(log::LevelFilter::Warn, Duration::from_millis(config.slow_query_ms)))
+}
+
+/// `?, ?, ?` for an `IN (…)` list of `count` values. Callers skip the query when there are none, since `IN ()` is a
+/// syntax error.
+pub(crate) fn placeholders(count: usize) -> String {
+  vec!["?"; count].join(", ")
+}
 
+/// Why a conditional update was not applied.
+#[derive(Debug)]
+pub enum UpdateError {
+  /// The row changed since the caller read it. Holds the version it is at now.
+  Conflict(u32),
+  Database(sqlx::Error),
+}
+
+impl fmt::Display for UpdateError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      UpdateError::Conflict(version) => write!(f, "changed since it was read, now at version {}", version),
+      UpdateError::Database(err) => write!(f, "database error: {}", err),
+    }
+  }
+}
+
+impl std::error::Error for UpdateError {}
+
+impl From<sqlx::Error> for UpdateError {
+  fn from(err: sqlx::Error) -> Self {
+    UpdateError::Database(err)
+  }
+}
+
+#[derive(Clone)]
 pub struct Db {
   pub conn: MySqlPool,
 }
 
 #[allow(dead_code)]
 impl Db {
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
   pub async fn new(url: &str) -> Result<Db, sqlx::Error> {
-    let conn = MySqlPoolOptions::new().max_connections(5).connect(url).await?;
+    let conn = pool_options(5).connect(url).await?;
 
     let db = Db { conn };
     db.migrate().await?;
     Ok(db)
   }
 
+  /// Connects with the pool sized and timed out as configured, then migrates.
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn open(config: &DatabaseConfig) -> Result<Db, sqlx::Error> {
+    let idle_timeout = (config.idle_timeout_secs > 0).then(|| Duration::from_secs(config.idle_timeout_secs));
+    let conn = pool_options(config.max_connections)
+      .min_connections(config.min_connections)
+      .acquire_timeout(Duration::from_secs(config.acquire_timeout_secs))
+      .idle_timeout(idle_timeout)
+      .connect_with(connect_options(config)?)
+      .await?;
+
+    let db = Db { conn };
+    db.migrate().await?;
+    Ok(db)
+  }
+
+  /// Connects without migrating, for a restore that has to choose how far to migrate.
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn connect(url: &str) -> Result<Db, sqlx::Error> {
+    let conn = pool_options(5).connect(url).await?;
+    Ok(Db { conn })
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
   pub async fn new_with_max(&self, url: &str, max: u32) -> Result<Db, sqlx::Error> {
-    let conn = MySqlPoolOptions::new().max_connections(max).connect(url).await?;
+    let conn = pool_options(max).connect(url).await?;
 
     let db = Db { conn };
     db.migrate().await?;
     Ok(db)
   }
 
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
   pub async fn migrate(&self) -> Result<(), sqlx::Error> {
-    self.migrate_v1().await
+    self.migrate_to(SCHEMA_VERSION).await
+  }
+
+  /// Runs the migrations up to and including `target`. Restoring a backup stops at the archive's version so its rows fit,
+  /// then migrates the rest of the way once they are in.
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn migrate_to(&self, target: u16) -> Result<(), sqlx::Error> {
+    self.migrate_v1().await?;
+
+    let version = self.schema_version().await?;
+    if version < 2 && target >= 2 {
+      self.migrate_v2().await?;
+    }
+    if version < 3 && target >= 3 {
+      self.migrate_v3().await?;
+    }
+    if version < 4 && target >= 4 {
+      self.migrate_v4().await?;
+    }
+    if version < 5 && target >= 5 {
+      self.migrate_v5().await?;
+    }
+    if version < 6 && target >= 6 {
+      self.migrate_v6().await?;
+    }
+    if version < 7 && target >= 7 {
+      self.migrate_v7().await?;
+    }
+    if version < 8 && target >= 8 {
+      self.migrate_v8().await?;
+    }
+    if version < 9 && target >= 9 {
+      self.migrate_v9().await?;
+    }
+    if version < 10 && target >= 10 {
+      self.migrate_v10().await?;
+    }
+    if version < 11 && target >= 11 {
+      self.migrate_v11().await?;
+    }
+    if version < 12 && target >= 12 {
+      self.migrate_v12().await?;
+    }
+    if version < 13 && target >= 13 {
+      self.migrate_v13().await?;
+    }
+    if version < 14 && target >= 14 {
+      self.migrate_v14().await?;
+    }
+    if version < 15 && target >= 15 {
+      self.migrate_v15().await?;
+    }
+    if version < 16 && target >= 16 {
+      self.migrate_v16().await?;
+    }
+    if version < 17 && target >= 17 {
+      self.migrate_v17().await?;
+    }
+    if version < 18 && target >= 18 {
+      self.migrate_v18().await?;
+    }
+    Ok(())
+  }
+
+  /// Returns the highest migration recorded in `schema_version`, treating a fresh or pre-versioning database as v1.
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn schema_version(&self) -> Result<u16, sqlx::Error> {
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `schema_version` (
+          `version` SMALLINT UNSIGNED PRIMARY KEY NOT NULL,
+          `date_applied` TIMESTAMP DEFAULT NOW()
+        );
+      "#,
+    )
+    .execute(&self.conn)
+    .await?;
+
+    let mut tx = self.conn.begin().await?;
+    Db::schema_version_in(&mut tx).await
+  }
+
+  /// [`schema_version`](Db::schema_version) as `tx` sees it. It does not create the table, since DDL would commit `tx`.
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn schema_version_in<'a>(tx: &mut Transaction<'a, MySql>) -> Result<u16, sqlx::Error> {
+    let version: Option<u16> = sqlx::query_scalar(r#"SELECT MAX(`version`) FROM `schema_version`"#)
+      .fetch_one(&mut **tx)
+      .await?;
+    Ok(version.unwrap_or(1))
   }
 
+  /// Round trips a trivial statement, to tell whether the database can be reached.
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn ping(&self) -> Result<(), sqlx::Error> {
+    query("SELECT 1").execute(&self.conn).await?;
+    Ok(())
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
   pub async fn migrate_v1(&self) -> Result<(), sqlx::Error> {
     let mut tx = self.conn.begin().await?;
 
@@ -119,4 +317,591 @@ impl Db {
 
     tx.commit().await
   }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn migrate_v2(&self) -> Result<(), sqlx::Error> {
+    let mut tx = self.conn.begin().await?;
+
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `series` (
+          `id` BIGINT UNSIGNED PRIMARY KEY NOT NULL AUTO_INCREMENT,
+          `name` TEXT NOT NULL,
+          `description` TEXT,
+          `date_added` TIMESTAMP DEFAULT NOW(),
+          `date_last_updated` TIMESTAMP ON UPDATE NOW()
+        );
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(
+      r#"
+        ALTER TABLE `book`
+          ADD COLUMN `series_id` BIGINT UNSIGNED AFTER `publisher_id`,
+          ADD COLUMN `series_index` SMALLINT UNSIGNED AFTER `series_id`,
+          ADD CONSTRAINT `fk_series_id` FOREIGN KEY (`series_id`) REFERENCES `series`(`id`);
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `book_author` (
+          `book_id` BIGINT UNSIGNED NOT NULL,
+          `author_id` BIGINT UNSIGNED NOT NULL,
+          PRIMARY KEY (`book_id`, `author_id`),
+          CONSTRAINT `fk_book_author_book_id` FOREIGN KEY (`book_id`) REFERENCES `book`(`id`),
+          CONSTRAINT `fk_book_author_author_id` FOREIGN KEY (`author_id`) REFERENCES `author`(`id`)
+        );
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(r#"INSERT INTO `schema_version` (`version`) VALUES (2)"#).execute(&mut *tx).await?;
+
+    tx.commit().await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn migrate_v3(&self) -> Result<(), sqlx::Error> {
+    let mut tx = self.conn.begin().await?;
+
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `koreader_user` (
+          `user_id` TINYINT UNSIGNED PRIMARY KEY NOT NULL,
+          `username` VARCHAR(255) NOT NULL UNIQUE,
+          `userkey` CHAR(64) NOT NULL,
+          `date_added` TIMESTAMP DEFAULT NOW(),
+          CONSTRAINT `fk_koreader_user_user_id` FOREIGN KEY (`user_id`) REFERENCES `user`(`id`)
+        );
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `koreader_document` (
+          `document` CHAR(32) PRIMARY KEY NOT NULL,
+          `book_id` BIGINT UNSIGNED NOT NULL,
+          `date_added` TIMESTAMP DEFAULT NOW(),
+          CONSTRAINT `fk_koreader_document_book_id` FOREIGN KEY (`book_id`) REFERENCES `book`(`id`)
+        );
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `koreader_progress` (
+          `user_id` TINYINT UNSIGNED NOT NULL,
+          `document` CHAR(32) NOT NULL,
+          `progress` TEXT NOT NULL,
+          `percentage` DOUBLE NOT NULL,
+          `device` TEXT NOT NULL,
+          `device_id` TEXT NOT NULL,
+          `date_last_updated` TIMESTAMP DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+          PRIMARY KEY (`user_id`, `document`),
+          CONSTRAINT `fk_koreader_progress_user_id` FOREIGN KEY (`user_id`) REFERENCES `user`(`id`)
+        );
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(r#"INSERT INTO `schema_version` (`version`) VALUES (3)"#).execute(&mut *tx).await?;
+
+    tx.commit().await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn migrate_v4(&self) -> Result<(), sqlx::Error> {
+    let mut tx = self.conn.begin().await?;
+
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `book_file` (
+          `id` BIGINT UNSIGNED PRIMARY KEY NOT NULL AUTO_INCREMENT,
+          `book_id` BIGINT UNSIGNED NOT NULL,
+          `sha256` CHAR(64) NOT NULL,
+          `format` VARCHAR(8) NOT NULL,
+          `mime` VARCHAR(64) NOT NULL,
+          `size` BIGINT UNSIGNED NOT NULL,
+          `filename` TEXT,
+          `date_added` TIMESTAMP DEFAULT NOW(),
+          UNIQUE KEY `uq_book_file` (`book_id`, `sha256`),
+          INDEX `idx_book_file_sha256` (`sha256`),
+          CONSTRAINT `fk_book_file_book_id` FOREIGN KEY (`book_id`) REFERENCES `book`(`id`)
+        );
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(r#"INSERT INTO `schema_version` (`version`) VALUES (4)"#).execute(&mut *tx).await?;
+
+    tx.commit().await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn migrate_v5(&self) -> Result<(), sqlx::Error> {
+    let mut tx = self.conn.begin().await?;
+
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `book_cover` (
+          `book_id` BIGINT UNSIGNED PRIMARY KEY NOT NULL,
+          `sha256` CHAR(64) NOT NULL,
+          `mime` VARCHAR(32) NOT NULL,
+          `width` INT UNSIGNED NOT NULL,
+          `height` INT UNSIGNED NOT NULL,
+          `dominant_colour` CHAR(7) NOT NULL,
+          `source` VARCHAR(16) NOT NULL,
+          `date_added` TIMESTAMP DEFAULT NOW(),
+          CONSTRAINT `fk_book_cover_book_id` FOREIGN KEY (`book_id`) REFERENCES `book`(`id`)
+        );
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `book_cover_thumbnail` (
+          `book_id` BIGINT UNSIGNED NOT NULL,
+          `size` INT UNSIGNED NOT NULL,
+          `format` VARCHAR(8) NOT NULL,
+          `sha256` CHAR(64) NOT NULL,
+          `width` INT UNSIGNED NOT NULL,
+          `height` INT UNSIGNED NOT NULL,
+          PRIMARY KEY (`book_id`, `size`, `format`),
+          CONSTRAINT `fk_book_cover_thumbnail_book_id` FOREIGN KEY (`book_id`) REFERENCES `book_cover`(`book_id`)
+        );
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(r#"INSERT INTO `schema_version` (`version`) VALUES (5)"#).execute(&mut *tx).await?;
+
+    tx.commit().await
+  }
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn migrate_v6(&self) -> Result<(), sqlx::Error> {
+    let mut tx = self.conn.begin().await?;
+
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `shelf` (
+          `id` BIGINT UNSIGNED PRIMARY KEY NOT NULL AUTO_INCREMENT,
+          `user_id` TINYINT UNSIGNED NOT NULL,
+          `name` VARCHAR(255) NOT NULL,
+          `date_added` TIMESTAMP DEFAULT NOW(),
+          UNIQUE KEY `uq_shelf_user_name` (`user_id`, `name`),
+          CONSTRAINT `fk_shelf_user_id` FOREIGN KEY (`user_id`) REFERENCES `user`(`id`)
+        );
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `shelf_book` (
+          `shelf_id` BIGINT UNSIGNED NOT NULL,
+          `book_id` BIGINT UNSIGNED NOT NULL,
+          `date_added` TIMESTAMP DEFAULT NOW(),
+          PRIMARY KEY (`shelf_id`, `book_id`),
+          CONSTRAINT `fk_shelf_book_shelf_id` FOREIGN KEY (`shelf_id`) REFERENCES `shelf`(`id`),
+          CONSTRAINT `fk_shelf_book_book_id` FOREIGN KEY (`book_id`) REFERENCES `book`(`id`)
+        );
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(
+      r#"
+        ALTER TABLE `progress`
+          ADD COLUMN `rating` TINYINT UNSIGNED,
+          ADD COLUMN `date_started` TIMESTAMP NULL,
+          ADD COLUMN `date_finished` TIMESTAMP NULL;
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(r#"INSERT INTO `schema_version` (`version`) VALUES (6)"#).execute(&mut *tx).await?;
+
+    tx.commit().await
+  }
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn migrate_v7(&self) -> Result<(), sqlx::Error> {
+    let mut tx = self.conn.begin().await?;
+
+    query(r#"ALTER TABLE `author` ADD COLUMN `sort_name` TEXT"#).execute(&mut *tx).await?;
+
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `tag` (
+          `id` BIGINT UNSIGNED PRIMARY KEY NOT NULL AUTO_INCREMENT,
+          `name` VARCHAR(255) NOT NULL UNIQUE
+        );
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `book_tag` (
+          `book_id` BIGINT UNSIGNED NOT NULL,
+          `tag_id` BIGINT UNSIGNED NOT NULL,
+          PRIMARY KEY (`book_id`, `tag_id`),
+          CONSTRAINT `fk_book_tag_book_id` FOREIGN KEY (`book_id`) REFERENCES `book`(`id`),
+          CONSTRAINT `fk_book_tag_tag_id` FOREIGN KEY (`tag_id`) REFERENCES `tag`(`id`)
+        );
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `book_identifier` (
+          `book_id` BIGINT UNSIGNED NOT NULL,
+          `scheme` VARCHAR(32) NOT NULL,
+          `value` VARCHAR(255) NOT NULL,
+          PRIMARY KEY (`book_id`, `scheme`),
+          CONSTRAINT `fk_book_identifier_book_id` FOREIGN KEY (`book_id`) REFERENCES `book`(`id`)
+        );
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `calibre_book` (
+          `uuid` CHAR(36) PRIMARY KEY NOT NULL,
+          `book_id` BIGINT UNSIGNED NOT NULL,
+          `last_modified` VARCHAR(64) NOT NULL,
+          `date_synced` TIMESTAMP DEFAULT NOW() ON UPDATE NOW(),
+          CONSTRAINT `fk_calibre_book_book_id` FOREIGN KEY (`book_id`) REFERENCES `book`(`id`)
+        );
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(r#"INSERT INTO `schema_version` (`version`) VALUES (7)"#).execute(&mut *tx).await?;
+
+    tx.commit().await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn migrate_v8(&self) -> Result<(), sqlx::Error> {
+    let mut tx = self.conn.begin().await?;
+
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `audit_log` (
+          `id` BIGINT UNSIGNED PRIMARY KEY NOT NULL AUTO_INCREMENT,
+          `actor` VARCHAR(255),
+          `entity` VARCHAR(64) NOT NULL,
+          `entity_id` VARCHAR(255) NOT NULL,
+          `operation` VARCHAR(8) NOT NULL,
+          `before` JSON,
+          `after` JSON,
+          `date_added` TIMESTAMP(6) DEFAULT NOW(6),
+          INDEX `idx_audit_log_entity` (`entity`, `entity_id`),
+          INDEX `idx_audit_log_actor` (`actor`),
+          INDEX `idx_audit_log_date_added` (`date_added`)
+        );
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    audit::install_triggers(&mut tx).await?;
+
+    query(r#"INSERT INTO `schema_version` (`version`) VALUES (8)"#).execute(&mut *tx).await?;
+
+    tx.commit().await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn migrate_v9(&self) -> Result<(), sqlx::Error> {
+    let mut tx = self.conn.begin().await?;
+
+    query(
+      r#"
+        ALTER TABLE `book`
+          ADD COLUMN `deleted_at` TIMESTAMP NULL,
+          ADD INDEX `idx_book_deleted_at` (`deleted_at`);
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(
+      r#"
+        ALTER TABLE `author`
+          ADD COLUMN `deleted_at` TIMESTAMP NULL,
+          ADD INDEX `idx_author_deleted_at` (`deleted_at`);
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    audit::install_triggers(&mut tx).await?;
+
+    query(r#"INSERT INTO `schema_version` (`version`) VALUES (9)"#).execute(&mut *tx).await?;
+
+    tx.commit().await
+  }
+
+  /// Catalog records carry a version, bumped by every write, so clients can make conditional updates.
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn migrate_v10(&self) -> Result<(), sqlx::Error> {
+    let mut tx = self.conn.begin().await?;
+
+    for table in ["book", "author", "publisher", "series"] {
+      query(&format!("ALTER TABLE `{}` ADD COLUMN `version` INT UNSIGNED NOT NULL DEFAULT 1", table))
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    audit::install_triggers(&mut tx).await?;
+
+    query(r#"INSERT INTO `schema_version` (`version`) VALUES (10)"#).execute(&mut *tx).await?;
+
+    tx.commit().await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn migrate_v11(&self) -> Result<(), sqlx::Error> {
+    let mut tx = self.conn.begin().await?;
+
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `change_event` (
+          `id` BIGINT UNSIGNED PRIMARY KEY NOT NULL AUTO_INCREMENT,
+          `entity` VARCHAR(64) NOT NULL,
+          `entity_id` BIGINT UNSIGNED NOT NULL,
+          `operation` VARCHAR(8) NOT NULL,
+          `date_added` TIMESTAMP(6) DEFAULT NOW(6),
+          INDEX `idx_change_event_date_added` (`date_added`)
+        );
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(r#"INSERT INTO `schema_version` (`version`) VALUES (11)"#).execute(&mut *tx).await?;
+
+    tx.commit().await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn migrate_v12(&self) -> Result<(), sqlx::Error> {
+    let mut tx = self.conn.begin().await?;
+
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `webhook` (
+          `id` BIGINT UNSIGNED PRIMARY KEY NOT NULL AUTO_INCREMENT,
+          `url` TEXT NOT NULL,
+          `secret` VARCHAR(255) NOT NULL,
+          `events` VARCHAR(255) NOT NULL,
+          `enabled` BOOLEAN NOT NULL DEFAULT TRUE,
+          `failures` INT UNSIGNED NOT NULL DEFAULT 0,
+          `date_added` TIMESTAMP DEFAULT NOW(),
+          `date_last_updated` TIMESTAMP ON UPDATE NOW()
+        );
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `webhook_delivery` (
+          `id` BIGINT UNSIGNED PRIMARY KEY NOT NULL AUTO_INCREMENT,
+          `webhook_id` BIGINT UNSIGNED NOT NULL,
+          `event` VARCHAR(64) NOT NULL,
+          `payload` MEDIUMTEXT NOT NULL,
+          `status` VARCHAR(16) NOT NULL DEFAULT 'pending',
+          `attempts` INT UNSIGNED NOT NULL DEFAULT 0,
+          `response_status` SMALLINT UNSIGNED,
+          `error` TEXT,
+          `next_attempt_at` TIMESTAMP NULL DEFAULT NOW(),
+          `date_added` TIMESTAMP DEFAULT NOW(),
+          `date_delivered` TIMESTAMP NULL,
+          INDEX `idx_webhook_delivery_due` (`status`, `next_attempt_at`),
+          CONSTRAINT `fk_webhook_delivery_webhook_id` FOREIGN KEY (`webhook_id`) REFERENCES `webhook`(`id`)
+        );
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(r#"INSERT INTO `schema_version` (`version`) VALUES (12)"#).execute(&mut *tx).await?;
+
+    tx.commit().await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn migrate_v13(&self) -> Result<(), sqlx::Error> {
+    let mut tx = self.conn.begin().await?;
+
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `job` (
+          `id` BIGINT UNSIGNED PRIMARY KEY NOT NULL AUTO_INCREMENT,
+          `kind` VARCHAR(64) NOT NULL,
+          `payload` MEDIUMTEXT NOT NULL,
+          `status` VARCHAR(16) NOT NULL DEFAULT 'pending',
+          `attempts` INT UNSIGNED NOT NULL DEFAULT 0,
+          `max_attempts` INT UNSIGNED NOT NULL,
+          `result` MEDIUMTEXT,
+          `error` TEXT,
+          `run_at` TIMESTAMP NULL DEFAULT NOW(),
+          `date_added` TIMESTAMP DEFAULT NOW(),
+          `date_started` TIMESTAMP NULL,
+          `date_finished` TIMESTAMP NULL,
+          INDEX `idx_job_due` (`status`, `run_at`),
+          INDEX `idx_job_kind` (`kind`)
+        );
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(r#"INSERT INTO `schema_version` (`version`) VALUES (13)"#).execute(&mut *tx).await?;
+
+    tx.commit().await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn migrate_v14(&self) -> Result<(), sqlx::Error> {
+    let mut tx = self.conn.begin().await?;
+
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `scheduled_task` (
+          `name` VARCHAR(64) PRIMARY KEY NOT NULL,
+          `last_run_at` TIMESTAMP NOT NULL,
+          `last_job_id` BIGINT UNSIGNED,
+          `date_added` TIMESTAMP DEFAULT NOW()
+        );
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `daily_stats` (
+          `date` DATE PRIMARY KEY NOT NULL,
+          `books` INT UNSIGNED NOT NULL,
+          `users` INT UNSIGNED NOT NULL,
+          `active_readers` INT UNSIGNED NOT NULL,
+          `books_finished` INT UNSIGNED NOT NULL,
+          `date_last_updated` TIMESTAMP DEFAULT NOW() ON UPDATE NOW()
+        );
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(r#"INSERT INTO `schema_version` (`version`) VALUES (14)"#).execute(&mut *tx).await?;
+
+    tx.commit().await
+  }
+
+  /// Jobs record which claim is running them, so only that run can record how it went.
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn migrate_v15(&self) -> Result<(), sqlx::Error> {
+    let mut tx = self.conn.begin().await?;
+
+    query(r#"ALTER TABLE `job` ADD COLUMN `claim` CHAR(32) NULL"#).execute(&mut *tx).await?;
+
+    query(r#"INSERT INTO `schema_version` (`version`) VALUES (15)"#).execute(&mut *tx).await?;
+
+    tx.commit().await
+  }
+
+  /// Change events are given their feed offset once committed, from a single counter row. Existing events keep their
+  /// ids as offsets, so consumers resume where they were; the counter row is only written when there are any, so a
+  /// fresh database stays empty for restores.
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn migrate_v16(&self) -> Result<(), sqlx::Error> {
+    let mut tx = self.conn.begin().await?;
+
+    query(r#"ALTER TABLE `change_event` ADD COLUMN `seq` BIGINT UNSIGNED NULL, ADD UNIQUE INDEX `uq_change_event_seq` (`seq`)"#)
+      .execute(&mut *tx)
+      .await?;
+    query(r#"UPDATE `change_event` SET `seq` = `id`"#).execute(&mut *tx).await?;
+    query(
+      r#"
+        CREATE TABLE IF NOT EXISTS `change_sequence` (
+          `id` TINYINT UNSIGNED PRIMARY KEY NOT NULL,
+          `value` BIGINT UNSIGNED NOT NULL
+        );
+      "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+    query(r#"INSERT INTO `change_sequence` (`id`, `value`) SELECT 1, MAX(`id`) FROM `change_event` HAVING COUNT(*) > 0"#)
+      .execute(&mut *tx)
+      .await?;
+
+    query(r#"INSERT INTO `schema_version` (`version`) VALUES (16)"#).execute(&mut *tx).await?;
+
+    tx.commit().await
+  }
+
+  /// ISBNs are stored normalised, as [`normalize_isbn`](books::normalize_isbn) writes them, so lookups can match them
+  /// exactly.
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn migrate_v17(&self) -> Result<(), sqlx::Error> {
+    let mut tx = self.conn.begin().await?;
+
+    query(
+      r#"UPDATE `book`
+      SET `isbn` = REGEXP_REPLACE(UPPER(`isbn`), '[^0-9X]', ''), `version` = `version` + 1
+      WHERE `isbn` REGEXP '[^0-9X]'"#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query(r#"INSERT INTO `schema_version` (`version`) VALUES (17)"#).execute(&mut *tx).await?;
+
+    tx.commit().await
+  }
+
+  /// Users and progress carry a version too, since a reader's devices update them concurrently.
+  #[instrument(level = "debug", skip_all, fields(entity = "schema"))]
+  pub async fn migrate_v18(&self) -> Result<(), sqlx::Error> {
+    let mut tx = self.conn.begin().await?;
+
+    for table in ["user", "progress"] {
+      query(&format!("ALTER TABLE `{}` ADD COLUMN `version` INT UNSIGNED NOT NULL DEFAULT 1", table))
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    audit::install_triggers(&mut tx).await?;
+
+    query(r#"INSERT INTO `schema_version` (`version`) VALUES (18)"#).execute(&mut *tx).await?;
+
+    tx.commit().await
+  }
 }
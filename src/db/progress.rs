@@ -1,5 +1,9 @@
 use chrono::{DateTime, Utc};
+use serde_json::json;
 use sqlx::{mysql::MySqlQueryResult, query, query_as, FromRow, MySql, Transaction};
+use tracing::instrument;
+
+use super::{books::Book, changes::ChangeEvent, placeholders, webhooks::WebhookDelivery, UpdateError};
 
 #[derive(Debug, Clone, FromRow, PartialEq, Eq)]
 pub struct Progress {
@@ -7,11 +11,18 @@ pub struct Progress {
   pub user_id: u8,
   pub book_id: u64,
   pub current_page: u16,
+  /// Out of 10, so half stars survive imports.
+  pub rating: Option<u8>,
+  pub date_started: Option<DateTime<Utc>>,
+  pub date_finished: Option<DateTime<Utc>>,
   pub date_added: Option<DateTime<Utc>>,
   pub date_last_updated: Option<DateTime<Utc>>,
+  /// Bumped by every update, and checked by [`Progress::update_if`].
+  pub version: u32,
 }
 
 impl Progress {
+  #[instrument(level = "debug", skip_all, fields(entity = "progress", user_id = user_id))]
   pub async fn fetch_one<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8, book_id: u64) -> Result<Progress, sqlx::Error> {
     query_as::<MySql, Progress>(
       r#"SELECT * FROM `progress`
@@ -23,10 +34,66 @@ impl Progress {
     .await
   }
 
+  /// The user's progress on whichever of `book_ids` they have started.
+  #[instrument(level = "debug", skip_all, fields(entity = "progress", user_id = user_id))]
+  pub async fn fetch_many<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8, book_ids: &[u64]) -> Result<Vec<Progress>, sqlx::Error> {
+    if book_ids.is_empty() {
+      return Ok(Vec::new());
+    }
+    let sql = format!(
+      "SELECT * FROM `progress` WHERE `user_id` = ? AND `book_id` IN ({})",
+      placeholders(book_ids.len())
+    );
+    let mut progress = query_as::<MySql, Progress>(&sql).bind(user_id);
+    for book_id in book_ids {
+      progress = progress.bind(book_id);
+    }
+    progress.fetch_all(&mut **tx).await
+  }
+
+  /// Everything each of `user_ids` has started.
+  #[instrument(level = "debug", skip_all, fields(entity = "progress"))]
+  pub async fn fetch_by_users<'a>(tx: &mut Transaction<'a, MySql>, user_ids: &[u8]) -> Result<Vec<Progress>, sqlx::Error> {
+    if user_ids.is_empty() {
+      return Ok(Vec::new());
+    }
+    let sql = format!("SELECT * FROM `progress` WHERE `user_id` IN ({})", placeholders(user_ids.len()));
+    let mut progress = query_as::<MySql, Progress>(&sql);
+    for user_id in user_ids {
+      progress = progress.bind(user_id);
+    }
+    progress.fetch_all(&mut **tx).await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "progress", user_id = user_id))]
+  pub async fn fetch_by_user<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8) -> Result<Vec<Progress>, sqlx::Error> {
+    query_as::<MySql, Progress>(
+      r#"SELECT * FROM `progress`
+      WHERE `user_id`= ?"#,
+    )
+    .bind(user_id)
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "progress"))]
   pub async fn fetch_all<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Vec<Progress>, sqlx::Error> {
     query_as::<MySql, Progress>(r#"SELECT * FROM `progress`"#).fetch_all(&mut **tx).await
   }
 
+  /// Users who have moved their place in any book since `since`.
+  #[instrument(level = "debug", skip_all, fields(entity = "progress"))]
+  pub async fn count_readers_since<'a>(tx: &mut Transaction<'a, MySql>, since: DateTime<Utc>) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar(
+      r#"SELECT COUNT(DISTINCT `user_id`) FROM `progress`
+      WHERE `date_last_updated` >= ?"#,
+    )
+    .bind(since)
+    .fetch_one(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "progress"))]
   pub async fn fetch_last<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Progress, sqlx::Error> {
     query_as::<MySql, Progress>(
       r#"SELECT * FROM `progress`
@@ -36,41 +103,143 @@ impl Progress {
     .await
   }
 
+  #[instrument(level = "debug", skip_all, fields(entity = "progress", user_id = user_id))]
   pub async fn create<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8, book_id: u64, current_page: u16) -> Result<Progress, sqlx::Error> {
     query(
       r#"INSERT INTO `progress` (`user_id`, `book_id`, `current_page`)
       VALUES (?, ?, ?)"#,
     )
-    .bind(current_page)
     .bind(user_id)
     .bind(book_id)
-    .fetch_one(&mut **tx)
+    .bind(current_page)
+    .execute(&mut **tx)
     .await?;
 
-    Progress::fetch_last(tx).await
+    let progress = Progress::fetch_last(tx).await?;
+    ChangeEvent::record(tx, "progress", progress.id, "create").await?;
+    Ok(progress)
   }
 
+  #[instrument(level = "debug", skip_all, fields(entity = "progress", user_id = user_id))]
   pub async fn update<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8, book_id: u64, current_page: u16) -> Result<Progress, sqlx::Error> {
-    query_as::<MySql, Progress>(
+    query(
       r#"UPDATE `progress`
-      SET `current_page` = ?
+      SET `current_page` = ?, `version` = `version` + 1
       WHERE `user_id`= ? AND `book_id` = ?"#,
     )
     .bind(current_page)
     .bind(user_id)
     .bind(book_id)
+    .execute(&mut **tx)
+    .await?;
+
+    let progress = Progress::fetch_one(tx, user_id, book_id).await?;
+    ChangeEvent::record(tx, "progress", progress.id, "update").await?;
+    Ok(progress)
+  }
+
+  /// Fails with [`UpdateError::Conflict`] unless the user's progress on the book is at `version`, and locks it until the
+  /// transaction ends so the write that follows cannot interleave with another.
+  #[instrument(level = "debug", skip_all, fields(entity = "progress", user_id = user_id))]
+  pub async fn expect_version<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8, book_id: u64, version: u32) -> Result<(), UpdateError> {
+    let current: u32 = sqlx::query_scalar(
+      r#"SELECT `version` FROM `progress`
+      WHERE `user_id`= ? AND `book_id` = ?
+      FOR UPDATE"#,
+    )
+    .bind(user_id)
+    .bind(book_id)
     .fetch_one(&mut **tx)
-    .await
+    .await?;
+    if current != version {
+      return Err(UpdateError::Conflict(current));
+    }
+    Ok(())
   }
 
-  pub async fn delete<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8, book_id: u64) -> Result<MySqlQueryResult, sqlx::Error> {
+  /// [`Progress::update`] for a client moving a place it read earlier: fails with [`UpdateError::Conflict`] if the
+  /// progress is no longer at `version`.
+  #[instrument(level = "debug", skip_all, fields(entity = "progress", user_id = user_id))]
+  pub async fn update_if<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8, book_id: u64, version: u32, current_page: u16) -> Result<Progress, UpdateError> {
+    Progress::expect_version(tx, user_id, book_id, version).await?;
+    Ok(Progress::update(tx, user_id, book_id, current_page).await?)
+  }
+
+  /// Updates the user's place in a book, creating the progress row on first read.
+  #[instrument(level = "debug", skip_all, fields(entity = "progress", user_id = user_id))]
+  pub async fn upsert<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8, book_id: u64, current_page: u16) -> Result<Progress, sqlx::Error> {
+    match Progress::fetch_one(tx, user_id, book_id).await {
+      Ok(_) => Progress::update(tx, user_id, book_id, current_page).await,
+      Err(sqlx::Error::RowNotFound) => Progress::create(tx, user_id, book_id, current_page).await,
+      Err(err) => Err(err),
+    }
+  }
+
+  /// Moves the user's place in a book they are reading, creating the progress row on first read. Reaching its last page
+  /// finishes the book, which fires `book.finished` the first time.
+  #[instrument(level = "debug", skip_all, fields(entity = "progress", user_id = user_id))]
+  pub async fn read_to<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8, book: &Book, current_page: u16) -> Result<Progress, sqlx::Error> {
+    let progress = Progress::upsert(tx, user_id, book.id, current_page).await?;
+    if progress.date_finished.is_some() || book.num_pages == 0 || current_page < book.num_pages {
+      return Ok(progress);
+    }
+
     query(
-      r#"DELETE FROM `progress`
+      r#"UPDATE `progress`
+      SET `date_finished` = NOW(), `version` = `version` + 1
+      WHERE `user_id`= ? AND `book_id` = ?"#,
+    )
+    .bind(user_id)
+    .bind(book.id)
+    .execute(&mut **tx)
+    .await?;
+    let progress = Progress::fetch_one(tx, user_id, book.id).await?;
+    ChangeEvent::record(tx, "progress", progress.id, "update").await?;
+    let payload = json!({ "user_id": user_id, "book_id": book.id, "rating": progress.rating, "date_finished": progress.date_finished });
+    WebhookDelivery::enqueue(tx, "book.finished", &payload).await?;
+    Ok(progress)
+  }
+
+  /// Records the user's rating and reading dates for a book, leaving their current page alone. This is history being
+  /// imported, so it fires no `book.finished`; only [`Progress::read_to`] does.
+  #[instrument(level = "debug", skip_all, fields(entity = "progress", user_id = user_id))]
+  pub async fn set_reading<'a>(
+    tx: &mut Transaction<'a, MySql>,
+    user_id: u8,
+    book_id: u64,
+    rating: Option<u8>,
+    date_started: Option<DateTime<Utc>>,
+    date_finished: Option<DateTime<Utc>>,
+  ) -> Result<Progress, sqlx::Error> {
+    query(
+      r#"UPDATE `progress`
+      SET `rating` = ?, `date_started` = ?, `date_finished` = ?, `version` = `version` + 1
       WHERE `user_id`= ? AND `book_id` = ?"#,
     )
+    .bind(rating)
+    .bind(date_started)
+    .bind(date_finished)
     .bind(user_id)
     .bind(book_id)
     .execute(&mut **tx)
-    .await
+    .await?;
+
+    let progress = Progress::fetch_one(tx, user_id, book_id).await?;
+    ChangeEvent::record(tx, "progress", progress.id, "update").await?;
+    Ok(progress)
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "progress", user_id = user_id))]
+  pub async fn delete<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8, book_id: u64) -> Result<MySqlQueryResult, sqlx::Error> {
+    let progress = Progress::fetch_one(tx, user_id, book_id).await?;
+    let result = query(
+      r#"DELETE FROM `progress`
+      WHERE `id`= ?"#,
+    )
+    .bind(progress.id)
+    .execute(&mut **tx)
+    .await?;
+    ChangeEvent::record(tx, "progress", progress.id, "delete").await?;
+    Ok(result)
   }
 }
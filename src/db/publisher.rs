@@ -1,21 +1,26 @@
 use chrono::{DateTime, Utc};
 use sqlx::{mysql::MySqlQueryResult, query, query_as, FromRow, MySql, Transaction};
+use tracing::instrument;
+
+use super::{changes::ChangeEvent, placeholders, UpdateError};
 
 #[derive(Debug, Clone, FromRow, PartialEq, Eq)]
 pub struct Publisher {
-  id: u16,
-  name: String,
-  description: String,
-  city: Option<String>,
+  pub id: u16,
+  pub name: String,
+  pub description: String,
+  pub city: Option<String>,
   pub date_added: Option<DateTime<Utc>>,
   pub date_last_updated: Option<DateTime<Utc>>,
+  /// Bumped by every update, and checked by [`Publisher::update_if`].
+  pub version: u32,
 }
 
 #[derive(Debug, Clone, FromRow, PartialEq, Eq)]
 pub struct PartialPublisher {
-  name: Option<String>,
-  description: Option<String>,
-  city: Option<String>,
+  pub name: Option<String>,
+  pub description: Option<String>,
+  pub city: Option<String>,
 }
 
 impl Publisher {
@@ -30,6 +35,7 @@ impl Publisher {
     self
   }
 
+  #[instrument(level = "debug", skip_all, fields(entity = "publisher", id = publisher_id))]
   pub async fn fetch_one<'a>(tx: &mut Transaction<'a, MySql>, publisher_id: u16) -> Result<Publisher, sqlx::Error> {
     query_as::<MySql, Publisher>(
       r#"SELECT * FROM `publisher`
@@ -40,6 +46,38 @@ impl Publisher {
     .await
   }
 
+  #[instrument(level = "debug", skip_all, fields(entity = "publisher"))]
+  pub async fn fetch_all<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Vec<Publisher>, sqlx::Error> {
+    query_as::<MySql, Publisher>(r#"SELECT * FROM `publisher`"#).fetch_all(&mut **tx).await
+  }
+
+  /// The publisher rows among `ids` that exist, in no particular order.
+  #[instrument(level = "debug", skip_all, fields(entity = "publisher"))]
+  pub async fn fetch_many<'a>(tx: &mut Transaction<'a, MySql>, ids: &[u16]) -> Result<Vec<Publisher>, sqlx::Error> {
+    if ids.is_empty() {
+      return Ok(Vec::new());
+    }
+    let sql = format!("SELECT * FROM `publisher` WHERE `id` IN ({})", placeholders(ids.len()));
+    let mut rows = query_as::<MySql, Publisher>(&sql);
+    for id in ids {
+      rows = rows.bind(id);
+    }
+    rows.fetch_all(&mut **tx).await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "publisher"))]
+  pub async fn fetch_by_name<'a>(tx: &mut Transaction<'a, MySql>, name: &str) -> Result<Option<Publisher>, sqlx::Error> {
+    query_as::<MySql, Publisher>(
+      r#"SELECT * FROM `publisher`
+      WHERE `name`= ?
+      LIMIT 1"#,
+    )
+    .bind(name)
+    .fetch_optional(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "publisher"))]
   pub async fn fetch_last<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Publisher, sqlx::Error> {
     query_as::<MySql, Publisher>(
       r#"SELECT * FROM `publisher`
@@ -49,6 +87,7 @@ impl Publisher {
     .await
   }
 
+  #[instrument(level = "debug", skip_all, fields(entity = "publisher"))]
   pub async fn create<'a>(tx: &mut Transaction<'a, MySql>, name: String, description: String, city: Option<String>) -> Result<Publisher, sqlx::Error> {
     query(
       r#"INSERT INTO `publisher` (`name`, `description`, `city`)
@@ -57,35 +96,75 @@ impl Publisher {
     .bind(name)
     .bind(description)
     .bind(city)
-    .fetch_one(&mut **tx)
+    .execute(&mut **tx)
     .await?;
 
-    Publisher::fetch_last(tx).await
+    let publisher = Publisher::fetch_last(tx).await?;
+    ChangeEvent::record(tx, "publisher", publisher.id.into(), "create").await?;
+    Ok(publisher)
   }
 
-  pub async fn update<'a>(tx: &mut Transaction<'a, MySql>, id: u16, partial: PartialPublisher) -> Result<Publisher, sqlx::Error> {
-    let old_publisher = Publisher::fetch_one(tx, id).await?;
-    let updated_publisher = old_publisher.merge(partial);
-
+  /// Reads the publisher and locks its row until the transaction ends, so a read-merge-write cannot interleave with another.
+  #[instrument(level = "debug", skip_all, fields(entity = "publisher", id = id))]
+  async fn fetch_locked<'a>(tx: &mut Transaction<'a, MySql>, id: u16) -> Result<Publisher, sqlx::Error> {
     query_as::<MySql, Publisher>(
-      r#"UPDATE `publisher`
-      SET `name` = ?, `description` = ?, `city` = ?
-      WHERE `id` = ?"#,
+      r#"SELECT * FROM `publisher`
+      WHERE `id`= ?
+      FOR UPDATE"#,
     )
-    .bind(updated_publisher.name)
-    .bind(updated_publisher.description)
-    .bind(updated_publisher.city)
+    .bind(id)
     .fetch_one(&mut **tx)
     .await
   }
 
-  pub async fn delete<'a>(tx: &mut Transaction<'a, MySql>, id: u16) -> Result<MySqlQueryResult, sqlx::Error> {
+  #[instrument(level = "debug", skip_all, fields(entity = "publisher", id = id))]
+  pub async fn update<'a>(tx: &mut Transaction<'a, MySql>, id: u16, partial: PartialPublisher) -> Result<Publisher, sqlx::Error> {
+    let old_publisher = Publisher::fetch_locked(tx, id).await?;
+    Publisher::write(tx, old_publisher.merge(partial)).await
+  }
+
+  /// [`Publisher::update`] for a client editing what it read earlier: fails with [`UpdateError::Conflict`] if the publisher is no
+  /// longer at `version`.
+  #[instrument(level = "debug", skip_all, fields(entity = "publisher", id = id))]
+  pub async fn update_if<'a>(tx: &mut Transaction<'a, MySql>, id: u16, version: u32, partial: PartialPublisher) -> Result<Publisher, UpdateError> {
+    let old_publisher = Publisher::fetch_locked(tx, id).await?;
+    if old_publisher.version != version {
+      return Err(UpdateError::Conflict(old_publisher.version));
+    }
+    Ok(Publisher::write(tx, old_publisher.merge(partial)).await?)
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "publisher"))]
+  async fn write<'a>(tx: &mut Transaction<'a, MySql>, publisher: Publisher) -> Result<Publisher, sqlx::Error> {
     query(
+      r#"UPDATE `publisher`
+      SET `name` = ?, `description` = ?, `city` = ?, `version` = `version` + 1
+      WHERE `id` = ? AND `version` = ?"#,
+    )
+    .bind(publisher.name)
+    .bind(publisher.description)
+    .bind(publisher.city)
+    .bind(publisher.id)
+    .bind(publisher.version)
+    .execute(&mut **tx)
+    .await?;
+    ChangeEvent::record(tx, "publisher", publisher.id.into(), "update").await?;
+
+    Publisher::fetch_one(tx, publisher.id).await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "publisher", id = id))]
+  pub async fn delete<'a>(tx: &mut Transaction<'a, MySql>, id: u16) -> Result<MySqlQueryResult, sqlx::Error> {
+    let result = query(
       r#"DELETE FROM `publisher`
       WHERE `id` = ?"#,
     )
     .bind(id)
     .execute(&mut **tx)
-    .await
+    .await?;
+    if result.rows_affected() > 0 {
+      ChangeEvent::record(tx, "publisher", id.into(), "delete").await?;
+    }
+    Ok(result)
   }
 }
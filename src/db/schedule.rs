@@ -0,0 +1,77 @@
+use chrono::{DateTime, Utc};
+use sqlx::{mysql::MySqlQueryResult, query, query_as, FromRow, MySql, Transaction};
+use tracing::instrument;
+
+/// The last run of a recurring task, shared by every server so that each run happens once.
+#[derive(Debug, Clone, FromRow, PartialEq, Eq)]
+pub struct ScheduledTask {
+  pub name: String,
+  /// The scheduled time of the last run claimed, not when it actually ran.
+  pub last_run_at: DateTime<Utc>,
+  pub last_job_id: Option<u64>,
+  pub date_added: Option<DateTime<Utc>>,
+}
+
+impl ScheduledTask {
+  #[instrument(level = "debug", skip_all, fields(entity = "scheduled_task", name = name))]
+  pub async fn fetch_one<'a>(tx: &mut Transaction<'a, MySql>, name: &str) -> Result<Option<ScheduledTask>, sqlx::Error> {
+    query_as::<MySql, ScheduledTask>(
+      r#"SELECT * FROM `scheduled_task`
+      WHERE `name` = ?"#,
+    )
+    .bind(name)
+    .fetch_optional(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "scheduled_task"))]
+  pub async fn fetch_all<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Vec<ScheduledTask>, sqlx::Error> {
+    query_as::<MySql, ScheduledTask>(r#"SELECT * FROM `scheduled_task` ORDER BY `name`"#)
+      .fetch_all(&mut **tx)
+      .await
+  }
+
+  /// Starts tracking a task as if it last ran at `at`, so a newly scheduled task waits for its next time rather than
+  /// running at once. Does nothing if another server got there first.
+  #[instrument(level = "debug", skip_all, fields(entity = "scheduled_task", name = name))]
+  pub async fn register<'a>(tx: &mut Transaction<'a, MySql>, name: &str, at: DateTime<Utc>) -> Result<MySqlQueryResult, sqlx::Error> {
+    query(
+      r#"INSERT IGNORE INTO `scheduled_task` (`name`, `last_run_at`)
+      VALUES (?, ?)"#,
+    )
+    .bind(name)
+    .bind(at)
+    .execute(&mut **tx)
+    .await
+  }
+
+  /// Claims the run due at `run_at`, provided the last run is still the `previous` one read. Of several servers racing
+  /// for the same run only one sees this return true.
+  #[instrument(level = "debug", skip_all, fields(entity = "scheduled_task", name = name))]
+  pub async fn claim<'a>(tx: &mut Transaction<'a, MySql>, name: &str, previous: DateTime<Utc>, run_at: DateTime<Utc>) -> Result<bool, sqlx::Error> {
+    let claimed = query(
+      r#"UPDATE `scheduled_task`
+      SET `last_run_at` = ?
+      WHERE `name` = ? AND `last_run_at` = ?"#,
+    )
+    .bind(run_at)
+    .bind(name)
+    .bind(previous)
+    .execute(&mut **tx)
+    .await?;
+    Ok(claimed.rows_affected() == 1)
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "scheduled_task", name = name))]
+  pub async fn set_job<'a>(tx: &mut Transaction<'a, MySql>, name: &str, job_id: u64) -> Result<MySqlQueryResult, sqlx::Error> {
+    query(
+      r#"UPDATE `scheduled_task`
+      SET `last_job_id` = ?
+      WHERE `name` = ?"#,
+    )
+    .bind(job_id)
+    .bind(name)
+    .execute(&mut **tx)
+    .await
+  }
+}
@@ -0,0 +1,167 @@
+use chrono::{DateTime, Utc};
+use sqlx::{mysql::MySqlQueryResult, query, query_as, FromRow, MySql, Transaction};
+use tracing::instrument;
+
+use super::{changes::ChangeEvent, placeholders, UpdateError};
+
+pub type SeriesList = Vec<Series>;
+
+#[derive(Debug, Clone, FromRow, PartialEq, Eq)]
+pub struct Series {
+  pub id: u64,
+  pub name: String,
+  pub description: Option<String>,
+  pub date_added: Option<DateTime<Utc>>,
+  pub date_last_updated: Option<DateTime<Utc>>,
+  /// Bumped by every update, and checked by [`Series::update_if`].
+  pub version: u32,
+}
+
+#[derive(Debug, Clone, FromRow, PartialEq, Eq)]
+pub struct PartialSeries {
+  pub name: Option<String>,
+  pub description: Option<String>,
+}
+
+impl Series {
+  fn merge(mut self, partial: PartialSeries) -> Self {
+    if let Some(name) = partial.name {
+      self.name = name;
+    }
+    self.description = partial.description;
+    self
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "series", id = series_id))]
+  pub async fn fetch_one<'a>(tx: &mut Transaction<'a, MySql>, series_id: u64) -> Result<Series, sqlx::Error> {
+    query_as::<MySql, Series>(
+      r#"SELECT * FROM `series`
+      WHERE `id`= ?"#,
+    )
+    .bind(series_id)
+    .fetch_one(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "series"))]
+  pub async fn fetch_all<'a>(tx: &mut Transaction<'a, MySql>) -> Result<SeriesList, sqlx::Error> {
+    query_as::<MySql, Series>(r#"SELECT * FROM `series` ORDER BY `name`"#)
+      .fetch_all(&mut **tx)
+      .await
+  }
+
+  /// The series rows among `ids` that exist, in no particular order.
+  #[instrument(level = "debug", skip_all, fields(entity = "series"))]
+  pub async fn fetch_many<'a>(tx: &mut Transaction<'a, MySql>, ids: &[u64]) -> Result<SeriesList, sqlx::Error> {
+    if ids.is_empty() {
+      return Ok(Vec::new());
+    }
+    let sql = format!("SELECT * FROM `series` WHERE `id` IN ({})", placeholders(ids.len()));
+    let mut rows = query_as::<MySql, Series>(&sql);
+    for id in ids {
+      rows = rows.bind(id);
+    }
+    rows.fetch_all(&mut **tx).await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "series"))]
+  pub async fn fetch_by_name<'a>(tx: &mut Transaction<'a, MySql>, name: &str) -> Result<Option<Series>, sqlx::Error> {
+    query_as::<MySql, Series>(
+      r#"SELECT * FROM `series`
+      WHERE `name`= ?
+      LIMIT 1"#,
+    )
+    .bind(name)
+    .fetch_optional(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "series"))]
+  pub async fn fetch_last<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Series, sqlx::Error> {
+    query_as::<MySql, Series>(
+      r#"SELECT * FROM `series`
+      WHERE `id` = LAST_INSERT_ID();"#,
+    )
+    .fetch_one(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "series"))]
+  pub async fn create<'a>(tx: &mut Transaction<'a, MySql>, partial: PartialSeries) -> Result<Series, sqlx::Error> {
+    query(
+      r#"INSERT INTO `series` (`name`, `description`)
+      VALUES (?, ?)"#,
+    )
+    .bind(partial.name)
+    .bind(partial.description)
+    .execute(&mut **tx)
+    .await?;
+
+    let series = Series::fetch_last(tx).await?;
+    ChangeEvent::record(tx, "series", series.id, "create").await?;
+    Ok(series)
+  }
+
+  /// Reads the series and locks its row until the transaction ends, so a read-merge-write cannot interleave with another.
+  #[instrument(level = "debug", skip_all, fields(entity = "series", id = series_id))]
+  async fn fetch_locked<'a>(tx: &mut Transaction<'a, MySql>, series_id: u64) -> Result<Series, sqlx::Error> {
+    query_as::<MySql, Series>(
+      r#"SELECT * FROM `series`
+      WHERE `id`= ?
+      FOR UPDATE"#,
+    )
+    .bind(series_id)
+    .fetch_one(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "series", id = series_id))]
+  pub async fn update<'a>(tx: &mut Transaction<'a, MySql>, series_id: u64, partial: PartialSeries) -> Result<Series, sqlx::Error> {
+    let old_series = Series::fetch_locked(tx, series_id).await?;
+    Series::write(tx, old_series.merge(partial)).await
+  }
+
+  /// [`Series::update`] for a client editing what it read earlier: fails with [`UpdateError::Conflict`] if the series is no
+  /// longer at `version`.
+  #[instrument(level = "debug", skip_all, fields(entity = "series", id = series_id))]
+  pub async fn update_if<'a>(tx: &mut Transaction<'a, MySql>, series_id: u64, version: u32, partial: PartialSeries) -> Result<Series, UpdateError> {
+    let old_series = Series::fetch_locked(tx, series_id).await?;
+    if old_series.version != version {
+      return Err(UpdateError::Conflict(old_series.version));
+    }
+    Ok(Series::write(tx, old_series.merge(partial)).await?)
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "series"))]
+  async fn write<'a>(tx: &mut Transaction<'a, MySql>, series: Series) -> Result<Series, sqlx::Error> {
+    query(
+      r#"UPDATE `series`
+      SET `name` = ?, `description` = ?, `version` = `version` + 1
+      WHERE `id` = ? AND `version` = ?"#,
+    )
+    .bind(series.name)
+    .bind(series.description)
+    .bind(series.id)
+    .bind(series.version)
+    .execute(&mut **tx)
+    .await?;
+    ChangeEvent::record(tx, "series", series.id, "update").await?;
+
+    Series::fetch_one(tx, series.id).await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "series", id = series_id))]
+  pub async fn delete<'a>(tx: &mut Transaction<'a, MySql>, series_id: u64) -> Result<MySqlQueryResult, sqlx::Error> {
+    let result = query(
+      r#"DELETE FROM `series`
+      WHERE `id` = ?"#,
+    )
+    .bind(series_id)
+    .execute(&mut **tx)
+    .await?;
+    if result.rows_affected() > 0 {
+      ChangeEvent::record(tx, "series", series_id, "delete").await?;
+    }
+    Ok(result)
+  }
+}
@@ -0,0 +1,158 @@
+use chrono::{DateTime, Utc};
+use sqlx::{mysql::MySqlQueryResult, query, query_as, FromRow, MySql, Transaction};
+use tracing::instrument;
+
+use super::{
+  books::{Book, Books},
+  changes::ChangeEvent,
+};
+
+/// A user's named list of books, such as `to-read` or `favourites`.
+#[derive(Debug, Clone, FromRow, PartialEq, Eq)]
+pub struct Shelf {
+  pub id: u64,
+  pub user_id: u8,
+  pub name: String,
+  pub date_added: Option<DateTime<Utc>>,
+}
+
+impl Shelf {
+  #[instrument(level = "debug", skip_all, fields(entity = "shelf", id = shelf_id))]
+  pub async fn fetch_one<'a>(tx: &mut Transaction<'a, MySql>, shelf_id: u64) -> Result<Shelf, sqlx::Error> {
+    query_as::<MySql, Shelf>(
+      r#"SELECT * FROM `shelf`
+      WHERE `id`= ?"#,
+    )
+    .bind(shelf_id)
+    .fetch_one(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "shelf", user_id = user_id))]
+  pub async fn fetch_by_user<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8) -> Result<Vec<Shelf>, sqlx::Error> {
+    query_as::<MySql, Shelf>(
+      r#"SELECT * FROM `shelf`
+      WHERE `user_id`= ?
+      ORDER BY `name`"#,
+    )
+    .bind(user_id)
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "shelf", user_id = user_id))]
+  pub async fn fetch_by_name<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8, name: &str) -> Result<Option<Shelf>, sqlx::Error> {
+    query_as::<MySql, Shelf>(
+      r#"SELECT * FROM `shelf`
+      WHERE `user_id`= ? AND `name` = ?"#,
+    )
+    .bind(user_id)
+    .bind(name)
+    .fetch_optional(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "shelf"))]
+  pub async fn fetch_last<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Shelf, sqlx::Error> {
+    query_as::<MySql, Shelf>(
+      r#"SELECT * FROM `shelf`
+      WHERE `id` = LAST_INSERT_ID();"#,
+    )
+    .fetch_one(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "shelf", user_id = user_id))]
+  pub async fn create<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8, name: &str) -> Result<Shelf, sqlx::Error> {
+    query(
+      r#"INSERT INTO `shelf` (`user_id`, `name`)
+      VALUES (?, ?)"#,
+    )
+    .bind(user_id)
+    .bind(name)
+    .execute(&mut **tx)
+    .await?;
+
+    let shelf = Shelf::fetch_last(tx).await?;
+    ChangeEvent::record(tx, "shelf", shelf.id, "create").await?;
+    Ok(shelf)
+  }
+
+  /// Returns the user's shelf called `name`, creating it the first time it is used.
+  #[instrument(level = "debug", skip_all, fields(entity = "shelf", user_id = user_id))]
+  pub async fn fetch_or_create<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8, name: &str) -> Result<Shelf, sqlx::Error> {
+    match Shelf::fetch_by_name(tx, user_id, name).await? {
+      Some(shelf) => Ok(shelf),
+      None => Shelf::create(tx, user_id, name).await,
+    }
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "shelf"))]
+  pub async fn fetch_books<'a>(&self, tx: &mut Transaction<'a, MySql>) -> Result<Books, sqlx::Error> {
+    query_as::<MySql, Book>(
+      r#"SELECT `book`.* FROM `book`
+      INNER JOIN `shelf_book` ON `shelf_book`.`book_id` = `book`.`id`
+      WHERE `shelf_book`.`shelf_id` = ? AND `book`.`deleted_at` IS NULL
+      ORDER BY `shelf_book`.`date_added`"#,
+    )
+    .bind(self.id)
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  /// Adding a book that is already on the shelf is a no-op.
+  #[instrument(level = "debug", skip_all, fields(entity = "shelf", id = shelf_id))]
+  pub async fn add_book<'a>(tx: &mut Transaction<'a, MySql>, shelf_id: u64, book_id: u64) -> Result<MySqlQueryResult, sqlx::Error> {
+    let result = query(
+      r#"INSERT IGNORE INTO `shelf_book` (`shelf_id`, `book_id`)
+      VALUES (?, ?)"#,
+    )
+    .bind(shelf_id)
+    .bind(book_id)
+    .execute(&mut **tx)
+    .await?;
+    if result.rows_affected() > 0 {
+      ChangeEvent::record(tx, "shelf", shelf_id, "update").await?;
+    }
+    Ok(result)
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "shelf", id = shelf_id))]
+  pub async fn remove_book<'a>(tx: &mut Transaction<'a, MySql>, shelf_id: u64, book_id: u64) -> Result<MySqlQueryResult, sqlx::Error> {
+    let result = query(
+      r#"DELETE FROM `shelf_book`
+      WHERE `shelf_id` = ? AND `book_id` = ?"#,
+    )
+    .bind(shelf_id)
+    .bind(book_id)
+    .execute(&mut **tx)
+    .await?;
+    if result.rows_affected() > 0 {
+      ChangeEvent::record(tx, "shelf", shelf_id, "update").await?;
+    }
+    Ok(result)
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "shelf", id = shelf_id))]
+  pub async fn delete<'a>(tx: &mut Transaction<'a, MySql>, shelf_id: u64) -> Result<MySqlQueryResult, sqlx::Error> {
+    query(
+      r#"DELETE FROM `shelf_book`
+      WHERE `shelf_id` = ?"#,
+    )
+    .bind(shelf_id)
+    .execute(&mut **tx)
+    .await?;
+
+    let result = query(
+      r#"DELETE FROM `shelf`
+      WHERE `id` = ?"#,
+    )
+    .bind(shelf_id)
+    .execute(&mut **tx)
+    .await?;
+    if result.rows_affected() > 0 {
+      ChangeEvent::record(tx, "shelf", shelf_id, "delete").await?;
+    }
+    Ok(result)
+  }
+}
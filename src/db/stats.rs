@@ -0,0 +1,64 @@
+use chrono::{DateTime, Days, NaiveDate, Utc};
+use sqlx::{query, query_as, FromRow, MySql, Transaction};
+use tracing::instrument;
+
+/// The catalog and its readers as they stood at the end of a day (UTC).
+#[derive(Debug, Clone, FromRow, PartialEq, Eq)]
+pub struct DailyStats {
+  pub date: NaiveDate,
+  /// Books in the catalog, not counting the trash.
+  pub books: u32,
+  pub users: u32,
+  /// Users who updated their progress in any book that day.
+  pub active_readers: u32,
+  pub books_finished: u32,
+  pub date_last_updated: Option<DateTime<Utc>>,
+}
+
+impl DailyStats {
+  /// Counts up `date` and stores it, replacing any earlier rollup of the same day. Meant to run at the end of the day:
+  /// the catalog counts are as of now, and readers active that day are only known until they read again.
+  #[instrument(level = "debug", skip_all, fields(entity = "daily_stats"))]
+  pub async fn rollup<'a>(tx: &mut Transaction<'a, MySql>, date: NaiveDate) -> Result<DailyStats, sqlx::Error> {
+    let start = date.and_hms_opt(0, 0, 0).unwrap().and_utc();
+    let end = (date + Days::new(1)).and_hms_opt(0, 0, 0).unwrap().and_utc();
+    query(
+      r#"INSERT INTO `daily_stats` (`date`, `books`, `users`, `active_readers`, `books_finished`)
+      SELECT ?,
+        (SELECT COUNT(*) FROM `book` WHERE `deleted_at` IS NULL),
+        (SELECT COUNT(*) FROM `user`),
+        (SELECT COUNT(DISTINCT `user_id`) FROM `progress` WHERE `date_last_updated` >= ? AND `date_last_updated` < ?),
+        (SELECT COUNT(*) FROM `progress` WHERE `date_finished` >= ? AND `date_finished` < ?)
+      ON DUPLICATE KEY UPDATE `books` = VALUES(`books`), `users` = VALUES(`users`),
+        `active_readers` = VALUES(`active_readers`), `books_finished` = VALUES(`books_finished`)"#,
+    )
+    .bind(date)
+    .bind(start)
+    .bind(end)
+    .bind(start)
+    .bind(end)
+    .execute(&mut **tx)
+    .await?;
+
+    query_as::<MySql, DailyStats>(
+      r#"SELECT * FROM `daily_stats`
+      WHERE `date` = ?"#,
+    )
+    .bind(date)
+    .fetch_one(&mut **tx)
+    .await
+  }
+
+  /// Every day rolled up since `since`, oldest first.
+  #[instrument(level = "debug", skip_all, fields(entity = "daily_stats"))]
+  pub async fn fetch_since<'a>(tx: &mut Transaction<'a, MySql>, since: NaiveDate) -> Result<Vec<DailyStats>, sqlx::Error> {
+    query_as::<MySql, DailyStats>(
+      r#"SELECT * FROM `daily_stats`
+      WHERE `date` >= ?
+      ORDER BY `date`"#,
+    )
+    .bind(since)
+    .fetch_all(&mut **tx)
+    .await
+  }
+}
@@ -0,0 +1,71 @@
+use sqlx::{query, query_as, FromRow, MySql, Transaction};
+use tracing::instrument;
+
+use super::changes::ChangeEvent;
+
+#[derive(Debug, Clone, FromRow, PartialEq, Eq)]
+pub struct Tag {
+  pub id: u64,
+  pub name: String,
+}
+
+impl Tag {
+  #[instrument(level = "debug", skip_all, fields(entity = "tag"))]
+  pub async fn fetch_all<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Vec<Tag>, sqlx::Error> {
+    query_as::<MySql, Tag>(r#"SELECT * FROM `tag` ORDER BY `name`"#).fetch_all(&mut **tx).await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "tag", book_id = book_id))]
+  pub async fn fetch_by_book<'a>(tx: &mut Transaction<'a, MySql>, book_id: u64) -> Result<Vec<Tag>, sqlx::Error> {
+    query_as::<MySql, Tag>(
+      r#"SELECT `tag`.* FROM `tag`
+      INNER JOIN `book_tag` ON `book_tag`.`tag_id` = `tag`.`id`
+      WHERE `book_tag`.`book_id` = ?
+      ORDER BY `tag`.`name`"#,
+    )
+    .bind(book_id)
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "tag"))]
+  pub async fn fetch_or_create<'a>(tx: &mut Transaction<'a, MySql>, name: &str) -> Result<Tag, sqlx::Error> {
+    let result = query(r#"INSERT IGNORE INTO `tag` (`name`) VALUES (?)"#).bind(name).execute(&mut **tx).await?;
+
+    let tag = query_as::<MySql, Tag>(
+      r#"SELECT * FROM `tag`
+      WHERE `name` = ?"#,
+    )
+    .bind(name)
+    .fetch_one(&mut **tx)
+    .await?;
+    if result.rows_affected() > 0 {
+      ChangeEvent::record(tx, "tag", tag.id, "create").await?;
+    }
+    Ok(tag)
+  }
+
+  /// Replaces the book's tags with `names`, creating any tag not seen before.
+  #[instrument(level = "debug", skip_all, fields(entity = "tag", book_id = book_id))]
+  pub async fn set_for_book<'a>(tx: &mut Transaction<'a, MySql>, book_id: u64, names: &[String]) -> Result<Vec<Tag>, sqlx::Error> {
+    query(
+      r#"DELETE FROM `book_tag`
+      WHERE `book_id` = ?"#,
+    )
+    .bind(book_id)
+    .execute(&mut **tx)
+    .await?;
+
+    for name in names {
+      let tag = Tag::fetch_or_create(tx, name).await?;
+      query(r#"INSERT IGNORE INTO `book_tag` (`book_id`, `tag_id`) VALUES (?, ?)"#)
+        .bind(book_id)
+        .bind(tag.id)
+        .execute(&mut **tx)
+        .await?;
+    }
+    ChangeEvent::record(tx, "book_tag", book_id, "update").await?;
+
+    Tag::fetch_by_book(tx, book_id).await
+  }
+}
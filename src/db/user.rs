@@ -1,5 +1,8 @@
 use chrono::{DateTime, Utc};
 use sqlx::{mysql::MySqlQueryResult, query, query_as, FromRow, MySql, Transaction};
+use tracing::instrument;
+
+use super::{changes::ChangeEvent, placeholders, UpdateError};
 
 #[derive(Debug, Clone, FromRow, PartialEq, Eq)]
 pub struct User {
@@ -7,19 +10,37 @@ pub struct User {
   pub name: String,
   pub date_added: Option<DateTime<Utc>>,
   pub date_last_updated: Option<DateTime<Utc>>,
+  /// Bumped by every update, and checked by [`User::update_if`].
+  pub version: u32,
 }
 
 impl User {
+  #[instrument(level = "debug", skip_all, fields(entity = "user", id = user_id))]
   pub async fn fetch_one<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8) -> Result<User, sqlx::Error> {
     query_as::<MySql, User>(
       r#"SELECT * FROM `user`
-      WHERE `user_id`= ?"#,
+      WHERE `id`= ?"#,
     )
     .bind(user_id)
     .fetch_one(&mut **tx)
     .await
   }
 
+  /// The users among `user_ids` that exist, in no particular order.
+  #[instrument(level = "debug", skip_all, fields(entity = "user"))]
+  pub async fn fetch_many<'a>(tx: &mut Transaction<'a, MySql>, user_ids: &[u8]) -> Result<Vec<User>, sqlx::Error> {
+    if user_ids.is_empty() {
+      return Ok(Vec::new());
+    }
+    let sql = format!("SELECT * FROM `user` WHERE `id` IN ({})", placeholders(user_ids.len()));
+    let mut users = query_as::<MySql, User>(&sql);
+    for user_id in user_ids {
+      users = users.bind(user_id);
+    }
+    users.fetch_all(&mut **tx).await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "user"))]
   pub async fn fetch_last<'a>(tx: &mut Transaction<'a, MySql>) -> Result<User, sqlx::Error> {
     query_as::<MySql, User>(
       r#"SELECT * FROM `user` 
@@ -29,39 +50,91 @@ impl User {
     .await
   }
 
-  pub async fn create<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8, user_name: String) -> Result<User, sqlx::Error> {
+  #[instrument(level = "debug", skip_all, fields(entity = "user"))]
+  pub async fn fetch_all<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Vec<User>, sqlx::Error> {
+    query_as::<MySql, User>(r#"SELECT * FROM `user`"#).fetch_all(&mut **tx).await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "user"))]
+  pub async fn count<'a>(tx: &mut Transaction<'a, MySql>) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar(r#"SELECT COUNT(*) FROM `user`"#).fetch_one(&mut **tx).await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "user"))]
+  pub async fn fetch_by_name<'a>(tx: &mut Transaction<'a, MySql>, user_name: &str) -> Result<User, sqlx::Error> {
+    query_as::<MySql, User>(
+      r#"SELECT * FROM `user`
+      WHERE `name`= ?"#,
+    )
+    .bind(user_name)
+    .fetch_one(&mut **tx)
+    .await
+  }
+
+  /// Creates a user, letting the database assign the id when `user_id` is `None`.
+  #[instrument(level = "debug", skip_all, fields(entity = "user", id = user_id))]
+  pub async fn create<'a>(tx: &mut Transaction<'a, MySql>, user_id: Option<u8>, user_name: String) -> Result<User, sqlx::Error> {
     query(
       r#"INSERT INTO `user` (`id`, `name`)
       VALUES (?, ?)"#,
     )
     .bind(user_id)
     .bind(user_name)
-    .fetch_one(&mut **tx)
+    .execute(&mut **tx)
     .await?;
 
-    User::fetch_last(tx).await
+    let user = User::fetch_last(tx).await?;
+    ChangeEvent::record(tx, "user", user.id.into(), "create").await?;
+    Ok(user)
   }
 
+  #[instrument(level = "debug", skip_all, fields(entity = "user", id = user_id))]
   pub async fn update<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8, user_name: String) -> Result<User, sqlx::Error> {
     // some logic here for partial user with merge fn when/if i add user prefs
-    query_as::<MySql, User>(
+    query(
       r#"UPDATE `user` 
-      SET `name` = ?
-      WHERE `user_id`= ?"#,
+      SET `name` = ?, `version` = `version` + 1
+      WHERE `id`= ?"#,
     )
     .bind(user_name)
     .bind(user_id)
+    .execute(&mut **tx)
+    .await?;
+    ChangeEvent::record(tx, "user", user_id.into(), "update").await?;
+
+    User::fetch_one(tx, user_id).await
+  }
+
+  /// [`User::update`] for a client editing what it read earlier: fails with [`UpdateError::Conflict`] if the user is no
+  /// longer at `version`.
+  #[instrument(level = "debug", skip_all, fields(entity = "user", id = user_id))]
+  pub async fn update_if<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8, version: u32, user_name: String) -> Result<User, UpdateError> {
+    let current: u32 = sqlx::query_scalar(
+      r#"SELECT `version` FROM `user`
+      WHERE `id`= ?
+      FOR UPDATE"#,
+    )
+    .bind(user_id)
     .fetch_one(&mut **tx)
-    .await
+    .await?;
+    if current != version {
+      return Err(UpdateError::Conflict(current));
+    }
+    Ok(User::update(tx, user_id, user_name).await?)
   }
 
+  #[instrument(level = "debug", skip_all, fields(entity = "user", id = user_id))]
   pub async fn delete<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8) -> Result<MySqlQueryResult, sqlx::Error> {
-    query(
+    let result = query(
       r#"DELETE FROM `user` 
-      WHERE `user_id`= ?"#,
+      WHERE `id`= ?"#,
     )
     .bind(user_id)
     .execute(&mut **tx)
-    .await
+    .await?;
+    if result.rows_affected() > 0 {
+      ChangeEvent::record(tx, "user", user_id.into(), "delete").await?;
+    }
+    Ok(result)
   }
 }
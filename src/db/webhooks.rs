@@ -0,0 +1,249 @@
+use chrono::{DateTime, Utc};
+use sqlx::{mysql::MySqlQueryResult, query, query_as, FromRow, MySql, Transaction};
+use tracing::instrument;
+
+/// The events a webhook can subscribe to.
+pub const EVENTS: &[&str] = &["book.added", "book.finished"];
+
+#[derive(Debug, Clone, FromRow, PartialEq, Eq)]
+pub struct Webhook {
+  pub id: u64,
+  pub url: String,
+  /// Key for the HMAC-SHA256 signature on every delivery.
+  pub secret: String,
+  /// Comma-separated names from [`EVENTS`].
+  pub events: String,
+  pub enabled: bool,
+  /// Failed attempts since the last success. Reaching the dispatcher's limit disables the webhook.
+  pub failures: u32,
+  pub date_added: Option<DateTime<Utc>>,
+  pub date_last_updated: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, FromRow, PartialEq, Eq)]
+pub struct WebhookDelivery {
+  pub id: u64,
+  pub webhook_id: u64,
+  pub event: String,
+  /// The event's JSON, as written by [`WebhookDelivery::enqueue`].
+  pub payload: String,
+  /// `pending`, `delivered` or `failed` once every attempt is spent.
+  pub status: String,
+  pub attempts: u32,
+  /// The HTTP status of the last attempt, if it got a response.
+  pub response_status: Option<u16>,
+  pub error: Option<String>,
+  pub next_attempt_at: Option<DateTime<Utc>>,
+  pub date_added: Option<DateTime<Utc>>,
+  pub date_delivered: Option<DateTime<Utc>>,
+}
+
+impl Webhook {
+  #[instrument(level = "debug", skip_all, fields(entity = "webhook", id = webhook_id))]
+  pub async fn fetch_one<'a>(tx: &mut Transaction<'a, MySql>, webhook_id: u64) -> Result<Webhook, sqlx::Error> {
+    query_as::<MySql, Webhook>(
+      r#"SELECT * FROM `webhook`
+      WHERE `id`= ?"#,
+    )
+    .bind(webhook_id)
+    .fetch_one(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "webhook"))]
+  pub async fn fetch_all<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Vec<Webhook>, sqlx::Error> {
+    query_as::<MySql, Webhook>(r#"SELECT * FROM `webhook` ORDER BY `id`"#)
+      .fetch_all(&mut **tx)
+      .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "webhook"))]
+  pub async fn fetch_last<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Webhook, sqlx::Error> {
+    query_as::<MySql, Webhook>(
+      r#"SELECT * FROM `webhook`
+      WHERE `id` = LAST_INSERT_ID();"#,
+    )
+    .fetch_one(&mut **tx)
+    .await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "webhook"))]
+  pub async fn create<'a>(tx: &mut Transaction<'a, MySql>, url: &str, secret: &str, events: &[String]) -> Result<Webhook, sqlx::Error> {
+    query(
+      r#"INSERT INTO `webhook` (`url`, `secret`, `events`)
+      VALUES (?, ?, ?)"#,
+    )
+    .bind(url)
+    .bind(secret)
+    .bind(events.join(","))
+    .execute(&mut **tx)
+    .await?;
+
+    Webhook::fetch_last(tx).await
+  }
+
+  /// Turning a webhook back on forgives its failures, and its pending deliveries resume.
+  #[instrument(level = "debug", skip_all, fields(entity = "webhook", id = webhook_id))]
+  pub async fn set_enabled<'a>(tx: &mut Transaction<'a, MySql>, webhook_id: u64, enabled: bool) -> Result<Webhook, sqlx::Error> {
+    query(
+      r#"UPDATE `webhook`
+      SET `enabled` = ?, `failures` = IF(?, 0, `failures`)
+      WHERE `id` = ?"#,
+    )
+    .bind(enabled)
+    .bind(enabled)
+    .bind(webhook_id)
+    .execute(&mut **tx)
+    .await?;
+
+    Webhook::fetch_one(tx, webhook_id).await
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "webhook", id = webhook_id))]
+  pub async fn record_success<'a>(tx: &mut Transaction<'a, MySql>, webhook_id: u64) -> Result<MySqlQueryResult, sqlx::Error> {
+    query(
+      r#"UPDATE `webhook`
+      SET `failures` = 0
+      WHERE `id` = ?"#,
+    )
+    .bind(webhook_id)
+    .execute(&mut **tx)
+    .await
+  }
+
+  /// Counts a failed attempt, disabling the webhook once `limit` have happened in a row. Returns whether it was disabled.
+  #[instrument(level = "debug", skip_all, fields(entity = "webhook", id = webhook_id))]
+  pub async fn record_failure<'a>(tx: &mut Transaction<'a, MySql>, webhook_id: u64, limit: u32) -> Result<bool, sqlx::Error> {
+    // MySQL applies single-table assignments left to right, so `enabled` sees the incremented count.
+    query(
+      r#"UPDATE `webhook`
+      SET `failures` = `failures` + 1, `enabled` = `enabled` AND `failures` < ?
+      WHERE `id` = ?"#,
+    )
+    .bind(limit)
+    .bind(webhook_id)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(!Webhook::fetch_one(tx, webhook_id).await?.enabled)
+  }
+
+  /// Deliveries go with it.
+  #[instrument(level = "debug", skip_all, fields(entity = "webhook", id = webhook_id))]
+  pub async fn delete<'a>(tx: &mut Transaction<'a, MySql>, webhook_id: u64) -> Result<MySqlQueryResult, sqlx::Error> {
+    query(
+      r#"DELETE FROM `webhook_delivery`
+      WHERE `webhook_id` = ?"#,
+    )
+    .bind(webhook_id)
+    .execute(&mut **tx)
+    .await?;
+
+    query(
+      r#"DELETE FROM `webhook`
+      WHERE `id` = ?"#,
+    )
+    .bind(webhook_id)
+    .execute(&mut **tx)
+    .await
+  }
+}
+
+impl WebhookDelivery {
+  /// Queues `event` for every enabled webhook subscribed to it. Runs in the caller's transaction, so the delivery exists
+  /// exactly when the change that caused it commits.
+  #[instrument(level = "debug", skip_all, fields(entity = "webhook_delivery"))]
+  pub async fn enqueue<'a>(tx: &mut Transaction<'a, MySql>, event: &str, payload: &serde_json::Value) -> Result<MySqlQueryResult, sqlx::Error> {
+    query(
+      r#"INSERT INTO `webhook_delivery` (`webhook_id`, `event`, `payload`)
+      SELECT `id`, ?, ? FROM `webhook`
+      WHERE `enabled` AND FIND_IN_SET(?, `events`)"#,
+    )
+    .bind(event)
+    .bind(payload.to_string())
+    .bind(event)
+    .execute(&mut **tx)
+    .await
+  }
+
+  /// The delivery log for a webhook, newest first.
+  #[instrument(level = "debug", skip_all, fields(entity = "webhook_delivery", webhook_id = webhook_id))]
+  pub async fn fetch_by_webhook<'a>(tx: &mut Transaction<'a, MySql>, webhook_id: u64, limit: u32) -> Result<Vec<WebhookDelivery>, sqlx::Error> {
+    query_as::<MySql, WebhookDelivery>(
+      r#"SELECT * FROM `webhook_delivery`
+      WHERE `webhook_id` = ?
+      ORDER BY `id` DESC
+      LIMIT ?"#,
+    )
+    .bind(webhook_id)
+    .bind(limit)
+    .fetch_all(&mut **tx)
+    .await
+  }
+
+  /// Claims up to `limit` pending deliveries that are due, pushing their next attempt out by `lease` so that another
+  /// dispatcher will not pick them up while this one is sending them.
+  #[instrument(level = "debug", skip_all, fields(entity = "webhook_delivery"))]
+  pub async fn claim_due<'a>(tx: &mut Transaction<'a, MySql>, limit: u32, lease: DateTime<Utc>) -> Result<Vec<WebhookDelivery>, sqlx::Error> {
+    let due = query_as::<MySql, WebhookDelivery>(
+      r#"SELECT `webhook_delivery`.* FROM `webhook_delivery`
+      INNER JOIN `webhook` ON `webhook`.`id` = `webhook_delivery`.`webhook_id`
+      WHERE `webhook_delivery`.`status` = 'pending' AND `webhook`.`enabled` AND `webhook_delivery`.`next_attempt_at` <= NOW()
+      ORDER BY `webhook_delivery`.`id`
+      LIMIT ?
+      FOR UPDATE OF `webhook_delivery` SKIP LOCKED"#,
+    )
+    .bind(limit)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    for delivery in &due {
+      query(
+        r#"UPDATE `webhook_delivery`
+        SET `next_attempt_at` = ?
+        WHERE `id` = ?"#,
+      )
+      .bind(lease)
+      .bind(delivery.id)
+      .execute(&mut **tx)
+      .await?;
+    }
+    Ok(due)
+  }
+
+  #[instrument(level = "debug", skip_all, fields(entity = "webhook_delivery", id = delivery_id))]
+  pub async fn mark_delivered<'a>(tx: &mut Transaction<'a, MySql>, delivery_id: u64, response_status: u16) -> Result<MySqlQueryResult, sqlx::Error> {
+    query(
+      r#"UPDATE `webhook_delivery`
+      SET `status` = 'delivered', `attempts` = `attempts` + 1, `response_status` = ?, `error` = NULL, `date_delivered` = NOW()
+      WHERE `id` = ?"#,
+    )
+    .bind(response_status)
+    .bind(delivery_id)
+    .execute(&mut **tx)
+    .await
+  }
+
+  /// Records a failed attempt. With a `retry_at` the delivery is tried again then; without one it has failed for good.
+  #[instrument(level = "debug", skip_all, fields(entity = "webhook_delivery", id = delivery_id))]
+  pub async fn mark_failed<'a>(
+    tx: &mut Transaction<'a, MySql>,
+    delivery_id: u64,
+    response_status: Option<u16>,
+    error: &str,
+    retry_at: Option<DateTime<Utc>>,
+  ) -> Result<MySqlQueryResult, sqlx::Error> {
+    query(
+      r#"UPDATE `webhook_delivery`
+      SET `status` = IF(? IS NULL, 'failed', 'pending'), `attempts` = `attempts` + 1, `response_status` = ?, `error` = ?, `next_attempt_at` = ?
+      WHERE `id` = ?"#,
+    )
+    .bind(retry_at)
+    .bind(response_status)
+    .bind(error)
+    .bind(retry_at)
+    .bind(delivery_id)
+    .execute(&mut **tx)
+    .await
+  }
+}
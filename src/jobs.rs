@@ -0,0 +1,331 @@
+//! Background jobs. Work too slow for a request, such as imports, thumbnailing, backups and reindexing, is queued in the
+//! `job` table as a [`Task`] (see [`enqueue`]) and run from there by a [`Worker`], so it survives restarts and is shared
+//! between every server pointed at the same database. Recurring tasks are queued the same way by the
+//! [scheduler](crate::schedule).
+//!
+//! A job that fails is retried with exponential backoff until its attempts run out. A job that was running when its
+//! server died is picked up again once its lease expires, so tasks should be safe to run twice. Each claim counts as an
+//! attempt, so a job that keeps killing its worker still runs out, and only the latest claim can record an outcome.
+
+use std::{
+  collections::HashSet,
+  path::{Component, Path, PathBuf},
+  time::Duration,
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{MySql, Transaction};
+use tokio::{io::AsyncReadExt, task::JoinSet};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+  api::{
+    covers::{cover_from_files, save_cover},
+    transfer::calibre_import,
+    AppState,
+  },
+  backup,
+  db::{audit::AuditEntry, cache, changes::ChangeEvent, covers::Cover, files::BookFile, jobs::Job, koreader::KoreaderDocument, stats::DailyStats},
+  storage::ORPHAN_GRACE,
+  trash,
+};
+
+/// How long a claimed job is left to its worker before another may take it over. Longer than any task should run.
+const LEASE: chrono::Duration = chrono::Duration::hours(1);
+
+/// How often an idle worker looks for due jobs.
+const POLL: Duration = Duration::from_secs(1);
+
+/// The work a job does, stored as its payload. `kind` names the variant.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Task {
+  /// Regenerates a book's thumbnails from its stored cover, such as after the configured sizes change, or extracts a cover
+  /// from its ebooks when it has none.
+  Thumbnails { book_id: u64 },
+  /// Syncs the Calibre library at `storage.calibre_library`.
+  CalibreImport,
+  /// Writes a backup archive called `name` into `schedule.backup_dir`.
+  Backup { name: String },
+  /// Recomputes the KOReader document hash of every stored ebook.
+  Reindex,
+  /// Purges what has been in the trash for more than `days`.
+  PurgeTrash { days: i64 },
+  /// Deletes audit log entries older than `days`.
+  PruneAudit { days: i64 },
+  /// Deletes change feed events older than `days`.
+  PruneChanges { days: i64 },
+  /// Deletes stored files and covers that nothing refers to any more.
+  CleanupOrphans,
+  /// Records the day's [`DailyStats`].
+  StatsRollup { date: NaiveDate },
+}
+
+impl Task {
+  pub fn kind(&self) -> &'static str {
+    match self {
+      Task::Thumbnails { .. } => "thumbnails",
+      Task::CalibreImport => "calibre_import",
+      Task::Backup { .. } => "backup",
+      Task::Reindex => "reindex",
+      Task::PurgeTrash { .. } => "purge_trash",
+      Task::PruneAudit { .. } => "prune_audit",
+      Task::PruneChanges { .. } => "prune_changes",
+      Task::CleanupOrphans => "cleanup_orphans",
+      Task::StatsRollup { .. } => "stats_rollup",
+    }
+  }
+
+  /// Refuses tasks that would reach outside the folders the config gives them.
+  pub fn check(&self) -> Result<(), String> {
+    match self {
+      Task::Backup { name } => backup_name(name),
+      _ => Ok(()),
+    }
+  }
+
+  /// Does the work, returning a summary for the job's `result`.
+  pub async fn run(&self, state: &AppState) -> Result<Value, String> {
+    self.check()?;
+    match self {
+      Task::Thumbnails { book_id } => thumbnails(state, *book_id).await,
+      Task::CalibreImport => calibre_import(state).await.map_err(|err| format!("{:?}", err)),
+      Task::Backup { name } => {
+        let archive: PathBuf = Path::new(&state.config.schedule.backup_dir).join(name);
+        let manifest = backup::backup(&state.db, &state.storage, &state.covers.storage, &archive)
+          .await
+          .map_err(|err| err.to_string())?;
+        Ok(json!({
+          "archive": archive.to_string_lossy(),
+          "schema_version": manifest.schema_version,
+          "tables": manifest.tables.len(),
+          "rows": manifest.tables.iter().map(|table| table.rows).sum::<usize>(),
+          "files": manifest.files.len(),
+          "covers": manifest.covers.len(),
+        }))
+      }
+      Task::Reindex => reindex(state).await.map_err(|err| err.to_string()),
+      Task::PurgeTrash { days } => {
+        let cutoff = Utc::now() - chrono::Duration::days(*days);
+        let report = trash::purge_expired(&state.db, &state.storage, &state.covers.storage, cutoff, "job:purge_trash")
+          .await
+          .map_err(|err| err.to_string())?;
+        Ok(json!({
+          "books": report.books,
+          "authors": report.authors,
+          "files": report.files,
+          "covers": report.covers,
+        }))
+      }
+      Task::PruneAudit { days } => {
+        let cutoff = Utc::now() - chrono::Duration::days(*days);
+        let prune = async {
+          let mut tx = state.db.conn.begin().await?;
+          let pruned = AuditEntry::prune(&mut tx, cutoff).await?;
+          tx.commit().await?;
+          Ok::<u64, sqlx::Error>(pruned)
+        };
+        let pruned = prune.await.map_err(|err| err.to_string())?;
+        Ok(json!({ "pruned": pruned }))
+      }
+      Task::PruneChanges { days } => {
+        let cutoff = Utc::now() - chrono::Duration::days(*days);
+        let prune = async {
+          let mut tx = state.db.conn.begin().await?;
+          let pruned = ChangeEvent::prune(&mut tx, cutoff).await?;
+          tx.commit().await?;
+          Ok::<u64, sqlx::Error>(pruned)
+        };
+        let pruned = prune.await.map_err(|err| err.to_string())?;
+        Ok(json!({ "pruned": pruned }))
+      }
+      Task::CleanupOrphans => cleanup_orphans(state).await,
+      Task::StatsRollup { date } => {
+        let rollup = async {
+          let mut tx = state.db.conn.begin().await?;
+          let stats = DailyStats::rollup(&mut tx, *date).await?;
+          tx.commit().await?;
+          Ok::<DailyStats, sqlx::Error>(stats)
+        };
+        let stats = rollup.await.map_err(|err| err.to_string())?;
+        Ok(json!({
+          "date": stats.date,
+          "books": stats.books,
+          "users": stats.users,
+          "active_readers": stats.active_readers,
+          "books_finished": stats.books_finished,
+        }))
+      }
+    }
+  }
+}
+
+/// A backup is named by a plain `.zip` file name, so it can only be written into the backup folder.
+fn backup_name(name: &str) -> Result<(), String> {
+  let mut components = Path::new(name).components();
+  let plain = matches!((components.next(), components.next()), (Some(Component::Normal(file)), None) if file == name);
+  if plain && !name.starts_with('.') && name.ends_with(".zip") {
+    Ok(())
+  } else {
+    Err(format!("backup name `{}` is not a plain .zip file name", name))
+  }
+}
+
+/// Queues `task` to start at `run_at`, or as soon as a worker is free, with `max_attempts` tries.
+pub async fn enqueue<'a>(tx: &mut Transaction<'a, MySql>, task: &Task, run_at: Option<DateTime<Utc>>, max_attempts: u32) -> Result<Job, sqlx::Error> {
+  let payload = serde_json::to_value(task).expect("tasks serialize");
+  Job::enqueue(tx, task.kind(), &payload, run_at, max_attempts).await
+}
+
+/// The wait before retrying after the `attempt`th failure: 10 seconds, doubling each time, capped at an hour.
+pub fn backoff(attempt: u32) -> chrono::Duration {
+  let seconds = 10i64 << attempt.saturating_sub(1).min(20);
+  chrono::Duration::seconds(seconds.min(60 * 60))
+}
+
+async fn thumbnails(state: &AppState, book_id: u64) -> Result<Value, String> {
+  let cover = {
+    let mut tx = state.db.conn.begin().await.map_err(|err| err.to_string())?;
+    match Cover::fetch_one(&mut tx, book_id).await {
+      Ok(cover) => Some(cover),
+      Err(sqlx::Error::RowNotFound) => None,
+      Err(err) => return Err(err.to_string()),
+    }
+  };
+
+  let cover = match cover {
+    Some(cover) => {
+      let mut bytes = Vec::new();
+      let read = async { state.covers.storage.open(&cover.sha256).await?.read_to_end(&mut bytes).await };
+      read.await.map_err(|err| err.to_string())?;
+      Some(
+        save_cover(state, book_id, bytes, &cover.source, "job:thumbnails")
+          .await
+          .map_err(|err| format!("{:?}", err))?,
+      )
+    }
+    None => cover_from_files(state, book_id, "job:thumbnails").await.map_err(|err| format!("{:?}", err))?,
+  };
+  Ok(json!({
+    "book_id": book_id,
+    "source": cover.map(|cover| cover.source),
+  }))
+}
+
+/// Files whose blob is missing are skipped and counted, so one lost file does not stop the rest.
+async fn reindex(state: &AppState) -> Result<Value, sqlx::Error> {
+  let files = {
+    let mut tx = state.db.conn.begin().await?;
+    BookFile::fetch_all(&mut tx).await?
+  };
+
+  let (mut linked, mut missing) = (0, 0);
+  for file in &files {
+    let Ok(hash) = state.storage.koreader_hash(&file.sha256).await else {
+      missing += 1;
+      continue;
+    };
+    let mut tx = state.db.conn.begin().await?;
+    KoreaderDocument::link(&mut tx, &hash, file.book_id).await?;
+    tx.commit().await?;
+    linked += 1;
+  }
+  Ok(json!({ "files": files.len(), "linked": linked, "missing": missing }))
+}
+
+async fn cleanup_orphans(state: &AppState) -> Result<Value, String> {
+  let (files, covers) = {
+    let mut tx = state.db.conn.begin().await.map_err(|err| err.to_string())?;
+    let files: HashSet<String> = BookFile::fetch_hashes(&mut tx).await.map_err(|err| err.to_string())?.into_iter().collect();
+    let covers: HashSet<String> = Cover::fetch_hashes(&mut tx).await.map_err(|err| err.to_string())?.into_iter().collect();
+    (files, covers)
+  };
+  let files = state.storage.cleanup_orphans(&files, ORPHAN_GRACE).await.map_err(|err| err.to_string())?;
+  let covers = state
+    .covers
+    .storage
+    .cleanup_orphans(&covers, ORPHAN_GRACE)
+    .await
+    .map_err(|err| err.to_string())?;
+  Ok(json!({ "files": files, "covers": covers }))
+}
+
+/// Runs due jobs, up to `concurrency` at a time.
+#[derive(Clone)]
+pub struct Worker {
+  pub state: AppState,
+  pub concurrency: u32,
+}
+
+impl Worker {
+  pub fn new(state: AppState, concurrency: u32) -> Self {
+    Worker { state, concurrency }
+  }
+
+  /// Claims due jobs whenever a slot is free until `shutdown` is cancelled, then waits for those running to finish.
+  pub async fn run(self, shutdown: CancellationToken) {
+    let mut running = JoinSet::new();
+    let mut poll = tokio::time::interval(POLL);
+    loop {
+      tokio::select! {
+        _ = shutdown.cancelled() => break,
+        _ = poll.tick() => {}
+        Some(_) = running.join_next(), if !running.is_empty() => {}
+      }
+      let free = self.concurrency.saturating_sub(running.len() as u32);
+      if free == 0 {
+        continue;
+      }
+      match self.claim(free).await {
+        Ok(jobs) => {
+          for job in jobs {
+            let worker = self.clone();
+            running.spawn(async move { worker.execute(job).await });
+          }
+        }
+        Err(err) => tracing::error!(error = %err, "claiming jobs failed"),
+      }
+    }
+    while running.join_next().await.is_some() {}
+  }
+
+  async fn claim(&self, limit: u32) -> Result<Vec<Job>, sqlx::Error> {
+    let mut tx = self.state.db.conn.begin().await?;
+    let due = Job::claim_due(&mut tx, limit, Utc::now() + LEASE).await?;
+    tx.commit().await?;
+    Ok(due)
+  }
+
+  /// Runs one claimed job and records how it went.
+  pub async fn execute(&self, job: Job) {
+    let outcome = match serde_json::from_str::<Task>(&job.payload) {
+      Ok(task) => cache::settle_after(task.run(&self.state)).await,
+      Err(err) => Err(format!("unreadable payload: {}", err)),
+    };
+
+    let claim = job.claim.as_deref().unwrap_or_default();
+    let recorded = async {
+      let mut tx = self.state.db.conn.begin().await?;
+      let held = match &outcome {
+        Ok(result) => Job::mark_succeeded(&mut tx, job.id, claim, result).await?,
+        Err(error) => {
+          let retry_at = (job.attempts < job.max_attempts).then(|| Utc::now() + backoff(job.attempts));
+          if retry_at.is_none() {
+            tracing::warn!(job = job.id, kind = %job.kind, error = %error, "job failed for good");
+          }
+          Job::mark_failed(&mut tx, job.id, claim, error, retry_at).await?
+        }
+      };
+      tx.commit().await?;
+      Ok::<bool, sqlx::Error>(held)
+    }
+    .await;
+    match recorded {
+      Ok(true) => {}
+      Ok(false) => tracing::warn!(job = job.id, kind = %job.kind, "job outlived its lease; outcome discarded"),
+      Err(err) => tracing::error!(job = job.id, error = %err, "recording job outcome failed"),
+    }
+  }
+}
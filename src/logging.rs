@@ -0,0 +1,70 @@
+//! Logging. Everything is emitted through `tracing`: each HTTP request runs in a `request` span carrying its trace id,
+//! and each `db` function in a debug-level span naming the entity and id it works on, so a statement logged by sqlx, or
+//! a slow-statement warning, can be traced back to the request that ran it.
+
+use axum::{
+  extract::Request,
+  http::{HeaderName, HeaderValue},
+  middleware::Next,
+  response::Response,
+};
+use tracing::{field, info_span, Instrument, Level};
+use tracing_subscriber::{filter::Targets, fmt::format::FmtSpan, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+use crate::{config::LoggingConfig, metrics::QueryTimings};
+
+/// The header a trace id is read from and echoed back in, so a client or proxy can correlate its own logs with ours.
+pub const TRACE_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest trace id accepted from a client; longer ones are replaced rather than logged.
+const MAX_TRACE_ID: usize = 128;
+
+/// Installs the global subscriber. Spans are logged when they close, with how long they took.
+///
+/// The `db` spans are also timed for the query latency metrics, whatever the log level.
+pub fn init(config: &LoggingConfig) {
+  let filter = EnvFilter::try_new(&config.level).unwrap_or_else(|_| EnvFilter::new("info"));
+  let fmt = tracing_subscriber::fmt::layer().with_span_events(FmtSpan::CLOSE);
+  let fmt = match config.format.as_str() {
+    "json" => fmt.json().with_current_span(true).with_span_list(true).boxed(),
+    _ => fmt.pretty().boxed(),
+  };
+  tracing_subscriber::registry()
+    .with(fmt.with_filter(filter))
+    .with(QueryTimings.with_filter(Targets::new().with_target("libby_rs::db", Level::DEBUG)))
+    .init();
+}
+
+/// The client's trace id if it sent a usable one, else a new one.
+pub fn trace_id(request: &Request) -> String {
+  let sent = request.headers().get(TRACE_HEADER).and_then(|value| value.to_str().ok());
+  match sent {
+    Some(id) if !id.is_empty() && id.len() <= MAX_TRACE_ID && id.chars().all(|c| c.is_ascii_graphic()) => id.to_string(),
+    _ => uuid::Uuid::new_v4().simple().to_string(),
+  }
+}
+
+/// Middleware running each request in its own span. The span is logged when the response is ready, with its status;
+/// server errors are also logged as errors.
+pub async fn trace_requests(request: Request, next: Next) -> Response {
+  let trace_id = trace_id(&request);
+  let span = info_span!(
+    "request",
+    trace_id = %trace_id,
+    method = %request.method(),
+    path = %request.uri().path(),
+    status = field::Empty,
+  );
+
+  let mut response = next.run(request).instrument(span.clone()).await;
+  let status = response.status();
+  span.record("status", status.as_u16());
+  if status.is_server_error() {
+    tracing::error!(parent: &span, status = status.as_u16(), "request failed");
+  }
+
+  if let Ok(value) = HeaderValue::from_str(&trace_id) {
+    response.headers_mut().insert(TRACE_HEADER, value);
+  }
+  response
+}
@@ -1,21 +1,166 @@
-use {db::Db, dotenv::dotenv, sqlx::Error, std::process::exit};
+use {
+  api::AppState,
+  config::Config,
+  covers::Covers,
+  db::Db,
+  dotenv::dotenv,
+  std::{process::exit, sync::Arc, time::Duration},
+  storage::Storage,
+  tokio::task::JoinSet,
+  tokio_util::sync::CancellationToken,
+};
 
+pub mod api;
+pub mod backup;
+pub mod config;
+pub mod covers;
 pub mod db;
+pub mod jobs;
+pub mod logging;
+pub mod metrics;
+pub mod schedule;
+pub mod shutdown;
+pub mod storage;
+// The baseline tests predate the lint settings and are kept as they were written.
+#[allow(clippy::clone_on_copy, clippy::bool_assert_comparison)]
 pub mod test;
+pub mod transfer;
+pub mod trash;
+pub mod webhooks;
 
 #[tokio::main]
 async fn main() -> Result<(), sqlx::Error> {
   dotenv().ok();
 
-  let conn_str = std::env::var("DATABASE_URL").expect("DATABASE URL NOT PRESENT IN ENVIRONMENT");
+  let args = match config::Args::parse(std::env::args().skip(1)) {
+    Ok(args) => args,
+    Err(err) => {
+      eprintln!("{}", err);
+      exit(2);
+    }
+  };
+  let config = match Config::load(&args, |var| std::env::var(var).ok()) {
+    Ok(config) => config,
+    Err(err) => {
+      eprintln!("{}", err);
+      exit(2);
+    }
+  };
+  logging::init(&config.logging);
 
-  let database = Db::new(&conn_str).await?;
+  // `libby-rs backup <archive>` and `libby-rs restore <archive>` run once and exit instead of serving.
+  if let Some(command) = args.command.first() {
+    let [_, archive] = args.command.as_slice() else {
+      eprintln!("usage: libby-rs [--config <file>] [--<section>.<key> <value>]... [backup|restore <archive>]");
+      exit(2);
+    };
+    let archive = std::path::Path::new(archive);
+    let (files, covers) = (Storage::new(&config.storage.files), Storage::new(&config.storage.covers));
+    let result = match command.as_str() {
+      "backup" => backup::backup(&Db::open(&config.database).await?, &files, &covers, archive).await,
+      "restore" => backup::restore(&Db::connect(&config.database.url).await?, &files, &covers, archive).await,
+      _ => {
+        eprintln!("usage: libby-rs [--config <file>] [--<section>.<key> <value>]... [backup|restore <archive>]");
+        exit(2);
+      }
+    };
+    match result {
+      Ok(manifest) => {
+        let rows: usize = manifest.tables.iter().map(|table| table.rows).sum();
+        println!(
+          "{} {}: schema v{}, {} tables, {} rows, {} files, {} covers",
+          command,
+          archive.display(),
+          manifest.schema_version,
+          manifest.tables.len(),
+          rows,
+          manifest.files.len(),
+          manifest.covers.len()
+        );
+        exit(0);
+      }
+      Err(err) => {
+        eprintln!("{} failed: {}", command, err);
+        exit(1);
+      }
+    }
+  }
 
+  let database = Db::open(&config.database).await?;
+  db::cache::configure(&config.cache);
+  // Background tasks stop taking new work once `shutdown` is cancelled, and are waited on like in-flight requests.
+  let shutdown = CancellationToken::new();
+  let state = AppState {
+    db: database.clone(),
+    storage: Storage::new(&config.storage.files),
+    covers: Covers {
+      storage: Storage::new(&config.storage.covers),
+      sizes: config.storage.cover_sizes.clone(),
+      format: config.cover_format(),
+    },
+    config: Arc::new(config.clone()),
+    shutdown: shutdown.clone(),
+  };
+  let app = api::router(state.clone());
+
+  let mut background = JoinSet::new();
+
+  if config.features.webhooks {
+    let dispatcher = webhooks::Dispatcher::new(database.clone(), config.webhooks.max_attempts, config.webhooks.disable_after);
+    background.spawn(shutdown::every(Duration::from_secs(5), shutdown.clone(), move || {
+      let dispatcher = dispatcher.clone();
+      async move {
+        if let Err(err) = dispatcher.run_once().await {
+          tracing::error!(error = %err, "webhook delivery failed");
+        }
+      }
+    }));
+  }
+
+  if config.features.jobs {
+    background.spawn(jobs::Worker::new(state.clone(), config.jobs.concurrency).run(shutdown.clone()));
+  }
+
+  // Trash purges, backups and the other recurring tasks are queued as jobs, by whichever server claims each run first.
+  let scheduler = schedule::Scheduler::new(database.clone(), &config).expect("validated cron expressions parse");
+  if !scheduler.entries.is_empty() {
+    background.spawn(shutdown::every(Duration::from_secs(30), shutdown.clone(), move || {
+      let scheduler = scheduler.clone();
+      async move {
+        if let Err(err) = scheduler.run_once(chrono::Utc::now()).await {
+          tracing::error!(error = %err, "scheduling tasks failed");
+        }
+      }
+    }));
+  }
+
+  let signal = shutdown.clone();
   tokio::spawn(async move {
-    tokio::signal::ctrl_c().await.unwrap();
-    database.conn.close().await;
-    exit(0);
+    match shutdown::signalled().await {
+      Ok(name) => tracing::info!(signal = name, "shutting down"),
+      Err(err) => tracing::error!(error = %err, "cannot listen for signals, shutting down"),
+    }
+    signal.cancel();
   });
 
-  loop {}
+  let listener = tokio::net::TcpListener::bind(&config.server.bind_address).await?;
+  tracing::info!(address = %config.server.bind_address, "listening");
+  let deadline = Duration::from_secs(config.server.shutdown_timeout_secs);
+  let stopped = shutdown::serve(listener, app, background, shutdown, deadline).await;
+
+  // Anything cut off at the deadline has been aborted, so its connections are back in the pool; closing only waits on
+  // those still checked out, which the timeout bounds regardless.
+  if tokio::time::timeout(Duration::from_secs(5), database.conn.close()).await.is_err() {
+    tracing::error!("database pool did not close in time");
+  }
+  match stopped {
+    Ok(()) => {
+      tracing::info!("stopped");
+      Ok(())
+    }
+    Err(err) => {
+      tracing::error!(error = %err, "shutdown failed");
+      exit(1);
+    }
+  }
 }
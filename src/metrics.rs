@@ -0,0 +1,134 @@
+//! Prometheus metrics, served at `/metrics`. Request counts and latencies are recorded by [`track_requests`], query
+//! latencies by [`QueryTimings`] from the span each `db` function runs in, cache hits and misses by each
+//! [`EntityCache`](crate::db::cache::EntityCache), and the pool and catalog gauges are read fresh on every scrape.
+
+use std::time::Instant;
+
+use axum::{
+  extract::{MatchedPath, Request},
+  middleware::Next,
+  response::Response,
+};
+use lazy_static::lazy_static;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use tracing::{
+  field::{Field, Visit},
+  span, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// Query latency buckets, in seconds: most statements take a few milliseconds, and anything past a second is a problem.
+const QUERY_BUCKETS: &[f64] = &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+lazy_static! {
+  pub static ref REGISTRY: Registry = Registry::new_custom(Some(String::from("libby")), None).unwrap();
+  pub static ref HTTP_REQUESTS: IntCounterVec = register(IntCounterVec::new(
+    Opts::new("http_requests_total", "HTTP requests answered, by route and status"),
+    &["method", "route", "status"]
+  ));
+  pub static ref HTTP_DURATION: HistogramVec = register(HistogramVec::new(
+    HistogramOpts::new("http_request_duration_seconds", "Time taken to answer HTTP requests, by route"),
+    &["method", "route"]
+  ));
+  pub static ref QUERY_DURATION: HistogramVec = register(HistogramVec::new(
+    HistogramOpts::new("db_query_duration_seconds", "Time taken by database operations, by entity and operation").buckets(QUERY_BUCKETS.to_vec()),
+    &["entity", "operation"]
+  ));
+  pub static ref CACHE_LOOKUPS: IntCounterVec = register(IntCounterVec::new(
+    Opts::new("cache_lookups_total", "Cached record lookups, by entity and whether they were a hit or a miss"),
+    &["entity", "result"]
+  ));
+  pub static ref POOL_ACTIVE: IntGauge = register(IntGauge::new("db_pool_active_connections", "Pool connections in use"));
+  pub static ref POOL_IDLE: IntGauge = register(IntGauge::new("db_pool_idle_connections", "Pool connections open and idle"));
+  pub static ref BOOKS: IntGauge = register(IntGauge::new("books", "Books in the catalog, not counting the trash"));
+  pub static ref USERS: IntGauge = register(IntGauge::new("users", "Registered users"));
+  pub static ref ACTIVE_READERS: IntGauge = register(IntGauge::new(
+    "active_readers_today",
+    "Users who have updated their progress since midnight UTC"
+  ));
+}
+
+fn register<M: prometheus::core::Collector + Clone + 'static>(metric: prometheus::Result<M>) -> M {
+  let metric = metric.unwrap();
+  REGISTRY.register(Box::new(metric.clone())).unwrap();
+  metric
+}
+
+/// Every metric in the Prometheus text format, with its content type.
+pub fn render() -> (&'static str, Vec<u8>) {
+  let encoder = TextEncoder::new();
+  let mut buffer = Vec::new();
+  // Encoding into a `Vec` only fails on malformed metrics, which `register` would have refused.
+  encoder.encode(&REGISTRY.gather(), &mut buffer).unwrap();
+  (prometheus::TEXT_FORMAT, buffer)
+}
+
+/// Middleware counting and timing each request. Requests are labelled with the route they matched, such as
+/// `/books/{id}`, rather than their path, so there is one series per route however many records are fetched.
+pub async fn track_requests(request: Request, next: Next) -> Response {
+  let method = request.method().to_string();
+  let route = match request.extensions().get::<MatchedPath>() {
+    Some(path) => path.as_str().to_string(),
+    None => String::from("unmatched"),
+  };
+  let started = Instant::now();
+
+  let response = next.run(request).await;
+  HTTP_DURATION.with_label_values(&[&method, &route]).observe(started.elapsed().as_secs_f64());
+  HTTP_REQUESTS.with_label_values(&[&method, &route, response.status().as_str()]).inc();
+  response
+}
+
+/// A tracing layer observing [`QUERY_DURATION`] when a `db` span closes, labelled with its `entity` field and its name,
+/// which is that of the function, such as `fetch_one`.
+pub struct QueryTimings;
+
+struct Timing {
+  started: Instant,
+  entity: String,
+}
+
+#[derive(Default)]
+struct EntityVisitor(Option<String>);
+
+impl Visit for EntityVisitor {
+  fn record_str(&mut self, field: &Field, value: &str) {
+    if field.name() == "entity" {
+      self.0 = Some(value.to_string());
+    }
+  }
+
+  fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+    if field.name() == "entity" {
+      self.0 = Some(format!("{:?}", value));
+    }
+  }
+}
+
+impl<S> Layer<S> for QueryTimings
+where
+  S: Subscriber + for<'a> LookupSpan<'a>,
+{
+  fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+    let mut visitor = EntityVisitor::default();
+    attrs.record(&mut visitor);
+    if let (Some(entity), Some(span)) = (visitor.0, ctx.span(id)) {
+      span.extensions_mut().insert(Timing {
+        started: Instant::now(),
+        entity,
+      });
+    }
+  }
+
+  fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+    let Some(span) = ctx.span(&id) else {
+      return;
+    };
+    let extensions = span.extensions();
+    if let Some(timing) = extensions.get::<Timing>() {
+      QUERY_DURATION
+        .with_label_values(&[&timing.entity, span.name()])
+        .observe(timing.started.elapsed().as_secs_f64());
+    }
+  }
+}
@@ -0,0 +1,104 @@
+//! Recurring tasks: backups, trash purges, orphan cleanup and the daily stats rollup, each run on the cron schedule set
+//! in the `[schedule]` config. A due run is not done here but queued as a [job](crate::jobs), so it gets the workers'
+//! retries and shows up in `/jobs`.
+//!
+//! Every server runs a [`Scheduler`], and each run is claimed through its `scheduled_task` row, which only the first
+//! server to ask can move on to that run. So however many servers share the database, each run is queued once. Runs
+//! missed while no server was up are collapsed into one, queued as soon as a server comes back.
+
+use chrono::{DateTime, Utc};
+use croner::{errors::CronError, Cron};
+
+use crate::{
+  config::Config,
+  db::{schedule::ScheduledTask, Db},
+  jobs::{self, Task},
+};
+
+/// Parses a five-field cron expression, read in UTC.
+pub fn parse(expression: &str) -> Result<Cron, CronError> {
+  Cron::new(expression).parse()
+}
+
+/// The latest time `cron` fell due after `last` and no later than `now`, if any.
+pub fn latest_due(cron: &Cron, last: DateTime<Utc>, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+  cron.iter_after(last).take_while(|due| *due <= now).last()
+}
+
+/// The next time `cron` falls due after `after`.
+pub fn next_due(cron: &Cron, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+  cron.find_next_occurrence(&after, false).ok()
+}
+
+#[derive(Clone)]
+pub struct Scheduler {
+  pub db: Db,
+  /// Each task switched on, by name, with its schedule.
+  pub entries: Vec<(&'static str, Cron)>,
+  pub trash_days: i64,
+  pub audit_days: i64,
+  pub change_days: i64,
+  pub max_attempts: u32,
+}
+
+impl Scheduler {
+  /// The config has been validated, so its expressions parse.
+  pub fn new(db: Db, config: &Config) -> Result<Self, CronError> {
+    let entries = config
+      .schedule
+      .expressions()
+      .into_iter()
+      .map(|(name, expression)| Ok((name, parse(expression)?)))
+      .collect::<Result<Vec<(&'static str, Cron)>, CronError>>()?;
+    Ok(Scheduler {
+      db,
+      entries,
+      trash_days: config.retention.trash_days,
+      audit_days: config.retention.audit_days,
+      change_days: config.retention.change_days,
+      max_attempts: config.jobs.max_attempts,
+    })
+  }
+
+  /// The job to queue for the run of `name` due at `due`, if it has anything to do.
+  pub fn task(&self, name: &str, due: DateTime<Utc>) -> Option<Task> {
+    match name {
+      "backup" => Some(Task::Backup {
+        name: format!("libby-{}.zip", due.format("%Y%m%d-%H%M")),
+      }),
+      "purge_trash" => (self.trash_days > 0).then_some(Task::PurgeTrash { days: self.trash_days }),
+      "prune_audit" => (self.audit_days > 0).then_some(Task::PruneAudit { days: self.audit_days }),
+      "prune_changes" => (self.change_days > 0).then_some(Task::PruneChanges { days: self.change_days }),
+      "cleanup_orphans" => Some(Task::CleanupOrphans),
+      "stats_rollup" => Some(Task::StatsRollup { date: due.date_naive() }),
+      _ => None,
+    }
+  }
+
+  /// Queues every run that has fallen due and no other server has claimed, returning how many were queued.
+  pub async fn run_once(&self, now: DateTime<Utc>) -> Result<usize, sqlx::Error> {
+    let mut queued = 0;
+    for (name, cron) in &self.entries {
+      let mut tx = self.db.conn.begin().await?;
+      let Some(last) = ScheduledTask::fetch_one(&mut tx, name).await? else {
+        ScheduledTask::register(&mut tx, name, now).await?;
+        tx.commit().await?;
+        continue;
+      };
+      let Some(due) = latest_due(cron, last.last_run_at, now) else {
+        continue;
+      };
+      if !ScheduledTask::claim(&mut tx, name, last.last_run_at, due).await? {
+        continue;
+      }
+      if let Some(task) = self.task(name, due) {
+        let job = jobs::enqueue(&mut tx, &task, None, self.max_attempts).await?;
+        ScheduledTask::set_job(&mut tx, name, job.id).await?;
+        tracing::info!(task = name, job = job.id, due = %due, "scheduled task queued");
+        queued += 1;
+      }
+      tx.commit().await?;
+    }
+    Ok(queued)
+  }
+}
@@ -0,0 +1,106 @@
+//! The server's lifecycle. It serves until SIGINT or SIGTERM, then stops accepting connections and gives in-flight
+//! requests and background jobs until the configured deadline to finish. Whatever is still running then is cut off and
+//! the shutdown counts as failed, so the process exits non-zero.
+
+use std::{fmt, future::Future, io, time::Duration};
+
+use axum::Router;
+use tokio::{net::TcpListener, task::JoinSet};
+use tokio_util::sync::CancellationToken;
+
+#[derive(Debug)]
+pub enum ShutdownError {
+  /// The server stopped on its own with an error.
+  Server(io::Error),
+  /// A background job panicked.
+  Panicked(tokio::task::JoinError),
+  /// Requests or jobs were still running at the deadline. Holds how many jobs were cut off.
+  Deadline(usize),
+}
+
+impl fmt::Display for ShutdownError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ShutdownError::Server(err) => write!(f, "server failed: {}", err),
+      ShutdownError::Panicked(err) => write!(f, "background job panicked: {}", err),
+      ShutdownError::Deadline(jobs) => write!(f, "still running at the deadline, {} background jobs cut off", jobs),
+    }
+  }
+}
+
+impl std::error::Error for ShutdownError {}
+
+/// Resolves with the signal's name on the first SIGINT or SIGTERM.
+pub async fn signalled() -> io::Result<&'static str> {
+  #[cfg(unix)]
+  {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+      interrupted = tokio::signal::ctrl_c() => interrupted.map(|_| "SIGINT"),
+      _ = terminate.recv() => Ok("SIGTERM"),
+    }
+  }
+  #[cfg(not(unix))]
+  {
+    tokio::signal::ctrl_c().await.map(|_| "SIGINT")
+  }
+}
+
+/// Runs `job` every `period`, starting now, until `shutdown` is cancelled. A run already under way is let finish.
+pub async fn every<F, Fut>(period: Duration, shutdown: CancellationToken, mut job: F)
+where
+  F: FnMut() -> Fut,
+  Fut: Future<Output = ()>,
+{
+  let mut interval = tokio::time::interval(period);
+  loop {
+    tokio::select! {
+      _ = shutdown.cancelled() => return,
+      _ = interval.tick() => job().await,
+    }
+  }
+}
+
+/// Serves `app` until `shutdown` is cancelled, then waits up to `deadline` for in-flight requests and `jobs` to finish.
+/// If the server fails first, the jobs are asked to stop too.
+pub async fn serve(listener: TcpListener, app: Router, mut jobs: JoinSet<()>, shutdown: CancellationToken, deadline: Duration) -> Result<(), ShutdownError> {
+  let stopping = shutdown.clone().cancelled_owned();
+  let mut server = tokio::spawn(async move { axum::serve(listener, app).with_graceful_shutdown(stopping).await });
+
+  let stopped = tokio::select! {
+    served = &mut server => {
+      shutdown.cancel();
+      Some(served)
+    }
+    _ = shutdown.cancelled() => None,
+  };
+
+  let drained = tokio::time::timeout(deadline, async {
+    let served = match stopped {
+      Some(served) => served,
+      None => (&mut server).await,
+    };
+    let mut result = match served {
+      Ok(Ok(())) => Ok(()),
+      Ok(Err(err)) => Err(ShutdownError::Server(err)),
+      Err(err) => Err(ShutdownError::Panicked(err)),
+    };
+    while let Some(joined) = jobs.join_next().await {
+      if let Err(err) = joined {
+        result = result.and(Err(ShutdownError::Panicked(err)));
+      }
+    }
+    result
+  })
+  .await;
+
+  match drained {
+    Ok(result) => result,
+    Err(_) => {
+      server.abort();
+      let cut_off = jobs.len();
+      jobs.shutdown().await;
+      Err(ShutdownError::Deadline(cut_off))
+    }
+  }
+}
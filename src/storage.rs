@@ -0,0 +1,253 @@
+use std::{
+  collections::HashSet,
+  io,
+  path::{Path, PathBuf},
+  sync::atomic::{AtomicU64, Ordering},
+  time::{Duration, SystemTime},
+};
+
+use futures_util::{Stream, StreamExt};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use tokio::{
+  fs::{self, File},
+  io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom},
+};
+
+/// How many leading bytes of an upload are kept for format sniffing.
+const HEAD_LEN: usize = 4096;
+
+/// How long a blob is left alone after it was written, or uploaded again, before it may be removed as unreferenced. An
+/// upload stores its blob before the row pointing at it commits, so anything younger may be about to gain one.
+pub const ORPHAN_GRACE: Duration = Duration::from_secs(60 * 60);
+
+static UPLOAD_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Content-addressed blob store on the local filesystem, laid out as `<root>/ab/cd/abcd…` by SHA-256.
+#[derive(Debug, Clone)]
+pub struct Storage {
+  root: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredFile {
+  pub sha256: String,
+  pub size: u64,
+  pub head: Vec<u8>,
+}
+
+impl Storage {
+  pub fn new(root: impl Into<PathBuf>) -> Self {
+    Storage { root: root.into() }
+  }
+
+  pub fn root(&self) -> &Path {
+    &self.root
+  }
+
+  fn tmp_dir(&self) -> PathBuf {
+    self.root.join("tmp")
+  }
+
+  pub fn path_for(&self, sha256: &str) -> PathBuf {
+    self.root.join(&sha256[0..2]).join(&sha256[2..4]).join(sha256)
+  }
+
+  /// Streams `body` to disk while hashing it, then moves it into place. Identical content is only kept once.
+  pub async fn store<S, B, E>(&self, mut body: S) -> io::Result<StoredFile>
+  where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+    E: std::error::Error + Send + Sync + 'static,
+  {
+    fs::create_dir_all(self.tmp_dir()).await?;
+    let tmp_path = self
+      .tmp_dir()
+      .join(format!("upload-{}-{}", std::process::id(), UPLOAD_COUNTER.fetch_add(1, Ordering::Relaxed)));
+
+    let mut tmp = File::create(&tmp_path).await?;
+    let mut hasher = Sha256::new();
+    let mut size = 0u64;
+    let mut head = Vec::with_capacity(HEAD_LEN);
+
+    let written: io::Result<()> = async {
+      while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(io::Error::other)?;
+        let chunk = chunk.as_ref();
+        hasher.update(chunk);
+        size += chunk.len() as u64;
+        if head.len() < HEAD_LEN {
+          head.extend_from_slice(&chunk[..chunk.len().min(HEAD_LEN - head.len())]);
+        }
+        tmp.write_all(chunk).await?;
+      }
+      tmp.sync_all().await
+    }
+    .await;
+
+    if let Err(err) = written {
+      let _ = fs::remove_file(&tmp_path).await;
+      return Err(err);
+    }
+
+    let sha256 = hex::encode(hasher.finalize());
+    let path = self.path_for(&sha256);
+    if fs::try_exists(&path).await? {
+      fs::remove_file(&tmp_path).await?;
+      // Restarts the grace period, so a cleanup that already judged the blob unreferenced leaves it for this upload.
+      let existing = File::options().write(true).open(&path).await?.into_std().await;
+      tokio::task::spawn_blocking(move || existing.set_modified(SystemTime::now()))
+        .await
+        .map_err(io::Error::other)??;
+    } else {
+      fs::create_dir_all(path.parent().expect("blob paths are nested")).await?;
+      fs::rename(&tmp_path, &path).await?;
+    }
+
+    Ok(StoredFile { sha256, size, head })
+  }
+
+  pub async fn store_bytes(&self, bytes: &[u8]) -> io::Result<StoredFile> {
+    self.store(futures_util::stream::iter([Ok::<_, io::Error>(bytes)])).await
+  }
+
+  pub async fn open(&self, sha256: &str) -> io::Result<File> {
+    File::open(self.path_for(sha256)).await
+  }
+
+  pub async fn exists(&self, sha256: &str) -> io::Result<bool> {
+    fs::try_exists(self.path_for(sha256)).await
+  }
+
+  pub async fn remove(&self, sha256: &str) -> io::Result<()> {
+    match fs::remove_file(self.path_for(sha256)).await {
+      Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+      result => result,
+    }
+  }
+
+  /// Removes the blob unless it was written within `grace`. Returns whether it was removed.
+  pub async fn remove_if_older(&self, sha256: &str, grace: Duration) -> io::Result<bool> {
+    let modified = match fs::metadata(self.path_for(sha256)).await {
+      Ok(metadata) => metadata.modified()?,
+      Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+      Err(err) => return Err(err),
+    };
+    if modified.elapsed().is_ok_and(|age| age >= grace) {
+      self.remove(sha256).await?;
+      Ok(true)
+    } else {
+      Ok(false)
+    }
+  }
+
+  /// Writes and removes a scratch file in the upload directory, which fails if uploads would.
+  pub async fn check_writable(&self) -> io::Result<()> {
+    fs::create_dir_all(self.tmp_dir()).await?;
+    let probe = self
+      .tmp_dir()
+      .join(format!("probe-{}-{}", std::process::id(), UPLOAD_COUNTER.fetch_add(1, Ordering::Relaxed)));
+    let mut file = File::create(&probe).await?;
+    let written = async {
+      file.write_all(b"ok").await?;
+      file.sync_all().await
+    }
+    .await;
+    drop(file);
+    fs::remove_file(&probe).await?;
+    written
+  }
+
+  /// Re-hashes a blob and reports whether it still matches its address. Missing blobs are reported as an error.
+  pub async fn verify(&self, sha256: &str) -> io::Result<bool> {
+    let mut file = self.open(sha256).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+      let read = file.read(&mut buf).await?;
+      if read == 0 {
+        break;
+      }
+      hasher.update(&buf[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()) == sha256)
+  }
+
+  /// Every blob currently on disk, by hash.
+  pub async fn list(&self) -> io::Result<Vec<String>> {
+    let mut hashes = Vec::new();
+    for first in read_dirs(&self.root).await? {
+      if first.file_name().is_some_and(|name| name == "tmp") {
+        continue;
+      }
+      for second in read_dirs(&first).await? {
+        let mut entries = fs::read_dir(&second).await?;
+        while let Some(entry) = entries.next_entry().await? {
+          if entry.file_type().await?.is_file() {
+            hashes.push(entry.file_name().to_string_lossy().into_owned());
+          }
+        }
+      }
+    }
+    Ok(hashes)
+  }
+
+  /// Deletes blobs no longer referenced by any row in `known` and older than `grace`, plus abandoned uploads. Returns the
+  /// removed hashes.
+  pub async fn cleanup_orphans(&self, known: &HashSet<String>, grace: Duration) -> io::Result<Vec<String>> {
+    let mut removed = Vec::new();
+    for sha256 in self.list().await? {
+      if !known.contains(&sha256) && self.remove_if_older(&sha256, grace).await? {
+        removed.push(sha256);
+      }
+    }
+
+    if fs::try_exists(self.tmp_dir()).await? {
+      let mut entries = fs::read_dir(self.tmp_dir()).await?;
+      while let Some(entry) = entries.next_entry().await? {
+        let abandoned = entry.metadata().await?.modified()?.elapsed().is_ok_and(|age| age.as_secs() > 24 * 60 * 60);
+        if abandoned {
+          fs::remove_file(entry.path()).await?;
+        }
+      }
+    }
+
+    Ok(removed)
+  }
+
+  /// KOReader's "partial MD5" document hash: 1 KiB samples at offsets 0 and 1024 << 2i for i in 0..=10.
+  pub async fn koreader_hash(&self, sha256: &str) -> io::Result<String> {
+    let mut file = self.open(sha256).await?;
+    let len = file.metadata().await?.len();
+    let mut hasher = Md5::new();
+    let mut sample = Vec::with_capacity(1024);
+
+    for i in -1i32..=10 {
+      let offset = if i < 0 { 0 } else { 1024u64 << (2 * i) };
+      if offset >= len {
+        break;
+      }
+      file.seek(SeekFrom::Start(offset)).await?;
+      sample.clear();
+      (&mut file).take(1024).read_to_end(&mut sample).await?;
+      hasher.update(&sample);
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+  }
+}
+
+async fn read_dirs(path: &Path) -> io::Result<Vec<PathBuf>> {
+  let mut dirs = Vec::new();
+  let mut entries = match fs::read_dir(path).await {
+    Ok(entries) => entries,
+    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(dirs),
+    Err(err) => return Err(err),
+  };
+  while let Some(entry) = entries.next_entry().await? {
+    if entry.file_type().await?.is_dir() {
+      dirs.push(entry.path());
+    }
+  }
+  Ok(dirs)
+}
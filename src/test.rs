@@ -88,3 +88,1708 @@ async fn authors_delete() -> Result<(), sqlx::Error> {
 
   Ok(())
 }
+
+#[tokio::test]
+async fn authors_update_if_conflicts() -> Result<(), sqlx::Error> {
+  use crate::db::UpdateError;
+
+  let mut tx = create_tx().await;
+  let author = authors::Author::create(
+    &mut tx,
+    authors::PartialAuthor {
+      name: Some(String::from("TEST AUTHOR")),
+      description: Some(String::from("TEST DESCRIPTION")),
+      birth: chrono::NaiveDate::from_ymd_opt(1900, 1, 1),
+    },
+  )
+  .await?;
+  let edit = |description: Option<&str>| authors::PartialAuthor {
+    name: Some(String::from("TEST AUTHOR")),
+    description: description.map(String::from),
+    birth: None,
+  };
+
+  // An edit at the current version applies, bumps the version and clears what it leaves empty
+  let edited = match authors::Author::update_if(&mut tx, author.id, author.version, edit(Some("EDITED"))).await {
+    Ok(edited) => edited,
+    Err(err) => panic!("expected the edit to apply, got {:?}", err),
+  };
+  assert_eq!(edited.version, author.version + 1);
+  assert_eq!(edited.description.as_deref(), Some("EDITED"));
+  assert_eq!(edited.birth, None);
+
+  // One based on the version before it is refused with the version now stored
+  match authors::Author::update_if(&mut tx, author.id, author.version, edit(None)).await {
+    Err(UpdateError::Conflict(version)) => assert_eq!(version, edited.version),
+    other => panic!("expected a conflict, got {:?}", other),
+  }
+  assert_eq!(authors::Author::fetch_one(&mut tx, author.id).await?.description.as_deref(), Some("EDITED"));
+
+  // Trashing and restoring count as changes too
+  authors::Author::delete(&mut tx, author.id).await?;
+  let restored = authors::Author::restore(&mut tx, author.id).await?;
+  assert_eq!(restored.version, edited.version + 2);
+
+  Ok(())
+}
+
+#[tokio::test]
+async fn books_fetch_pages_in_order() -> Result<(), sqlx::Error> {
+  use crate::db::books::Book;
+
+  let mut tx = create_tx().await;
+  let first = Book::fetch_page(&mut tx, 2, 0).await?;
+  let second = Book::fetch_page(&mut tx, 2, 2).await?;
+  let all = Book::fetch_page(&mut tx, 4, 0).await?;
+
+  // Consecutive pages pick up where the last stopped
+  let paged: Vec<u64> = first.iter().chain(second.iter()).map(|book| book.id).collect();
+  assert_eq!(paged, all.iter().map(|book| book.id).collect::<Vec<u64>>());
+  assert!(paged.windows(2).all(|ids| ids[0] < ids[1]));
+
+  Ok(())
+}
+
+#[tokio::test]
+async fn books_match_isbns_however_written() -> Result<(), sqlx::Error> {
+  use crate::db::books::{normalize_isbn, Book, PartialBook};
+
+  assert_eq!(normalize_isbn(" 978-0-306-40615-7 "), "9780306406157");
+  assert_eq!(normalize_isbn("0-8044-2957-x"), "080442957X");
+
+  let mut tx = create_tx().await;
+  let book = Book::create_partial(
+    &mut tx,
+    PartialBook {
+      isbn: Some(String::from("978-0-306-40615-7")),
+      name: Some(String::from("TEST BOOK")),
+      description: None,
+      language: None,
+      nsfw: Some(false),
+      num_pages: Some(100),
+      image_formatted: Some(false),
+      publisher_id: None,
+      series_id: None,
+      series_index: None,
+      date_published: None,
+    },
+  )
+  .await?;
+
+  // Stored normalised, and found however the lookup writes it
+  assert_eq!(book.isbn, "9780306406157");
+  for written in ["9780306406157", "978 0 306 40615 7", "978-0-306-40615-7"] {
+    assert_eq!(Book::fetch_by_isbn(&mut tx, written).await?.map(|found| found.id), Some(book.id), "{}", written);
+  }
+  // No ISBN matches nothing, not every book without one
+  assert!(Book::fetch_by_isbn(&mut tx, "").await?.is_none());
+  assert!(Book::fetch_by_isbn(&mut tx, "n/a").await?.is_none());
+
+  Ok(())
+}
+
+#[tokio::test]
+async fn books_trash_restore_and_purge() -> Result<(), sqlx::Error> {
+  use crate::db::{
+    books::{Book, PartialBook},
+    progress::Progress,
+    tags::Tag,
+    user::User,
+  };
+
+  let mut tx = create_tx().await;
+  let book = Book::create_partial(
+    &mut tx,
+    PartialBook {
+      isbn: Some(String::from("9780000000002")),
+      name: Some(String::from("TEST BOOK")),
+      description: None,
+      language: Some(String::from("en")),
+      nsfw: Some(false),
+      num_pages: Some(100),
+      image_formatted: Some(false),
+      publisher_id: None,
+      series_id: None,
+      series_index: None,
+      date_published: None,
+    },
+  )
+  .await?;
+  let user = User::create(&mut tx, None, String::from("TEST READER")).await?;
+  Tag::set_for_book(&mut tx, book.id, &[String::from("TEST TAG")]).await?;
+  Progress::upsert(&mut tx, user.id, book.id, 10).await?;
+
+  // A trashed book is gone from lookups but listed in the trash, with what depends on it kept
+  Book::delete(&mut tx, book.id).await?;
+  assert!(matches!(Book::fetch_one(&mut tx, book.id).await, Err(sqlx::Error::RowNotFound)));
+  assert!(Book::fetch_by_isbn(&mut tx, "9780000000002").await?.is_none());
+  assert!(Book::is_trashed(&mut tx, book.id).await?);
+  assert!(Book::fetch_deleted(&mut tx).await?.iter().any(|trashed| trashed.id == book.id));
+  assert_eq!(Tag::fetch_by_book(&mut tx, book.id).await?.len(), 1);
+
+  // Restoring brings it back as it was
+  let restored = Book::restore(&mut tx, book.id).await?;
+  assert_eq!(restored.name, book.name);
+  assert!(!Book::is_trashed(&mut tx, book.id).await?);
+  assert!(matches!(Book::restore(&mut tx, book.id).await, Err(sqlx::Error::RowNotFound)));
+
+  // Only a trashed book can be purged, and purging takes its dependent rows with it
+  assert!(matches!(Book::purge(&mut tx, book.id).await, Err(sqlx::Error::RowNotFound)));
+  Book::delete(&mut tx, book.id).await?;
+  Book::purge(&mut tx, book.id).await?;
+  assert!(!Book::is_trashed(&mut tx, book.id).await?);
+  assert!(Book::fetch_deleted(&mut tx).await?.iter().all(|trashed| trashed.id != book.id));
+  assert!(Tag::fetch_by_book(&mut tx, book.id).await?.is_empty());
+  assert!(matches!(Progress::fetch_one(&mut tx, user.id, book.id).await, Err(sqlx::Error::RowNotFound)));
+
+  Ok(())
+}
+
+#[test]
+fn catalog_etags_and_preconditions() {
+  use crate::api::{
+    catalog::{fetched, if_match},
+    ApiError,
+  };
+  use axum::{
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::IntoResponse,
+  };
+  use serde_json::json;
+
+  let headers = |name: header::HeaderName, value: &'static str| {
+    let mut headers = HeaderMap::new();
+    headers.insert(name, HeaderValue::from_static(value));
+    headers
+  };
+
+  // If-Match names the version an edit was based on; none, or `*`, takes whatever is stored
+  assert_eq!(if_match(&HeaderMap::new()).unwrap(), None);
+  assert_eq!(if_match(&headers(header::IF_MATCH, "*")).unwrap(), None);
+  assert_eq!(if_match(&headers(header::IF_MATCH, "\"7\"")).unwrap(), Some(7));
+  assert!(matches!(if_match(&headers(header::IF_MATCH, "7")), Err(ApiError::BadRequest(_))));
+  assert!(matches!(if_match(&headers(header::IF_MATCH, "\"abc\"")), Err(ApiError::BadRequest(_))));
+
+  // Reads carry the version as their ETag, and are not sent again while the client has it
+  let read = fetched(&HeaderMap::new(), 3, json!({ "id": 1 }));
+  assert_eq!(read.status(), StatusCode::OK);
+  assert_eq!(read.headers()[header::ETAG], "\"3\"");
+  for current in ["\"3\"", "W/\"3\"", "\"1\", \"3\"", "*"] {
+    let read = fetched(&headers(header::IF_NONE_MATCH, current), 3, json!({ "id": 1 }));
+    assert_eq!(read.status(), StatusCode::NOT_MODIFIED, "{}", current);
+    assert_eq!(read.headers()[header::ETAG], "\"3\"");
+  }
+  assert_eq!(
+    fetched(&headers(header::IF_NONE_MATCH, "\"2\""), 3, json!({ "id": 1 })).status(),
+    StatusCode::OK
+  );
+
+  // A conflict answers 412 with the version now stored
+  let conflict = ApiError::Conflict(4).into_response();
+  assert_eq!(conflict.status(), StatusCode::PRECONDITION_FAILED);
+  assert_eq!(conflict.headers()[header::ETAG], "\"4\"");
+}
+
+#[tokio::test]
+async fn progress_and_users_are_versioned() -> Result<(), crate::db::UpdateError> {
+  use crate::db::{
+    books::{Book, PartialBook},
+    progress::Progress,
+    user::User,
+    UpdateError,
+  };
+
+  let mut tx = create_tx().await;
+  let user = User::create(&mut tx, None, String::from("TEST READER")).await?;
+  let book = Book::create_partial(
+    &mut tx,
+    PartialBook {
+      isbn: Some(String::from("9780000000200")),
+      name: Some(String::from("TEST BOOK")),
+      description: None,
+      language: None,
+      nsfw: Some(false),
+      num_pages: Some(100),
+      image_formatted: Some(false),
+      publisher_id: None,
+      series_id: None,
+      series_index: None,
+      date_published: None,
+    },
+  )
+  .await?;
+
+  // Every write bumps the version, and a device holding an old one is refused
+  let started = Progress::create(&mut tx, user.id, book.id, 1).await?;
+  let moved = Progress::update_if(&mut tx, user.id, book.id, started.version, 10).await?;
+  assert_eq!(moved.version, started.version + 1);
+  let stale = Progress::update_if(&mut tx, user.id, book.id, started.version, 5).await;
+  assert!(matches!(stale, Err(UpdateError::Conflict(version)) if version == moved.version));
+  assert_eq!(Progress::fetch_one(&mut tx, user.id, book.id).await?.current_page, 10);
+
+  let renamed = User::update_if(&mut tx, user.id, user.version, String::from("RENAMED READER")).await?;
+  assert_eq!(renamed.version, user.version + 1);
+  assert!(matches!(
+    User::update_if(&mut tx, user.id, user.version, String::from("STALE")).await,
+    Err(UpdateError::Conflict(_))
+  ));
+
+  Ok(())
+}
+
+#[tokio::test]
+async fn progress_finishing_fires_once() -> Result<(), sqlx::Error> {
+  use crate::db::{
+    books::{Book, PartialBook},
+    progress::Progress,
+    user::User,
+    webhooks::{Webhook, WebhookDelivery},
+  };
+
+  let mut tx = create_tx().await;
+  let webhook = Webhook::create(&mut tx, "https://example.invalid/hook", "TEST SECRET", &[String::from("book.finished")]).await?;
+  let user = User::create(&mut tx, None, String::from("TEST READER")).await?;
+  let mut books = Vec::new();
+  for name in ["TEST BOOK", "TEST BOOK 2"] {
+    let partial = PartialBook {
+      isbn: Some(String::new()),
+      name: Some(String::from(name)),
+      description: None,
+      language: None,
+      nsfw: Some(false),
+      num_pages: Some(100),
+      image_formatted: Some(false),
+      publisher_id: None,
+      series_id: None,
+      series_index: None,
+      date_published: None,
+    };
+    books.push(Book::create_partial(&mut tx, partial).await?);
+  }
+  let finished = |deliveries: Vec<WebhookDelivery>| deliveries.iter().filter(|delivery| delivery.event == "book.finished").count();
+
+  // Reading part of the way finishes nothing
+  let progress = Progress::read_to(&mut tx, user.id, &books[0], 50).await?;
+  assert_eq!(progress.date_finished, None);
+  assert_eq!(finished(WebhookDelivery::fetch_by_webhook(&mut tx, webhook.id, 100).await?), 0);
+
+  // Reaching the last page finishes the book, and only the first time
+  let progress = Progress::read_to(&mut tx, user.id, &books[0], 100).await?;
+  assert!(progress.date_finished.is_some());
+  Progress::read_to(&mut tx, user.id, &books[0], 100).await?;
+  assert_eq!(finished(WebhookDelivery::fetch_by_webhook(&mut tx, webhook.id, 100).await?), 1);
+
+  // Imported reading history is not news
+  Progress::upsert(&mut tx, user.id, books[1].id, 100).await?;
+  let read_long_ago = chrono::Utc::now() - chrono::Duration::days(400);
+  Progress::set_reading(&mut tx, user.id, books[1].id, Some(8), None, Some(read_long_ago)).await?;
+  assert_eq!(finished(WebhookDelivery::fetch_by_webhook(&mut tx, webhook.id, 100).await?), 1);
+
+  Ok(())
+}
+
+#[tokio::test]
+async fn opds_feed_render() {
+  use crate::api::opds::feed::{path_segment, Feed, FeedKind, Format, Link, Publication, REL_ACQUISITION};
+
+  let updated = chrono::Utc::now();
+  let feed = Feed {
+    id: String::from("urn:libby:opds/new"),
+    title: String::from("Newest Additions"),
+    kind: FeedKind::Acquisition,
+    updated,
+    links: vec![Link::new("self", Format::Atom.href("/new"), Format::Atom.feed_type(FeedKind::Acquisition))],
+    navigation: Vec::new(),
+    publications: vec![Publication {
+      id: String::from("urn:libby:book:1"),
+      title: String::from("Cats & <Dogs>"),
+      authors: vec![String::from("TEST AUTHOR")],
+      publisher: None,
+      language: Some(String::from("en")),
+      description: None,
+      isbn: String::from("9780000000000"),
+      series: Some((String::from("TEST SERIES"), Some(2))),
+      issued: None,
+      updated,
+      links: vec![Link::new(REL_ACQUISITION, String::from("/books/1/download"), "application/epub+zip")],
+    }],
+  };
+
+  // Atom output escapes text and carries the OPDS acquisition link
+  let atom = feed.to_atom();
+  assert!(atom.contains("<title>Cats &amp; &lt;Dogs&gt;</title>"));
+  assert!(atom.contains(r#"rel="http://opds-spec.org/acquisition" href="/books/1/download""#));
+  assert!(atom.contains("<dc:language>en</dc:language>"));
+
+  // JSON output lists the publication with its series position
+  let json = feed.to_json();
+  assert_eq!(json["publications"][0]["metadata"]["title"], "Cats & <Dogs>");
+  assert_eq!(json["publications"][0]["metadata"]["belongsTo"]["series"][0]["position"], 2);
+  assert_eq!(json["links"][0]["href"], "/opds/new");
+
+  // Free-text values are encoded before they become part of a link
+  assert_eq!(path_segment("en-GB"), "en-GB");
+  assert_eq!(path_segment("zh Hant/TW?x=1#a"), "zh%20Hant%2FTW%3Fx%3D1%23a");
+  assert_eq!(path_segment("fr\u{e7}"), "fr%C3%A7");
+}
+
+#[tokio::test]
+async fn opds_feed_without_cover_row() -> Result<(), crate::api::ApiError> {
+  use crate::{
+    api::opds::{acquisition_feed, feed::Format},
+    db::books::{Book, PartialBook},
+  };
+
+  let mut tx = create_tx().await;
+  let book = Book::create_partial(
+    &mut tx,
+    PartialBook {
+      isbn: Some(String::from("9780000000000")),
+      name: Some(String::from("TEST BOOK")),
+      description: None,
+      language: None,
+      nsfw: Some(false),
+      num_pages: Some(100),
+      image_formatted: Some(true),
+      publisher_id: None,
+      series_id: None,
+      series_index: None,
+      date_published: None,
+    },
+  )
+  .await?;
+
+  // A book flagged as having a cover but without its row is listed, just without image links
+  let feed = acquisition_feed(&mut tx, Format::Atom, "/new", "Newest Additions", vec![book]).await?;
+  assert_eq!(feed.publications.len(), 1);
+  assert!(feed.publications[0].links.is_empty());
+
+  Ok(())
+}
+
+#[tokio::test]
+async fn koreader_documents_keep_their_first_link() -> Result<(), sqlx::Error> {
+  use crate::db::{
+    books::{Book, PartialBook},
+    koreader::KoreaderDocument,
+  };
+
+  let mut tx = create_tx().await;
+  let mut books = Vec::new();
+  for (isbn, name) in [("9780000000101", "TEST BOOK"), ("9780000000102", "OTHER TEST BOOK")] {
+    let partial = PartialBook {
+      isbn: Some(String::from(isbn)),
+      name: Some(String::from(name)),
+      description: None,
+      language: None,
+      nsfw: Some(false),
+      num_pages: Some(100),
+      image_formatted: Some(false),
+      publisher_id: None,
+      series_id: None,
+      series_index: None,
+      date_published: None,
+    };
+    books.push(Book::create_partial(&mut tx, partial).await?);
+  }
+
+  // A reader can link an unknown document, but not repoint one another reader's progress follows
+  assert_eq!(KoreaderDocument::link_new(&mut tx, "TESTDOCUMENT", books[0].id).await?.book_id, books[0].id);
+  assert_eq!(KoreaderDocument::link_new(&mut tx, "TESTDOCUMENT", books[1].id).await?.book_id, books[0].id);
+
+  // Re-linking it is left to uploads
+  assert_eq!(KoreaderDocument::link(&mut tx, "TESTDOCUMENT", books[1].id).await?.book_id, books[1].id);
+
+  Ok(())
+}
+
+#[tokio::test]
+async fn koreader_registration_takes_a_free_name() -> Result<(), sqlx::Error> {
+  use crate::db::{koreader::KoreaderUser, user::User};
+
+  let mut tx = create_tx().await;
+  let existing = User::create(&mut tx, None, String::from("TEST READER")).await?;
+
+  // A name an existing user has is refused rather than attached to them
+  assert!(KoreaderUser::create(&mut tx, existing.name.clone(), "key").await?.is_none());
+
+  // A free name gets a user of its own, and cannot be registered twice
+  let account = KoreaderUser::create(&mut tx, String::from("TEST KOREADER"), "key").await?.expect("free name");
+  assert_ne!(account.user_id, existing.id);
+  assert_eq!(User::fetch_by_name(&mut tx, "TEST KOREADER").await?.id, account.user_id);
+  assert!(KoreaderUser::create(&mut tx, String::from("TEST KOREADER"), "key").await?.is_none());
+
+  Ok(())
+}
+
+#[tokio::test]
+async fn koreader_page_for() {
+  use crate::db::koreader::page_for;
+
+  assert_eq!(page_for(0.0, 300), 0);
+  assert_eq!(page_for(0.5, 300), 150);
+  assert_eq!(page_for(0.3333, 300), 100);
+  // Out of range percentages are clamped to the book
+  assert_eq!(page_for(1.7, 300), 300);
+  assert_eq!(page_for(-0.2, 300), 0);
+}
+
+#[tokio::test]
+async fn files_parse_range() {
+  use crate::api::files::{parse_range, ByteRange};
+
+  assert_eq!(parse_range(None, 1000), ByteRange::Full);
+  assert_eq!(parse_range(Some("bytes=0-99"), 1000), ByteRange::Partial(0, 99));
+  assert_eq!(parse_range(Some("bytes=900-"), 1000), ByteRange::Partial(900, 999));
+  assert_eq!(parse_range(Some("bytes=-100"), 1000), ByteRange::Partial(900, 999));
+  // End offsets past the file are clamped, starts past it cannot be served
+  assert_eq!(parse_range(Some("bytes=500-5000"), 1000), ByteRange::Partial(500, 999));
+  assert_eq!(parse_range(Some("bytes=1000-"), 1000), ByteRange::Unsatisfiable);
+  // Multiple or malformed ranges are ignored
+  assert_eq!(parse_range(Some("bytes=0-1,5-6"), 1000), ByteRange::Full);
+  assert_eq!(parse_range(Some("items=0-1"), 1000), ByteRange::Full);
+}
+
+#[tokio::test]
+async fn storage_store_and_verify() -> Result<(), std::io::Error> {
+  use crate::{
+    db::files::FileFormat,
+    storage::{Storage, ORPHAN_GRACE},
+  };
+  use std::{collections::HashSet, time::Duration};
+
+  let root = std::env::temp_dir().join(format!("libby-storage-{}", std::process::id()));
+  let storage = Storage::new(&root);
+
+  // Store the same bytes twice, they share one blob
+  let chunks: Vec<Result<Vec<u8>, std::io::Error>> = vec![Ok(b"%PDF-1.7 ".to_vec()), Ok(b"test body".to_vec())];
+  let first = storage.store(futures_util::stream::iter(chunks.into_iter())).await?;
+  let again: Vec<Result<Vec<u8>, std::io::Error>> = vec![Ok(b"%PDF-1.7 test body".to_vec())];
+  let second = storage.store(futures_util::stream::iter(again.into_iter())).await?;
+  assert_eq!(first.sha256, second.sha256);
+  assert_eq!(first.size, 18);
+  assert_eq!(FileFormat::detect(&first.head), Some(FileFormat::Pdf));
+  assert!(storage.verify(&first.sha256).await?);
+
+  // Unreferenced blobs are kept while they may still be gaining a row, then removed by cleanup
+  assert!(storage.cleanup_orphans(&HashSet::new(), ORPHAN_GRACE).await?.is_empty());
+  assert!(!storage.remove_if_older(&first.sha256, ORPHAN_GRACE).await?);
+  assert!(storage.exists(&first.sha256).await?);
+  let removed = storage.cleanup_orphans(&HashSet::new(), Duration::ZERO).await?;
+  assert_eq!(removed, vec![first.sha256.clone()]);
+  assert!(!storage.exists(&first.sha256).await?);
+
+  tokio::fs::remove_dir_all(root).await
+}
+
+#[tokio::test]
+async fn covers_process_thumbnails() -> Result<(), std::io::Error> {
+  use crate::covers::{process, ThumbnailFormat};
+
+  // A 300x150 red image with a blue stripe
+  let image = image::RgbImage::from_fn(300, 150, |x, _| if x < 30 { image::Rgb([0, 0, 255]) } else { image::Rgb([200, 10, 10]) });
+  let mut png = std::io::Cursor::new(Vec::new());
+  image.write_to(&mut png, image::ImageFormat::Png).expect("encode png");
+
+  let processed = process(png.get_ref(), &[64, 128, 512], ThumbnailFormat::Jpeg)?;
+  assert_eq!(processed.mime, "image/png");
+  assert_eq!((processed.width, processed.height), (300, 150));
+  assert_eq!(processed.dominant_colour, "#c80a0a");
+
+  // Sizes larger than the original are skipped, others keep the aspect ratio
+  let sizes: Vec<(u32, u32, u32)> = processed.thumbnails.iter().map(|t| (t.size, t.width, t.height)).collect();
+  assert_eq!(sizes, vec![(64, 64, 32), (128, 128, 64)]);
+  assert_eq!(image::guess_format(&processed.thumbnails[0].bytes).ok(), Some(image::ImageFormat::Jpeg));
+
+  Ok(())
+}
+
+#[tokio::test]
+async fn csv_parse_and_validate() {
+  use crate::transfer::csv::{parse, ColumnMapping, Entity, Rows};
+  use std::collections::HashMap;
+
+  // Headers from another tool, mapped onto our field names
+  let mapping = ColumnMapping::new(HashMap::from([
+    (String::from("isbn"), String::from("ISBN13")),
+    (String::from("name"), String::from("Title")),
+    (String::from("num_pages"), String::from("Pages")),
+    (String::from("authors"), String::from("Author")),
+  ]));
+  let data = "ISBN13,Title,Pages,Author,date_published\n\
+    978-0-13-468599-1,The Rust Book,560,Steve Klabnik; Carol Nichols,2019-08-12\n\
+    ,Missing ISBN,100,,\n\
+    9780134685991,Duplicate,200,,\n\
+    9781718500440,Bad pages,lots,,not a date\n";
+
+  let (rows, errors) = parse(Entity::Books, &mapping, data.as_bytes());
+  let Rows::Books(books) = rows else { panic!("expected book rows") };
+  assert_eq!(books.len(), 1);
+  assert_eq!(books[0].1.isbn, "9780134685991");
+  assert_eq!(books[0].1.authors, vec!["Steve Klabnik", "Carol Nichols"]);
+  assert!(books[0].1.date_published.is_some());
+
+  // Every problem is reported against its CSV line before anything is written
+  let located: Vec<(u64, Option<&str>)> = errors.iter().map(|e| (e.row, e.column.as_deref())).collect();
+  assert_eq!(located, vec![(3, Some("isbn")), (4, None), (5, Some("num_pages")), (5, Some("date_published"))]);
+}
+
+#[tokio::test]
+async fn goodreads_parse_and_match() {
+  use crate::transfer::goodreads::{fuzzy_match, parse, Candidate, FuzzyMatch, Source, Status};
+
+  let data = "Book Id,Title,Author,Additional Authors,ISBN,ISBN13,My Rating,Publisher,Number of Pages,Date Read,Bookshelves,Exclusive Shelf\n\
+    1,\"Guards! Guards! (Discworld, #8)\",Terry Pratchett,,=\"0552134635\",=\"\",4,Corgi,416,2020/01/15,\"favourites, read\",read\n\
+    2,Untitled,,,,,0,,,,,to-read\n\
+    3,,Nobody,,,,0,,,,,to-read\n";
+  let (entries, errors) = parse(Source::Goodreads, data.as_bytes());
+  assert_eq!(errors.len(), 1);
+  assert_eq!(errors[0].row, 4);
+  assert_eq!(entries[0].isbn.as_deref(), Some("0552134635"));
+  assert_eq!(entries[0].rating, Some(8));
+  assert_eq!(entries[0].status, Some(Status::Read));
+  assert_eq!(entries[0].shelves, vec!["favourites"]);
+  assert_eq!(entries[0].num_pages, Some(416));
+  assert_eq!(entries[1].rating, None);
+
+  let storygraph = "Title,Authors,ISBN/UID,Read Status,Star Rating,Dates Read,Tags\n\
+    Mort,Terry Pratchett,9780552131063,read,4.5,2021/03/01-2021/03/04,comfort\n";
+  let (entries, _) = parse(Source::StoryGraph, storygraph.as_bytes());
+  assert_eq!(entries[0].rating, Some(9));
+  assert!(entries[0].date_started.is_some() && entries[0].date_read.is_some());
+
+  // Series suffixes, articles and "Last, First" authors still match
+  let candidates = vec![
+    Candidate {
+      book_id: 1,
+      title: String::from("Guards! Guards!"),
+      authors: vec![String::from("Pratchett, Terry")],
+    },
+    Candidate {
+      book_id: 2,
+      title: String::from("The Colour of Magic"),
+      authors: vec![String::from("Terry Pratchett")],
+    },
+    Candidate {
+      book_id: 3,
+      title: String::from("Colour of Magic"),
+      authors: vec![String::from("Terry Pratchett")],
+    },
+  ];
+  let authors = vec![String::from("Terry Pratchett")];
+  assert!(matches!(
+    fuzzy_match("Guards! Guards! (Discworld, #8)", &authors, &candidates),
+    FuzzyMatch::One { book_id: 1, .. }
+  ));
+  assert_eq!(
+    fuzzy_match("The Colour of Magic", &authors, &candidates),
+    FuzzyMatch::Ambiguous { candidates: vec![2, 3] }
+  );
+  assert_eq!(fuzzy_match("Guards! Guards!", &[String::from("Someone Else")], &candidates), FuzzyMatch::None);
+}
+
+#[tokio::test]
+async fn calibre_read_library() -> Result<(), sqlx::Error> {
+  use crate::transfer::calibre::{calibre_date, read_library, strip_html};
+  use sqlx::{sqlite::SqliteConnectOptions, Connection, SqliteConnection};
+
+  assert!(calibre_date("0101-01-01 00:00:00+00:00").is_none());
+  assert_eq!(
+    calibre_date("2021-03-04 12:00:00.123456+00:00").map(|date| date.to_rfc3339()).as_deref(),
+    Some("2021-03-04T12:00:00.123456+00:00")
+  );
+  assert_eq!(strip_html("<p>A <b>wizard</b>&nbsp;&amp; a luggage.</p>"), "A wizard & a luggage.");
+
+  // A cut-down metadata.db with the tables and columns the importer reads
+  let root = std::env::temp_dir().join(format!("libby-calibre-{}", std::process::id()));
+  std::fs::create_dir_all(&root)?;
+  let options = SqliteConnectOptions::new().filename(root.join("metadata.db")).create_if_missing(true);
+  let mut conn = SqliteConnection::connect_with(&options).await?;
+  for statement in [
+    "CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT, path TEXT, series_index REAL, has_cover BOOL, uuid TEXT, last_modified TIMESTAMP, pubdate TIMESTAMP)",
+    "CREATE TABLE authors (id INTEGER PRIMARY KEY, name TEXT, sort TEXT)",
+    "CREATE TABLE books_authors_link (id INTEGER PRIMARY KEY, book INTEGER, author INTEGER)",
+    "CREATE TABLE publishers (id INTEGER PRIMARY KEY, name TEXT)",
+    "CREATE TABLE books_publishers_link (id INTEGER PRIMARY KEY, book INTEGER, publisher INTEGER)",
+    "CREATE TABLE series (id INTEGER PRIMARY KEY, name TEXT)",
+    "CREATE TABLE books_series_link (id INTEGER PRIMARY KEY, book INTEGER, series INTEGER)",
+    "CREATE TABLE tags (id INTEGER PRIMARY KEY, name TEXT)",
+    "CREATE TABLE books_tags_link (id INTEGER PRIMARY KEY, book INTEGER, tag INTEGER)",
+    "CREATE TABLE identifiers (id INTEGER PRIMARY KEY, book INTEGER, type TEXT, val TEXT)",
+    "CREATE TABLE languages (id INTEGER PRIMARY KEY, lang_code TEXT)",
+    "CREATE TABLE books_languages_link (id INTEGER PRIMARY KEY, book INTEGER, lang_code INTEGER, item_order INTEGER)",
+    "CREATE TABLE comments (id INTEGER PRIMARY KEY, book INTEGER, text TEXT)",
+    "CREATE TABLE data (id INTEGER PRIMARY KEY, book INTEGER, format TEXT, uncompressed_size INTEGER, name TEXT)",
+    "INSERT INTO books VALUES (1, 'Mort', 'Terry Pratchett/Mort (1)', 4.0, 1, 'b7c1a3e2-0000-4000-8000-000000000001', '2024-05-01 10:00:00+00:00', '0101-01-01 00:00:00+00:00')",
+    "INSERT INTO authors VALUES (1, 'Terry Pratchett', 'Pratchett, Terry')",
+    "INSERT INTO books_authors_link VALUES (1, 1, 1)",
+    "INSERT INTO publishers VALUES (1, 'Corgi')",
+    "INSERT INTO books_publishers_link VALUES (1, 1, 1)",
+    "INSERT INTO series VALUES (1, 'Discworld')",
+    "INSERT INTO books_series_link VALUES (1, 1, 1)",
+    "INSERT INTO tags VALUES (1, 'Fantasy'), (2, 'Comedy')",
+    "INSERT INTO books_tags_link VALUES (1, 1, 1), (2, 1, 2)",
+    "INSERT INTO identifiers VALUES (1, 1, 'isbn', '978-0-552-13106-3'), (2, 1, 'goodreads', '386372')",
+    "INSERT INTO languages VALUES (1, 'eng')",
+    "INSERT INTO books_languages_link VALUES (1, 1, 1, 0)",
+    "INSERT INTO comments VALUES (1, 1, '<p>Death takes an apprentice.</p>')",
+    "INSERT INTO data VALUES (1, 1, 'EPUB', 1000, 'Mort - Terry Pratchett')",
+  ] {
+    sqlx::query(statement).execute(&mut conn).await?;
+  }
+  conn.close().await?;
+
+  let library = read_library(&root).await?;
+  let book = &library.books[0];
+  assert_eq!(book.isbn().as_deref(), Some("9780552131063"));
+  assert_eq!(book.authors[0].sort.as_deref(), Some("Pratchett, Terry"));
+  assert_eq!((book.series.as_deref(), book.series_index), (Some("Discworld"), 4.0));
+  assert_eq!(book.tags, vec!["Comedy", "Fantasy"]);
+  assert_eq!(book.languages, vec!["eng"]);
+  assert_eq!(book.description.as_deref(), Some("Death takes an apprentice."));
+  assert!(book.pubdate.is_none() && book.has_cover);
+  assert_eq!(
+    book.format_paths(&root),
+    vec![root.join("Terry Pratchett/Mort (1)/Mort - Terry Pratchett.epub")]
+  );
+
+  std::fs::remove_dir_all(root)?;
+  Ok(())
+}
+
+#[tokio::test]
+async fn marc_round_trip() -> Result<(), std::io::Error> {
+  use crate::transfer::marc::{parse, write_iso2709, write_marcxml, MarcBook};
+
+  let xml = include_str!("../test-data/marc/corpus.xml");
+  let iso = include_bytes!("../test-data/marc/corpus.mrc");
+
+  // Both serialisations of the corpus hold the same records, and each writes back out unchanged
+  let records = parse(xml.as_bytes())?;
+  assert_eq!(records.len(), 3);
+  assert_eq!(parse(iso)?, records);
+  assert_eq!(write_iso2709(&records), iso.to_vec());
+  assert_eq!(parse(write_marcxml(&records).as_bytes())?, records);
+
+  let books: Vec<MarcBook> = records.iter().map(MarcBook::from_record).collect();
+  assert_eq!(
+    books[0],
+    MarcBook {
+      isbn: Some(String::from("9780552131063")),
+      title: String::from("Mort: a Discworld novel"),
+      authors: vec![String::from("Pratchett, Terry")],
+      publisher: Some(String::from("Corgi")),
+      city: Some(String::from("London")),
+      year: Some(1987),
+      num_pages: Some(272),
+      language: Some(String::from("eng")),
+      description: Some(String::from("Death takes an apprentice & things go wrong.")),
+    }
+  );
+  assert_eq!(books[1].isbn.as_deref(), Some("843760494X"));
+  assert_eq!(books[1].authors, vec!["Pérez-Reverte, Arturo", "Smith, J."]);
+  assert_eq!(
+    (books[1].publisher.as_deref(), books[1].year, books[1].num_pages),
+    (Some("Alfaguara"), Some(1998), Some(486))
+  );
+  assert_eq!(
+    (books[2].isbn.as_deref(), books[2].title.as_str(), books[2].year),
+    (None, "Anonymous pamphlets", Some(2020))
+  );
+
+  // What we map survives being written as a record and read back
+  for book in &books {
+    let record = book.to_record();
+    assert_eq!(&MarcBook::from_record(&parse(&write_iso2709(&[record]))?[0]), book);
+  }
+  Ok(())
+}
+
+#[tokio::test]
+async fn onix_parse_products() -> Result<(), std::io::Error> {
+  use crate::transfer::onix::{onix_date, parse_products, unmapped_paths, Notification, OnixProduct};
+
+  let products = parse_products(include_str!("../test-data/onix/message.xml"))?;
+  assert_eq!(products.len(), 3);
+  let parsed: Vec<OnixProduct> = products.iter().map(OnixProduct::from_element).collect();
+
+  // Reference tags, with authors in sequence order and non-authors left out
+  let mort = &parsed[0];
+  assert_eq!(mort.notification, Notification::Upsert);
+  assert_eq!(mort.isbn.as_deref(), Some("9780552131063"));
+  assert_eq!(mort.title.as_deref(), Some("Mort: A Discworld Novel"));
+  assert_eq!(
+    mort.authors,
+    vec![
+      (String::from("Terry Pratchett"), Some(String::from("Pratchett, Terry"))),
+      (String::from("Josh Kirby"), None)
+    ]
+  );
+  assert_eq!(mort.series, Some((String::from("Discworld"), Some(4))));
+  assert_eq!((mort.num_pages, mort.language.as_deref()), (Some(272), Some("eng")));
+  assert_eq!(mort.description.as_deref(), Some("Death takes an apprentice & things go wrong."));
+  assert_eq!((mort.publisher.as_deref(), mort.city.as_deref()), (Some("Corgi"), Some("London")));
+  assert_eq!(mort.date_published, onix_date("19871112"));
+  assert_eq!(mort.identifiers, vec![(String::from("proprietary"), String::from("TW-0001"))]);
+
+  // Short tags, a GTIN-13 standing in for the ISBN and an inverted name only
+  let club = &parsed[1];
+  assert_eq!(club.isbn.as_deref(), Some("9788437604947"));
+  assert_eq!(club.title.as_deref(), Some("El club Dumas"));
+  assert_eq!(
+    club.authors,
+    vec![(String::from("Arturo Pérez-Reverte"), Some(String::from("Pérez-Reverte, Arturo")))]
+  );
+  assert_eq!((club.num_pages, club.date_published), (Some(486), onix_date("19980101")));
+
+  assert_eq!(parsed[2].notification, Notification::Delete);
+  assert_eq!(parsed[2].isbn.as_deref(), Some("0552131069"));
+
+  assert_eq!(
+    unmapped_paths(&products[0]),
+    vec![
+      "descriptivedetail/productcomposition",
+      "descriptivedetail/productform",
+      "descriptivedetail/subject/mainsubject",
+      "descriptivedetail/subject/subjectschemeidentifier",
+      "descriptivedetail/subject/subjectcode",
+      "collateraldetail/textcontent/contentaudience",
+      "collateraldetail/textcontent/contentaudience",
+      "productsupply/supplydetail/productavailability",
+    ]
+  );
+  assert!(unmapped_paths(&products[1]).is_empty());
+  Ok(())
+}
+
+#[tokio::test]
+async fn citation_styles() {
+  use crate::transfer::citation::{Citation, Name, Style};
+
+  let good_omens = Citation {
+    isbn: String::from("9780575048003"),
+    title: String::from("Good Omens"),
+    authors: vec![
+      Name::from_author("Terry Pratchett", Some("Pratchett, Terry")),
+      Name::from_author("Neil Gaiman", None),
+    ],
+    publisher: Some(String::from("Gollancz")),
+    city: Some(String::from("London")),
+    date_published: chrono::NaiveDate::from_ymd_opt(1990, 5, 1)
+      .and_then(|date| date.and_hms_opt(0, 0, 0))
+      .map(|date| date.and_utc()),
+    num_pages: Some(354),
+    language: Some(String::from("eng")),
+    series: None,
+  };
+
+  assert_eq!(good_omens.key(), "pratchett1990good");
+  assert_eq!(
+    good_omens.render(Style::Bibtex),
+    "@book{pratchett1990good,\n  author = {Pratchett, Terry and Gaiman, Neil},\n  title = {Good Omens},\n  publisher = {Gollancz},\n  address = {London},\n  year = {1990},\n  month = {5},\n  pagetotal = {354},\n  language = {eng},\n  isbn = {9780575048003}\n}\n"
+  );
+  assert_eq!(
+    good_omens.render(Style::Ris),
+    "TY  - BOOK\r\nAU  - Pratchett, Terry\r\nAU  - Gaiman, Neil\r\nTI  - Good Omens\r\nPB  - Gollancz\r\nCY  - London\r\nPY  - 1990\r\nDA  - 1990/05/01\r\nSP  - 354\r\nLA  - eng\r\nSN  - 9780575048003\r\nID  - pratchett1990good\r\nER  -\r\n"
+  );
+  let csl = good_omens.csl_json();
+  assert_eq!(csl["author"][1], serde_json::json!({ "family": "Gaiman", "given": "Neil" }));
+  assert_eq!(csl["issued"]["date-parts"], serde_json::json!([[1990, 5, 1]]));
+  assert_eq!(csl["publisher-place"], "London");
+
+  assert_eq!(good_omens.apa(), "Pratchett, T., & Gaiman, N. (1990). Good Omens. Gollancz.");
+  assert_eq!(good_omens.mla(), "Pratchett, Terry, and Neil Gaiman. Good Omens. Gollancz, 1990.");
+  assert_eq!(good_omens.chicago(), "Pratchett, Terry, and Neil Gaiman. Good Omens. London: Gollancz, 1990.");
+
+  // A single author with initials, no date and characters BibTeX must escape
+  let hobbit = Citation {
+    authors: vec![Name::from_author("J. R. R. Tolkien", None)],
+    title: String::from("Notes & Queries"),
+    date_published: None,
+    city: None,
+    ..good_omens.clone()
+  };
+  assert_eq!(hobbit.apa(), "Tolkien, J. R. R. (n.d.). Notes & Queries. Gollancz.");
+  assert_eq!(hobbit.mla(), "Tolkien, J. R. R. Notes & Queries. Gollancz.");
+  assert_eq!(hobbit.chicago(), "Tolkien, J. R. R. Notes & Queries. Gollancz.");
+  assert!(hobbit.bibtex().contains("title = {Notes \\& Queries}"));
+
+  let many = Citation {
+    authors: ["Ann Able", "Ben Baker", "Cat Cole"].iter().map(|name| Name::from_author(name, None)).collect(),
+    ..good_omens.clone()
+  };
+  assert_eq!(many.mla(), "Able, Ann, et al. Good Omens. Gollancz, 1990.");
+  assert_eq!(many.apa(), "Able, A., Baker, B., & Cole, C. (1990). Good Omens. Gollancz.");
+  assert_eq!(many.chicago(), "Able, Ann, Ben Baker, and Cat Cole. Good Omens. London: Gollancz, 1990.");
+}
+
+#[tokio::test]
+async fn backup_archive_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+  use crate::backup::{extract_blobs, read_manifest, read_table, write_archive, Manifest, TableDump, TableManifest, FORMAT_VERSION};
+  use crate::db::SCHEMA_VERSION;
+  use crate::storage::Storage;
+
+  let root = std::env::temp_dir().join(format!("libby-backup-{}", std::process::id()));
+  let files = Storage::new(root.join("files"));
+  let covers = Storage::new(root.join("covers"));
+  let book = files.store_bytes(b"an ebook").await?;
+  let cover = covers.store_bytes(b"a cover").await?;
+
+  let table = TableDump {
+    name: String::from("book"),
+    columns: vec![String::from("id"), String::from("name"), String::from("date_published"), String::from("nsfw")],
+    rows: vec![
+      vec![
+        serde_json::json!(1),
+        serde_json::json!("Mort"),
+        serde_json::json!("1987-11-12 00:00:00"),
+        serde_json::json!(false),
+      ],
+      vec![
+        serde_json::json!(2),
+        serde_json::json!("Sourcery"),
+        serde_json::Value::Null,
+        serde_json::json!(true),
+      ],
+    ],
+  };
+  let manifest = Manifest {
+    format: FORMAT_VERSION,
+    libby_version: String::from("0.1.0"),
+    schema_version: SCHEMA_VERSION,
+    created: chrono::Utc::now(),
+    tables: vec![TableManifest {
+      name: table.name.clone(),
+      columns: table.columns.clone(),
+      rows: table.rows.len(),
+    }],
+    files: vec![book.sha256.clone()],
+    covers: vec![cover.sha256.clone()],
+  };
+  let path = root.join("backup.zip");
+  write_archive(&path, &manifest, std::slice::from_ref(&table), &files, &covers)?;
+
+  let mut archive = zip::ZipArchive::new(std::fs::File::open(&path)?)?;
+  let read = read_manifest(&mut archive)?;
+  assert_eq!(read, manifest);
+  read.validate()?;
+  assert_eq!(read_table(&mut archive, &read.tables[0])?, table);
+
+  // Blobs land in a fresh store under their hash; ones already present are not reported as created
+  let restored = Storage::new(root.join("restored"));
+  let created = extract_blobs(&mut archive, "files", &read.files, &restored)?;
+  assert_eq!(created, vec![restored.path_for(&book.sha256)]);
+  assert!(restored.verify(&book.sha256).await?);
+  assert!(extract_blobs(&mut archive, "files", &read.files, &restored)?.is_empty());
+  // The book's hash is not under covers/, so a fresh store cannot be filled from there
+  assert!(extract_blobs(&mut archive, "covers", &read.files, &Storage::new(root.join("empty"))).is_err());
+
+  // Archives from a newer schema, or with rows the manifest does not account for, are refused
+  let newer = Manifest {
+    schema_version: SCHEMA_VERSION + 1,
+    ..manifest.clone()
+  };
+  assert!(newer.validate().is_err());
+  let short = TableManifest {
+    rows: 3,
+    ..read.tables[0].clone()
+  };
+  assert!(read_table(&mut archive, &short).is_err());
+
+  std::fs::remove_dir_all(&root)?;
+  Ok(())
+}
+
+#[tokio::test]
+async fn audit_trigger_statements() {
+  use crate::db::audit::trigger_statements;
+
+  let columns: Vec<String> = ["id", "name", "date_last_updated"].iter().map(|column| column.to_string()).collect();
+  let [insert, update, delete] = &trigger_statements("author", &[String::from("id")], &columns)[..] else {
+    panic!("expected insert, update and delete triggers");
+  };
+
+  assert!(insert.starts_with("CREATE TRIGGER `audit_author_insert` AFTER INSERT ON `author`"));
+  assert!(
+    insert.contains("CONCAT_WS(':', NEW.`id`), 'create', NULL, JSON_OBJECT('id', NEW.`id`, 'name', NEW.`name`, 'date_last_updated', NEW.`date_last_updated`))")
+  );
+  assert!(delete.contains("CONCAT_WS(':', OLD.`id`), 'delete', JSON_OBJECT('id', OLD.`id`,"));
+
+  // Updates only log when a column besides the update timestamp changed, and only those columns
+  assert!(update.contains("AND (NOT (OLD.`id` <=> NEW.`id`) OR NOT (OLD.`name` <=> NEW.`name`)) THEN"));
+  assert!(update.contains("IF(OLD.`name` <=> NEW.`name`, JSON_OBJECT(), JSON_OBJECT('name', OLD.`name`))"));
+  assert!(update.contains("IF(OLD.`name` <=> NEW.`name`, JSON_OBJECT(), JSON_OBJECT('name', NEW.`name`))"));
+  assert!(!update.contains("'date_last_updated'"));
+
+  // Join tables key on every primary key column, and an update re-pointing one is still logged
+  let keys = vec![String::from("book_id"), String::from("author_id")];
+  let statements = trigger_statements("book_author", &keys, &keys);
+  assert!(statements[0].contains("CONCAT_WS(':', NEW.`book_id`, NEW.`author_id`)"));
+  assert!(statements[1].contains("AND (NOT (OLD.`book_id` <=> NEW.`book_id`) OR NOT (OLD.`author_id` <=> NEW.`author_id`)) THEN"));
+}
+
+#[tokio::test]
+async fn change_feed_numbers_committed_events() -> Result<(), sqlx::Error> {
+  use crate::db::{changes::ChangeEvent, shelves::Shelf, user::User};
+
+  let mut tx = create_tx().await;
+  ChangeEvent::sequence(&mut tx).await?;
+  let head = ChangeEvent::fetch_head(&mut tx).await?;
+
+  // Events are not in the feed until they are numbered
+  let user = User::create(&mut tx, None, String::from("TEST READER")).await?;
+  let shelf = Shelf::create(&mut tx, user.id, "TEST SHELF").await?;
+  assert!(ChangeEvent::fetch_after(&mut tx, head, 10).await?.is_empty());
+
+  // Then they follow on from the head in the order they were written, each numbered once
+  assert_eq!(ChangeEvent::sequence(&mut tx).await?, 2);
+  assert_eq!(ChangeEvent::sequence(&mut tx).await?, 0);
+  let events = ChangeEvent::fetch_after(&mut tx, head, 10).await?;
+  let seen: Vec<(u64, &str, u64, &str)> = events
+    .iter()
+    .map(|event| (event.seq, event.entity.as_str(), event.entity_id, event.operation.as_str()))
+    .collect();
+  assert_eq!(
+    seen,
+    vec![(head + 1, "user", u64::from(user.id), "create"), (head + 2, "shelf", shelf.id, "create")]
+  );
+  assert_eq!(ChangeEvent::fetch_head(&mut tx).await?, head + 2);
+
+  Ok(())
+}
+
+#[tokio::test]
+async fn webhook_delivery_signing_and_backoff() {
+  use crate::webhooks::{backoff, send, sign, Attempt};
+  use axum::{http::HeaderMap, http::StatusCode, routing::post, Router};
+  use std::sync::{Arc, Mutex};
+
+  // A local stand-in receiver that records what it was sent and fails when asked to
+  let received: Arc<Mutex<Vec<(HeaderMap, String)>>> = Arc::default();
+  let log = received.clone();
+  let app = Router::new()
+    .route(
+      "/hook",
+      post(move |headers: HeaderMap, body: String| async move {
+        log.lock().unwrap().push((headers, body));
+        StatusCode::NO_CONTENT
+      }),
+    )
+    .route("/broken", post(|| async { StatusCode::INTERNAL_SERVER_ERROR }));
+  let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let address = listener.local_addr().unwrap();
+  tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+  let client = reqwest::Client::new();
+  let payload = serde_json::json!({ "book": { "id": 7 } });
+  let secret = "0123456789abcdef";
+
+  let attempt = send(&client, &format!("http://{}/hook", address), secret, 42, "book.added", payload.clone()).await;
+  assert_eq!(attempt, Attempt::Delivered(204));
+  let (headers, body) = received.lock().unwrap().pop().unwrap();
+  let header = |name: &str| headers.get(name).unwrap().to_str().unwrap().to_string();
+  assert_eq!(header("x-libby-event"), "book.added");
+  assert_eq!(header("x-libby-delivery"), "42");
+  let timestamp: i64 = header("x-libby-timestamp").parse().unwrap();
+  assert_eq!(header("x-libby-signature"), sign(secret, timestamp, &body));
+  assert_ne!(header("x-libby-signature"), sign("another secret!!", timestamp, &body));
+  let sent: serde_json::Value = serde_json::from_str(&body).unwrap();
+  assert_eq!(sent["data"], payload);
+
+  let attempt = send(&client, &format!("http://{}/broken", address), secret, 43, "book.added", payload.clone()).await;
+  assert_eq!(attempt, Attempt::Rejected(500));
+  let attempt = send(&client, "http://127.0.0.1:1/hook", secret, 44, "book.added", payload).await;
+  assert!(matches!(attempt, Attempt::Unreachable(_)));
+
+  assert_eq!(backoff(1).num_seconds(), 30);
+  assert_eq!(backoff(2).num_seconds(), 60);
+  assert_eq!(backoff(5).num_seconds(), 480);
+  assert_eq!(backoff(40).num_seconds(), 6 * 60 * 60);
+}
+
+#[tokio::test]
+async fn graphql_schema_shape() {
+  use crate::api::{
+    auth::Caller,
+    graphql::{schema, Viewer},
+  };
+
+  let schema = schema();
+  let sdl = schema.sdl();
+  for expected in [
+    "type Book",
+    "type Author",
+    "type Publisher",
+    "type User",
+    "type Progress",
+    "authors: [Author!]!",
+    "progress: Progress",
+  ] {
+    assert!(sdl.contains(expected), "schema is missing {}", expected);
+  }
+  assert!(sdl.contains("updateBook(id: Int!, version: Int, input: BookChanges!): Book!"));
+
+  // Without a viewer nothing needs loading, so this runs without a database
+  let response = schema.execute(async_graphql::Request::new("{ viewer { id name } }").data(Viewer(None))).await;
+  assert!(response.errors.is_empty(), "{:?}", response.errors);
+  assert_eq!(response.data.into_json().unwrap(), serde_json::json!({ "viewer": null }));
+
+  let nested = format!("{{ book(id: 1) {} id {} }}", "{ authors { books ".repeat(7), "} } ".repeat(7));
+  let response = schema.execute(async_graphql::Request::new(nested).data(Viewer(None))).await;
+  assert!(!response.errors.is_empty());
+
+  // Readers can only move their own progress; editing the catalog takes the admin token
+  let reader = async_graphql::Request::new("mutation { deleteBook(id: 1) }")
+    .data(Viewer(Some(1)))
+    .data(Some(Caller::User(1)));
+  let response = schema.execute(reader).await;
+  assert_eq!(
+    response.errors[0].extensions.as_ref().unwrap().get("code"),
+    Some(&async_graphql::Value::from("FORBIDDEN"))
+  );
+  let anonymous = async_graphql::Request::new("mutation { updateProgress(bookId: 1, currentPage: 2) { currentPage } }")
+    .data(Viewer(None))
+    .data(None::<Caller>);
+  let response = schema.execute(anonymous).await;
+  assert_eq!(
+    response.errors[0].extensions.as_ref().unwrap().get("code"),
+    Some(&async_graphql::Value::from("FORBIDDEN"))
+  );
+}
+
+#[tokio::test]
+async fn graphql_progress_is_private() -> Result<(), sqlx::Error> {
+  use crate::{
+    api::{
+      auth::Caller,
+      graphql::{loaders, schema, Viewer},
+    },
+    db::{
+      books::{Book, PartialBook},
+      progress::Progress,
+      user::User,
+    },
+  };
+
+  dotenv().ok();
+  let db = Db::new(&std::env::var("DATABASE_URL").expect("DATABASE URL NOT PRESENT IN ENVIRONMENT"))
+    .await
+    .expect("Failed to connect to DB");
+
+  // The schema reads through its own connections, so this reader has to be committed, and is removed again below
+  let mut tx = db.conn.begin().await?;
+  let reader = User::create(&mut tx, None, String::from("TEST GRAPHQL READER")).await?;
+  let book = Book::create_partial(
+    &mut tx,
+    PartialBook {
+      isbn: Some(String::from("9780000000000")),
+      name: Some(String::from("TEST BOOK")),
+      description: None,
+      language: None,
+      nsfw: Some(false),
+      num_pages: Some(100),
+      image_formatted: Some(false),
+      publisher_id: None,
+      series_id: None,
+      series_index: None,
+      date_published: None,
+    },
+  )
+  .await?;
+  Progress::create(&mut tx, reader.id, book.id, 10).await?;
+  tx.commit().await?;
+
+  let schema = schema();
+  let query = "{ users { id progress { currentPage } } }";
+  let mut seen = Vec::new();
+  for (viewer, caller) in [(None, None), (Some(reader.id), Some(Caller::User(reader.id))), (None, Some(Caller::Admin))] {
+    let request = loaders::attach(async_graphql::Request::new(query), &db)
+      .data(Viewer(viewer))
+      .data(caller)
+      .data(db.clone());
+    let response = schema.execute(request).await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    let data = response.data.into_json().unwrap();
+    let users = data["users"].as_array().unwrap();
+    // Nobody's reading is shown to an anonymous caller
+    if viewer.is_none() && caller.is_none() {
+      assert!(users.iter().all(|user| user["progress"].as_array().unwrap().is_empty()));
+    }
+    let own = users.iter().find(|user| user["id"] == reader.id).unwrap();
+    seen.push(own["progress"].as_array().unwrap().len());
+  }
+  // The reader and the administrator see it
+  assert_eq!(seen, [0, 1, 1]);
+
+  let mut tx = db.conn.begin().await?;
+  Progress::delete(&mut tx, reader.id, book.id).await?;
+  User::delete(&mut tx, reader.id).await?;
+  Book::delete(&mut tx, book.id).await?;
+  Book::purge(&mut tx, book.id).await?;
+  tx.commit().await
+}
+
+#[test]
+fn graphql_updates_keep_omitted_fields() {
+  use crate::{
+    api::graphql::mutations::{AuthorChanges, SeriesChanges},
+    db::{authors::Author, series::Series},
+  };
+  use async_graphql::{value, InputType};
+
+  let author = Author {
+    id: 1,
+    name: String::from("TEST AUTHOR"),
+    description: Some(String::from("TEST DESCRIPTION")),
+    birth: chrono::NaiveDate::from_ymd_opt(1948, 4, 28),
+    sort_name: None,
+    date_added: None,
+    date_last_updated: None,
+    deleted_at: None,
+    version: 1,
+  };
+
+  // Renaming keeps what was left out; only an explicit null clears a field
+  let renamed = AuthorChanges::parse(Some(value!({ "name": "RENAMED" }))).unwrap().apply_to(author.clone());
+  assert_eq!(renamed.name.as_deref(), Some("RENAMED"));
+  assert_eq!(renamed.description, author.description);
+  assert_eq!(renamed.birth, author.birth);
+  let cleared = AuthorChanges::parse(Some(value!({ "birth": null }))).unwrap().apply_to(author.clone());
+  assert_eq!(cleared.description, author.description);
+  assert_eq!(cleared.birth, None);
+
+  let series = Series {
+    id: 1,
+    name: String::from("TEST SERIES"),
+    description: Some(String::from("TEST DESCRIPTION")),
+    date_added: None,
+    date_last_updated: None,
+    version: 1,
+  };
+  let renamed = SeriesChanges::parse(Some(value!({ "name": "RENAMED" }))).unwrap().apply_to(series.clone());
+  assert_eq!(renamed.description, series.description);
+  let cleared = SeriesChanges::parse(Some(value!({ "description": null }))).unwrap().apply_to(series);
+  assert_eq!(cleared.description, None);
+}
+
+#[tokio::test]
+async fn config_layers_and_validation() {
+  use crate::config::{Args, Config, ConfigError};
+  use std::collections::HashMap;
+
+  let dir = std::env::temp_dir().join(format!("libby-config-{}", std::process::id()));
+  std::fs::create_dir_all(&dir).unwrap();
+  let file = dir.join("libby.toml");
+  std::fs::write(
+    &file,
+    "[database]\nurl = \"mysql://file/libby\"\nmax_connections = 10\n\n[server]\nbind_address = \"127.0.0.1:9000\"\n\n[features]\ngraphql = false\n",
+  )
+  .unwrap();
+
+  // Each layer wins over the one below it, and only for the keys it sets
+  let env: HashMap<&str, &str> = HashMap::from([
+    ("DATABASE_MAX_CONNECTIONS", "12"),
+    ("COVER_SIZES", "64, 128"),
+    ("BIND_ADDRESS", "127.0.0.1:9001"),
+    ("ADMIN_TOKEN", "a-long-enough-admin-token"),
+  ]);
+  let cli = ["--config", file.to_str().unwrap(), "--server.bind-address=127.0.0.1:9002", "backup", "out.zip"];
+  let args = Args::parse(cli.iter().map(|arg| arg.to_string())).unwrap();
+  assert_eq!(args.command, vec!["backup", "out.zip"]);
+  let config = Config::load(&args, |var| env.get(var).map(|value| value.to_string())).unwrap();
+  assert_eq!(config.database.url, "mysql://file/libby");
+  assert_eq!(config.database.max_connections, 12);
+  assert_eq!(config.database.acquire_timeout_secs, 30);
+  assert_eq!(config.storage.cover_sizes, vec![64, 128]);
+  assert_eq!(config.server.bind_address, "127.0.0.1:9002");
+  assert!(!config.features.graphql);
+  assert!(config.features.opds);
+
+  // Every problem is reported, naming where it came from
+  let bad = Args::parse(["--database.max-connections", "2", "--database.min-connections", "3", "--storage.colour", "red"].map(String::from)).unwrap();
+  let no_env = |var: &str| (var == "WEBHOOK_MAX_ATTEMPTS").then(|| String::from("lots"));
+  let Err(ConfigError::Invalid(problems)) = Config::load(&bad, no_env) else {
+    panic!("expected the settings to be rejected");
+  };
+  assert_eq!(problems.len(), 2);
+  assert!(problems[0].starts_with("WEBHOOK_MAX_ATTEMPTS: expected a whole number"));
+  assert!(problems[1].contains("unknown setting `storage.colour`"));
+
+  let bad = Args::parse(["--database.max-connections", "2", "--database.min-connections", "3"].map(String::from)).unwrap();
+  let Err(ConfigError::Invalid(problems)) = Config::load(&bad, |_| None) else {
+    panic!("expected the settings to be rejected");
+  };
+  assert!(problems.iter().any(|problem| problem.starts_with("database.url is required")));
+  assert!(problems.iter().any(|problem| problem.contains("min_connections (3) is more than")));
+  assert!(problems.iter().any(|problem| problem.starts_with("auth.admin_token is required")));
+
+  std::fs::remove_dir_all(&dir).ok();
+}
+
+#[tokio::test]
+async fn request_trace_ids() {
+  use crate::logging::{trace_requests, TRACE_HEADER};
+  use axum::{middleware, routing::get, Router};
+
+  let app = Router::new().route("/", get(|| async { "ok" })).layer(middleware::from_fn(trace_requests));
+  let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let url = format!("http://{}/", listener.local_addr().unwrap());
+  tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+  let client = reqwest::Client::new();
+
+  // A usable id from the client is kept and echoed back
+  let response = client.get(&url).header("x-request-id", "abc-123").send().await.unwrap();
+  assert_eq!(response.headers()[TRACE_HEADER.as_str()], "abc-123");
+
+  // Otherwise each request gets a fresh one
+  let first = client.get(&url).send().await.unwrap();
+  let second = client.get(&url).header("x-request-id", "not ok").send().await.unwrap();
+  assert_eq!(first.headers()[TRACE_HEADER.as_str()].len(), 32);
+  assert_ne!(first.headers()[TRACE_HEADER.as_str()], second.headers()[TRACE_HEADER.as_str()]);
+}
+
+#[tokio::test]
+async fn metrics_by_route_and_operation() {
+  use crate::metrics::{render, track_requests, QueryTimings, HTTP_REQUESTS, QUERY_DURATION};
+  use axum::{middleware, routing::get, Router};
+  use tracing_subscriber::layer::SubscriberExt;
+
+  let app = Router::new()
+    .route("/metered/{id}", get(|| async { "ok" }))
+    .layer(middleware::from_fn(track_requests));
+  let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let url = format!("http://{}", listener.local_addr().unwrap());
+  tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+  let client = reqwest::Client::new();
+
+  // Requests are counted by the route they matched, not their path
+  client.get(format!("{}/metered/1", url)).send().await.unwrap();
+  client.get(format!("{}/metered/2", url)).send().await.unwrap();
+  assert_eq!(HTTP_REQUESTS.with_label_values(&["GET", "/metered/{id}", "200"]).get(), 2);
+
+  // A span is timed by its entity and name when it closes
+  let subscriber = tracing_subscriber::registry().with(QueryTimings);
+  tracing::subscriber::with_default(subscriber, || {
+    let span = tracing::debug_span!("fetch_metered", entity = "metered", id = 1);
+    drop(span.enter());
+  });
+  assert_eq!(QUERY_DURATION.with_label_values(&["metered", "fetch_metered"]).get_sample_count(), 1);
+
+  let (content_type, body) = render();
+  let body = String::from_utf8(body).unwrap();
+  assert!(content_type.starts_with("text/plain"));
+  assert!(body.contains(r#"libby_http_requests_total{method="GET",route="/metered/{id}",status="200"} 2"#));
+  assert!(body.contains("libby_db_query_duration_seconds_bucket"));
+}
+
+#[tokio::test]
+async fn health_probes() {
+  use crate::{
+    api::{health, AppState},
+    config::Config,
+    covers::{Covers, ThumbnailFormat},
+    storage::Storage,
+  };
+  use std::{sync::Arc, time::Duration};
+  use tokio_util::sync::CancellationToken;
+
+  // Nothing listens on port 1, and the covers root sits under a plain file so it cannot be created
+  let root = std::env::temp_dir().join(format!("libby-health-{}", std::process::id()));
+  tokio::fs::create_dir_all(&root).await.unwrap();
+  tokio::fs::write(root.join("blocker"), b"").await.unwrap();
+  let conn = sqlx::mysql::MySqlPoolOptions::new()
+    .acquire_timeout(Duration::from_millis(500))
+    .connect_lazy("mysql://libby@127.0.0.1:1/libby")
+    .unwrap();
+  let state = AppState {
+    db: Db { conn },
+    storage: Storage::new(root.join("files")),
+    covers: Covers {
+      storage: Storage::new(root.join("blocker").join("covers")),
+      sizes: vec![],
+      format: ThumbnailFormat::Webp,
+    },
+    config: Arc::new(Config::default()),
+    shutdown: CancellationToken::new(),
+  };
+  let app = health::router().with_state(state);
+  let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let url = format!("http://{}", listener.local_addr().unwrap());
+  tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+  let client = reqwest::Client::new();
+
+  // Liveness does not depend on anything else
+  let live = client.get(format!("{}/health/live", url)).send().await.unwrap();
+  assert_eq!(live.status(), 200);
+
+  // Readiness reports each check, and fails if any does
+  let ready = client.get(format!("{}/health/ready", url)).send().await.unwrap();
+  assert_eq!(ready.status(), 503);
+  let body: serde_json::Value = serde_json::from_str(&ready.text().await.unwrap()).unwrap();
+  assert_eq!(body["status"], "unavailable");
+  assert_eq!(body["checks"]["database"]["status"], "failed");
+  assert_eq!(body["checks"]["migrations"]["status"], "failed");
+  assert_eq!(body["checks"]["files"]["status"], "ok");
+  assert_eq!(body["checks"]["covers"]["status"], "failed");
+  assert!(body["checks"]["covers"]["error"].is_string());
+
+  tokio::fs::remove_dir_all(root).await.unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_drains_then_cuts_off() {
+  use crate::shutdown::{every, serve, ShutdownError};
+  use axum::{routing::get, Router};
+  use std::{
+    sync::{
+      atomic::{AtomicUsize, Ordering},
+      Arc,
+    },
+    time::Duration,
+  };
+  use tokio::task::JoinSet;
+  use tokio_util::sync::CancellationToken;
+
+  let slow = Router::new().route(
+    "/",
+    get(|| async {
+      tokio::time::sleep(Duration::from_millis(300)).await;
+      "done"
+    }),
+  );
+
+  // A request in flight when shutdown starts is answered, and a periodic job stops between runs
+  let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let url = format!("http://{}/", listener.local_addr().unwrap());
+  let shutdown = CancellationToken::new();
+  let runs = Arc::new(AtomicUsize::new(0));
+  let mut jobs = JoinSet::new();
+  let counted = runs.clone();
+  jobs.spawn(every(Duration::from_millis(10), shutdown.clone(), move || {
+    let counted = counted.clone();
+    async move {
+      counted.fetch_add(1, Ordering::SeqCst);
+    }
+  }));
+  let server = tokio::spawn(serve(listener, slow.clone(), jobs, shutdown.clone(), Duration::from_secs(5)));
+  let request = tokio::spawn(reqwest::get(url));
+  tokio::time::sleep(Duration::from_millis(100)).await;
+  shutdown.cancel();
+  let response = request.await.unwrap().unwrap();
+  assert_eq!(response.text().await.unwrap(), "done");
+  assert!(server.await.unwrap().is_ok());
+  assert!(runs.load(Ordering::SeqCst) > 0);
+
+  // A job still running at the deadline is cut off, and the shutdown fails
+  let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let shutdown = CancellationToken::new();
+  let mut jobs = JoinSet::new();
+  jobs.spawn(std::future::pending::<()>());
+  shutdown.cancel();
+  let stopped = serve(listener, slow, jobs, shutdown, Duration::from_millis(100)).await;
+  assert!(matches!(stopped, Err(ShutdownError::Deadline(1))));
+}
+
+#[test]
+fn job_tasks_and_backoff() {
+  use crate::jobs::{backoff, Task};
+
+  // Payloads carry their kind alongside the task's fields, and read back as the same task
+  let tasks = [
+    Task::Thumbnails { book_id: 4 },
+    Task::CalibreImport,
+    Task::Backup {
+      name: String::from("nightly.zip"),
+    },
+    Task::Reindex,
+    Task::PurgeTrash { days: 30 },
+    Task::PruneAudit { days: 365 },
+    Task::PruneChanges { days: 30 },
+    Task::CleanupOrphans,
+    Task::StatsRollup {
+      date: chrono::NaiveDate::from_ymd_opt(2024, 2, 29).unwrap(),
+    },
+  ];
+  for task in &tasks {
+    let payload = serde_json::to_value(task).unwrap();
+    assert_eq!(payload["kind"], task.kind());
+    assert_eq!(&serde_json::from_value::<Task>(payload).unwrap(), task);
+  }
+  assert_eq!(
+    serde_json::to_value(&tasks[0]).unwrap(),
+    serde_json::json!({ "kind": "thumbnails", "book_id": 4 })
+  );
+  assert!(serde_json::from_str::<Task>(r#"{"kind": "format_disk"}"#).is_err());
+
+  // Backups can only be named, not placed
+  assert!(tasks.iter().all(|task| task.check().is_ok()));
+  for name in ["/etc/passwd", "../nightly.zip", "nested/nightly.zip", ".zip", "nightly.tar"] {
+    assert!(Task::Backup { name: String::from(name) }.check().is_err(), "{}", name);
+  }
+
+  // Retries back off exponentially up to an hour
+  assert_eq!(backoff(1), chrono::Duration::seconds(10));
+  assert_eq!(backoff(2), chrono::Duration::seconds(20));
+  assert_eq!(backoff(5), chrono::Duration::seconds(160));
+  assert_eq!(backoff(30), chrono::Duration::hours(1));
+}
+
+#[tokio::test]
+async fn schedule_due_runs() {
+  use crate::{
+    config::Config,
+    jobs::Task,
+    schedule::{latest_due, next_due, parse, Scheduler},
+  };
+  use chrono::{TimeZone, Utc};
+
+  assert!(parse("30 3 * * *").is_ok());
+  assert!(parse("61 3 * * *").is_err());
+  assert!(parse("every night").is_err());
+
+  // Nothing is due until the next time comes round, and missed runs collapse into the latest
+  let nightly = parse("30 3 * * *").unwrap();
+  let last = Utc.with_ymd_and_hms(2024, 3, 1, 3, 30, 0).unwrap();
+  assert_eq!(latest_due(&nightly, last, Utc.with_ymd_and_hms(2024, 3, 2, 3, 29, 59).unwrap()), None);
+  assert_eq!(
+    latest_due(&nightly, last, Utc.with_ymd_and_hms(2024, 3, 2, 3, 30, 0).unwrap()),
+    Some(Utc.with_ymd_and_hms(2024, 3, 2, 3, 30, 0).unwrap())
+  );
+  assert_eq!(
+    latest_due(&nightly, last, Utc.with_ymd_and_hms(2024, 3, 5, 12, 0, 0).unwrap()),
+    Some(Utc.with_ymd_and_hms(2024, 3, 5, 3, 30, 0).unwrap())
+  );
+  assert_eq!(next_due(&nightly, last), Some(Utc.with_ymd_and_hms(2024, 3, 2, 3, 30, 0).unwrap()));
+
+  // Tasks are switched off by an empty expression, and the purge and prunes by keeping their history forever
+  let mut config = Config::default();
+  config.schedule.backup = String::from("0 2 * * *");
+  config.schedule.cleanup_orphans = String::new();
+  config.retention.trash_days = 0;
+  config.retention.change_days = 0;
+  let conn = sqlx::mysql::MySqlPoolOptions::new().connect_lazy("mysql://libby@127.0.0.1:1/libby").unwrap();
+  let scheduler = Scheduler::new(Db { conn }, &config).unwrap();
+  let names: Vec<&str> = scheduler.entries.iter().map(|(name, _)| *name).collect();
+  assert_eq!(names, ["backup", "purge_trash", "prune_audit", "prune_changes", "stats_rollup"]);
+
+  let due = Utc.with_ymd_and_hms(2024, 3, 5, 2, 0, 0).unwrap();
+  assert_eq!(
+    scheduler.task("backup", due),
+    Some(Task::Backup {
+      name: String::from("libby-20240305-0200.zip")
+    })
+  );
+  assert_eq!(scheduler.task("purge_trash", due), None);
+  assert_eq!(scheduler.task("prune_audit", due), Some(Task::PruneAudit { days: 365 }));
+  assert_eq!(scheduler.task("prune_changes", due), None);
+  assert_eq!(scheduler.task("stats_rollup", due), Some(Task::StatsRollup { date: due.date_naive() }));
+
+  // A bad expression is reported with the key it came from
+  config.schedule.stats_rollup = String::from("55 25 * * *");
+  let problems = match config.validate() {
+    Err(crate::config::ConfigError::Invalid(problems)) => problems,
+    other => panic!("expected invalid config, got {:?}", other),
+  };
+  assert!(problems.iter().any(|problem| problem.starts_with("schedule.stats_rollup `55 25 * * *`")));
+}
+
+#[tokio::test]
+async fn entity_cache_evicts_expires_and_counts() {
+  use crate::{
+    config::CacheConfig,
+    db::cache::{settle_after, EntityCache},
+    metrics::CACHE_LOOKUPS,
+  };
+
+  static CACHE: EntityCache<String> = EntityCache::new("test");
+  let lookups = |result: &str| CACHE_LOOKUPS.with_label_values(&["test", result]).get();
+  let put = |id: u64, record: &str| CACHE.insert(id, String::from(record), CACHE.read_started());
+
+  // Off, nothing is kept or counted
+  put(1, "one");
+  assert_eq!(CACHE.get(1), None);
+  assert_eq!((lookups("hit"), lookups("miss")), (0, 0));
+
+  // The least recently used record makes way
+  let mut config = CacheConfig {
+    enabled: true,
+    capacity: 2,
+    ttl_secs: 60,
+  };
+  CACHE.configure(&config);
+  put(1, "one");
+  put(2, "two");
+  assert_eq!(CACHE.get(1).as_deref(), Some("one"));
+  put(3, "three");
+  assert_eq!(CACHE.get(2), None);
+  assert_eq!(CACHE.get(3).as_deref(), Some("three"));
+  assert_eq!((lookups("hit"), lookups("miss")), (2, 1));
+
+  // A change drops the record
+  CACHE.invalidate(1);
+  assert_eq!(CACHE.get(1), None);
+
+  // A read that overlapped a change is not kept
+  let ticket = CACHE.read_started();
+  CACHE.invalidate(3);
+  CACHE.insert(3, String::from("old three"), ticket);
+  assert_eq!(CACHE.get(3), None);
+
+  // What was written stays out until the work writing it has finished, and nothing that work reads is kept
+  let (tx_written, rx_written) = tokio::sync::oneshot::channel();
+  let (tx_finish, rx_finish) = tokio::sync::oneshot::channel::<()>();
+  let writer = tokio::spawn(settle_after(async move {
+    CACHE.invalidate(1);
+    put(2, "two");
+    tx_written.send(()).unwrap();
+    rx_finish.await.unwrap();
+  }));
+  rx_written.await.unwrap();
+  put(1, "old one");
+  assert_eq!(CACHE.get(1), None);
+  assert_eq!(CACHE.get(2), None);
+  put(2, "two");
+  assert_eq!(CACHE.get(2).as_deref(), Some("two"));
+  tx_finish.send(()).unwrap();
+  writer.await.unwrap();
+  put(1, "one");
+  assert_eq!(CACHE.get(1).as_deref(), Some("one"));
+  assert_eq!((lookups("hit"), lookups("miss")), (4, 5));
+
+  // An expired record is read again
+  config.ttl_secs = 0;
+  CACHE.configure(&config);
+  put(1, "one");
+  assert_eq!(CACHE.get(1), None);
+  assert_eq!((lookups("hit"), lookups("miss")), (4, 6));
+
+  // Switching the cache off drops what it held
+  config.ttl_secs = 60;
+  CACHE.configure(&config);
+  put(1, "one");
+  config.enabled = false;
+  CACHE.configure(&config);
+  assert_eq!(CACHE.get(1), None);
+  assert_eq!((lookups("hit"), lookups("miss")), (4, 6));
+
+  // A cache that is on needs room and a lifetime
+  let mut config = crate::config::Config::default();
+  config.cache.capacity = 0;
+  config.cache.ttl_secs = 0;
+  let problems = match config.validate() {
+    Err(crate::config::ConfigError::Invalid(problems)) => problems,
+    other => panic!("expected invalid config, got {:?}", other),
+  };
+  assert!(problems.iter().any(|problem| problem.starts_with("cache.capacity")));
+  assert!(problems.iter().any(|problem| problem.starts_with("cache.ttl_secs")));
+}
+
+#[tokio::test]
+async fn admin_routes_need_the_token() {
+  use crate::{
+    api::{router, AppState},
+    config::Config,
+    covers::{Covers, ThumbnailFormat},
+    storage::Storage,
+  };
+  use std::{sync::Arc, time::Duration};
+  use tokio_util::sync::CancellationToken;
+
+  let root = std::env::temp_dir().join(format!("libby-auth-{}", std::process::id()));
+  let conn = sqlx::mysql::MySqlPoolOptions::new()
+    .acquire_timeout(Duration::from_millis(200))
+    .connect_lazy("mysql://libby@127.0.0.1:1/libby")
+    .unwrap();
+  let mut config = Config::default();
+  config.auth.admin_token = String::from("a-long-enough-admin-token");
+  let state = AppState {
+    db: Db { conn },
+    storage: Storage::new(root.join("files")),
+    covers: Covers {
+      storage: Storage::new(root.join("covers")),
+      sizes: vec![],
+      format: ThumbnailFormat::Webp,
+    },
+    config: Arc::new(config),
+    shutdown: CancellationToken::new(),
+  };
+  let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let url = format!("http://{}", listener.local_addr().unwrap());
+  tokio::spawn(async move { axum::serve(listener, router(state)).await.unwrap() });
+  let client = reqwest::Client::new();
+
+  // Probes are open to anyone
+  assert_eq!(client.get(format!("{}/health/live", url)).send().await.unwrap().status(), 200);
+
+  // Admin routes want the token, and say how to send it
+  let refused = client.get(format!("{}/jobs", url)).send().await.unwrap();
+  assert_eq!(refused.status(), 401);
+  assert_eq!(refused.headers()["www-authenticate"], r#"Bearer realm="libby""#);
+  let wrong = client.get(format!("{}/jobs", url)).bearer_auth("not-the-admin-token").send().await.unwrap();
+  assert_eq!(wrong.status(), 401);
+  let admin = client
+    .get(format!("{}/jobs", url))
+    .bearer_auth("a-long-enough-admin-token")
+    .send()
+    .await
+    .unwrap();
+  assert_ne!(admin.status(), 401);
+
+  // GraphQL checks for itself, refusing bad credentials rather than treating them as none
+  let explorer = client.get(format!("{}/graphql", url)).send().await.unwrap();
+  assert_eq!(explorer.status(), 200);
+  let query = client
+    .post(format!("{}/graphql", url))
+    .bearer_auth("not-the-admin-token")
+    .header("content-type", "application/json")
+    .body(r#"{"query": "{ viewer { id } }"}"#)
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(query.status(), 401);
+  for path in ["/jobs", "/audit", "/changes", "/webhooks", "/trash"] {
+    assert_eq!(client.get(format!("{}{}", url, path)).send().await.unwrap().status(), 401, "{}", path);
+  }
+
+  // The catalog can be read by anyone but only edited by the administrator
+  let read = client.get(format!("{}/books/1", url)).send().await.unwrap();
+  assert_ne!(read.status(), 401);
+  let edit = client
+    .patch(format!("{}/books/1", url))
+    .header("content-type", "application/json")
+    .body(r#"{"name": "x"}"#)
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(edit.status(), 401);
+}
+
+#[tokio::test]
+async fn jobs_claims_count_and_fence() -> Result<(), sqlx::Error> {
+  use crate::db::jobs::Job;
+
+  let mut tx = create_tx().await;
+  let queued = Job::enqueue(&mut tx, "reindex", &serde_json::json!({ "kind": "reindex" }), None, 2).await?;
+
+  // Claiming counts the attempt and hands out a token
+  let lease = chrono::Utc::now() + chrono::Duration::hours(1);
+  let claimed = Job::claim_due(&mut tx, 1000, lease).await?;
+  let job = claimed.iter().find(|job| job.id == queued.id).expect("the job was due");
+  assert_eq!(job.attempts, 1);
+  let claim = job.claim.clone().expect("a claimed job has a token");
+
+  // Only the run holding the claim can record its outcome
+  assert!(!Job::mark_succeeded(&mut tx, job.id, "someone-else", &serde_json::json!({})).await?);
+  assert!(Job::mark_failed(&mut tx, job.id, &claim, "boom", Some(chrono::Utc::now())).await?);
+  assert!(!Job::mark_failed(&mut tx, job.id, &claim, "boom", Some(chrono::Utc::now())).await?);
+
+  // A run whose lease ran out on its last attempt fails for good instead of running again
+  let expired = chrono::Utc::now() - chrono::Duration::seconds(1);
+  let again = Job::claim_due(&mut tx, 1000, expired).await?;
+  assert_eq!(again.iter().find(|job| job.id == queued.id).map(|job| job.attempts), Some(2));
+  let reclaimed = Job::claim_due(&mut tx, 1000, lease).await?;
+  assert!(reclaimed.iter().all(|job| job.id != queued.id));
+  assert_eq!(Job::fetch_one(&mut tx, queued.id).await?.status, "failed");
+
+  Ok(())
+}
@@ -0,0 +1,381 @@
+//! Reads a Calibre library, its `metadata.db` and the book folders beside it, and syncs it into the catalog.
+//!
+//! Every imported book is linked to its Calibre UUID, so re-running the sync only touches books Calibre has modified since.
+
+use std::{
+  collections::{HashMap, HashSet},
+  path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Datelike, Utc};
+use sqlx::{
+  sqlite::{SqliteConnectOptions, SqliteConnection},
+  Connection, MySql, Sqlite, Transaction,
+};
+
+use super::{author_id, publisher_id, series_id, set_book_authors};
+use crate::db::{
+  authors::Author,
+  books::{normalize_isbn, Book, PartialBook},
+  calibre::CalibreLink,
+  identifiers::Identifier,
+  tags::Tag,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CalibreAuthor {
+  pub name: String,
+  pub sort: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CalibreFormat {
+  /// Upper case, as Calibre stores it: `EPUB`, `PDF`.
+  pub format: String,
+  /// File name without the extension.
+  pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalibreBook {
+  pub id: i64,
+  pub uuid: String,
+  pub title: String,
+  /// Folder of the book, relative to the library root.
+  pub path: String,
+  pub series_index: f64,
+  pub has_cover: bool,
+  pub last_modified: String,
+  pub pubdate: Option<DateTime<Utc>>,
+  pub authors: Vec<CalibreAuthor>,
+  pub publisher: Option<String>,
+  pub series: Option<String>,
+  pub tags: Vec<String>,
+  /// Scheme and value pairs, such as `("isbn", "9780552134637")`.
+  pub identifiers: Vec<(String, String)>,
+  pub languages: Vec<String>,
+  pub description: Option<String>,
+  pub formats: Vec<CalibreFormat>,
+}
+
+impl CalibreBook {
+  pub fn isbn(&self) -> Option<String> {
+    self
+      .identifiers
+      .iter()
+      .find(|(scheme, _)| scheme == "isbn")
+      .map(|(_, isbn)| normalize_isbn(isbn))
+      .filter(|isbn| !isbn.is_empty())
+  }
+
+  pub fn cover_path(&self, root: &Path) -> PathBuf {
+    root.join(&self.path).join("cover.jpg")
+  }
+
+  pub fn format_paths(&self, root: &Path) -> Vec<PathBuf> {
+    self
+      .formats
+      .iter()
+      .map(|format| root.join(&self.path).join(format!("{}.{}", format.name, format.format.to_lowercase())))
+      .collect()
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Library {
+  pub root: PathBuf,
+  pub books: Vec<CalibreBook>,
+}
+
+/// Parses Calibre's `2021-03-04 12:00:00.123456+00:00` timestamps. Calibre marks unknown dates with the year 101.
+pub fn calibre_date(value: &str) -> Option<DateTime<Utc>> {
+  DateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f%:z")
+    .or_else(|_| DateTime::parse_from_rfc3339(value))
+    .ok()
+    .map(|date| date.with_timezone(&Utc))
+    .filter(|date| date.year() > 101)
+}
+
+/// Calibre keeps descriptions as HTML; we keep plain text.
+pub fn strip_html(html: &str) -> String {
+  let mut text = String::with_capacity(html.len());
+  let mut in_tag = false;
+  for c in html.chars() {
+    match c {
+      '<' => in_tag = true,
+      '>' if in_tag => {
+        in_tag = false;
+        text.push(' ');
+      }
+      c if !in_tag => text.push(c),
+      _ => {}
+    }
+  }
+  let text = text
+    .replace("&nbsp;", " ")
+    .replace("&lt;", "<")
+    .replace("&gt;", ">")
+    .replace("&quot;", "\"")
+    .replace("&amp;", "&");
+  text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+/// Collects `(book, value)` rows into a list per book, keeping the query's order.
+fn group<T>(rows: Vec<(i64, T)>) -> HashMap<i64, Vec<T>> {
+  let mut grouped: HashMap<i64, Vec<T>> = HashMap::new();
+  for (book, value) in rows {
+    grouped.entry(book).or_default().push(value);
+  }
+  grouped
+}
+
+/// Opens `metadata.db` read-only and loads every book with its related rows.
+pub async fn read_library(root: &Path) -> Result<Library, sqlx::Error> {
+  let options = SqliteConnectOptions::new().filename(root.join("metadata.db")).read_only(true);
+  let mut conn = SqliteConnection::connect_with(&options).await?;
+
+  // Calibre declares its dates as TIMESTAMP; casting keeps them as the text it wrote.
+  let books = sqlx::query_as::<Sqlite, (i64, String, String, String, f64, i64, String, Option<String>)>(
+    r#"SELECT id, uuid, title, path, CAST(series_index AS REAL), CAST(has_cover AS INTEGER), CAST(last_modified AS TEXT), CAST(pubdate AS TEXT)
+    FROM books
+    ORDER BY id"#,
+  )
+  .fetch_all(&mut conn)
+  .await?;
+
+  let mut authors = group(
+    sqlx::query_as::<Sqlite, (i64, String, Option<String>)>(
+      r#"SELECT books_authors_link.book, authors.name, authors.sort
+      FROM books_authors_link
+      INNER JOIN authors ON authors.id = books_authors_link.author
+      ORDER BY books_authors_link.id"#,
+    )
+    .fetch_all(&mut conn)
+    .await?
+    .into_iter()
+    .map(|(book, name, sort)| (book, CalibreAuthor { name, sort }))
+    .collect(),
+  );
+  let mut publishers = group(
+    sqlx::query_as::<Sqlite, (i64, String)>(
+      r#"SELECT books_publishers_link.book, publishers.name
+      FROM books_publishers_link
+      INNER JOIN publishers ON publishers.id = books_publishers_link.publisher"#,
+    )
+    .fetch_all(&mut conn)
+    .await?,
+  );
+  let mut series = group(
+    sqlx::query_as::<Sqlite, (i64, String)>(
+      r#"SELECT books_series_link.book, series.name
+      FROM books_series_link
+      INNER JOIN series ON series.id = books_series_link.series"#,
+    )
+    .fetch_all(&mut conn)
+    .await?,
+  );
+  let mut tags = group(
+    sqlx::query_as::<Sqlite, (i64, String)>(
+      r#"SELECT books_tags_link.book, tags.name
+      FROM books_tags_link
+      INNER JOIN tags ON tags.id = books_tags_link.tag
+      ORDER BY tags.name"#,
+    )
+    .fetch_all(&mut conn)
+    .await?,
+  );
+  let mut identifiers = group(
+    sqlx::query_as::<Sqlite, (i64, String, String)>(r#"SELECT book, type, val FROM identifiers ORDER BY type"#)
+      .fetch_all(&mut conn)
+      .await?
+      .into_iter()
+      .map(|(book, scheme, value)| (book, (scheme.to_lowercase(), value)))
+      .collect(),
+  );
+  let mut languages = group(
+    sqlx::query_as::<Sqlite, (i64, String)>(
+      r#"SELECT books_languages_link.book, languages.lang_code
+      FROM books_languages_link
+      INNER JOIN languages ON languages.id = books_languages_link.lang_code
+      ORDER BY books_languages_link.item_order"#,
+    )
+    .fetch_all(&mut conn)
+    .await?,
+  );
+  let mut comments = group(
+    sqlx::query_as::<Sqlite, (i64, String)>(r#"SELECT book, text FROM comments"#)
+      .fetch_all(&mut conn)
+      .await?,
+  );
+  let mut formats = group(
+    sqlx::query_as::<Sqlite, (i64, String, String)>(r#"SELECT book, format, name FROM data ORDER BY format"#)
+      .fetch_all(&mut conn)
+      .await?
+      .into_iter()
+      .map(|(book, format, name)| (book, CalibreFormat { format, name }))
+      .collect(),
+  );
+  conn.close().await?;
+
+  let books = books
+    .into_iter()
+    .map(|(id, uuid, title, path, series_index, has_cover, last_modified, pubdate)| CalibreBook {
+      id,
+      uuid,
+      title,
+      path,
+      series_index,
+      has_cover: has_cover != 0,
+      last_modified,
+      pubdate: pubdate.as_deref().and_then(calibre_date),
+      authors: authors.remove(&id).unwrap_or_default(),
+      publisher: publishers.remove(&id).and_then(|names| names.into_iter().next()),
+      series: series.remove(&id).and_then(|names| names.into_iter().next()),
+      tags: tags.remove(&id).unwrap_or_default(),
+      identifiers: identifiers.remove(&id).unwrap_or_default(),
+      languages: languages.remove(&id).unwrap_or_default(),
+      description: comments
+        .remove(&id)
+        .and_then(|texts| texts.into_iter().next())
+        .map(|html| strip_html(&html))
+        .filter(|text| !text.is_empty()),
+      formats: formats.remove(&id).unwrap_or_default(),
+    })
+    .collect();
+
+  Ok(Library {
+    root: root.to_path_buf(),
+    books,
+  })
+}
+
+/// Files to attach once the metadata has been committed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pending {
+  pub uuid: String,
+  pub book_id: u64,
+  pub files: Vec<PathBuf>,
+  pub cover: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+  pub created: usize,
+  pub updated: usize,
+  pub unchanged: usize,
+  /// UUIDs synced before that are no longer in the library. Their books are kept.
+  pub missing: Vec<String>,
+  /// UUIDs whose books are in the trash. They are left there rather than brought back or imported again.
+  pub trashed: Vec<String>,
+  pub pending: Vec<Pending>,
+}
+
+async fn existing_book<'a>(tx: &mut Transaction<'a, MySql>, book_id: u64) -> Result<Option<Book>, sqlx::Error> {
+  match Book::fetch_one(tx, book_id).await {
+    Ok(book) => Ok(Some(book)),
+    Err(sqlx::Error::RowNotFound) => Ok(None),
+    Err(err) => Err(err),
+  }
+}
+
+/// Writes one Calibre book's metadata over `existing`, or creates it. Calibre has no page counts, so ours are kept.
+async fn write_book<'a>(tx: &mut Transaction<'a, MySql>, existing: Option<Book>, calibre: &CalibreBook) -> Result<Book, sqlx::Error> {
+  let publisher_id = match &calibre.publisher {
+    Some(name) => Some(publisher_id(tx, name).await?),
+    None => None,
+  };
+  let series_id = match &calibre.series {
+    Some(name) => Some(series_id(tx, name).await?),
+    None => None,
+  };
+  // Without an ISBN in Calibre the one stored is kept.
+  let partial = PartialBook {
+    isbn: calibre.isbn(),
+    name: Some(calibre.title.clone()),
+    description: calibre.description.clone(),
+    language: calibre.languages.first().cloned(),
+    nsfw: None,
+    num_pages: None,
+    image_formatted: None,
+    publisher_id,
+    series_id,
+    series_index: series_id.map(|_| calibre.series_index.max(0.0) as u16),
+    date_published: calibre.pubdate,
+  };
+  let book = match existing {
+    Some(book) => Book::update(tx, book.id, partial).await?,
+    None => {
+      let isbn = partial.isbn.clone().or_else(|| Some(String::new()));
+      Book::create_partial(
+        tx,
+        PartialBook {
+          isbn,
+          num_pages: Some(0),
+          ..partial
+        },
+      )
+      .await?
+    }
+  };
+
+  let mut author_ids = Vec::with_capacity(calibre.authors.len());
+  for author in &calibre.authors {
+    let id = author_id(tx, &author.name).await?;
+    Author::set_sort_name(tx, id, author.sort.as_deref()).await?;
+    author_ids.push(id);
+  }
+  set_book_authors(tx, &book, &author_ids).await?;
+
+  Tag::set_for_book(tx, book.id, &calibre.tags).await?;
+  let identifiers: Vec<(String, String)> = calibre.identifiers.iter().filter(|(scheme, _)| scheme != "isbn").cloned().collect();
+  Identifier::set_for_book(tx, book.id, &identifiers).await?;
+  Ok(book)
+}
+
+/// Syncs the library's metadata in `tx`, which the caller commits. Books are matched by Calibre UUID, then ISBN.
+/// Files and covers of created or changed books come back as `pending`, since they live outside the database.
+pub async fn sync<'a>(tx: &mut Transaction<'a, MySql>, library: &Library) -> Result<SyncReport, sqlx::Error> {
+  let links: HashMap<String, CalibreLink> = CalibreLink::fetch_all(tx).await?.into_iter().map(|link| (link.uuid.clone(), link)).collect();
+  let mut report = SyncReport::default();
+
+  for calibre in &library.books {
+    let link = links.get(&calibre.uuid);
+    if link.is_some_and(|link| link.last_modified == calibre.last_modified) {
+      report.unchanged += 1;
+      continue;
+    }
+
+    let existing = match link {
+      Some(link) if Book::is_trashed(tx, link.book_id).await? => {
+        report.trashed.push(calibre.uuid.clone());
+        continue;
+      }
+      Some(link) => existing_book(tx, link.book_id).await?,
+      None => None,
+    };
+    let existing = match (existing, calibre.isbn()) {
+      (Some(book), _) => Some(book),
+      (None, Some(isbn)) => Book::fetch_by_isbn(tx, &isbn).await?,
+      (None, None) => None,
+    };
+    if existing.is_some() {
+      report.updated += 1;
+    } else {
+      report.created += 1;
+    }
+
+    let book = write_book(tx, existing, calibre).await?;
+    CalibreLink::upsert(tx, &calibre.uuid, book.id, &calibre.last_modified).await?;
+    report.pending.push(Pending {
+      uuid: calibre.uuid.clone(),
+      book_id: book.id,
+      files: calibre.format_paths(&library.root),
+      cover: calibre.has_cover.then(|| calibre.cover_path(&library.root)),
+    });
+  }
+
+  let present: HashSet<&str> = library.books.iter().map(|book| book.uuid.as_str()).collect();
+  report.missing = links.into_keys().filter(|uuid| !present.contains(uuid.as_str())).collect();
+  report.missing.sort();
+  Ok(report)
+}
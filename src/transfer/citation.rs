@@ -0,0 +1,484 @@
+//! Citations for catalog books, as reference-manager records (BibTeX, RIS, CSL-JSON) and as plain-text bibliography entries.
+
+use std::{collections::HashMap, str::FromStr};
+
+use chrono::{DateTime, Datelike, Utc};
+use serde_json::{json, Map, Value};
+use sqlx::{MySql, Transaction};
+
+use crate::db::{authors::Author, books::Book, publisher::Publisher, series::Series};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+  Bibtex,
+  Ris,
+  CslJson,
+  /// APA, 7th edition.
+  Apa,
+  /// MLA, 9th edition.
+  Mla,
+  /// Chicago, 17th edition, bibliography entry.
+  Chicago,
+}
+
+impl FromStr for Style {
+  type Err = String;
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    match value.to_lowercase().as_str() {
+      "bibtex" | "bib" => Ok(Style::Bibtex),
+      "ris" => Ok(Style::Ris),
+      "csl-json" | "csl" | "json" => Ok(Style::CslJson),
+      "apa" => Ok(Style::Apa),
+      "mla" => Ok(Style::Mla),
+      "chicago" => Ok(Style::Chicago),
+      _ => Err(format!("unknown citation style '{}'", value)),
+    }
+  }
+}
+
+impl Style {
+  pub fn mime(&self) -> &'static str {
+    match self {
+      Style::Bibtex => "application/x-bibtex",
+      Style::Ris => "application/x-research-info-systems",
+      Style::CslJson => "application/vnd.citationstyles.csl+json",
+      Style::Apa | Style::Mla | Style::Chicago => "text/plain; charset=utf-8",
+    }
+  }
+
+  pub fn extension(&self) -> &'static str {
+    match self {
+      Style::Bibtex => "bib",
+      Style::Ris => "ris",
+      Style::CslJson => "json",
+      Style::Apa | Style::Mla | Style::Chicago => "txt",
+    }
+  }
+}
+
+/// A personal name split the way citation styles need it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Name {
+  pub family: String,
+  pub given: Option<String>,
+}
+
+impl Name {
+  /// Prefers the author's sort name (`Pratchett, Terry`), otherwise takes the last word of the name as the family name.
+  pub fn from_author(name: &str, sort_name: Option<&str>) -> Name {
+    if let Some((family, given)) = sort_name.and_then(|sort_name| sort_name.split_once(',')) {
+      return Name {
+        family: family.trim().to_string(),
+        given: Some(given.trim().to_string()).filter(|given| !given.is_empty()),
+      };
+    }
+    match name.trim().rsplit_once(' ') {
+      Some((given, family)) => Name {
+        family: family.to_string(),
+        given: Some(given.trim().to_string()),
+      },
+      None => Name {
+        family: name.trim().to_string(),
+        given: None,
+      },
+    }
+  }
+
+  /// `Pratchett, Terry`
+  fn inverted(&self) -> String {
+    match &self.given {
+      Some(given) => format!("{}, {}", self.family, given),
+      None => self.family.clone(),
+    }
+  }
+
+  /// `Terry Pratchett`
+  fn natural(&self) -> String {
+    match &self.given {
+      Some(given) => format!("{} {}", given, self.family),
+      None => self.family.clone(),
+    }
+  }
+
+  /// `Tolkien, J. R. R.`, with hyphenated given names kept as `J.-P.`
+  fn initialed(&self) -> String {
+    let Some(given) = &self.given else {
+      return self.family.clone();
+    };
+    let initials: Vec<String> = given
+      .split_whitespace()
+      .map(|part| {
+        part
+          .split('-')
+          .filter_map(|piece| piece.chars().next())
+          .map(|initial| format!("{}.", initial))
+          .collect::<Vec<String>>()
+          .join("-")
+      })
+      .collect();
+    format!("{}, {}", self.family, initials.join(" "))
+  }
+}
+
+/// Everything a citation of one book draws on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Citation {
+  pub isbn: String,
+  pub title: String,
+  pub authors: Vec<Name>,
+  pub publisher: Option<String>,
+  pub city: Option<String>,
+  pub date_published: Option<DateTime<Utc>>,
+  pub num_pages: Option<u16>,
+  pub language: Option<String>,
+  pub series: Option<(String, Option<u16>)>,
+}
+
+impl Citation {
+  pub fn new(book: &Book, authors: &[Author], publisher: Option<(&str, Option<&str>)>, series: Option<&Series>) -> Citation {
+    Citation {
+      isbn: book.isbn.clone(),
+      title: book.name.clone(),
+      authors: authors
+        .iter()
+        .map(|author| Name::from_author(&author.name, author.sort_name.as_deref()))
+        .collect(),
+      publisher: publisher.map(|(name, _)| name.to_string()).filter(|name| !name.is_empty()),
+      city: publisher.and_then(|(_, city)| city).map(String::from).filter(|city| !city.is_empty()),
+      date_published: book.date_published,
+      num_pages: Some(book.num_pages).filter(|pages| *pages > 0),
+      language: book.language.clone(),
+      series: series.map(|series| (series.name.clone(), book.series_index)),
+    }
+  }
+
+  pub async fn fetch<'a>(tx: &mut Transaction<'a, MySql>, book: &Book) -> Result<Citation, sqlx::Error> {
+    let authors = book.fetch_authors(tx).await?;
+    let publisher = match book.publisher_id {
+      Some(_) => Some(book.fetch_publisher(tx).await?),
+      None => None,
+    };
+    let series = match book.series_id {
+      Some(series_id) => Some(Series::fetch_one(tx, series_id).await?),
+      None => None,
+    };
+    Ok(Citation::new(
+      book,
+      &authors,
+      publisher.as_ref().map(|publisher| (publisher.name.as_str(), publisher.city.as_deref())),
+      series.as_ref(),
+    ))
+  }
+
+  /// [`Citation::fetch`] for many books at once, with one query per related table rather than several per book.
+  pub async fn fetch_many<'a>(tx: &mut Transaction<'a, MySql>, books: &[Book]) -> Result<Vec<Citation>, sqlx::Error> {
+    let book_ids: Vec<u64> = books.iter().map(|book| book.id).collect();
+    let publisher_ids: Vec<u16> = books.iter().filter_map(|book| book.publisher_id).collect();
+    let series_ids: Vec<u64> = books.iter().filter_map(|book| book.series_id).collect();
+
+    let mut authors: HashMap<u64, Vec<Author>> = HashMap::new();
+    for entry in Author::fetch_by_books(tx, &book_ids).await? {
+      authors.entry(entry.book_id).or_default().push(entry.author);
+    }
+    let publishers: HashMap<u16, Publisher> = Publisher::fetch_many(tx, &publisher_ids)
+      .await?
+      .into_iter()
+      .map(|publisher| (publisher.id, publisher))
+      .collect();
+    let series: HashMap<u64, Series> = Series::fetch_many(tx, &series_ids)
+      .await?
+      .into_iter()
+      .map(|series| (series.id, series))
+      .collect();
+
+    Ok(
+      books
+        .iter()
+        .map(|book| {
+          let publisher = book.publisher_id.and_then(|publisher_id| publishers.get(&publisher_id));
+          Citation::new(
+            book,
+            authors.get(&book.id).map_or(&[], Vec::as_slice),
+            publisher.map(|publisher| (publisher.name.as_str(), publisher.city.as_deref())),
+            book.series_id.and_then(|series_id| series.get(&series_id)),
+          )
+        })
+        .collect(),
+    )
+  }
+
+  fn year(&self) -> Option<i32> {
+    self.date_published.map(|date| date.year())
+  }
+
+  /// `pratchett1987mort`: first author's family name, year and first significant title word, in lower-case ASCII.
+  pub fn key(&self) -> String {
+    let ascii = |text: &str| text.chars().filter(char::is_ascii_alphanumeric).collect::<String>().to_lowercase();
+    let family = self.authors.first().map(|name| ascii(&name.family)).unwrap_or_default();
+    let word = self
+      .title
+      .split_whitespace()
+      .map(ascii)
+      .find(|word| !word.is_empty() && !matches!(word.as_str(), "a" | "an" | "the"))
+      .unwrap_or_default();
+    let year = self.year().map(|year| year.to_string()).unwrap_or_default();
+    let key = format!("{}{}{}", family, year, word);
+    if key.is_empty() {
+      format!("isbn{}", self.isbn)
+    } else {
+      key
+    }
+  }
+
+  pub fn render(&self, style: Style) -> String {
+    match style {
+      Style::Bibtex => self.bibtex(),
+      Style::Ris => self.ris(),
+      Style::CslJson => serde_json::to_string_pretty(&Value::Array(vec![self.csl_json()])).unwrap_or_default(),
+      Style::Apa => self.apa(),
+      Style::Mla => self.mla(),
+      Style::Chicago => self.chicago(),
+    }
+  }
+
+  pub fn bibtex(&self) -> String {
+    let mut fields: Vec<(&str, String)> = Vec::new();
+    if !self.authors.is_empty() {
+      fields.push(("author", self.authors.iter().map(Name::inverted).collect::<Vec<String>>().join(" and ")));
+    }
+    fields.push(("title", self.title.clone()));
+    if let Some((series, index)) = &self.series {
+      fields.push(("series", series.clone()));
+      if let Some(index) = index {
+        fields.push(("number", index.to_string()));
+      }
+    }
+    if let Some(publisher) = &self.publisher {
+      fields.push(("publisher", publisher.clone()));
+    }
+    if let Some(city) = &self.city {
+      fields.push(("address", city.clone()));
+    }
+    if let Some(date) = self.date_published {
+      fields.push(("year", date.year().to_string()));
+      fields.push(("month", date.month().to_string()));
+    }
+    if let Some(pages) = self.num_pages {
+      fields.push(("pagetotal", pages.to_string()));
+    }
+    if let Some(language) = &self.language {
+      fields.push(("language", language.clone()));
+    }
+    if !self.isbn.is_empty() {
+      fields.push(("isbn", self.isbn.clone()));
+    }
+
+    let body: Vec<String> = fields
+      .iter()
+      .map(|(name, value)| format!("  {} = {{{}}}", name, bibtex_escape(value)))
+      .collect();
+    format!("@book{{{},\n{}\n}}\n", self.key(), body.join(",\n"))
+  }
+
+  pub fn ris(&self) -> String {
+    let mut lines = vec![(String::from("TY"), String::from("BOOK"))];
+    let mut push = |tag: &str, value: String| lines.push((tag.to_string(), value));
+    for author in &self.authors {
+      push("AU", author.inverted());
+    }
+    push("TI", self.title.clone());
+    if let Some((series, index)) = &self.series {
+      push("T3", series.clone());
+      if let Some(index) = index {
+        push("VL", index.to_string());
+      }
+    }
+    if let Some(publisher) = &self.publisher {
+      push("PB", publisher.clone());
+    }
+    if let Some(city) = &self.city {
+      push("CY", city.clone());
+    }
+    if let Some(date) = self.date_published {
+      push("PY", date.year().to_string());
+      push("DA", date.format("%Y/%m/%d").to_string());
+    }
+    if let Some(pages) = self.num_pages {
+      push("SP", pages.to_string());
+    }
+    if let Some(language) = &self.language {
+      push("LA", language.clone());
+    }
+    if !self.isbn.is_empty() {
+      push("SN", self.isbn.clone());
+    }
+    push("ID", self.key());
+    push("ER", String::new());
+
+    // RIS lines are `TAG  - value`, CRLF terminated; the closing `ER` carries no value.
+    lines
+      .iter()
+      .map(|(tag, value)| format!("{}  - {}", tag, value).trim_end().to_string() + "\r\n")
+      .collect()
+  }
+
+  pub fn csl_json(&self) -> Value {
+    let mut item = Map::new();
+    item.insert(String::from("id"), json!(self.key()));
+    item.insert(String::from("type"), json!("book"));
+    item.insert(String::from("title"), json!(self.title));
+    if !self.authors.is_empty() {
+      let authors: Vec<Value> = self
+        .authors
+        .iter()
+        .map(|name| match &name.given {
+          Some(given) => json!({ "family": name.family, "given": given }),
+          None => json!({ "literal": name.family }),
+        })
+        .collect();
+      item.insert(String::from("author"), Value::Array(authors));
+    }
+    if let Some(publisher) = &self.publisher {
+      item.insert(String::from("publisher"), json!(publisher));
+    }
+    if let Some(city) = &self.city {
+      item.insert(String::from("publisher-place"), json!(city));
+    }
+    if let Some(date) = self.date_published {
+      item.insert(String::from("issued"), json!({ "date-parts": [[date.year(), date.month(), date.day()]] }));
+    }
+    if let Some((series, index)) = &self.series {
+      item.insert(String::from("collection-title"), json!(series));
+      if let Some(index) = index {
+        item.insert(String::from("collection-number"), json!(index.to_string()));
+      }
+    }
+    if let Some(pages) = self.num_pages {
+      item.insert(String::from("number-of-pages"), json!(pages.to_string()));
+    }
+    if let Some(language) = &self.language {
+      item.insert(String::from("language"), json!(language));
+    }
+    if !self.isbn.is_empty() {
+      item.insert(String::from("ISBN"), json!(self.isbn));
+    }
+    Value::Object(item)
+  }
+
+  /// `Pratchett, T., & Gaiman, N. (1990). Good omens. Gollancz.` APA lists up to 20 authors and no longer gives the city.
+  pub fn apa(&self) -> String {
+    let names: Vec<String> = self.authors.iter().map(Name::initialed).collect();
+    let authors = match names.len() {
+      0 => String::new(),
+      1 => names[0].clone(),
+      count if count <= 20 => format!("{}, & {}", names[..count - 1].join(", "), names[count - 1]),
+      count => format!("{}, . . . {}", names[..19].join(", "), names[count - 1]),
+    };
+    let year = self.year().map_or(String::from("n.d."), |year| year.to_string());
+
+    let mut citation = if authors.is_empty() {
+      format!("{}. ({}).", self.title, year)
+    } else {
+      format!("{} ({}). {}.", terminate(&authors), year, self.title)
+    };
+    if let Some(publisher) = &self.publisher {
+      citation.push_str(&format!(" {}.", publisher));
+    }
+    citation
+  }
+
+  /// `Pratchett, Terry, and Neil Gaiman. Good Omens. Gollancz, 1990.` Three or more authors become the first and `et al.`
+  pub fn mla(&self) -> String {
+    let authors = match self.authors.as_slice() {
+      [] => String::new(),
+      [only] => only.inverted(),
+      [first, second] => format!("{}, and {}", first.inverted(), second.natural()),
+      [first, ..] => format!("{}, et al", first.inverted()),
+    };
+
+    let mut citation = String::new();
+    if !authors.is_empty() {
+      citation.push_str(&format!("{} ", terminate(&authors)));
+    }
+    citation.push_str(&format!("{}.", self.title));
+    let imprint: Vec<String> = self.publisher.iter().cloned().chain(self.year().map(|year| year.to_string())).collect();
+    if !imprint.is_empty() {
+      citation.push_str(&format!(" {}.", imprint.join(", ")));
+    }
+    citation
+  }
+
+  /// `Pratchett, Terry, and Neil Gaiman. Good Omens. London: Gollancz, 1990.` Up to ten authors are listed, otherwise seven and `et al.`
+  pub fn chicago(&self) -> String {
+    let listed = if self.authors.len() > 10 { &self.authors[..7] } else { &self.authors[..] };
+    let names: Vec<String> = listed
+      .iter()
+      .enumerate()
+      .map(|(index, name)| if index == 0 { name.inverted() } else { name.natural() })
+      .collect();
+    let mut authors = match names.len() {
+      0 => String::new(),
+      1 => names[0].clone(),
+      2 => format!("{}, and {}", names[0], names[1]),
+      count => format!("{}, and {}", names[..count - 1].join(", "), names[count - 1]),
+    };
+    if listed.len() < self.authors.len() {
+      authors = format!("{}, et al", names.join(", "));
+    }
+
+    let mut citation = String::new();
+    if !authors.is_empty() {
+      citation.push_str(&format!("{} ", terminate(&authors)));
+    }
+    citation.push_str(&format!("{}.", self.title));
+    let place = match (&self.city, &self.publisher) {
+      (Some(city), Some(publisher)) => Some(format!("{}: {}", city, publisher)),
+      (None, Some(publisher)) => Some(publisher.clone()),
+      (Some(city), None) => Some(city.clone()),
+      (None, None) => None,
+    };
+    let imprint: Vec<String> = place.into_iter().chain(self.year().map(|year| year.to_string())).collect();
+    if !imprint.is_empty() {
+      citation.push_str(&format!(" {}.", imprint.join(", ")));
+    }
+    citation
+  }
+}
+
+/// Ends `text` with a full stop, without doubling one left by an initial or `et al.`
+fn terminate(text: &str) -> String {
+  if text.ends_with('.') {
+    text.to_string()
+  } else {
+    format!("{}.", text)
+  }
+}
+
+/// Escapes the characters BibTeX treats specially. Braces stay balanced, so they are escaped rather than dropped.
+fn bibtex_escape(value: &str) -> String {
+  let mut escaped = String::with_capacity(value.len());
+  for c in value.chars() {
+    match c {
+      '\\' => escaped.push_str(r"\textbackslash{}"),
+      '{' | '}' | '&' | '%' | '$' | '#' | '_' => {
+        escaped.push('\\');
+        escaped.push(c);
+      }
+      '~' => escaped.push_str(r"\textasciitilde{}"),
+      '^' => escaped.push_str(r"\textasciicircum{}"),
+      c => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+/// Renders several citations as one document in `style`: concatenated records, a CSL-JSON array, or one entry per line.
+pub fn render_all(citations: &[Citation], style: Style) -> String {
+  match style {
+    Style::CslJson => serde_json::to_string_pretty(&Value::Array(citations.iter().map(Citation::csl_json).collect())).unwrap_or_default(),
+    Style::Bibtex => citations.iter().map(Citation::bibtex).collect::<Vec<String>>().join("\n"),
+    Style::Ris => citations.iter().map(Citation::ris).collect::<Vec<String>>().join("\r\n"),
+    Style::Apa | Style::Mla | Style::Chicago => citations.iter().map(|citation| citation.render(style) + "\n").collect(),
+  }
+}
@@ -0,0 +1,668 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use sqlx::{MySql, Transaction};
+
+use super::{author_id, publisher_id, series_id, set_book_authors};
+use crate::db::{
+  authors::{Author, PartialAuthor},
+  books::{normalize_isbn, Book, PartialBook},
+  progress::Progress,
+  publisher::{PartialPublisher, Publisher},
+  series::Series,
+  user::User,
+};
+
+/// Separator between author names in the `authors` column of a books CSV.
+pub const AUTHOR_SEPARATOR: char = ';';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entity {
+  Authors,
+  Publishers,
+  Books,
+  Progress,
+}
+
+impl Entity {
+  pub fn parse(value: &str) -> Option<Entity> {
+    match value {
+      "authors" => Some(Entity::Authors),
+      "publishers" => Some(Entity::Publishers),
+      "books" => Some(Entity::Books),
+      "progress" => Some(Entity::Progress),
+      _ => None,
+    }
+  }
+
+  /// Our field names, in export order. Natural keys come first.
+  pub fn fields(self) -> &'static [&'static str] {
+    match self {
+      Entity::Authors => &["name", "description", "birth"],
+      Entity::Publishers => &["name", "description", "city"],
+      Entity::Books => &[
+        "isbn",
+        "name",
+        "authors",
+        "description",
+        "language",
+        "nsfw",
+        "num_pages",
+        "publisher",
+        "series",
+        "series_index",
+        "date_published",
+      ],
+      Entity::Progress => &["user", "isbn", "current_page"],
+    }
+  }
+}
+
+/// Maps our field names onto the CSV headers of a particular file. Unmapped fields use their own name as the header.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ColumnMapping {
+  columns: HashMap<String, String>,
+}
+
+impl ColumnMapping {
+  pub fn new(columns: HashMap<String, String>) -> Self {
+    ColumnMapping { columns }
+  }
+
+  pub fn header<'a>(&'a self, field: &'a str) -> &'a str {
+    self.columns.get(field).map(String::as_str).unwrap_or(field)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RowError {
+  /// 1-based line in the CSV, counting the header.
+  pub row: u64,
+  pub column: Option<String>,
+  pub message: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportReport {
+  pub rows: usize,
+  pub created: usize,
+  pub updated: usize,
+  pub unchanged: usize,
+  pub errors: Vec<RowError>,
+  /// False when validation failed or the import was a dry run; nothing was written.
+  pub applied: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthorRow {
+  pub name: String,
+  pub description: Option<String>,
+  pub birth: Option<NaiveDate>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublisherRow {
+  pub name: String,
+  pub description: Option<String>,
+  pub city: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BookRow {
+  pub isbn: String,
+  pub name: String,
+  pub authors: Vec<String>,
+  pub description: Option<String>,
+  pub language: Option<String>,
+  pub nsfw: bool,
+  pub num_pages: u16,
+  pub publisher: Option<String>,
+  pub series: Option<String>,
+  pub series_index: Option<u16>,
+  pub date_published: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgressRow {
+  pub user: String,
+  pub isbn: String,
+  pub current_page: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rows {
+  Authors(Vec<(u64, AuthorRow)>),
+  Publishers(Vec<(u64, PublisherRow)>),
+  Books(Vec<(u64, BookRow)>),
+  Progress(Vec<(u64, ProgressRow)>),
+}
+
+impl Rows {
+  fn len(&self) -> usize {
+    match self {
+      Rows::Authors(rows) => rows.len(),
+      Rows::Publishers(rows) => rows.len(),
+      Rows::Books(rows) => rows.len(),
+      Rows::Progress(rows) => rows.len(),
+    }
+  }
+}
+
+pub fn parse_date(value: &str) -> Option<DateTime<Utc>> {
+  DateTime::parse_from_rfc3339(value).map(|date| date.with_timezone(&Utc)).ok().or_else(|| {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+      .ok()
+      .map(|date| date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+  })
+}
+
+/// One CSV record, read through the column mapping and collecting errors against its row.
+struct Record<'a> {
+  row: u64,
+  record: &'a ::csv::StringRecord,
+  positions: &'a HashMap<&'static str, usize>,
+  errors: &'a mut Vec<RowError>,
+}
+
+impl Record<'_> {
+  fn error(&mut self, field: &str, message: impl Into<String>) {
+    self.errors.push(RowError {
+      row: self.row,
+      column: Some(field.to_string()),
+      message: message.into(),
+    });
+  }
+
+  fn optional(&self, field: &str) -> Option<String> {
+    self
+      .positions
+      .get(field)
+      .and_then(|&position| self.record.get(position))
+      .map(str::trim)
+      .filter(|value| !value.is_empty())
+      .map(String::from)
+  }
+
+  fn required(&mut self, field: &str) -> Option<String> {
+    let value = self.optional(field);
+    if value.is_none() {
+      self.error(field, "is required");
+    }
+    value
+  }
+
+  fn parsed<T: std::str::FromStr>(&mut self, field: &str, required: bool) -> Option<T> {
+    let value = if required { self.required(field)? } else { self.optional(field)? };
+    match value.parse() {
+      Ok(parsed) => Some(parsed),
+      Err(_) => {
+        self.error(field, format!("'{}' is not a valid number", value));
+        None
+      }
+    }
+  }
+
+  fn flag(&mut self, field: &str) -> bool {
+    match self.optional(field).map(|value| value.to_ascii_lowercase()).as_deref() {
+      None | Some("false") | Some("0") | Some("no") => false,
+      Some("true") | Some("1") | Some("yes") => true,
+      Some(value) => {
+        let message = format!("'{}' is not true or false", value);
+        self.error(field, message);
+        false
+      }
+    }
+  }
+
+  fn date(&mut self, field: &str) -> Option<DateTime<Utc>> {
+    let value = self.optional(field)?;
+    let date = parse_date(&value);
+    if date.is_none() {
+      self.error(field, format!("'{}' is not a YYYY-MM-DD or RFC 3339 date", value));
+    }
+    date
+  }
+}
+
+/// Reads and validates every row without touching the database. Rows with errors are left out of the result.
+pub fn parse(entity: Entity, mapping: &ColumnMapping, data: &[u8]) -> (Rows, Vec<RowError>) {
+  let mut errors = Vec::new();
+  let mut reader = ::csv::ReaderBuilder::new().flexible(true).from_reader(data);
+
+  let headers = match reader.headers() {
+    Ok(headers) => headers.clone(),
+    Err(err) => {
+      errors.push(RowError {
+        row: 1,
+        column: None,
+        message: err.to_string(),
+      });
+      return (empty_rows(entity), errors);
+    }
+  };
+  let positions: HashMap<&'static str, usize> = entity
+    .fields()
+    .iter()
+    .filter_map(|field| {
+      headers
+        .iter()
+        .position(|header| header.trim() == mapping.header(field))
+        .map(|position| (*field, position))
+    })
+    .collect();
+
+  let mut rows = empty_rows(entity);
+  let mut seen = HashSet::new();
+  for result in reader.records() {
+    let record = match result {
+      Ok(record) => record,
+      Err(err) => {
+        let row = err.position().map_or(0, |position| position.line());
+        errors.push(RowError {
+          row,
+          column: None,
+          message: err.to_string(),
+        });
+        continue;
+      }
+    };
+    let row = record.position().map_or(0, |position| position.line());
+    let before = errors.len();
+    let mut record = Record {
+      row,
+      record: &record,
+      positions: &positions,
+      errors: &mut errors,
+    };
+
+    let key = match &mut rows {
+      Rows::Authors(rows) => {
+        let name = record.required("name");
+        let description = record.optional("description");
+        let birth = record.date("birth").map(|date| date.date_naive());
+        name.inspect(|name| {
+          rows.push((
+            row,
+            AuthorRow {
+              name: name.clone(),
+              description,
+              birth,
+            },
+          ))
+        })
+      }
+      Rows::Publishers(rows) => {
+        let name = record.required("name");
+        let description = record.optional("description");
+        let city = record.optional("city");
+        name.inspect(|name| {
+          rows.push((
+            row,
+            PublisherRow {
+              name: name.clone(),
+              description,
+              city,
+            },
+          ))
+        })
+      }
+      Rows::Books(rows) => {
+        let isbn = record.required("isbn").map(|isbn| normalize_isbn(&isbn));
+        let name = record.required("name");
+        let num_pages = record.parsed("num_pages", true);
+        let series_index = record.parsed("series_index", false);
+        let nsfw = record.flag("nsfw");
+        let date_published = record.date("date_published");
+        let authors = record
+          .optional("authors")
+          .map(|authors| {
+            authors
+              .split(AUTHOR_SEPARATOR)
+              .map(str::trim)
+              .filter(|a| !a.is_empty())
+              .map(String::from)
+              .collect()
+          })
+          .unwrap_or_default();
+        if isbn.as_deref() == Some("") {
+          record.error("isbn", "has no digits");
+        }
+        match (isbn, name, num_pages) {
+          (Some(isbn), Some(name), Some(num_pages)) => {
+            rows.push((
+              row,
+              BookRow {
+                isbn: isbn.clone(),
+                name,
+                authors,
+                description: record.optional("description"),
+                language: record.optional("language"),
+                nsfw,
+                num_pages,
+                publisher: record.optional("publisher"),
+                series: record.optional("series"),
+                series_index,
+                date_published,
+              },
+            ));
+            Some(isbn)
+          }
+          _ => None,
+        }
+      }
+      Rows::Progress(rows) => {
+        let user = record.required("user");
+        let isbn = record.required("isbn").map(|isbn| normalize_isbn(&isbn));
+        let current_page = record.parsed("current_page", true);
+        match (user, isbn, current_page) {
+          (Some(user), Some(isbn), Some(current_page)) => {
+            let key = format!("{}\u{0}{}", user, isbn);
+            rows.push((row, ProgressRow { user, isbn, current_page }));
+            Some(key)
+          }
+          _ => None,
+        }
+      }
+    };
+
+    if let Some(key) = key {
+      if !seen.insert(key) {
+        errors.push(RowError {
+          row,
+          column: None,
+          message: String::from("duplicates an earlier row's key"),
+        });
+      }
+    }
+    // Drop the row again if any of its fields failed validation.
+    if errors.len() > before {
+      drop_last(&mut rows, row);
+    }
+  }
+
+  (rows, errors)
+}
+
+fn empty_rows(entity: Entity) -> Rows {
+  match entity {
+    Entity::Authors => Rows::Authors(Vec::new()),
+    Entity::Publishers => Rows::Publishers(Vec::new()),
+    Entity::Books => Rows::Books(Vec::new()),
+    Entity::Progress => Rows::Progress(Vec::new()),
+  }
+}
+
+fn drop_last(rows: &mut Rows, row: u64) {
+  fn pop<T>(rows: &mut Vec<(u64, T)>, row: u64) {
+    if rows.last().is_some_and(|(last, _)| *last == row) {
+      rows.pop();
+    }
+  }
+  match rows {
+    Rows::Authors(rows) => pop(rows, row),
+    Rows::Publishers(rows) => pop(rows, row),
+    Rows::Books(rows) => pop(rows, row),
+    Rows::Progress(rows) => pop(rows, row),
+  }
+}
+
+/// Checks rows against the database. Only progress refers to rows it cannot create itself.
+async fn validate_references<'a>(tx: &mut Transaction<'a, MySql>, rows: &Rows, errors: &mut Vec<RowError>) -> Result<(), sqlx::Error> {
+  if let Rows::Progress(rows) = rows {
+    for (row, progress) in rows {
+      if User::fetch_by_name(tx, &progress.user).await.is_err() {
+        errors.push(RowError {
+          row: *row,
+          column: Some(String::from("user")),
+          message: format!("no user named '{}'", progress.user),
+        });
+      }
+      match Book::fetch_by_isbn(tx, &progress.isbn).await? {
+        Some(book) if progress.current_page > book.num_pages => errors.push(RowError {
+          row: *row,
+          column: Some(String::from("current_page")),
+          message: format!("is past the book's last page ({})", book.num_pages),
+        }),
+        Some(_) => {}
+        None => errors.push(RowError {
+          row: *row,
+          column: Some(String::from("isbn")),
+          message: format!("no book with ISBN {}", progress.isbn),
+        }),
+      }
+    }
+  }
+  Ok(())
+}
+
+/// What an upsert did with a row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+  Created,
+  Updated,
+  Unchanged,
+}
+
+pub(crate) async fn upsert_author<'a>(tx: &mut Transaction<'a, MySql>, row: &AuthorRow) -> Result<(Author, Outcome), sqlx::Error> {
+  let partial = PartialAuthor {
+    name: Some(row.name.clone()),
+    description: row.description.clone(),
+    birth: row.birth,
+  };
+  match Author::fetch_by_name(tx, &row.name).await? {
+    Some(existing) if existing.description == row.description && existing.birth == row.birth => Ok((existing, Outcome::Unchanged)),
+    Some(existing) => Ok((Author::update(tx, existing.id, partial).await?, Outcome::Updated)),
+    None => Ok((Author::create(tx, partial).await?, Outcome::Created)),
+  }
+}
+
+pub(crate) async fn upsert_publisher<'a>(tx: &mut Transaction<'a, MySql>, row: &PublisherRow) -> Result<(Publisher, Outcome), sqlx::Error> {
+  let description = row.description.clone().unwrap_or_default();
+  match Publisher::fetch_by_name(tx, &row.name).await? {
+    Some(existing) if existing.description == description && existing.city == row.city => Ok((existing, Outcome::Unchanged)),
+    Some(existing) => {
+      let partial = PartialPublisher {
+        name: Some(row.name.clone()),
+        description: Some(description),
+        city: row.city.clone(),
+      };
+      Ok((Publisher::update(tx, existing.id, partial).await?, Outcome::Updated))
+    }
+    None => Ok((Publisher::create(tx, row.name.clone(), description, row.city.clone()).await?, Outcome::Created)),
+  }
+}
+
+/// Matches the book by ISBN, creating the publisher, series and authors it names when they do not exist yet.
+pub(crate) async fn upsert_book<'a>(tx: &mut Transaction<'a, MySql>, row: &BookRow) -> Result<(Book, Outcome), sqlx::Error> {
+  let publisher_id = match &row.publisher {
+    Some(name) => Some(publisher_id(tx, name).await?),
+    None => None,
+  };
+  let series_id = match &row.series {
+    Some(name) => Some(series_id(tx, name).await?),
+    None => None,
+  };
+  let mut author_ids = Vec::with_capacity(row.authors.len());
+  for name in &row.authors {
+    author_ids.push(author_id(tx, name).await?);
+  }
+
+  let partial = PartialBook {
+    isbn: Some(row.isbn.clone()),
+    name: Some(row.name.clone()),
+    description: row.description.clone(),
+    language: row.language.clone(),
+    nsfw: Some(row.nsfw),
+    num_pages: Some(row.num_pages),
+    image_formatted: None,
+    publisher_id,
+    series_id,
+    series_index: row.series_index,
+    date_published: row.date_published,
+  };
+
+  let (book, mut outcome) = match Book::fetch_by_isbn(tx, &row.isbn).await? {
+    Some(existing) => {
+      let unchanged = existing.name == row.name
+        && existing.description == row.description
+        && existing.language == row.language
+        && existing.nsfw == row.nsfw
+        && existing.num_pages == row.num_pages
+        && existing.publisher_id == publisher_id
+        && existing.series_id == series_id
+        && existing.series_index == row.series_index
+        && existing.date_published == row.date_published;
+      if unchanged {
+        (existing, Outcome::Unchanged)
+      } else {
+        (Book::update(tx, existing.id, partial).await?, Outcome::Updated)
+      }
+    }
+    None => (Book::create_partial(tx, partial).await?, Outcome::Created),
+  };
+
+  if set_book_authors(tx, &book, &author_ids).await? && outcome == Outcome::Unchanged {
+    outcome = Outcome::Updated;
+  }
+
+  Ok((book, outcome))
+}
+
+async fn upsert_progress<'a>(tx: &mut Transaction<'a, MySql>, row: &ProgressRow) -> Result<Outcome, sqlx::Error> {
+  let user = User::fetch_by_name(tx, &row.user).await?;
+  let book = Book::fetch_by_isbn(tx, &row.isbn).await?.ok_or(sqlx::Error::RowNotFound)?;
+  match Progress::fetch_one(tx, user.id, book.id).await {
+    Ok(existing) if existing.current_page == row.current_page => Ok(Outcome::Unchanged),
+    Ok(_) => Progress::update(tx, user.id, book.id, row.current_page).await.map(|_| Outcome::Updated),
+    Err(sqlx::Error::RowNotFound) => Progress::create(tx, user.id, book.id, row.current_page).await.map(|_| Outcome::Created),
+    Err(err) => Err(err),
+  }
+}
+
+/// Validates the whole file first and only writes when every row is valid, so a failed import leaves the catalog untouched.
+/// The caller owns `tx` and commits it.
+pub async fn import<'a>(
+  tx: &mut Transaction<'a, MySql>,
+  entity: Entity,
+  mapping: &ColumnMapping,
+  data: &[u8],
+  dry_run: bool,
+) -> Result<ImportReport, sqlx::Error> {
+  let (rows, mut errors) = parse(entity, mapping, data);
+  validate_references(tx, &rows, &mut errors).await?;
+
+  let mut report = ImportReport {
+    rows: rows.len() + errors.iter().map(|error| error.row).collect::<HashSet<_>>().len(),
+    ..Default::default()
+  };
+  if !errors.is_empty() || dry_run {
+    errors.sort_by_key(|error| error.row);
+    report.errors = errors;
+    return Ok(report);
+  }
+
+  let mut outcomes = Vec::with_capacity(rows.len());
+  match &rows {
+    Rows::Authors(rows) => {
+      for (_, row) in rows {
+        outcomes.push(upsert_author(tx, row).await?.1);
+      }
+    }
+    Rows::Publishers(rows) => {
+      for (_, row) in rows {
+        outcomes.push(upsert_publisher(tx, row).await?.1);
+      }
+    }
+    Rows::Books(rows) => {
+      for (_, row) in rows {
+        outcomes.push(upsert_book(tx, row).await?.1);
+      }
+    }
+    Rows::Progress(rows) => {
+      for (_, row) in rows {
+        outcomes.push(upsert_progress(tx, row).await?);
+      }
+    }
+  }
+
+  report.created = outcomes.iter().filter(|outcome| **outcome == Outcome::Created).count();
+  report.updated = outcomes.iter().filter(|outcome| **outcome == Outcome::Updated).count();
+  report.unchanged = outcomes.iter().filter(|outcome| **outcome == Outcome::Unchanged).count();
+  report.applied = true;
+  Ok(report)
+}
+
+fn date_field(date: Option<DateTime<Utc>>) -> String {
+  date.map(|date| date.format("%Y-%m-%d").to_string()).unwrap_or_default()
+}
+
+/// Renders every row of `entity` as CSV, with headers named through `mapping` so the file can be re-imported with the same config.
+pub async fn export<'a>(tx: &mut Transaction<'a, MySql>, entity: Entity, mapping: &ColumnMapping) -> Result<String, sqlx::Error> {
+  let mut writer = ::csv::Writer::from_writer(Vec::new());
+  let write_error = |err: ::csv::Error| sqlx::Error::Protocol(err.to_string());
+  writer
+    .write_record(entity.fields().iter().map(|field| mapping.header(field)))
+    .map_err(write_error)?;
+
+  match entity {
+    Entity::Authors => {
+      for author in Author::fetch_all(tx).await? {
+        let birth = author.birth.map(|birth| birth.format("%Y-%m-%d").to_string()).unwrap_or_default();
+        writer
+          .write_record([author.name, author.description.unwrap_or_default(), birth])
+          .map_err(write_error)?;
+      }
+    }
+    Entity::Publishers => {
+      for publisher in Publisher::fetch_all(tx).await? {
+        writer
+          .write_record([publisher.name, publisher.description, publisher.city.unwrap_or_default()])
+          .map_err(write_error)?;
+      }
+    }
+    Entity::Books => {
+      for book in Book::fetch_all(tx).await? {
+        let authors: Vec<String> = book.fetch_authors(tx).await?.into_iter().map(|author| author.name).collect();
+        let publisher = match book.publisher_id {
+          Some(_) => book.fetch_publisher(tx).await?.name,
+          None => String::new(),
+        };
+        let series = match book.series_id {
+          Some(series_id) => Series::fetch_one(tx, series_id).await?.name,
+          None => String::new(),
+        };
+        writer
+          .write_record([
+            book.isbn,
+            book.name,
+            authors.join(&format!("{} ", AUTHOR_SEPARATOR)),
+            book.description.unwrap_or_default(),
+            book.language.unwrap_or_default(),
+            book.nsfw.to_string(),
+            book.num_pages.to_string(),
+            publisher,
+            series,
+            book.series_index.map(|index| index.to_string()).unwrap_or_default(),
+            date_field(book.date_published),
+          ])
+          .map_err(write_error)?;
+      }
+    }
+    Entity::Progress => {
+      let users: HashMap<u8, String> = User::fetch_all(tx).await?.into_iter().map(|user| (user.id, user.name)).collect();
+      for progress in Progress::fetch_all(tx).await? {
+        // Progress on a book in the trash is left out, like the book itself.
+        let book = match Book::fetch_one(tx, progress.book_id).await {
+          Ok(book) => book,
+          Err(sqlx::Error::RowNotFound) => continue,
+          Err(err) => return Err(err),
+        };
+        let user = users.get(&progress.user_id).cloned().unwrap_or_default();
+        writer.write_record([user, book.isbn, progress.current_page.to_string()]).map_err(write_error)?;
+      }
+    }
+  }
+
+  let bytes = writer.into_inner().map_err(|err| sqlx::Error::Protocol(err.to_string()))?;
+  Ok(String::from_utf8(bytes).expect("csv writer only receives strings"))
+}
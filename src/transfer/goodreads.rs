@@ -0,0 +1,524 @@
+//! Imports a reader's library from a Goodreads or StoryGraph CSV export into their shelves and progress.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use sqlx::{MySql, Transaction};
+
+use super::{
+  author_id,
+  csv::{parse_date, RowError},
+  publisher_id,
+};
+use crate::db::{
+  books::{normalize_isbn, Book, PartialBook},
+  progress::Progress,
+  shelves::Shelf,
+};
+
+/// Title similarity, from 0 to 1, a catalog book needs before it is matched without an ISBN.
+pub const TITLE_THRESHOLD: f64 = 0.85;
+/// Author similarity needed alongside the title when both sides name authors.
+pub const AUTHOR_THRESHOLD: f64 = 0.75;
+/// Both exports name their title column the same.
+const TITLE: &str = "Title";
+/// Matches scoring within this much of the best are too close to choose between.
+const AMBIGUITY_MARGIN: f64 = 0.02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+  Goodreads,
+  StoryGraph,
+}
+
+impl Source {
+  pub fn parse(value: &str) -> Option<Source> {
+    match value {
+      "goodreads" => Some(Source::Goodreads),
+      "storygraph" => Some(Source::StoryGraph),
+      _ => None,
+    }
+  }
+}
+
+/// Where the reader is with a book. Each becomes a shelf of the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+  Read,
+  Reading,
+  ToRead,
+  DidNotFinish,
+}
+
+impl Status {
+  pub fn parse(value: &str) -> Option<Status> {
+    match value.trim().to_ascii_lowercase().as_str() {
+      "read" => Some(Status::Read),
+      "currently-reading" | "reading" => Some(Status::Reading),
+      "to-read" => Some(Status::ToRead),
+      "did-not-finish" | "dnf" => Some(Status::DidNotFinish),
+      _ => None,
+    }
+  }
+
+  pub fn as_str(self) -> &'static str {
+    match self {
+      Status::Read => "read",
+      Status::Reading => "currently-reading",
+      Status::ToRead => "to-read",
+      Status::DidNotFinish => "did-not-finish",
+    }
+  }
+}
+
+/// One book from an export, in the source's terms.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+  pub row: u64,
+  pub title: String,
+  pub authors: Vec<String>,
+  pub isbn: Option<String>,
+  /// Out of 10, matching `Progress::rating`.
+  pub rating: Option<u8>,
+  pub status: Option<Status>,
+  /// Custom shelves or tags, besides the status.
+  pub shelves: Vec<String>,
+  pub num_pages: Option<u16>,
+  pub publisher: Option<String>,
+  pub date_started: Option<DateTime<Utc>>,
+  pub date_read: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resolution {
+  Isbn,
+  Fuzzy {
+    score: f64,
+  },
+  Created,
+  /// Several catalog books match equally well. Nothing is written for the row.
+  Ambiguous {
+    candidates: Vec<u64>,
+  },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reconciliation {
+  pub row: u64,
+  pub title: String,
+  pub isbn: Option<String>,
+  /// `None` for ambiguous rows, and for books a dry run would create.
+  pub book_id: Option<u64>,
+  pub resolution: Resolution,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadingReport {
+  pub entries: Vec<Reconciliation>,
+  pub errors: Vec<RowError>,
+  pub applied: bool,
+}
+
+/// Goodreads wraps ISBNs as `="0316769177"` so spreadsheets keep the leading zeros.
+fn unwrap_isbn(value: &str) -> Option<String> {
+  let isbn = normalize_isbn(value.trim().trim_start_matches('=').trim_matches('"'));
+  if isbn.is_empty() {
+    None
+  } else {
+    Some(isbn)
+  }
+}
+
+fn date(value: &str) -> Option<DateTime<Utc>> {
+  NaiveDate::parse_from_str(value.trim(), "%Y/%m/%d")
+    .ok()
+    .map(|date| date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+    .or_else(|| parse_date(value.trim()))
+}
+
+fn list(value: &str) -> Vec<String> {
+  value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect()
+}
+
+fn entry(source: Source, row: u64, field: &dyn Fn(&str) -> String) -> Result<Entry, RowError> {
+  let title = field(TITLE);
+  if title.is_empty() {
+    return Err(RowError {
+      row,
+      column: Some(String::from(TITLE)),
+      message: String::from("is required"),
+    });
+  }
+
+  let entry = match source {
+    Source::Goodreads => {
+      let mut authors = list(&field("Author"));
+      authors.extend(list(&field("Additional Authors")));
+      let mut shelves = list(&field("Bookshelves"));
+      let exclusive = field("Exclusive Shelf");
+      shelves.retain(|shelf| *shelf != exclusive);
+      let status = Status::parse(&exclusive);
+      if status.is_none() && !exclusive.is_empty() {
+        shelves.push(exclusive);
+      }
+      Entry {
+        row,
+        title,
+        authors,
+        isbn: unwrap_isbn(&field("ISBN13")).or_else(|| unwrap_isbn(&field("ISBN"))),
+        // Goodreads uses whole stars and 0 for unrated.
+        rating: field("My Rating").parse::<u8>().ok().filter(|stars| *stars > 0).map(|stars| stars.min(5) * 2),
+        status,
+        shelves,
+        num_pages: field("Number of Pages").parse().ok(),
+        publisher: Some(field("Publisher")).filter(|publisher| !publisher.is_empty()),
+        date_started: None,
+        date_read: date(&field("Date Read")),
+      }
+    }
+    Source::StoryGraph => {
+      // `Dates Read` holds ranges such as `2021/03/01-2021/03/04`, most recent last.
+      let dates_read = field("Dates Read");
+      let last_range = dates_read.split(',').next_back().unwrap_or_default();
+      let (started, finished) = last_range.split_once('-').unwrap_or(("", last_range));
+      Entry {
+        row,
+        title,
+        authors: list(&field("Authors")),
+        isbn: unwrap_isbn(&field("ISBN/UID")).filter(|isbn| isbn.len() == 10 || isbn.len() == 13),
+        rating: field("Star Rating")
+          .parse::<f64>()
+          .ok()
+          .filter(|stars| *stars > 0.0)
+          .map(|stars| (stars.min(5.0) * 2.0).round() as u8),
+        status: Status::parse(&field("Read Status")),
+        shelves: list(&field("Tags")),
+        num_pages: None,
+        publisher: None,
+        date_started: date(started),
+        date_read: date(&field("Last Date Read")).or_else(|| date(finished)),
+      }
+    }
+  };
+  Ok(entry)
+}
+
+/// Reads an export into entries. Rows that cannot be used are reported and skipped rather than failing the import.
+pub fn parse(source: Source, data: &[u8]) -> (Vec<Entry>, Vec<RowError>) {
+  let mut reader = ::csv::ReaderBuilder::new().flexible(true).from_reader(data);
+  let mut entries = Vec::new();
+  let mut errors = Vec::new();
+
+  let headers = match reader.headers() {
+    Ok(headers) => headers.clone(),
+    Err(err) => {
+      errors.push(RowError {
+        row: 1,
+        column: None,
+        message: err.to_string(),
+      });
+      return (entries, errors);
+    }
+  };
+  if !headers.iter().any(|header| header == TITLE) {
+    errors.push(RowError {
+      row: 1,
+      column: None,
+      message: format!("not a {:?} export: no '{}' column", source, TITLE),
+    });
+    return (entries, errors);
+  }
+  let positions: HashMap<&str, usize> = headers.iter().enumerate().map(|(position, header)| (header.trim(), position)).collect();
+
+  for result in reader.records() {
+    let record = match result {
+      Ok(record) => record,
+      Err(err) => {
+        errors.push(RowError {
+          row: err.position().map_or(0, |position| position.line()),
+          column: None,
+          message: err.to_string(),
+        });
+        continue;
+      }
+    };
+    let row = record.position().map_or(0, |position| position.line());
+    let field = |name: &str| {
+      positions
+        .get(name)
+        .and_then(|&position| record.get(position))
+        .unwrap_or_default()
+        .trim()
+        .to_string()
+    };
+    match entry(source, row, &field) {
+      Ok(entry) => entries.push(entry),
+      Err(err) => errors.push(err),
+    }
+  }
+
+  (entries, errors)
+}
+
+/// Lowercased alphanumeric words. Titles also lose series suffixes like `(Discworld, #1)`, subtitles and a leading article.
+fn normalize_title(title: &str) -> String {
+  let title = title.split(" (").next().unwrap_or(title);
+  let title = title.split(':').next().unwrap_or(title);
+  let words: Vec<String> = words(title);
+  match words.first().map(String::as_str) {
+    Some("the") | Some("a") | Some("an") if words.len() > 1 => words[1..].join(" "),
+    _ => words.join(" "),
+  }
+}
+
+fn words(text: &str) -> Vec<String> {
+  text
+    .split(|c: char| !c.is_alphanumeric())
+    .filter(|word| !word.is_empty())
+    .map(str::to_lowercase)
+    .collect()
+}
+
+/// Sorted name parts, so `Pratchett, Terry` and `Terry Pratchett` compare equal.
+fn normalize_author(author: &str) -> String {
+  let mut parts = words(author);
+  parts.sort();
+  parts.join(" ")
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+  let b: Vec<char> = b.chars().collect();
+  let mut previous: Vec<usize> = (0..=b.len()).collect();
+  for (i, ca) in a.chars().enumerate() {
+    let mut current = vec![i + 1; b.len() + 1];
+    for (j, cb) in b.iter().enumerate() {
+      let substitution = previous[j] + usize::from(ca != *cb);
+      current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+    }
+    previous = current;
+  }
+  previous[b.len()]
+}
+
+/// 1 for identical strings, falling towards 0 as the edit distance approaches the longer length.
+pub fn similarity(a: &str, b: &str) -> f64 {
+  let longest = a.chars().count().max(b.chars().count());
+  if longest == 0 {
+    return 1.0;
+  }
+  1.0 - levenshtein(a, b) as f64 / longest as f64
+}
+
+/// A catalog book as the fuzzy matcher sees it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+  pub book_id: u64,
+  pub title: String,
+  pub authors: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FuzzyMatch {
+  None,
+  One { book_id: u64, score: f64 },
+  Ambiguous { candidates: Vec<u64> },
+}
+
+/// Scores `candidates` by title, and by the best-matching author pair when both sides have authors.
+pub fn fuzzy_match(title: &str, authors: &[String], candidates: &[Candidate]) -> FuzzyMatch {
+  let title = normalize_title(title);
+  let authors: Vec<String> = authors.iter().map(|author| normalize_author(author)).collect();
+
+  let mut scored: Vec<(u64, f64)> = candidates
+    .iter()
+    .filter_map(|candidate| {
+      let title_score = similarity(&title, &normalize_title(&candidate.title));
+      if title_score < TITLE_THRESHOLD {
+        return None;
+      }
+      if authors.is_empty() || candidate.authors.is_empty() {
+        return Some((candidate.book_id, title_score));
+      }
+      let author_score = authors
+        .iter()
+        .flat_map(|author| candidate.authors.iter().map(move |other| similarity(author, &normalize_author(other))))
+        .fold(0.0, f64::max);
+      (author_score >= AUTHOR_THRESHOLD).then_some((candidate.book_id, 0.7 * title_score + 0.3 * author_score))
+    })
+    .collect();
+  scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+  match scored.as_slice() {
+    [] => FuzzyMatch::None,
+    [(book_id, score)] => FuzzyMatch::One {
+      book_id: *book_id,
+      score: *score,
+    },
+    [(book_id, score), rest @ ..] => {
+      let close: Vec<u64> = rest
+        .iter()
+        .take_while(|(_, other)| score - other <= AMBIGUITY_MARGIN)
+        .map(|(id, _)| *id)
+        .collect();
+      if close.is_empty() {
+        FuzzyMatch::One {
+          book_id: *book_id,
+          score: *score,
+        }
+      } else {
+        FuzzyMatch::Ambiguous {
+          candidates: std::iter::once(*book_id).chain(close).collect(),
+        }
+      }
+    }
+  }
+}
+
+async fn candidates<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Vec<Candidate>, sqlx::Error> {
+  let mut candidates = Vec::new();
+  for book in Book::fetch_all(tx).await? {
+    let authors = book.fetch_authors(tx).await?.into_iter().map(|author| author.name).collect();
+    candidates.push(Candidate {
+      book_id: book.id,
+      title: book.name,
+      authors,
+    });
+  }
+  Ok(candidates)
+}
+
+async fn create_book<'a>(tx: &mut Transaction<'a, MySql>, entry: &Entry) -> Result<Book, sqlx::Error> {
+  let publisher_id = match &entry.publisher {
+    Some(name) => Some(publisher_id(tx, name).await?),
+    None => None,
+  };
+  let book = Book::create_partial(
+    tx,
+    PartialBook {
+      // Books without an ISBN are still matched by title on later imports.
+      isbn: Some(entry.isbn.clone().unwrap_or_default()),
+      name: Some(entry.title.clone()),
+      description: None,
+      language: None,
+      nsfw: None,
+      num_pages: Some(entry.num_pages.unwrap_or(0)),
+      image_formatted: None,
+      publisher_id,
+      series_id: None,
+      series_index: None,
+      date_published: None,
+    },
+  )
+  .await?;
+  for name in &entry.authors {
+    let author_id = author_id(tx, name).await?;
+    Book::add_author(tx, book.id, author_id).await?;
+  }
+  Ok(book)
+}
+
+/// Puts the book on the reader's shelves and records where they are with it. Existing ratings and dates are kept when the export has none.
+async fn apply<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8, book: &Book, entry: &Entry) -> Result<(), sqlx::Error> {
+  if book.num_pages == 0 {
+    if let Some(num_pages) = entry.num_pages {
+      let partial = PartialBook {
+        isbn: None,
+        name: None,
+        description: book.description.clone(),
+        language: book.language.clone(),
+        nsfw: None,
+        num_pages: Some(num_pages),
+        image_formatted: None,
+        publisher_id: book.publisher_id,
+        series_id: book.series_id,
+        series_index: book.series_index,
+        date_published: book.date_published,
+      };
+      Book::update(tx, book.id, partial).await?;
+    }
+  }
+  let num_pages = entry.num_pages.filter(|_| book.num_pages == 0).unwrap_or(book.num_pages);
+
+  let shelves = entry
+    .status
+    .map(|status| status.as_str().to_string())
+    .into_iter()
+    .chain(entry.shelves.iter().cloned());
+  for name in shelves {
+    let shelf = Shelf::fetch_or_create(tx, user_id, &name).await?;
+    Shelf::add_book(tx, shelf.id, book.id).await?;
+  }
+
+  let existing = match Progress::fetch_one(tx, user_id, book.id).await {
+    Ok(progress) => Some(progress),
+    Err(sqlx::Error::RowNotFound) => None,
+    Err(err) => return Err(err),
+  };
+  let wants_progress = entry.rating.is_some() || entry.date_read.is_some() || matches!(entry.status, Some(Status::Read) | Some(Status::Reading));
+  let progress = match (existing, entry.status) {
+    (_, Some(Status::Read)) => Progress::upsert(tx, user_id, book.id, num_pages).await?,
+    (Some(progress), _) => progress,
+    (None, _) if wants_progress => Progress::create(tx, user_id, book.id, 0).await?,
+    (None, _) => return Ok(()),
+  };
+
+  Progress::set_reading(
+    tx,
+    user_id,
+    book.id,
+    entry.rating.or(progress.rating),
+    entry.date_started.or(progress.date_started),
+    entry.date_read.or(progress.date_finished),
+  )
+  .await?;
+  Ok(())
+}
+
+/// Matches each entry by ISBN, then by fuzzy title and author, creating books that match nothing.
+/// Ambiguous rows are reported for the reader to resolve and left alone. The caller owns `tx` and commits it.
+pub async fn import<'a>(tx: &mut Transaction<'a, MySql>, user_id: u8, source: Source, data: &[u8], dry_run: bool) -> Result<ReadingReport, sqlx::Error> {
+  let (entries, errors) = parse(source, data);
+  let mut candidates = candidates(tx).await?;
+  let mut reconciled = Vec::with_capacity(entries.len());
+
+  for entry in &entries {
+    let by_isbn = match &entry.isbn {
+      Some(isbn) => Book::fetch_by_isbn(tx, isbn).await?,
+      None => None,
+    };
+    let (book, resolution) = match by_isbn {
+      Some(book) => (Some(book), Resolution::Isbn),
+      None => match fuzzy_match(&entry.title, &entry.authors, &candidates) {
+        FuzzyMatch::One { book_id, score } => (Some(Book::fetch_one(tx, book_id).await?), Resolution::Fuzzy { score }),
+        FuzzyMatch::Ambiguous { candidates } => (None, Resolution::Ambiguous { candidates }),
+        FuzzyMatch::None if dry_run => (None, Resolution::Created),
+        FuzzyMatch::None => {
+          let book = create_book(tx, entry).await?;
+          candidates.push(Candidate {
+            book_id: book.id,
+            title: book.name.clone(),
+            authors: entry.authors.clone(),
+          });
+          (Some(book), Resolution::Created)
+        }
+      },
+    };
+
+    if let (Some(book), false) = (&book, dry_run) {
+      apply(tx, user_id, book, entry).await?;
+    }
+    reconciled.push(Reconciliation {
+      row: entry.row,
+      title: entry.title.clone(),
+      isbn: entry.isbn.clone(),
+      book_id: book.map(|book| book.id),
+      resolution,
+    });
+  }
+
+  Ok(ReadingReport {
+    entries: reconciled,
+    errors,
+    applied: !dry_run,
+  })
+}
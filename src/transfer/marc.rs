@@ -0,0 +1,583 @@
+//! MARC 21 bibliographic records, as ISO 2709 and MARCXML, mapped onto books, authors and publishers.
+//!
+//! Only the fields our catalog can hold are mapped: 008, 020, 041, 100/700, 245, 260/264, 300 and 520. Other fields survive
+//! a parse and write of the same record, but not an import and export.
+
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use quick_xml::{events::Event, Reader};
+use sqlx::{MySql, Transaction};
+
+use super::{
+  csv::{Outcome, RowError},
+  upsert_descriptor, Descriptor,
+};
+use crate::db::books::{normalize_isbn, Book};
+
+const SUBFIELD_DELIMITER: u8 = 0x1f;
+const FIELD_TERMINATOR: u8 = 0x1e;
+const RECORD_TERMINATOR: u8 = 0x1d;
+const MARCXML_NAMESPACE: &str = "http://www.loc.gov/MARC21/slim";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Field {
+  /// Tags 001 to 009: a single value, no indicators or subfields.
+  Control { tag: String, value: String },
+  Data {
+    tag: String,
+    indicators: [char; 2],
+    subfields: Vec<(char, String)>,
+  },
+}
+
+impl Field {
+  pub fn tag(&self) -> &str {
+    match self {
+      Field::Control { tag, .. } | Field::Data { tag, .. } => tag,
+    }
+  }
+
+  pub fn subfield(&self, code: char) -> Option<&str> {
+    match self {
+      Field::Data { subfields, .. } => subfields.iter().find(|(c, _)| *c == code).map(|(_, value)| value.as_str()),
+      Field::Control { .. } => None,
+    }
+  }
+
+  fn data(tag: &str, indicators: [char; 2], subfields: Vec<(char, String)>) -> Field {
+    Field::Data {
+      tag: tag.to_string(),
+      indicators,
+      subfields,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+  /// 24 characters. Lengths and the base address are recomputed when writing ISO 2709.
+  pub leader: String,
+  pub fields: Vec<Field>,
+}
+
+fn invalid(message: impl Into<String>) -> std::io::Error {
+  std::io::Error::new(std::io::ErrorKind::InvalidData, message.into())
+}
+
+fn number(bytes: &[u8]) -> std::io::Result<usize> {
+  std::str::from_utf8(bytes)
+    .ok()
+    .and_then(|text| text.trim().parse().ok())
+    .ok_or_else(|| invalid(format!("'{}' is not a number", String::from_utf8_lossy(bytes))))
+}
+
+impl Record {
+  pub fn fields(&self, tag: &str) -> impl Iterator<Item = &Field> {
+    let tag = tag.to_string();
+    self.fields.iter().filter(move |field| field.tag() == tag)
+  }
+
+  pub fn field(&self, tag: &str) -> Option<&Field> {
+    self.fields(tag).next()
+  }
+
+  /// Parses one ISO 2709 record, with or without its record terminator.
+  pub fn from_iso2709(bytes: &[u8]) -> std::io::Result<Record> {
+    if bytes.len() < 24 {
+      return Err(invalid("record is shorter than its leader"));
+    }
+    let leader = String::from_utf8_lossy(&bytes[..24]).into_owned();
+    let base_address = number(&bytes[12..17])?;
+    if base_address > bytes.len() || base_address < 25 {
+      return Err(invalid("base address is outside the record"));
+    }
+
+    let directory = &bytes[24..base_address - 1];
+    if !directory.len().is_multiple_of(12) {
+      return Err(invalid("directory length is not a multiple of 12"));
+    }
+    let mut fields = Vec::with_capacity(directory.len() / 12);
+    for entry in directory.chunks(12) {
+      let tag = String::from_utf8_lossy(&entry[..3]).into_owned();
+      let length = number(&entry[3..7])?;
+      let start = base_address + number(&entry[7..12])?;
+      let Some(data) = bytes.get(start..start + length) else {
+        return Err(invalid(format!("field {} runs past the end of the record", tag)));
+      };
+      let data = data.strip_suffix(&[FIELD_TERMINATOR]).unwrap_or(data);
+
+      if tag.as_str() < "010" {
+        fields.push(Field::Control {
+          tag,
+          value: String::from_utf8_lossy(data).into_owned(),
+        });
+        continue;
+      }
+      let mut parts = data.split(|byte| *byte == SUBFIELD_DELIMITER);
+      let indicators: Vec<char> = String::from_utf8_lossy(parts.next().unwrap_or_default()).chars().collect();
+      let subfields = parts
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+          let text = String::from_utf8_lossy(part);
+          let mut chars = text.chars();
+          let code = chars.next().unwrap_or(' ');
+          (code, chars.collect())
+        })
+        .collect();
+      fields.push(Field::Data {
+        tag,
+        indicators: [indicators.first().copied().unwrap_or(' '), indicators.get(1).copied().unwrap_or(' ')],
+        subfields,
+      });
+    }
+
+    Ok(Record { leader, fields })
+  }
+
+  /// Writes the record as ISO 2709, UTF-8 encoded, filling in the leader's lengths.
+  pub fn to_iso2709(&self) -> Vec<u8> {
+    let mut directory = Vec::new();
+    let mut data = Vec::new();
+    for field in &self.fields {
+      let start = data.len();
+      match field {
+        Field::Control { value, .. } => data.extend_from_slice(value.as_bytes()),
+        Field::Data { indicators, subfields, .. } => {
+          data.extend(indicators.iter().collect::<String>().as_bytes());
+          for (code, value) in subfields {
+            data.push(SUBFIELD_DELIMITER);
+            data.extend(code.to_string().as_bytes());
+            data.extend(value.as_bytes());
+          }
+        }
+      }
+      data.push(FIELD_TERMINATOR);
+      directory.extend(format!("{:0>3.3}{:04}{:05}", field.tag(), data.len() - start, start).as_bytes());
+    }
+    directory.push(FIELD_TERMINATOR);
+    data.push(RECORD_TERMINATOR);
+
+    let base_address = 24 + directory.len();
+    let length = base_address + data.len();
+    let mut leader: Vec<char> = format!("{:<24.24}", self.leader).chars().collect();
+    // Record length, then `a` for UTF-8 at position 9, the indicator and subfield code counts, and the entry map.
+    leader.splice(0..5, format!("{:05}", length).chars());
+    leader[9] = 'a';
+    leader[10] = '2';
+    leader[11] = '2';
+    leader.splice(12..17, format!("{:05}", base_address).chars());
+    leader.splice(20..24, "4500".chars());
+
+    let mut record = leader.into_iter().collect::<String>().into_bytes();
+    record.extend(directory);
+    record.extend(data);
+    record
+  }
+
+  pub fn to_marcxml(&self, out: &mut String) {
+    out.push_str("<record>");
+    out.push_str(&format!("<leader>{}</leader>", escape(&self.leader)));
+    for field in &self.fields {
+      match field {
+        Field::Control { tag, value } => out.push_str(&format!(r#"<controlfield tag="{}">{}</controlfield>"#, escape(tag), escape(value))),
+        Field::Data { tag, indicators, subfields } => {
+          out.push_str(&format!(
+            r#"<datafield tag="{}" ind1="{}" ind2="{}">"#,
+            escape(tag),
+            escape(&indicators[0].to_string()),
+            escape(&indicators[1].to_string())
+          ));
+          for (code, value) in subfields {
+            out.push_str(&format!(r#"<subfield code="{}">{}</subfield>"#, escape(&code.to_string()), escape(value)));
+          }
+          out.push_str("</datafield>");
+        }
+      }
+    }
+    out.push_str("</record>");
+  }
+}
+
+fn escape(text: &str) -> String {
+  quick_xml::escape::escape(text).into_owned()
+}
+
+/// Splits a file of concatenated ISO 2709 records.
+pub fn parse_iso2709(bytes: &[u8]) -> std::io::Result<Vec<Record>> {
+  bytes
+    .split_inclusive(|byte| *byte == RECORD_TERMINATOR)
+    .filter(|record| record.iter().any(|byte| !byte.is_ascii_whitespace()))
+    .map(Record::from_iso2709)
+    .collect()
+}
+
+pub fn write_iso2709(records: &[Record]) -> Vec<u8> {
+  records.iter().flat_map(Record::to_iso2709).collect()
+}
+
+fn attribute(element: &quick_xml::events::BytesStart, key: &[u8]) -> Option<String> {
+  element
+    .attributes()
+    .flatten()
+    .find(|attr| attr.key.local_name().as_ref() == key)
+    .and_then(|attr| attr.unescape_value().ok().map(|value| value.into_owned()))
+}
+
+fn first_char(value: Option<String>) -> char {
+  value.and_then(|value| value.chars().next()).unwrap_or(' ')
+}
+
+/// Reads every `<record>` in a MARCXML document, whether wrapped in a `<collection>` or not.
+pub fn parse_marcxml(xml: &str) -> std::io::Result<Vec<Record>> {
+  let mut reader = Reader::from_str(xml);
+  let mut records = Vec::new();
+  let mut record: Option<Record> = None;
+  let mut text = String::new();
+  let mut subfield_code = None;
+
+  loop {
+    let event = reader.read_event().map_err(|err| invalid(err.to_string()))?;
+    match event {
+      Event::Start(element) | Event::Empty(element) => {
+        text.clear();
+        let Some(current) = record.as_mut() else {
+          if element.local_name().as_ref() == b"record" {
+            record = Some(Record {
+              leader: String::new(),
+              fields: Vec::new(),
+            });
+          }
+          continue;
+        };
+        match element.local_name().as_ref() {
+          b"controlfield" => current.fields.push(Field::Control {
+            tag: attribute(&element, b"tag").unwrap_or_default(),
+            value: String::new(),
+          }),
+          b"datafield" => current.fields.push(Field::Data {
+            tag: attribute(&element, b"tag").unwrap_or_default(),
+            indicators: [first_char(attribute(&element, b"ind1")), first_char(attribute(&element, b"ind2"))],
+            subfields: Vec::new(),
+          }),
+          b"subfield" => subfield_code = Some(first_char(attribute(&element, b"code"))),
+          _ => {}
+        }
+      }
+      Event::Text(value) => text.push_str(&value.unescape().map_err(|err| invalid(err.to_string()))?),
+      Event::CData(value) => text.push_str(&String::from_utf8_lossy(&value)),
+      Event::End(element) => {
+        let Some(current) = record.as_mut() else {
+          continue;
+        };
+        match element.local_name().as_ref() {
+          b"leader" => current.leader = std::mem::take(&mut text),
+          b"controlfield" => {
+            if let Some(Field::Control { value, .. }) = current.fields.last_mut() {
+              *value = std::mem::take(&mut text);
+            }
+          }
+          b"subfield" => {
+            if let (Some(code), Some(Field::Data { subfields, .. })) = (subfield_code.take(), current.fields.last_mut()) {
+              subfields.push((code, std::mem::take(&mut text)));
+            }
+          }
+          b"record" => records.extend(record.take()),
+          _ => {}
+        }
+      }
+      Event::Eof => break,
+      _ => {}
+    }
+  }
+  Ok(records)
+}
+
+pub fn write_marcxml(records: &[Record]) -> String {
+  let mut out = format!(r#"<?xml version="1.0" encoding="UTF-8"?><collection xmlns="{}">"#, MARCXML_NAMESPACE);
+  for record in records {
+    record.to_marcxml(&mut out);
+  }
+  out.push_str("</collection>");
+  out
+}
+
+/// Parses either serialisation, telling them apart by the leading `<` of XML.
+pub fn parse(bytes: &[u8]) -> std::io::Result<Vec<Record>> {
+  let start = bytes
+    .iter()
+    .position(|byte| !byte.is_ascii_whitespace() && *byte != 0xef && *byte != 0xbb && *byte != 0xbf);
+  match start.map(|start| bytes[start]) {
+    Some(b'<') => parse_marcxml(&String::from_utf8_lossy(bytes)),
+    _ => parse_iso2709(bytes),
+  }
+}
+
+/// The book described by a record, in the terms of our catalog.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MarcBook {
+  pub isbn: Option<String>,
+  pub title: String,
+  /// Names as catalogued, inverted: `Pratchett, Terry`.
+  pub authors: Vec<String>,
+  pub publisher: Option<String>,
+  pub city: Option<String>,
+  pub year: Option<i32>,
+  pub num_pages: Option<u16>,
+  pub language: Option<String>,
+  pub description: Option<String>,
+}
+
+/// Drops the ISBD punctuation cataloguers leave between subfields, such as the ` /` after a title.
+pub fn trim_isbd(value: &str) -> String {
+  let trimmed = value.trim().trim_end_matches([' ', '/', ':', ';', ',', '=']).trim_end();
+  // A final full stop belongs to the punctuation unless it ends an initial or abbreviation such as `Jr.`.
+  let trimmed = match trimmed.strip_suffix('.') {
+    Some(rest) if rest.rsplit(' ').next().is_some_and(|word| word.chars().count() > 2) => rest,
+    _ => trimmed,
+  };
+  trimmed.trim_start_matches('[').trim_end_matches(']').to_string()
+}
+
+/// `Pratchett, Terry` becomes `Terry Pratchett`; names without a comma are left alone.
+pub fn uninvert(name: &str) -> String {
+  match name.split_once(", ") {
+    Some((last, first)) => format!("{} {}", first.trim(), last.trim()),
+    None => name.to_string(),
+  }
+}
+
+/// `Terry Pratchett` becomes `Pratchett, Terry`, the form MARC headings use.
+pub fn invert(name: &str) -> String {
+  match name.rsplit_once(' ') {
+    Some((first, last)) if !name.contains(',') => format!("{}, {}", last, first),
+    _ => name.to_string(),
+  }
+}
+
+fn first_year(value: &str) -> Option<i32> {
+  value
+    .as_bytes()
+    .windows(4)
+    .find(|window| window.iter().all(u8::is_ascii_digit))
+    .and_then(|window| std::str::from_utf8(window).ok()?.parse().ok())
+}
+
+impl MarcBook {
+  pub fn from_record(record: &Record) -> MarcBook {
+    let isbn = record
+      .fields("020")
+      .filter_map(|field| field.subfield('a'))
+      // 020 $a may carry a qualifier after the number: `9780552131063 (pbk.)`.
+      .map(|value| normalize_isbn(value.split_whitespace().next().unwrap_or_default()))
+      .find(|isbn| !isbn.is_empty());
+
+    let title = record
+      .field("245")
+      .map(|field| {
+        let main = field.subfield('a').map(trim_isbd).unwrap_or_default();
+        match field.subfield('b').map(trim_isbd) {
+          Some(remainder) if !remainder.is_empty() => format!("{}: {}", main, remainder),
+          _ => main,
+        }
+      })
+      .unwrap_or_default();
+
+    let authors = record
+      .fields("100")
+      .chain(record.fields("700"))
+      .filter_map(|field| field.subfield('a'))
+      .map(trim_isbd)
+      .filter(|name| !name.is_empty())
+      .collect();
+
+    // RDA records use 264 with second indicator 1 for publication; older ones use 260.
+    let imprint = record
+      .fields("264")
+      .find(|field| matches!(field, Field::Data { indicators, .. } if indicators[1] == '1'))
+      .or_else(|| record.field("260"));
+    let control = record.field("008").and_then(|field| match field {
+      Field::Control { value, .. } => Some(value.clone()),
+      Field::Data { .. } => None,
+    });
+
+    MarcBook {
+      isbn,
+      title,
+      authors,
+      publisher: imprint.and_then(|field| field.subfield('b')).map(trim_isbd).filter(|name| !name.is_empty()),
+      city: imprint.and_then(|field| field.subfield('a')).map(trim_isbd).filter(|city| !city.is_empty()),
+      year: imprint
+        .and_then(|field| field.subfield('c'))
+        .and_then(first_year)
+        .or_else(|| control.as_deref().and_then(|value| value.get(7..11)).and_then(first_year)),
+      num_pages: record.field("300").and_then(|field| field.subfield('a')).and_then(|extent| {
+        extent
+          .split(|c: char| !c.is_ascii_digit())
+          .filter(|part| !part.is_empty())
+          .filter_map(|part| part.parse().ok())
+          .max()
+      }),
+      language: record
+        .field("041")
+        .and_then(|field| field.subfield('a'))
+        .map(str::to_string)
+        .or_else(|| control.as_deref().and_then(|value| value.get(35..38)).map(str::to_string))
+        .map(|language| language.trim().to_string())
+        .filter(|language| !language.is_empty() && language != "|||"),
+      description: record
+        .field("520")
+        .and_then(|field| field.subfield('a'))
+        .map(|summary| summary.trim().to_string()),
+    }
+  }
+
+  pub fn to_record(&self) -> Record {
+    let mut fields = Vec::new();
+    let year = self.year.map(|year| format!("{:04}", year)).unwrap_or_else(|| String::from("    "));
+    let language = self
+      .language
+      .clone()
+      .filter(|language| language.len() == 3)
+      .unwrap_or_else(|| String::from("   "));
+    // Fixed-length data elements: entry date, publication status and date, and language at 35-37.
+    let status = if self.year.is_some() { 's' } else { 'n' };
+    fields.push(Field::Control {
+      tag: String::from("008"),
+      value: format!("000000{}{}    xx {:17}{} d", status, year, "", language),
+    });
+    if let Some(isbn) = &self.isbn {
+      fields.push(Field::data("020", [' ', ' '], vec![('a', isbn.clone())]));
+    }
+    if let Some(language) = &self.language {
+      fields.push(Field::data("041", ['0', ' '], vec![('a', language.clone())]));
+    }
+    if let Some((first, rest)) = self.authors.split_first() {
+      fields.push(Field::data("100", ['1', ' '], vec![('a', first.clone())]));
+      for author in rest {
+        fields.push(Field::data("700", ['1', ' '], vec![('a', author.clone())]));
+      }
+    }
+    let (title, remainder) = match self.title.split_once(": ") {
+      Some((title, remainder)) => (title.to_string(), Some(remainder.to_string())),
+      None => (self.title.clone(), None),
+    };
+    let mut title_subfields = vec![('a', title)];
+    title_subfields.extend(remainder.map(|remainder| ('b', remainder)));
+    fields.push(Field::data("245", [if self.authors.is_empty() { '0' } else { '1' }, '0'], title_subfields));
+    if self.publisher.is_some() || self.city.is_some() || self.year.is_some() {
+      let mut imprint = Vec::new();
+      imprint.extend(self.city.clone().map(|city| ('a', city)));
+      imprint.extend(self.publisher.clone().map(|publisher| ('b', publisher)));
+      imprint.extend(self.year.map(|year| ('c', year.to_string())));
+      fields.push(Field::data("264", [' ', '1'], imprint));
+    }
+    if let Some(num_pages) = self.num_pages.filter(|pages| *pages > 0) {
+      fields.push(Field::data("300", [' ', ' '], vec![('a', format!("{} pages", num_pages))]));
+    }
+    if let Some(description) = &self.description {
+      fields.push(Field::data("520", [' ', ' '], vec![('a', description.clone())]));
+    }
+
+    Record {
+      // Lengths are filled in on write; `nam` is new, language material, monograph.
+      leader: String::from("00000nam a2200000 i 4500"),
+      fields,
+    }
+  }
+}
+
+fn year_to_date(year: i32) -> Option<DateTime<Utc>> {
+  NaiveDate::from_ymd_opt(year, 1, 1)
+    .and_then(|date| date.and_hms_opt(0, 0, 0))
+    .map(|date| date.and_utc())
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MarcReport {
+  pub records: usize,
+  pub created: usize,
+  pub updated: usize,
+  /// Records that could not be imported, numbered from 1 in file order.
+  pub errors: Vec<RowError>,
+}
+
+/// Imports records by ISBN, keeping what MARC does not describe, such as series, as it is. Records without an ISBN are reported and skipped.
+pub async fn import<'a>(tx: &mut Transaction<'a, MySql>, records: &[Record]) -> Result<MarcReport, sqlx::Error> {
+  let mut report = MarcReport {
+    records: records.len(),
+    ..Default::default()
+  };
+
+  for (index, record) in records.iter().enumerate() {
+    let marc = MarcBook::from_record(record);
+    let row = index as u64 + 1;
+    let Some(isbn) = &marc.isbn else {
+      report.errors.push(RowError {
+        row,
+        column: Some(String::from("020")),
+        message: String::from("has no ISBN"),
+      });
+      continue;
+    };
+    if marc.title.is_empty() {
+      report.errors.push(RowError {
+        row,
+        column: Some(String::from("245")),
+        message: String::from("has no title"),
+      });
+      continue;
+    }
+
+    let descriptor = Descriptor {
+      isbn: isbn.clone(),
+      title: marc.title.clone(),
+      authors: marc.authors.iter().map(|heading| (uninvert(heading), Some(heading.clone()))).collect(),
+      publisher: marc.publisher.clone(),
+      city: marc.city.clone(),
+      date_published: marc.year.and_then(year_to_date),
+      num_pages: marc.num_pages,
+      language: marc.language.clone(),
+      description: marc.description.clone(),
+      series: None,
+    };
+    match upsert_descriptor(tx, &descriptor).await?.1 {
+      Outcome::Created => report.created += 1,
+      _ => report.updated += 1,
+    }
+  }
+
+  Ok(report)
+}
+
+/// Describes a catalog book as a MARC record.
+pub async fn book_record<'a>(tx: &mut Transaction<'a, MySql>, book: &Book) -> Result<Record, sqlx::Error> {
+  let authors = book
+    .fetch_authors(tx)
+    .await?
+    .into_iter()
+    .map(|author| author.sort_name.unwrap_or_else(|| invert(&author.name)))
+    .collect();
+  let publisher = match book.publisher_id {
+    Some(_) => Some(book.fetch_publisher(tx).await?),
+    None => None,
+  };
+  let marc = MarcBook {
+    isbn: Some(book.isbn.clone()).filter(|isbn| !isbn.is_empty()),
+    title: book.name.clone(),
+    authors,
+    publisher: publisher.as_ref().map(|publisher| publisher.name.clone()),
+    city: publisher.and_then(|publisher| publisher.city),
+    year: book.date_published.map(|date| date.year()),
+    num_pages: Some(book.num_pages),
+    language: book.language.clone(),
+    description: book.description.clone(),
+  };
+  Ok(marc.to_record())
+}
+
+pub async fn export<'a>(tx: &mut Transaction<'a, MySql>) -> Result<Vec<Record>, sqlx::Error> {
+  let mut records = Vec::new();
+  for book in Book::fetch_all(tx).await? {
+    records.push(book_record(tx, &book).await?);
+  }
+  Ok(records)
+}
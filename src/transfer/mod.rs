@@ -0,0 +1,154 @@
+//! Moving catalog data in and out of the database in formats other tools understand.
+
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use sqlx::{MySql, Transaction};
+
+use self::csv::Outcome;
+use crate::db::{
+  authors::{Author, PartialAuthor},
+  books::{Book, PartialBook},
+  publisher::{PartialPublisher, Publisher},
+  series::{PartialSeries, Series},
+};
+
+pub mod calibre;
+pub mod citation;
+pub mod csv;
+pub mod goodreads;
+pub mod marc;
+pub mod onix;
+
+/// Looks up a publisher by name, creating it when an import names one we have not seen.
+pub(crate) async fn publisher_id<'a>(tx: &mut Transaction<'a, MySql>, name: &str) -> Result<u16, sqlx::Error> {
+  match Publisher::fetch_by_name(tx, name).await? {
+    Some(publisher) => Ok(publisher.id),
+    None => Ok(Publisher::create(tx, name.to_string(), String::new(), None).await?.id),
+  }
+}
+
+pub(crate) async fn series_id<'a>(tx: &mut Transaction<'a, MySql>, name: &str) -> Result<u64, sqlx::Error> {
+  match Series::fetch_by_name(tx, name).await? {
+    Some(series) => Ok(series.id),
+    None => Ok(
+      Series::create(
+        tx,
+        PartialSeries {
+          name: Some(name.to_string()),
+          description: None,
+        },
+      )
+      .await?
+      .id,
+    ),
+  }
+}
+
+pub(crate) async fn author_id<'a>(tx: &mut Transaction<'a, MySql>, name: &str) -> Result<u64, sqlx::Error> {
+  match Author::fetch_by_name(tx, name).await? {
+    Some(author) => Ok(author.id),
+    None => Ok(
+      Author::create(
+        tx,
+        PartialAuthor {
+          name: Some(name.to_string()),
+          description: None,
+          birth: None,
+        },
+      )
+      .await?
+      .id,
+    ),
+  }
+}
+
+/// Makes `author_ids` the book's authors, returning whether anything changed.
+pub(crate) async fn set_book_authors<'a>(tx: &mut Transaction<'a, MySql>, book: &Book, author_ids: &[u64]) -> Result<bool, sqlx::Error> {
+  let existing: HashSet<u64> = book.fetch_authors(tx).await?.into_iter().map(|author| author.id).collect();
+  let wanted: HashSet<u64> = author_ids.iter().copied().collect();
+  if existing == wanted {
+    return Ok(false);
+  }
+  for author_id in existing.difference(&wanted) {
+    Book::remove_author(tx, book.id, *author_id).await?;
+  }
+  for author_id in wanted.difference(&existing) {
+    Book::add_author(tx, book.id, *author_id).await?;
+  }
+  Ok(true)
+}
+
+/// Book metadata from a bibliographic record. Fields the record leaves out (`None`) keep the catalog's current value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Descriptor {
+  pub isbn: String,
+  pub title: String,
+  /// Display names, with the sort form when the source gives one.
+  pub authors: Vec<(String, Option<String>)>,
+  pub publisher: Option<String>,
+  pub city: Option<String>,
+  pub date_published: Option<DateTime<Utc>>,
+  pub num_pages: Option<u16>,
+  pub language: Option<String>,
+  pub description: Option<String>,
+  /// Series name and position.
+  pub series: Option<(String, Option<u16>)>,
+}
+
+/// Creates or updates the book with the descriptor's ISBN. A publisher's city is only filled in, never overwritten.
+pub(crate) async fn upsert_descriptor<'a>(tx: &mut Transaction<'a, MySql>, descriptor: &Descriptor) -> Result<(Book, Outcome), sqlx::Error> {
+  let publisher_id = match &descriptor.publisher {
+    Some(name) => {
+      let id = publisher_id(tx, name).await?;
+      let publisher = Publisher::fetch_one(tx, id).await?;
+      if publisher.city.is_none() && descriptor.city.is_some() {
+        let partial = PartialPublisher {
+          name: None,
+          description: None,
+          city: descriptor.city.clone(),
+        };
+        Publisher::update(tx, id, partial).await?;
+      }
+      Some(id)
+    }
+    None => None,
+  };
+  let series = match &descriptor.series {
+    Some((name, index)) => Some((series_id(tx, name).await?, *index)),
+    None => None,
+  };
+
+  let existing = Book::fetch_by_isbn(tx, &descriptor.isbn).await?;
+  let current = existing.as_ref();
+  let partial = PartialBook {
+    isbn: Some(descriptor.isbn.clone()),
+    name: Some(descriptor.title.clone()),
+    description: descriptor.description.clone().or_else(|| current.and_then(|book| book.description.clone())),
+    language: descriptor.language.clone().or_else(|| current.and_then(|book| book.language.clone())),
+    nsfw: None,
+    num_pages: descriptor.num_pages.or(current.map(|book| book.num_pages)).or(Some(0)),
+    image_formatted: None,
+    publisher_id: publisher_id.or(current.and_then(|book| book.publisher_id)),
+    series_id: series.map(|(id, _)| id).or(current.and_then(|book| book.series_id)),
+    series_index: series.map_or(current.and_then(|book| book.series_index), |(_, index)| index),
+    date_published: descriptor.date_published.or(current.and_then(|book| book.date_published)),
+  };
+  let (book, outcome) = match existing {
+    Some(existing) => (Book::update(tx, existing.id, partial).await?, Outcome::Updated),
+    None => (Book::create_partial(tx, partial).await?, Outcome::Created),
+  };
+
+  if !descriptor.authors.is_empty() {
+    let mut author_ids = Vec::with_capacity(descriptor.authors.len());
+    for (name, sort_name) in &descriptor.authors {
+      let id = author_id(tx, name).await?;
+      if sort_name.is_some() {
+        Author::set_sort_name(tx, id, sort_name.as_deref()).await?;
+      }
+      author_ids.push(id);
+    }
+    set_book_authors(tx, &book, &author_ids).await?;
+  }
+  Ok((book, outcome))
+}
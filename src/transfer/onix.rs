@@ -0,0 +1,460 @@
+//! ONIX for Books 3.0 ingestion. Product records become books, with their publisher and contributors.
+//!
+//! Both reference (`<RecordReference>`) and short (`<a001>`) tags are read. Element names are compared in lower case,
+//! since short-tag composites are the lower-cased reference names.
+
+use std::collections::{BTreeMap, HashSet};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use quick_xml::{events::Event, Reader};
+use sqlx::{MySql, Transaction};
+
+use super::{
+  csv::{Outcome, RowError},
+  upsert_descriptor, Descriptor,
+};
+use crate::db::{
+  books::{normalize_isbn, Book},
+  identifiers::Identifier,
+};
+
+/// Short tags for the data elements we read, with the reference names they stand for.
+const SHORT_TAGS: &[(&str, &str)] = &[
+  ("a001", "recordreference"),
+  ("a002", "notificationtype"),
+  ("b221", "productidtype"),
+  ("b244", "idvalue"),
+  ("b202", "titletype"),
+  ("x409", "titleelementlevel"),
+  ("b203", "titletext"),
+  ("b030", "titleprefix"),
+  ("b031", "titlewithoutprefix"),
+  ("b029", "subtitle"),
+  ("b034", "sequencenumber"),
+  ("b035", "contributorrole"),
+  ("b036", "personname"),
+  ("b037", "personnameinverted"),
+  ("b047", "corporatename"),
+  ("x329", "collectiontype"),
+  ("x410", "partnumber"),
+  ("b253", "languagerole"),
+  ("b252", "languagecode"),
+  ("b218", "extenttype"),
+  ("b219", "extentvalue"),
+  ("b220", "extentunit"),
+  ("x426", "texttype"),
+  ("d104", "text"),
+  ("b291", "publishingrole"),
+  ("b081", "publishername"),
+  ("b209", "cityofpublication"),
+  ("x448", "publishingdaterole"),
+  ("b306", "date"),
+];
+
+/// Element paths within `<Product>` that the ingester reads. Anything else is reported as unmapped.
+const MAPPED: &[&str] = &[
+  "recordreference",
+  "notificationtype",
+  "productidentifier",
+  "descriptivedetail/titledetail",
+  "descriptivedetail/contributor",
+  "descriptivedetail/collection",
+  "descriptivedetail/language",
+  "descriptivedetail/extent",
+  "collateraldetail/textcontent/texttype",
+  "collateraldetail/textcontent/text",
+  "publishingdetail/publisher",
+  "publishingdetail/cityofpublication",
+  "publishingdetail/publishingdate",
+];
+
+/// A parsed XML element. ONIX carries everything we need in element text, so attributes are dropped.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Element {
+  pub name: String,
+  pub text: String,
+  pub children: Vec<Element>,
+}
+
+impl Element {
+  pub fn child(&self, name: &str) -> Option<&Element> {
+    self.children.iter().find(|child| child.name == name)
+  }
+
+  pub fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> {
+    self.children.iter().filter(move |child| child.name == name)
+  }
+
+  /// Trimmed text of the child called `name`, if it has any.
+  pub fn value(&self, name: &str) -> Option<&str> {
+    self.child(name).map(|child| child.text.trim()).filter(|text| !text.is_empty())
+  }
+
+  /// Every path from this element to a leaf, such as `descriptivedetail/subject/subjectcode`.
+  fn leaf_paths(&self, prefix: &str, paths: &mut Vec<String>) {
+    for child in &self.children {
+      let path = if prefix.is_empty() {
+        child.name.clone()
+      } else {
+        format!("{}/{}", prefix, child.name)
+      };
+      if child.children.is_empty() {
+        paths.push(path);
+      } else {
+        child.leaf_paths(&path, paths);
+      }
+    }
+  }
+}
+
+fn normalize_name(name: &[u8]) -> String {
+  let name = String::from_utf8_lossy(name).to_lowercase();
+  SHORT_TAGS
+    .iter()
+    .find(|(short, _)| *short == name)
+    .map_or(name, |(_, reference)| reference.to_string())
+}
+
+/// Reads every `<Product>` in an ONIX message into an element tree.
+pub fn parse_products(xml: &str) -> std::io::Result<Vec<Element>> {
+  let mut reader = Reader::from_str(xml);
+  let mut products = Vec::new();
+  let mut stack: Vec<Element> = Vec::new();
+
+  loop {
+    let event = reader
+      .read_event()
+      .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()))?;
+    match event {
+      Event::Start(element) => {
+        let name = normalize_name(element.local_name().as_ref());
+        if name == "product" || !stack.is_empty() {
+          stack.push(Element { name, ..Default::default() });
+        }
+      }
+      Event::Empty(element) => {
+        if let Some(parent) = stack.last_mut() {
+          parent.children.push(Element {
+            name: normalize_name(element.local_name().as_ref()),
+            ..Default::default()
+          });
+        }
+      }
+      Event::Text(text) => {
+        if let Some(current) = stack.last_mut() {
+          let text = text
+            .unescape()
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()))?;
+          current.text.push_str(&text);
+        }
+      }
+      Event::CData(text) => {
+        if let Some(current) = stack.last_mut() {
+          current.text.push_str(&String::from_utf8_lossy(&text));
+        }
+      }
+      Event::End(_) => {
+        if let Some(element) = stack.pop() {
+          match stack.last_mut() {
+            Some(parent) => parent.children.push(element),
+            None => products.push(element),
+          }
+        }
+      }
+      Event::Eof => break,
+      _ => {}
+    }
+  }
+  Ok(products)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Notification {
+  /// Codes 01 to 04: early, advance, confirmed and update notices all describe the product as it is now.
+  Upsert,
+  /// Code 05.
+  Delete,
+}
+
+/// A product in the terms of our catalog.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OnixProduct {
+  pub record_reference: String,
+  pub notification: Notification,
+  pub isbn: Option<String>,
+  pub title: Option<String>,
+  /// Display name and inverted sort name, in sequence order.
+  pub authors: Vec<(String, Option<String>)>,
+  pub publisher: Option<String>,
+  pub city: Option<String>,
+  pub date_published: Option<DateTime<Utc>>,
+  pub num_pages: Option<u16>,
+  pub language: Option<String>,
+  pub description: Option<String>,
+  pub series: Option<(String, Option<u16>)>,
+  /// Identifiers besides the ISBN, as scheme and value.
+  pub identifiers: Vec<(String, String)>,
+}
+
+/// ONIX dates are `YYYYMMDD` by default, though `YYYY` and `YYYYMM` are common.
+pub fn onix_date(value: &str) -> Option<DateTime<Utc>> {
+  let digits: String = value.chars().filter(char::is_ascii_digit).collect();
+  let year = digits.get(0..4)?.parse().ok()?;
+  let month = digits.get(4..6).and_then(|month| month.parse().ok()).unwrap_or(1);
+  let day = digits.get(6..8).and_then(|day| day.parse().ok()).unwrap_or(1);
+  NaiveDate::from_ymd_opt(year, month, day)?.and_hms_opt(0, 0, 0).map(|date| date.and_utc())
+}
+
+/// The distinctive title: `TitleText`, or a prefix and the title without it, followed by any subtitle.
+fn title(detail: &Element) -> Option<String> {
+  let element = detail
+    .children("titleelement")
+    .find(|element| element.value("titleelementlevel").is_none_or(|level| level == "01"))?;
+  let main = match (element.value("titletext"), element.value("titleprefix"), element.value("titlewithoutprefix")) {
+    (Some(text), _, _) => text.to_string(),
+    (None, Some(prefix), Some(rest)) => format!("{} {}", prefix, rest),
+    (None, None, Some(rest)) => rest.to_string(),
+    _ => return None,
+  };
+  Some(match element.value("subtitle") {
+    Some(subtitle) => format!("{}: {}", main, subtitle),
+    None => main,
+  })
+}
+
+fn scheme(id_type: &str) -> String {
+  match id_type {
+    "01" => String::from("proprietary"),
+    "03" => String::from("gtin"),
+    "06" => String::from("doi"),
+    "13" => String::from("lccn"),
+    code => format!("onix-{}", code),
+  }
+}
+
+impl OnixProduct {
+  pub fn from_element(product: &Element) -> OnixProduct {
+    let notification = match product.value("notificationtype") {
+      Some("05") => Notification::Delete,
+      _ => Notification::Upsert,
+    };
+
+    let mut isbn13 = None;
+    let mut isbn10 = None;
+    let mut identifiers = Vec::new();
+    for identifier in product.children("productidentifier") {
+      let (Some(id_type), Some(value)) = (identifier.value("productidtype"), identifier.value("idvalue")) else {
+        continue;
+      };
+      match id_type {
+        "15" => isbn13 = Some(normalize_isbn(value)),
+        "02" => isbn10 = Some(normalize_isbn(value)),
+        // A GTIN-13 in the Bookland range is an ISBN-13.
+        "03" if value.starts_with("978") || value.starts_with("979") => {
+          isbn13.get_or_insert_with(|| normalize_isbn(value));
+        }
+        _ => identifiers.push((scheme(id_type), value.to_string())),
+      }
+    }
+
+    let descriptive = product.child("descriptivedetail");
+    let title = descriptive.and_then(|detail| {
+      detail
+        .children("titledetail")
+        .find(|title| title.value("titletype").is_none_or(|kind| kind == "01"))
+        .and_then(title)
+    });
+
+    let mut contributors: Vec<(u32, String, Option<String>)> = descriptive
+      .into_iter()
+      .flat_map(|detail| detail.children("contributor"))
+      // Roles A01 to A99 are authorship; illustrators, editors and the like are left out.
+      .filter(|contributor| contributor.children("contributorrole").any(|role| role.text.trim().starts_with('A')))
+      .filter_map(|contributor| {
+        let sequence = contributor.value("sequencenumber").and_then(|number| number.parse().ok()).unwrap_or(u32::MAX);
+        let inverted = contributor.value("personnameinverted").map(String::from);
+        let name = contributor
+          .value("personname")
+          .or(contributor.value("corporatename"))
+          .map(String::from)
+          .or_else(|| inverted.as_deref().map(super::marc::uninvert))?;
+        Some((sequence, name, inverted))
+      })
+      .collect();
+    contributors.sort_by_key(|(sequence, _, _)| *sequence);
+
+    let series = descriptive
+      .into_iter()
+      .flat_map(|detail| detail.children("collection"))
+      .flat_map(|collection| collection.children("titledetail"))
+      .flat_map(|detail| detail.children("titleelement"))
+      .find(|element| element.value("titleelementlevel") == Some("02"))
+      .and_then(|element| {
+        let name = element.value("titletext").map(String::from).or_else(|| {
+          element
+            .value("titlewithoutprefix")
+            .map(|rest| format!("{} {}", element.value("titleprefix").unwrap_or_default(), rest).trim().to_string())
+        })?;
+        Some((name, element.value("partnumber").and_then(|part| part.parse().ok())))
+      });
+
+    let num_pages = descriptive
+      .into_iter()
+      .flat_map(|detail| detail.children("extent"))
+      .filter(|extent| extent.value("extentunit").is_none_or(|unit| unit == "03"))
+      .filter_map(|extent| Some((extent.value("extenttype")?, extent.value("extentvalue")?.parse::<u16>().ok()?)))
+      .min_by_key(|(kind, _)| match *kind {
+        "00" => 0,
+        "11" => 1,
+        "07" | "08" => 2,
+        _ => 3,
+      })
+      .map(|(_, pages)| pages);
+
+    let language = descriptive
+      .into_iter()
+      .flat_map(|detail| detail.children("language"))
+      .find(|language| language.value("languagerole").is_none_or(|role| role == "01"))
+      .and_then(|language| language.value("languagecode"))
+      .map(String::from);
+
+    let description = product
+      .child("collateraldetail")
+      .into_iter()
+      .flat_map(|detail| detail.children("textcontent"))
+      .filter_map(|text| Some((text.value("texttype")?, text.value("text")?)))
+      .min_by_key(|(kind, _)| match *kind {
+        "03" => 0,
+        "02" => 1,
+        _ => 2,
+      })
+      .filter(|(kind, _)| matches!(*kind, "02" | "03"))
+      .map(|(_, text)| super::calibre::strip_html(text));
+
+    let publishing = product.child("publishingdetail");
+    let publisher = publishing
+      .into_iter()
+      .flat_map(|detail| detail.children("publisher"))
+      .find(|publisher| publisher.value("publishingrole").is_none_or(|role| role == "01"))
+      .and_then(|publisher| publisher.value("publishername"))
+      .map(String::from);
+    let date_published = publishing
+      .into_iter()
+      .flat_map(|detail| detail.children("publishingdate"))
+      .find(|date| date.value("publishingdaterole") == Some("01"))
+      .and_then(|date| date.value("date"))
+      .and_then(onix_date);
+
+    OnixProduct {
+      record_reference: product.value("recordreference").unwrap_or_default().to_string(),
+      notification,
+      isbn: isbn13.or(isbn10).filter(|isbn| !isbn.is_empty()),
+      title,
+      authors: contributors.into_iter().map(|(_, name, inverted)| (name, inverted)).collect(),
+      publisher,
+      city: publishing.and_then(|detail| detail.value("cityofpublication")).map(String::from),
+      date_published,
+      num_pages,
+      language,
+      description,
+      series,
+      identifiers,
+    }
+  }
+}
+
+/// Leaf paths in `product` that the ingester does not read.
+pub fn unmapped_paths(product: &Element) -> Vec<String> {
+  let mut paths = Vec::new();
+  product.leaf_paths("", &mut paths);
+  paths
+    .into_iter()
+    .filter(|path| !MAPPED.iter().any(|mapped| path == mapped || path.starts_with(&format!("{}/", mapped))))
+    .collect()
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OnixReport {
+  pub products: usize,
+  pub created: usize,
+  pub updated: usize,
+  pub deleted: usize,
+  /// Products that could not be applied, numbered from 1 in message order.
+  pub errors: Vec<RowError>,
+  /// How many products carried each element path we do not map.
+  pub unmapped: BTreeMap<String, usize>,
+}
+
+/// Applies every product in an ONIX message in `tx`, which the caller commits. Products that cannot be applied are reported and skipped.
+pub async fn ingest<'a>(tx: &mut Transaction<'a, MySql>, products: &[Element]) -> Result<OnixReport, sqlx::Error> {
+  let mut report = OnixReport {
+    products: products.len(),
+    ..Default::default()
+  };
+
+  for (index, element) in products.iter().enumerate() {
+    let row = index as u64 + 1;
+    let paths: HashSet<String> = unmapped_paths(element).into_iter().collect();
+    for path in paths {
+      *report.unmapped.entry(path).or_default() += 1;
+    }
+
+    let product = OnixProduct::from_element(element);
+    let error = |message: String| RowError {
+      row,
+      column: Some(String::from("ProductIdentifier")),
+      message: format!("{} ({})", message, product.record_reference),
+    };
+    let Some(isbn) = product.isbn.clone() else {
+      report.errors.push(error(String::from("has no ISBN")));
+      continue;
+    };
+
+    match product.notification {
+      Notification::Delete => match Book::fetch_by_isbn(tx, &isbn).await? {
+        // Into the trash, so a mistaken delete notification can be undone until the book is purged.
+        Some(book) => {
+          Book::delete(tx, book.id).await?;
+          report.deleted += 1;
+        }
+        None => report.errors.push(error(format!("deletes ISBN {} which is not in the catalog", isbn))),
+      },
+      Notification::Upsert => {
+        let Some(title) = product.title.clone() else {
+          report.errors.push(error(String::from("has no distinctive title")));
+          continue;
+        };
+        let descriptor = Descriptor {
+          isbn,
+          title,
+          authors: product.authors.clone(),
+          publisher: product.publisher.clone(),
+          city: product.city.clone(),
+          date_published: product.date_published,
+          num_pages: product.num_pages,
+          language: product.language.clone(),
+          description: product.description.clone(),
+          series: product.series.clone(),
+        };
+        let (book, outcome) = upsert_descriptor(tx, &descriptor).await?;
+        if !product.identifiers.is_empty() {
+          // Schemes this message does not mention, such as those from a Calibre import, are kept.
+          let mut identifiers: Vec<(String, String)> = Identifier::fetch_by_book(tx, book.id)
+            .await?
+            .into_iter()
+            .filter(|existing| !product.identifiers.iter().any(|(scheme, _)| *scheme == existing.scheme))
+            .map(|existing| (existing.scheme, existing.value))
+            .collect();
+          identifiers.extend(product.identifiers.iter().cloned());
+          Identifier::set_for_book(tx, book.id, &identifiers).await?;
+        }
+        match outcome {
+          Outcome::Created => report.created += 1,
+          _ => report.updated += 1,
+        }
+      }
+    }
+  }
+
+  Ok(report)
+}
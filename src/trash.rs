@@ -0,0 +1,92 @@
+//! The trash. Deleting a book or an author only sets its `deleted_at`, which hides it from every fetch but leaves it
+//! restorable. Purging removes it for good along with the rows that depend on it, then any file and cover blobs nothing
+//! references any more.
+
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+  db::{audit::AuditEntry, authors::Author, books::Book, covers::Cover, files::BookFile, Db},
+  storage::{Storage, ORPHAN_GRACE},
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PurgeReport {
+  pub books: Vec<u64>,
+  pub authors: Vec<u64>,
+  /// Blobs removed from the file and cover stores.
+  pub files: usize,
+  pub covers: usize,
+}
+
+/// Purges the given trashed books and authors in one transaction, crediting `actor` in the audit log. Ids that are not
+/// in the trash fail the whole purge with `RowNotFound`.
+pub async fn purge(db: &Db, files: &Storage, covers: &Storage, book_ids: &[u64], author_ids: &[u64], actor: &str) -> Result<PurgeReport, sqlx::Error> {
+  let mut tx = db.conn.begin().await?;
+  AuditEntry::set_actor(&mut tx, actor).await?;
+
+  let mut file_hashes = HashSet::new();
+  let mut cover_hashes = HashSet::new();
+  for &book_id in book_ids {
+    file_hashes.extend(BookFile::fetch_by_book(&mut tx, book_id).await?.into_iter().map(|file| file.sha256));
+    cover_hashes.extend(Cover::fetch_thumbnails(&mut tx, book_id).await?.into_iter().map(|thumbnail| thumbnail.sha256));
+    match Cover::fetch_one(&mut tx, book_id).await {
+      Ok(cover) => {
+        cover_hashes.insert(cover.sha256);
+      }
+      Err(sqlx::Error::RowNotFound) => {}
+      Err(err) => return Err(err),
+    }
+    Book::purge(&mut tx, book_id).await?;
+  }
+  for &author_id in author_ids {
+    Author::purge(&mut tx, author_id).await?;
+  }
+
+  // Blobs are content-addressed, so another book may share one; only those nothing points at any more go.
+  let mut orphaned_files = Vec::new();
+  for sha256 in file_hashes {
+    if !BookFile::is_referenced(&mut tx, &sha256).await? {
+      orphaned_files.push(sha256);
+    }
+  }
+  let mut orphaned_covers = Vec::new();
+  for sha256 in cover_hashes {
+    if !Cover::is_referenced(&mut tx, &sha256).await? {
+      orphaned_covers.push(sha256);
+    }
+  }
+  tx.commit().await?;
+
+  // The rows are gone at this point, so a blob that cannot be removed is only an orphan for `/files/cleanup`. One
+  // uploaded again since, whose new row may not have committed yet, is left for it too.
+  let mut report = PurgeReport {
+    books: book_ids.to_vec(),
+    authors: author_ids.to_vec(),
+    ..Default::default()
+  };
+  for sha256 in orphaned_files {
+    match files.remove_if_older(&sha256, ORPHAN_GRACE).await {
+      Ok(removed) => report.files += usize::from(removed),
+      Err(err) => tracing::warn!(%sha256, error = %err, "could not remove purged file"),
+    }
+  }
+  for sha256 in orphaned_covers {
+    match covers.remove_if_older(&sha256, ORPHAN_GRACE).await {
+      Ok(removed) => report.covers += usize::from(removed),
+      Err(err) => tracing::warn!(%sha256, error = %err, "could not remove purged cover"),
+    }
+  }
+  Ok(report)
+}
+
+/// Purges everything that went into the trash before `cutoff`.
+pub async fn purge_expired(db: &Db, files: &Storage, covers: &Storage, cutoff: DateTime<Utc>, actor: &str) -> Result<PurgeReport, sqlx::Error> {
+  let mut tx = db.conn.begin().await?;
+  let book_ids = Book::fetch_expired(&mut tx, cutoff).await?;
+  let author_ids = Author::fetch_expired(&mut tx, cutoff).await?;
+  tx.commit().await?;
+
+  purge(db, files, covers, &book_ids, &author_ids, actor).await
+}
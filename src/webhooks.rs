@@ -0,0 +1,143 @@
+//! Webhook delivery. Events are queued in the database by the change that causes them (see
+//! [`WebhookDelivery::enqueue`]) and sent from there by a [`Dispatcher`], so a slow or unreachable receiver never holds
+//! up the request that made the change.
+//!
+//! Each delivery is a JSON `POST` signed with the webhook's secret: `X-Libby-Signature` is `sha256=` and the hex
+//! HMAC-SHA256 of the `X-Libby-Timestamp` value, a `.`, and the body. Receivers should recompute it and reject stale
+//! timestamps. Any 2xx response counts as delivered; anything else is retried with exponential backoff.
+
+use std::time::Duration;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+use crate::db::{
+  webhooks::{Webhook, WebhookDelivery},
+  Db,
+};
+
+/// Longest a receiver is given to answer.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Deliveries claimed and sent, one after another, per pass.
+const BATCH: u32 = 10;
+
+/// How long a claimed delivery is left alone before another dispatcher may try it: twice what a pass takes when every
+/// receiver in it times out, so the last delivery of a batch is still sent under its claim.
+const LEASE: chrono::Duration = chrono::Duration::seconds(2 * BATCH as i64 * TIMEOUT.as_secs() as i64);
+
+/// The signature header's value for `body` sent at `timestamp`.
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+  let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+  mac.update(timestamp.to_string().as_bytes());
+  mac.update(b".");
+  mac.update(body.as_bytes());
+  format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// The wait before retrying after the `attempt`th failure: 30 seconds, doubling each time, capped at six hours.
+pub fn backoff(attempt: u32) -> chrono::Duration {
+  let seconds = 30i64 << attempt.saturating_sub(1).min(20);
+  chrono::Duration::seconds(seconds.min(6 * 60 * 60))
+}
+
+/// What a single attempt came to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Attempt {
+  Delivered(u16),
+  /// The receiver answered with something other than 2xx.
+  Rejected(u16),
+  /// No response: the connection failed or timed out.
+  Unreachable(String),
+}
+
+/// Sends one delivery to `url`.
+pub async fn send(client: &reqwest::Client, url: &str, secret: &str, delivery_id: u64, event: &str, payload: Value) -> Attempt {
+  let body = json!({
+    "id": delivery_id,
+    "event": event,
+    "data": payload,
+  })
+  .to_string();
+  let timestamp = Utc::now().timestamp();
+
+  let response = client
+    .post(url)
+    .header(reqwest::header::CONTENT_TYPE, "application/json")
+    .header("X-Libby-Event", event)
+    .header("X-Libby-Delivery", delivery_id.to_string())
+    .header("X-Libby-Timestamp", timestamp.to_string())
+    .header("X-Libby-Signature", sign(secret, timestamp, &body))
+    .body(body)
+    .send()
+    .await;
+  match response {
+    Ok(response) if response.status().is_success() => Attempt::Delivered(response.status().as_u16()),
+    Ok(response) => Attempt::Rejected(response.status().as_u16()),
+    Err(err) => Attempt::Unreachable(err.to_string()),
+  }
+}
+
+#[derive(Clone)]
+pub struct Dispatcher {
+  pub db: Db,
+  pub client: reqwest::Client,
+  /// Attempts per delivery before it is marked failed.
+  pub max_attempts: u32,
+  /// Failed attempts in a row, across all of a webhook's deliveries, before the webhook is disabled.
+  pub disable_after: u32,
+}
+
+impl Dispatcher {
+  pub fn new(db: Db, max_attempts: u32, disable_after: u32) -> Self {
+    let client = reqwest::Client::builder()
+      .timeout(TIMEOUT)
+      .user_agent(concat!("libby-rs/", env!("CARGO_PKG_VERSION")))
+      .build()
+      .expect("the TLS backend initializes");
+    Dispatcher {
+      db,
+      client,
+      max_attempts,
+      disable_after,
+    }
+  }
+
+  /// Sends the deliveries that are due, returning how many were attempted.
+  pub async fn run_once(&self) -> Result<usize, sqlx::Error> {
+    let mut tx = self.db.conn.begin().await?;
+    let due = WebhookDelivery::claim_due(&mut tx, BATCH, Utc::now() + LEASE).await?;
+    tx.commit().await?;
+
+    for delivery in &due {
+      let mut tx = self.db.conn.begin().await?;
+      let webhook = Webhook::fetch_one(&mut tx, delivery.webhook_id).await?;
+      tx.commit().await?;
+
+      let payload = serde_json::from_str(&delivery.payload).unwrap_or(Value::Null);
+      let attempt = send(&self.client, &webhook.url, &webhook.secret, delivery.id, &delivery.event, payload).await;
+
+      let mut tx = self.db.conn.begin().await?;
+      let (status, error) = match attempt {
+        Attempt::Delivered(status) => {
+          WebhookDelivery::mark_delivered(&mut tx, delivery.id, status).await?;
+          Webhook::record_success(&mut tx, webhook.id).await?;
+          tx.commit().await?;
+          continue;
+        }
+        Attempt::Rejected(status) => (Some(status), format!("HTTP {}", status)),
+        Attempt::Unreachable(error) => (None, error),
+      };
+      let attempts = delivery.attempts + 1;
+      let retry_at = (attempts < self.max_attempts).then(|| Utc::now() + backoff(attempts));
+      WebhookDelivery::mark_failed(&mut tx, delivery.id, status, &error, retry_at).await?;
+      if Webhook::record_failure(&mut tx, webhook.id, self.disable_after).await? && webhook.enabled {
+        tracing::warn!(webhook = webhook.id, failures = self.disable_after, "webhook disabled after failing repeatedly");
+      }
+      tx.commit().await?;
+    }
+    Ok(due.len())
+  }
+}